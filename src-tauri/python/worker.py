@@ -5,10 +5,12 @@ Sample Map Python Worker
 - Compute CLAP (HTSAT-base) 512-D embeddings (48kHz mono, first N sec)
 - Compute 2D UMAP (cosine) and store normalized coords
 - Update SQLite DB tables: embeddings(file_id,dim,vec), coords(file_id,x,y)
+- `serve`: stay running with the model loaded, answering JSON-line requests on stdin
 """
 from __future__ import annotations
 
 import argparse
+import json
 import os
 import sqlite3
 import subprocess
@@ -37,6 +39,9 @@ def venv_python() -> Path:
 
 
 def ensure_venv_and_deps() -> None:
+    if os.environ.get('SMAP_MANAGED_VENV') == '1':
+        # The app's pyenv.rs already set the venv up and launched us inside it
+        return
     py = venv_python()
     if not py.exists():
         vdir = appdata_dir() / 'venv'
@@ -50,6 +55,7 @@ def ensure_venv_and_deps() -> None:
         have = False
     if not have:
         print('[worker] installing dependencies...', flush=True)
+        # Same list as REQUIREMENTS in pyenv.rs
         pkgs = [
             'numpy',
             'soundfile',
@@ -77,6 +83,14 @@ def reexec_in_venv(argv: List[str]) -> None:
 
 # ---- Embedding + UMAP ----
 
+def torch_device(device: str):
+    """The torch device for a resolved `--device` name ('cpu', 'cuda' or 'directml')."""
+    if device == 'directml':
+        import torch_directml
+        return torch_directml.device()
+    return device
+
+
 def load_model(device: str = 'cpu'):
     import laion_clap
     # HTSAT-base audio encoder; fusion off (audio only)
@@ -97,11 +111,7 @@ def load_model(device: str = 'cpu'):
         pass
     model.eval()
     try:
-        import torch
-        if device == 'cuda' and torch.cuda.is_available():
-            model = model.to('cuda')
-        else:
-            model = model.to('cpu')
+        model = model.to(torch_device(device))
     except Exception:
         pass
     return model
@@ -121,8 +131,8 @@ def embed_files(model, paths: List[Path], sr: int, duration: float, device: str)
                 raise RuntimeError('empty audio')
             # Model expects batch of mono waveforms at 48k
             wav = torch.tensor(y, dtype=torch.float32).unsqueeze(0)
-            if device == 'cuda' and torch.cuda.is_available():
-                wav = wav.to('cuda', non_blocking=True)
+            if device != 'cpu':
+                wav = wav.to(torch_device(device), non_blocking=True)
             with torch.inference_mode():
                 feats = model.get_audio_embedding_from_data(x=wav, use_tensor=True)  # [1, D]
                 v = feats.squeeze(0).cpu().numpy().astype(np.float32)
@@ -138,95 +148,233 @@ def embed_files(model, paths: List[Path], sr: int, duration: float, device: str)
     return outs
 
 
-def run_pipeline(db_path: Path, dur: float, neighbors: int, min_dist: float, limit: int | None = None, device: str = 'auto', mode: str = 'all') -> None:
-    conn = sqlite3.connect(db_path)
-    conn.row_factory = sqlite3.Row
-    conn.execute("CREATE TABLE IF NOT EXISTS embeddings (file_id INTEGER PRIMARY KEY, dim INTEGER NOT NULL, vec BLOB NOT NULL)")
-    conn.execute("CREATE TABLE IF NOT EXISTS coords (file_id INTEGER PRIMARY KEY, x REAL NOT NULL, y REAL NOT NULL)")
+def stored_path(v) -> Path:
+    """files.path is TEXT, or a BLOB of the raw OS-string bytes when the name isn't valid UTF-8."""
+    if isinstance(v, bytes):
+        return Path(v.decode('utf-16-le', 'surrogatepass') if os.name == 'nt' else os.fsdecode(v))
+    return Path(v)
 
-    # Fetch files without embeddings
-    q = "SELECT id, path FROM files WHERE id NOT IN (SELECT file_id FROM embeddings) ORDER BY id"
-    if limit:
-        q += f" LIMIT {int(limit)}"
-    rows = conn.execute(q).fetchall()
-    all_rows = conn.execute("SELECT id FROM files ORDER BY id").fetchall()
 
-    if not rows and not all_rows:
-        print('[worker] no files in DB')
-        return
+def encode_vec(vec, dtype: str) -> bytes:
+    """Packs an embedding the way the app's quant.rs expects for `dtype`."""
+    import numpy as np
+    v = np.asarray(vec, dtype=np.float32)
+    if dtype == 'f16':
+        return v.astype('<f2').tobytes()
+    if dtype == 'int8':
+        m = float(np.abs(v).max()) if v.size else 0.0
+        scale = m / 127.0 if m > 0 else 1.0
+        q = np.clip(np.round(v / scale), -127, 127).astype(np.int8)
+        return np.float32(scale).astype('<f4').tobytes() + q.tobytes()
+    return v.astype('<f4').tobytes()
+
+
+def decode_vec(buf: bytes, dtype: str):
+    import numpy as np
+    if dtype == 'f16':
+        return np.frombuffer(buf, dtype='<f2').astype(np.float32)
+    if dtype == 'int8':
+        scale = np.frombuffer(buf[:4], dtype='<f4')[0]
+        return np.frombuffer(buf[4:], dtype=np.int8).astype(np.float32) * scale
+    return np.frombuffer(buf, dtype='<f4')
 
-    # Choose device and only load model if embedding needed
+
+def table_exists(conn, name: str) -> bool:
+    return conn.execute("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?", (name,)).fetchone() is not None
+
+
+def apply_pins(Y, ids, pins: dict):
+    """Fits the layout onto pinned positions and then places pinned files exactly.
+
+    A similarity transform (rotation/reflection, uniform scale, translation) from the pinned
+    files' fresh positions to their pins is applied to every point, so the rest of the map moves
+    with its landmarks instead of being torn away from them. One pin only translates.
+    """
+    import numpy as np
+    idx = [i for i, fid in enumerate(ids) if fid in pins]
+    if not idx:
+        return Y
+    Y = Y.copy()
+    src = Y[idx, :2]
+    dst = np.array([pins[ids[i]] for i in idx], dtype=Y.dtype)
+    mu_s, mu_d = src.mean(axis=0), dst.mean(axis=0)
+    P, Q = src - mu_s, dst - mu_d
+    spread = float((P ** 2).sum())
+    if len(idx) >= 2 and spread > 1e-12:
+        # Kabsch/Umeyama on row vectors: Q ~ s * P @ R
+        U, S, Vt = np.linalg.svd(P.T @ Q)
+        R = U @ Vt
+        scale = float(S.sum()) / spread
+        Y[:, :2] = scale * (Y[:, :2] - mu_s) @ R + mu_d
+    else:
+        Y[:, :2] = Y[:, :2] - mu_s + mu_d
+    Y[idx, :2] = dst
+    return Y
+
+
+def emit_progress(stage: str, processed: int, total: int, batch: int | None = None) -> None:
+    """One JSON line the app parses into the scan status; keep it on a line of its own."""
+    print(json.dumps({'type': 'progress', 'stage': stage, 'processed': processed, 'total': total, 'batch': batch}), flush=True)
+
+
+def resolve_device(device: str) -> str:
+    """Turns a `--device` choice into the device to use, falling back to CPU only for 'auto'."""
     use_device = device
     try:
         import torch
         if device == 'auto':
             use_device = 'cuda' if torch.cuda.is_available() else 'cpu'
+            if use_device == 'cpu':
+                try:
+                    import torch_directml
+                    if torch_directml.device_count() > 0:
+                        use_device = 'directml'
+                except ImportError:
+                    pass
     except Exception:
         use_device = 'cpu'
-    if use_device == 'cuda':
+    if use_device != 'cpu':
         try:
             import torch
-            _ = torch.randn(1, device='cuda')
+            _ = torch.randn(1).to(torch_device(use_device))
         except Exception as e:
-            print(f"[worker] CUDA not usable ({e}); falling back to CPU")
+            if device != 'auto':
+                # An explicit choice shouldn't quietly become a much slower CPU run
+                raise RuntimeError(f'device {use_device} not usable: {e}')
+            print(f"[worker] {use_device} not usable ({e}); falling back to CPU")
             use_device = 'cpu'
-    print(f"[worker] device: {use_device}")
+    print(f"[worker] device: {use_device}", flush=True)
+    return use_device
+
+
+def set_threads(threads: int | None) -> None:
+    if threads:
+        try:
+            import torch
+            torch.set_num_threads(threads)
+        except Exception:
+            pass
+
+
+def store_embeddings(conn: sqlite3.Connection, model, rows, dur: float, dtype: str, device: str) -> List[int]:
+    """Embeds `rows` (id, path) as the built-in model and commits them; returns the ids stored."""
+    paths = [stored_path(r['path']) for r in rows]
+    stored = []
+    for j, vec in embed_files(model, paths, sr=48000, duration=dur, device=device):
+        fid = rows[j]['id']
+        conn.execute(
+            "INSERT OR REPLACE INTO embeddings(file_id, model_id, dim, vec, dtype) VALUES(?,1,?,?,?)",
+            (fid, len(vec), encode_vec(vec, dtype), dtype)
+        )
+        stored.append(fid)
+    conn.commit()
+    return stored
+
+
+def run_pipeline(db_path: Path, dur: float, neighbors: int, min_dist: float, limit: int | None = None, device: str = 'auto', mode: str = 'all', metric: str = 'cosine', seed: int = 42, method: str = 'umap', perplexity: float = 30.0, components: int = 2, layout_id: int | None = None, dtype: str = 'f32', batch_size: int | None = None, threads: int | None = None) -> None:
+    conn = sqlite3.connect(db_path)
+    conn.row_factory = sqlite3.Row
+    conn.execute("CREATE TABLE IF NOT EXISTS embeddings (file_id INTEGER NOT NULL, model_id INTEGER NOT NULL DEFAULT 1, dim INTEGER NOT NULL, vec BLOB NOT NULL, dtype TEXT NOT NULL DEFAULT 'f32', outlier_score REAL, PRIMARY KEY(file_id, model_id))")
+    conn.execute("CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT)")
+    conn.execute("CREATE VIEW IF NOT EXISTS active_embeddings AS SELECT * FROM embeddings WHERE model_id = COALESCE((SELECT CAST(value AS INTEGER) FROM meta WHERE key = 'active_model'), 1)")
+    conn.execute("CREATE TABLE IF NOT EXISTS coords (file_id INTEGER PRIMARY KEY, x REAL NOT NULL, y REAL NOT NULL)")
+
+    # Fetch files without embeddings. The worker computes the built-in CLAP model (id 1); other
+    # registered models are native only
+    q = "SELECT id, path FROM files WHERE id NOT IN (SELECT file_id FROM embeddings WHERE model_id = 1) ORDER BY id"
+    if limit:
+        q += f" LIMIT {int(limit)}"
+    rows = conn.execute(q).fetchall()
+    all_rows = conn.execute("SELECT id FROM files ORDER BY id").fetchall()
+
+    if not rows and not all_rows:
+        print('[worker] no files in DB')
+        return
+
+    # Choose device and only load model if embedding needed
+    use_device = resolve_device(device)
     model = None
     do_embed = (mode in ('embed','all')) and len(rows) > 0
     if do_embed:
         model = load_model(device=use_device)
 
-    sr = 48000
     # Batch embed new files in small groups to limit RAM
-    BATCH = 32 if use_device == 'cuda' else 16
-    import struct
-    from math import ceil
+    BATCH = batch_size or (16 if use_device == 'cpu' else 32)
+    set_threads(threads)
     n = len(rows)
     if do_embed:
         print(f'[worker] embedding {n} new files', flush=True)
+        emit_progress('embedding', 0, n)
         for i in range(0, n, BATCH):
-            batch = rows[i:i+BATCH]
-            paths = [Path(r['path']) for r in batch]
-            embs = embed_files(model, paths, sr=sr, duration=dur, device=use_device)
-            for j, vec in embs:
-                fid = batch[j]['id']
-                blob = struct.pack('<' + 'f'*len(vec), *vec)
-                conn.execute(
-                    "INSERT OR REPLACE INTO embeddings(file_id, dim, vec) VALUES(?,?,?)",
-                    (fid, len(vec), blob)
-                )
-            conn.commit()
-
-    # Build UMAP over all embeddings
-    print('[worker] computing UMAP', flush=True)
+            store_embeddings(conn, model, rows[i:i+BATCH], dur=dur, dtype=dtype, device=use_device)
+            emit_progress('embedding', min(i + BATCH, n), n, batch=i // BATCH)
+    if mode == 'embed':
+        conn.close()
+        return
+
+    # Project all embeddings to 2D (or 3D)
+    print(f'[worker] computing {method.upper()}', flush=True)
+    emit_progress('projecting', 0, 0)
     import numpy as np
-    import umap
-    embed_rows = conn.execute("SELECT file_id, vec FROM embeddings ORDER BY file_id").fetchall()
+    embed_rows = conn.execute("SELECT file_id, vec, dtype FROM active_embeddings ORDER BY file_id").fetchall()
     if not embed_rows:
         print('[worker] no embeddings present')
         return
     ids = [r[0] for r in embed_rows]
     vecs = []
     for r in embed_rows:
-        buf = r[1]
-        arr = np.frombuffer(buf, dtype='<f4')
-        vecs.append(arr)
+        vecs.append(decode_vec(r[1], r[2]))
     X = np.vstack(vecs)
-    reducer = umap.UMAP(n_neighbors=neighbors, min_dist=min_dist, metric='cosine', random_state=42)
-    Y = reducer.fit_transform(X).astype('f4')
+    Y = project(X, method=method, neighbors=neighbors, min_dist=min_dist, metric=metric, seed=seed, perplexity=perplexity, components=components)
     # Normalize to [-1, 1]
     mins = Y.min(axis=0)
     maxs = Y.max(axis=0)
     rng = np.maximum(maxs - mins, 1e-6)
     Yn = (Y - mins) / rng * 2.0 - 1.0
-
+    # Pinned files are anchors the new layout is fitted onto
+    pins = {}
+    if table_exists(conn, 'pins'):
+        pins = {r[0]: (r[1], r[2]) for r in conn.execute("SELECT file_id, x, y FROM pins")}
+    Yn = apply_pins(Yn, ids, pins)
+
+    # z stays NULL for 2D layouts
+    rows_xyz = [(int(fid), float(p[0]), float(p[1]), float(p[2]) if len(p) > 2 else None) for fid, p in zip(ids, Yn.tolist())]
+    # Each run is its own layout; coords mirrors the newest one as the active map
+    if layout_id is None:
+        n = conn.execute("SELECT COUNT(*) FROM layouts WHERE method = ?", (method,)).fetchone()[0]
+        cur = conn.execute(
+            "INSERT INTO layouts(name, method, created_at) VALUES(?, ?, CAST(strftime('%s','now') AS INTEGER))",
+            (f'{method.upper()} {n + 1}', method),
+        )
+        layout_id = cur.lastrowid
+    conn.executemany(
+        "INSERT OR REPLACE INTO layout_coords(layout_id, file_id, x, y, z) VALUES(?,?,?,?,?)",
+        [(layout_id, fid, x, y, z) for fid, x, y, z in rows_xyz]
+    )
+    conn.execute("DELETE FROM coords")
     conn.executemany(
-        "INSERT OR REPLACE INTO coords(file_id, x, y) VALUES(?,?,?)",
-        [(int(fid), float(x), float(y)) for fid, (x, y) in zip(ids, Yn.tolist())]
+        "INSERT INTO coords(file_id, x, y, z, layout_id) VALUES(?,?,?,?,?)",
+        [(fid, x, y, z, layout_id) for fid, x, y, z in rows_xyz]
     )
     conn.commit()
     conn.close()
 
+
+def project(X, method: str, neighbors: int, min_dist: float, metric: str, seed: int, perplexity: float, components: int = 2):
+    import numpy as np
+    if method == 'pca':
+        Xc = X - X.mean(axis=0)
+        _u, _s, vt = np.linalg.svd(Xc, full_matrices=False)
+        return (Xc @ vt[:components].T).astype('f4')
+    if method == 'tsne':
+        from sklearn.manifold import TSNE
+        # Perplexity must stay below the sample count
+        p = max(1.0, min(perplexity, (X.shape[0] - 1) / 3.0))
+        return TSNE(n_components=components, perplexity=p, metric=metric, init='pca', random_state=seed).fit_transform(X).astype('f4')
+    import umap
+    reducer = umap.UMAP(n_components=components, n_neighbors=neighbors, min_dist=min_dist, metric=metric, random_state=seed)
+    return reducer.fit_transform(X).astype('f4')
+
 def ingest(db_path: Path, root: Path) -> None:
     import time
     import soundfile as sf
@@ -282,18 +430,87 @@ def ingest(db_path: Path, root: Path) -> None:
     conn.close()
 
 
+# ---- Persistent mode ----
+
+def reply(req_id, result=None, error: str | None = None) -> None:
+    msg = {'type': 'response', 'id': req_id}
+    if error is None:
+        msg['result'] = result
+    else:
+        msg['error'] = error
+    print(json.dumps(msg), flush=True)
+
+
+def serve(device: str, threads: int | None) -> int:
+    """Answers one JSON request per stdin line until `shutdown` or EOF, keeping the model loaded:
+    `{"id": 1, "method": "embed_batch", "params": {...}}` gets `{"type": "response", "id": 1,
+    "result": ...}` (or `"error"`); progress lines may come before it."""
+    import traceback
+    use_device = resolve_device(device)
+    set_threads(threads)
+    model = None
+    for line in sys.stdin:
+        if not line.strip():
+            continue
+        req = json.loads(line)
+        req_id, method, params = req.get('id'), req.get('method'), req.get('params') or {}
+        try:
+            if method == 'embed_batch':
+                # params: db, file_ids, duration, dtype
+                if model is None:
+                    model = load_model(device=use_device)
+                conn = sqlite3.connect(params['db'])
+                conn.row_factory = sqlite3.Row
+                ids = [int(i) for i in params['file_ids']]
+                rows = conn.execute(f"SELECT id, path FROM files WHERE id IN ({','.join('?' * len(ids))}) ORDER BY id", ids).fetchall() if ids else []
+                stored = store_embeddings(conn, model, rows, dur=params.get('duration', 10.0), dtype=params.get('dtype', 'f32'), device=use_device)
+                conn.close()
+                reply(req_id, {'embedded': stored})
+            elif method == 'project':
+                # params: db plus the one-shot command's projection options
+                p = params
+                run_pipeline(Path(p['db']), dur=10.0, neighbors=p.get('n_neighbors', 50), min_dist=p.get('min_dist', 0.05), metric=p.get('metric', 'cosine'), seed=p.get('seed', 42), method=p.get('method', 'umap'), perplexity=p.get('perplexity', 30.0), components=p.get('components', 2), layout_id=p.get('layout_id'), dtype=p.get('dtype', 'f32'), device=use_device, mode='umap')
+                reply(req_id, {})
+            elif method == 'shutdown':
+                reply(req_id, {})
+                return 0
+            else:
+                reply(req_id, error=f'unknown method {method!r}')
+        except Exception as e:
+            traceback.print_exc()
+            reply(req_id, error=f'{type(e).__name__}: {e}')
+    return 0
+
+
 def main() -> int:
     # Ensure venv and re-exec if needed
     ensure_venv_and_deps()
     reexec_in_venv(sys.argv)
 
+    if len(sys.argv) > 1 and sys.argv[1] == 'serve':
+        sp = argparse.ArgumentParser()
+        sp.add_argument('command', choices=['serve'])
+        sp.add_argument('--device', type=str, default='auto', choices=['auto', 'cpu', 'cuda', 'directml'])
+        sp.add_argument('--threads', type=int, default=None)
+        sargs = sp.parse_args()
+        return serve(sargs.device, sargs.threads)
+
     ap = argparse.ArgumentParser()
     ap.add_argument('db', type=Path)
     ap.add_argument('command', choices=['ingest', 'embed', 'umap', 'all'])
     ap.add_argument('--duration', type=float, default=10.0)
     ap.add_argument('--n_neighbors', type=int, default=50)
     ap.add_argument('--min_dist', type=float, default=0.05)
-    ap.add_argument('--device', type=str, default='auto', choices=['auto','cpu','cuda'])
+    ap.add_argument('--metric', type=str, default='cosine', choices=['cosine', 'euclidean', 'manhattan', 'correlation'])
+    ap.add_argument('--seed', type=int, default=42)
+    ap.add_argument('--method', type=str, default='umap', choices=['umap', 'pca', 'tsne'])
+    ap.add_argument('--perplexity', type=float, default=30.0)
+    ap.add_argument('--components', type=int, default=2, choices=[2, 3])
+    ap.add_argument('--dtype', type=str, default='f32', choices=['f32', 'f16', 'int8'], help='Encoding for newly stored embeddings')
+    ap.add_argument('--layout_id', type=int, default=None, help='Layout row to fill; a new one is created when omitted')
+    ap.add_argument('--device', type=str, default='auto', choices=['auto', 'cpu', 'cuda', 'directml'])
+    ap.add_argument('--batch_size', type=int, default=None, help='Files per embedding batch; default depends on the device')
+    ap.add_argument('--threads', type=int, default=None, help='CPU threads for torch')
     ap.add_argument('--root', type=Path, default=None, help='Root folder to ingest when command=ingest')
     args = ap.parse_args()
 
@@ -303,14 +520,14 @@ def main() -> int:
             return 2
         ingest(args.db, args.root)
     elif args.command == 'embed':
-        run_pipeline(args.db, dur=args.duration, neighbors=args.n_neighbors, min_dist=args.min_dist, device=args.device)
+        run_pipeline(args.db, dur=args.duration, neighbors=args.n_neighbors, min_dist=args.min_dist, metric=args.metric, seed=args.seed, method=args.method, perplexity=args.perplexity, components=args.components, dtype=args.dtype, device=args.device, batch_size=args.batch_size, threads=args.threads, mode='embed')
     elif args.command == 'umap':
-        # Force only UMAP over current embeddings
-        run_pipeline(args.db, dur=args.duration, neighbors=args.n_neighbors, min_dist=args.min_dist, device=args.device)
+        # Only UMAP over current embeddings (e.g. after the app embedded natively)
+        run_pipeline(args.db, dur=args.duration, neighbors=args.n_neighbors, min_dist=args.min_dist, metric=args.metric, seed=args.seed, method=args.method, perplexity=args.perplexity, components=args.components, layout_id=args.layout_id, dtype=args.dtype, device=args.device, batch_size=args.batch_size, threads=args.threads, mode='umap')
     else:  # all
         lim_env = os.environ.get('SMAP_EMBED_LIMIT')
         limit = int(lim_env) if lim_env else None
-        run_pipeline(args.db, dur=args.duration, neighbors=args.n_neighbors, min_dist=args.min_dist, limit=limit, device=args.device)
+        run_pipeline(args.db, dur=args.duration, neighbors=args.n_neighbors, min_dist=args.min_dist, metric=args.metric, seed=args.seed, method=args.method, perplexity=args.perplexity, components=args.components, layout_id=args.layout_id, dtype=args.dtype, limit=limit, device=args.device, batch_size=args.batch_size, threads=args.threads)
 
     return 0
 
@@ -0,0 +1,151 @@
+//! Ableton Live clips for the selection. Live can't tell a dropped WAV's name or grouping once
+//! it's in a slot, so we hand it `.alc` clips (or one `.als` set) that reference the samples in
+//! place: warp off, full length, named after the file and grouped by cluster in the set.
+//! Both are gzipped XML in Live 11's schema; Live fills in everything we leave out.
+
+use crate::{
+    db,
+    export::{self, escape_xml},
+    playback,
+};
+use anyhow::{bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use rusqlite::{Connection, OptionalExtension};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// Tempo of the generated sets; unwarped clips play at their own speed regardless.
+const TEMPO: f64 = 120.0;
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AbletonOptions {
+    /// Writes one `.als` set with a track per cluster instead of an `.alc` per sample.
+    pub set: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AbletonReport {
+    pub paths: Vec<PathBuf>,
+    pub clips: usize,
+}
+
+struct Clip {
+    name: String,
+    path: PathBuf,
+    frames: u64,
+    rate: u32,
+    cluster: Option<i64>,
+}
+
+/// Writes the clips (or `<dest>/<name>.als`) into `dest`, creating it.
+pub fn export_clips(conn: &Connection, file_ids: &[i64], dest: &Path, name: &str, opts: &AbletonOptions) -> Result<AbletonReport> {
+    let clips = load_clips(conn, file_ids)?;
+    if clips.is_empty() { bail!("no files to export"); }
+    std::fs::create_dir_all(dest).with_context(|| format!("create {}", dest.display()))?;
+    let paths = if opts.set {
+        let mut groups: BTreeMap<Option<i64>, Vec<&Clip>> = BTreeMap::new();
+        for c in &clips { groups.entry(c.cluster).or_default().push(c); }
+        let tracks: Vec<String> = groups
+            .iter()
+            .enumerate()
+            .map(|(i, (cluster, clips))| {
+                let track = cluster.map_or_else(|| "Unclustered".to_string(), |c| format!("Cluster {c}"));
+                audio_track(i, &track, clips)
+            })
+            .collect();
+        let path = dest.join(format!("{name}.als"));
+        write_gz(&path, &live_set(&tracks.concat()))?;
+        vec![path]
+    } else {
+        let mut taken = HashSet::new();
+        let mut out = Vec::with_capacity(clips.len());
+        for c in &clips {
+            let mut target = dest.join(format!("{}.alc", c.name));
+            if target.exists() || taken.contains(&target) { target = export::free_name(&target, &taken); }
+            write_gz(&target, &live_set(&audio_track(0, &c.name, &[c])))?;
+            taken.insert(target.clone());
+            out.push(target);
+        }
+        out
+    };
+    Ok(AbletonReport { paths, clips: clips.len() })
+}
+
+/// Clips for the files still on disk, with lengths from the scan or, failing that, a decode.
+fn load_clips(conn: &Connection, file_ids: &[i64]) -> Result<Vec<Clip>> {
+    let mut out = Vec::with_capacity(file_ids.len());
+    for &id in file_ids {
+        let Some(path) = db::file_path(conn, id)? else { continue };
+        if !path.is_file() { continue; }
+        let row: Option<(Option<f64>, Option<u32>, Option<i64>)> = conn
+            .query_row("SELECT duration, sample_rate, cluster_id FROM files WHERE id = ?1", [id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .optional()?;
+        let Some((duration, rate, cluster)) = row else { continue };
+        let (frames, rate) = match (duration, rate) {
+            (Some(d), Some(r)) if d > 0.0 && r > 0 => ((d * f64::from(r)).round() as u64, r),
+            _ => {
+                let (channels, rate, data) = playback::decode_samples(&path).with_context(|| format!("read {}", path.display()))?;
+                (data.len() as u64 / u64::from(channels.max(1)), rate)
+            }
+        };
+        let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        out.push(Clip { name, path, frames, rate, cluster });
+    }
+    Ok(out)
+}
+
+fn write_gz(path: &Path, xml: &str) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut gz = GzEncoder::new(file, Compression::default());
+    gz.write_all(xml.as_bytes())?;
+    gz.finish().with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+
+fn live_set(tracks: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Ableton MajorVersion=\"5\" MinorVersion=\"11.0_433\" SchemaChangeCount=\"3\" Creator=\"Sample Map\" Revision=\"\">\n\
+         <LiveSet>\n<Tracks>\n{tracks}</Tracks>\n\
+         <MasterTrack><DeviceChain><Mixer><Tempo><Manual Value=\"{TEMPO}\" /></Tempo></Mixer></DeviceChain></MasterTrack>\n\
+         </LiveSet>\n</Ableton>\n"
+    )
+}
+
+/// An audio track with one session slot per clip.
+fn audio_track(index: usize, name: &str, clips: &[&Clip]) -> String {
+    let mut slots = String::new();
+    for (i, c) in clips.iter().enumerate() {
+        let _ = writeln!(slots, "<ClipSlot Id=\"{i}\"><ClipSlot><Value>{}</Value></ClipSlot></ClipSlot>", audio_clip(c));
+    }
+    format!(
+        "<AudioTrack Id=\"{id}\">\n<Name><EffectiveName Value=\"{name}\" /><UserName Value=\"{name}\" /></Name>\n\
+         <DeviceChain><MainSequencer><ClipSlotList>\n{slots}</ClipSlotList></MainSequencer></DeviceChain>\n</AudioTrack>\n",
+        id = index + 10,
+        name = escape_xml(name),
+    )
+}
+
+/// Unwarped clips keep their loop bounds in seconds rather than beats.
+fn audio_clip(c: &Clip) -> String {
+    let seconds = c.frames as f64 / f64::from(c.rate);
+    let path = escape_xml(&c.path.to_string_lossy());
+    format!(
+        "<AudioClip Time=\"0\">\
+         <CurrentStart Value=\"0\" /><CurrentEnd Value=\"{seconds}\" />\
+         <Loop><LoopStart Value=\"0\" /><LoopEnd Value=\"{seconds}\" /><StartRelative Value=\"0\" /><LoopOn Value=\"false\" /></Loop>\
+         <Name Value=\"{name}\" /><IsWarped Value=\"false\" />\
+         <SampleRef><FileRef><RelativePathType Value=\"0\" /><RelativePath Value=\"\" /><Path Value=\"{path}\" /><Type Value=\"1\" /></FileRef>\
+         <DefaultDuration Value=\"{frames}\" /><DefaultSampleRate Value=\"{rate}\" /></SampleRef>\
+         </AudioClip>",
+        name = escape_xml(&c.name),
+        frames = c.frames,
+        rate = c.rate,
+    )
+}
@@ -0,0 +1,332 @@
+use crate::{models, quant};
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Links per node on the upper layers; layer 0 keeps twice as many.
+const M: usize = 16;
+const EF_CONSTRUCTION: usize = 100;
+const MAGIC: &[u8; 8] = b"SMHNSW01";
+
+/// Index file kept next to the library database, one per embedding model. The built-in model
+/// keeps the original name.
+pub fn index_path(db: &Path, model: i64) -> PathBuf {
+    if model == models::BUILTIN { db.with_extension("hnsw") } else { db.with_extension(format!("m{model}.hnsw")) }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Cand {
+    dist: f32,
+    node: u32,
+}
+
+impl Eq for Cand {}
+
+impl Ord for Cand {
+    fn cmp(&self, o: &Self) -> Ordering { self.dist.total_cmp(&o.dist).then(self.node.cmp(&o.node)) }
+}
+
+impl PartialOrd for Cand {
+    fn partial_cmp(&self, o: &Self) -> Option<Ordering> { Some(self.cmp(o)) }
+}
+
+/// Hierarchical navigable small-world graph over unit-normalized embeddings, so cosine distance
+/// is `1 - dot`. Files whose embedding disappeared are tombstoned rather than unlinked.
+pub struct Hnsw {
+    dim: usize,
+    ids: Vec<i64>,
+    vecs: Vec<f32>,
+    /// node → layer → neighbor nodes.
+    links: Vec<Vec<Vec<u32>>>,
+    removed: Vec<bool>,
+    by_id: HashMap<i64, u32>,
+    entry: Option<u32>,
+    rng: u64,
+}
+
+impl Default for Hnsw {
+    fn default() -> Self {
+        Self {
+            dim: 0,
+            ids: Vec::new(),
+            vecs: Vec::new(),
+            links: Vec::new(),
+            removed: Vec::new(),
+            by_id: HashMap::new(),
+            entry: None,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+}
+
+fn normalized(v: &[f32]) -> Vec<f32> {
+    let n = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if n == 0.0 { return v.to_vec(); }
+    v.iter().map(|x| x / n).collect()
+}
+
+impl Hnsw {
+    fn vec(&self, n: u32) -> &[f32] {
+        let i = n as usize * self.dim;
+        &self.vecs[i..i + self.dim]
+    }
+
+    fn dist(&self, q: &[f32], n: u32) -> f32 {
+        1.0 - q.iter().zip(self.vec(n)).map(|(a, b)| a * b).sum::<f32>()
+    }
+
+    /// Geometric level draw with the usual 1/ln(M) normalization (xorshift64*, deterministic per index).
+    fn random_level(&mut self) -> usize {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        let u = ((r >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-u.ln() / (M as f64).ln()) as usize
+    }
+
+    /// The `ef` nodes closest to `q` reachable on `layer` from `entry`, nearest first.
+    fn search_layer(&self, q: &[f32], entry: &[u32], ef: usize, layer: usize) -> Vec<Cand> {
+        let mut visited: HashSet<u32> = entry.iter().copied().collect();
+        let mut frontier: BinaryHeap<Reverse<Cand>> = entry.iter().map(|&n| Reverse(Cand { dist: self.dist(q, n), node: n })).collect();
+        let mut best: BinaryHeap<Cand> = frontier.iter().map(|c| c.0).collect();
+        while let Some(Reverse(c)) = frontier.pop() {
+            let worst = best.peek().map_or(f32::INFINITY, |b| b.dist);
+            if best.len() >= ef && c.dist > worst { break; }
+            for &nb in &self.links[c.node as usize][layer] {
+                if !visited.insert(nb) { continue; }
+                let d = self.dist(q, nb);
+                if best.len() < ef || d < best.peek().map_or(f32::INFINITY, |b| b.dist) {
+                    frontier.push(Reverse(Cand { dist: d, node: nb }));
+                    best.push(Cand { dist: d, node: nb });
+                    if best.len() > ef { best.pop(); }
+                }
+            }
+        }
+        best.into_sorted_vec()
+    }
+
+    /// Descends from the entry point to `layer + 1` greedily, returning the closest node found.
+    fn descend(&self, q: &[f32], mut ep: u32, layer: usize) -> u32 {
+        for l in (layer + 1..self.links[ep as usize].len()).rev() {
+            ep = self.search_layer(q, &[ep], 1, l).first().map_or(ep, |c| c.node);
+        }
+        ep
+    }
+
+    fn insert(&mut self, id: i64, v: &[f32]) {
+        if self.ids.is_empty() { self.dim = v.len(); }
+        let node = self.ids.len() as u32;
+        let q = normalized(v);
+        let level = self.random_level();
+        self.ids.push(id);
+        self.vecs.extend_from_slice(&q);
+        self.links.push(vec![Vec::new(); level + 1]);
+        self.removed.push(false);
+        self.by_id.insert(id, node);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+        let top = self.links[entry as usize].len() - 1;
+        let mut eps = vec![self.descend(&q, entry, level)];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&q, &eps, EF_CONSTRUCTION, layer);
+            let max = if layer == 0 { 2 * M } else { M };
+            let chosen: Vec<u32> = found.iter().take(M).map(|c| c.node).collect();
+            for &nb in &chosen {
+                self.links[nb as usize][layer].push(node);
+                if self.links[nb as usize][layer].len() > max { self.prune(nb, layer, max); }
+            }
+            self.links[node as usize][layer] = chosen;
+            eps = found.into_iter().map(|c| c.node).collect();
+        }
+        if level > top { self.entry = Some(node); }
+    }
+
+    /// Keeps only the `max` closest links of node `n` on `layer`.
+    fn prune(&mut self, n: u32, layer: usize, max: usize) {
+        let base = self.vec(n).to_vec();
+        let mut nbs: Vec<Cand> = self.links[n as usize][layer].iter().map(|&x| Cand { dist: self.dist(&base, x), node: x }).collect();
+        nbs.sort();
+        nbs.truncate(max);
+        self.links[n as usize][layer] = nbs.into_iter().map(|c| c.node).collect();
+    }
+
+    /// Up to `k` (file id, cosine distance) pairs nearest to `q`, nearest first. Larger `ef`
+    /// trades speed for recall.
+    pub fn search(&self, q: &[f32], k: usize, ef: usize) -> Vec<(i64, f32)> {
+        let Some(entry) = self.entry else { return Vec::new() };
+        if q.len() != self.dim { return Vec::new(); }
+        let q = normalized(q);
+        let ep = self.descend(&q, entry, 0);
+        self.search_layer(&q, &[ep], ef.max(k), 0)
+            .into_iter()
+            .filter(|c| !self.removed[c.node as usize])
+            .take(k)
+            .map(|c| (self.ids[c.node as usize], c.dist))
+            .collect()
+    }
+
+    /// Adds embeddings not yet indexed and tombstones ones that are gone, replacing those
+    /// `embedding_changes` lists for `model` up to `seq`. Returns whether anything changed.
+    fn sync(&mut self, conn: &Connection, model: i64, seq: i64) -> Result<bool> {
+        let live: HashSet<i64> = {
+            let mut stmt = conn.prepare("SELECT file_id FROM active_embeddings")?;
+            let ids = stmt.query_map([], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+            ids
+        };
+        let mut changed = false;
+        // Rewritten since indexed, possibly under an id a deleted file had: re-added from `live`
+        let mut stmt = conn.prepare("SELECT file_id FROM embedding_changes WHERE model_id = ? AND seq <= ?")?;
+        for id in stmt.query_map(params![model, seq], |r| r.get::<_, i64>(0))? {
+            if let Some(&n) = self.by_id.get(&id?) {
+                if !self.removed[n as usize] {
+                    self.removed[n as usize] = true;
+                    changed = true;
+                }
+            }
+        }
+        for (id, &n) in &self.by_id {
+            if !self.removed[n as usize] && !live.contains(id) {
+                self.removed[n as usize] = true;
+                changed = true;
+            }
+        }
+        let mut missing: Vec<i64> = live.into_iter().filter(|id| !matches!(self.by_id.get(id), Some(&n) if !self.removed[n as usize])).collect();
+        missing.sort_unstable();
+        let mut stmt = conn.prepare("SELECT vec, dtype FROM active_embeddings WHERE file_id = ?")?;
+        for id in missing {
+            let (blob, dtype): (Vec<u8>, String) = stmt.query_row(params![id], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            let v = quant::decode_row(&blob, &dtype);
+            // One dimension per index; vectors from another model are left out
+            if !self.ids.is_empty() && v.len() != self.dim { continue; }
+            self.insert(id, &v);
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("hnsw.tmp");
+        {
+            let mut w = BufWriter::new(fs::File::create(&tmp)?);
+            w.write_all(MAGIC)?;
+            w.write_all(&(self.dim as u32).to_le_bytes())?;
+            w.write_all(&(self.ids.len() as u32).to_le_bytes())?;
+            w.write_all(&self.entry.map_or(-1, i64::from).to_le_bytes())?;
+            w.write_all(&self.rng.to_le_bytes())?;
+            for (n, id) in self.ids.iter().enumerate() {
+                w.write_all(&id.to_le_bytes())?;
+                w.write_all(&[self.removed[n] as u8, self.links[n].len() as u8])?;
+                for layer in &self.links[n] {
+                    w.write_all(&(layer.len() as u32).to_le_bytes())?;
+                    for nb in layer { w.write_all(&nb.to_le_bytes())?; }
+                }
+            }
+            for x in &self.vecs { w.write_all(&x.to_le_bytes())?; }
+            w.flush()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let mut r = BufReader::new(fs::File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { bail!("not a samplemap index"); }
+        let dim = read_u32(&mut r)? as usize;
+        let count = read_u32(&mut r)? as usize;
+        let entry = i64::from_le_bytes(read_array(&mut r)?);
+        let rng = u64::from_le_bytes(read_array(&mut r)?);
+        let mut idx = Self { dim, rng, entry: u32::try_from(entry).ok(), ..Self::default() };
+        for n in 0..count {
+            let id = i64::from_le_bytes(read_array(&mut r)?);
+            let [removed, layers] = read_array(&mut r)?;
+            let mut links = Vec::with_capacity(layers as usize);
+            for _ in 0..layers {
+                let len = read_u32(&mut r)? as usize;
+                let layer = (0..len).map(|_| read_u32(&mut r)).collect::<std::io::Result<Vec<_>>>()?;
+                if layer.iter().any(|&nb| nb as usize >= count) { bail!("corrupt index: link out of range"); }
+                links.push(layer);
+            }
+            if layers == 0 { bail!("corrupt index: node without layers"); }
+            idx.ids.push(id);
+            idx.removed.push(removed != 0);
+            idx.links.push(links);
+            if removed == 0 { idx.by_id.insert(id, n as u32); }
+        }
+        idx.vecs = (0..count * dim).map(|_| read_array(&mut r).map(f32::from_le_bytes)).collect::<std::io::Result<_>>()?;
+        if idx.entry.is_some_and(|e| e as usize >= count) { bail!("corrupt index: entry out of range"); }
+        Ok(idx)
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut b = [0u8; N];
+    r.read_exact(&mut b)?;
+    Ok(b)
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    read_array(r).map(u32::from_le_bytes)
+}
+
+struct Loaded {
+    db: PathBuf,
+    model: i64,
+    index: Hnsw,
+    /// Last `embedding_changes` seq handed out at the last sync, which consumed rows don't lower;
+    /// cheap change detection.
+    stamp: Option<i64>,
+}
+
+/// The index of the active library, loaded on first use and kept in step with its embeddings.
+#[derive(Default)]
+pub struct AnnCache {
+    loaded: Mutex<Option<Loaded>>,
+}
+
+impl AnnCache {
+    /// Brings the index for `db`'s active model up to date with its embeddings, persisting any
+    /// change, then runs `f` on it. A missing or unreadable index file is rebuilt from scratch.
+    pub fn with<T>(&self, conn: &Connection, db: &Path, f: impl FnOnce(&Hnsw) -> T) -> Result<T> {
+        let model = models::active(conn)?;
+        let mut guard = self.loaded.lock();
+        if !matches!(&*guard, Some(l) if l.db == db && l.model == model) {
+            let path = index_path(db, model);
+            let index = match Hnsw::load(&path) {
+                Ok(idx) => idx,
+                Err(e) => {
+                    if path.exists() { log::warn!("ann: rebuilding unreadable index {}: {e}", path.display()); }
+                    Hnsw::default()
+                }
+            };
+            *guard = Some(Loaded { db: db.to_path_buf(), model, index, stamp: None });
+        }
+        let l = guard.as_mut().expect("index loaded above");
+        let stamp = conn.query_row("SELECT COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'embedding_changes'), 0)", [], |r| r.get(0))?;
+        if l.stamp != Some(stamp) {
+            // A failed save only costs a rebuild next launch; the changes stay logged for it
+            let saved = !l.index.sync(conn, model, stamp)? || match l.index.save(&index_path(db, model)) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("ann: could not persist index for {}: {e}", db.display());
+                    false
+                }
+            };
+            if saved { conn.execute("DELETE FROM embedding_changes WHERE model_id = ? AND seq <= ?", params![model, stamp])?; }
+            l.stamp = Some(stamp);
+        }
+        Ok(f(&l.index))
+    }
+}
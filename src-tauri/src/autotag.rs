@@ -0,0 +1,86 @@
+//! Zero-shot tagging from the audio embeddings: each label is turned into a CLAP text prompt,
+//! and a file gets the label whose prompt it's closest to when that wins a softmax over all
+//! labels by `settings.autotag.min_confidence`. These machine tags live in `file_tags` with
+//! `source = 'auto'` and a confidence, so the UI can tell them apart, and user tags take over
+//! a tag of the same name.
+
+use crate::{
+    db, embed::TextModel, models, quant,
+    settings::AutoTagSettings,
+    similar::cosine_distance,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+
+/// CLAP's learned logit scale, so similarities spread the way they did in training.
+const LOGIT_SCALE: f32 = 100.0;
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoTagSummary {
+    pub tagged: usize,
+    pub considered: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// Tags embedded files that have no machine tag yet; with `force`, replaces every machine tag.
+pub fn run(
+    app: &tauri::AppHandle,
+    conn: &mut Connection,
+    text: &TextModel,
+    cfg: &AutoTagSettings,
+    force: bool,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+) -> Result<AutoTagSummary> {
+    let labels: Vec<String> = cfg.labels.iter().filter_map(|l| db::normalize_tag(l)).collect();
+    if labels.is_empty() { return Ok(AutoTagSummary::default()); }
+    // The prompts come from CLAP's text encoder, so only CLAP audio vectors are comparable
+    if models::active(conn)? != models::BUILTIN { bail!("auto-tagging needs the built-in CLAP model to be active"); }
+    let prompts = labels.iter().map(|l| text.embed(app, &format!("the sound of a {l}"))).collect::<Result<Vec<_>>>()?;
+
+    let rows: Vec<(i64, Vec<f32>)> = {
+        let mut stmt = conn.prepare(
+            "SELECT e.file_id, e.vec, e.dtype FROM active_embeddings e \
+             WHERE ?1 OR NOT EXISTS (SELECT 1 FROM file_tags t WHERE t.file_id = e.file_id AND t.source = 'auto')",
+        )?;
+        let rows = stmt
+            .query_map(params![force], |r| Ok((r.get(0)?, quant::decode_row(r.get_ref(1)?.as_blob()?, r.get_ref(2)?.as_str()?))))?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let total = rows.len();
+    let mut summary = AutoTagSummary { considered: total, ..Default::default() };
+    let tx = conn.transaction()?;
+    {
+        let mut clear = tx.prepare("DELETE FROM file_tags WHERE file_id = ? AND source = 'auto'")?;
+        let mut insert = tx.prepare("INSERT OR IGNORE INTO file_tags(file_id, tag, source, confidence) VALUES(?, ?, 'auto', ?)")?;
+        for (i, (id, v)) in rows.iter().enumerate() {
+            if cancel.is_requested() { bail!("cancelled"); }
+            if i % 1000 == 0 { on_progress(&Progress { stage: "tagging".into(), processed: i, total, batch: None }); }
+            if force { clear.execute([id])?; }
+            if prompts.first().is_some_and(|p| p.len() != v.len()) { continue; }
+            let Some((label, confidence)) = best_label(v, &prompts) else { continue };
+            if confidence < cfg.min_confidence { continue; }
+            insert.execute(params![id, labels[label], confidence])?;
+            summary.tagged += 1;
+            summary.file_ids.push(*id);
+        }
+    }
+    tx.commit()?;
+    on_progress(&Progress { stage: "tagging".into(), processed: total, total, batch: None });
+    Ok(summary)
+}
+
+/// Index of the closest prompt and its softmax share across all prompts.
+fn best_label(v: &[f32], prompts: &[Vec<f32>]) -> Option<(usize, f32)> {
+    let logits: Vec<f32> = prompts.iter().map(|p| (1.0 - cosine_distance(v, p)) * LOGIT_SCALE).collect();
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() { return None; }
+    let exp: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    let (best, e) = exp.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+    Some((best, e / sum))
+}
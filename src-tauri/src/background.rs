@@ -0,0 +1,177 @@
+//! Low-priority enrichment of files already in the library: durations and format fields a scan
+//! couldn't read, content hashes, waveform thumbnails, then the native analyses. It runs on its
+//! own thread, only while the scan queue is idle, and steps aside as soon as a job is queued.
+//! A pass starts when the library has changed since the last complete one, so files that can't
+//! be decoded aren't retried in a loop.
+
+use crate::{
+    db, events, paths,
+    scan::{self, Analysis, ScanManager},
+    settings, waveform,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use std::{path::PathBuf, sync::Arc, thread, time::Duration};
+use tauri::AppHandle;
+
+/// Left alone at startup so opening the app stays quick.
+const STARTUP_DELAY: Duration = Duration::from_secs(30);
+const POLL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackgroundStatus {
+    /// A pass is underway (it may be paused while a job runs).
+    pub running: bool,
+    pub stage: String,
+    pub processed: usize,
+    pub total: usize,
+}
+
+#[derive(Default)]
+pub struct Enricher {
+    status: Arc<Mutex<BackgroundStatus>>,
+}
+
+/// Library path plus file count, newest id and mtime sum: changes whenever files are added,
+/// removed or rewritten.
+type Signature = (PathBuf, i64, i64, i64);
+
+impl Enricher {
+    pub fn status(&self) -> BackgroundStatus {
+        self.status.lock().clone()
+    }
+
+    pub fn start(&self, app: AppHandle, scans: Arc<ScanManager>) {
+        let status = self.status.clone();
+        thread::spawn(move || {
+            thread::sleep(STARTUP_DELAY);
+            let mut done: Option<Signature> = None;
+            loop {
+                thread::sleep(POLL);
+                if !scans.is_idle() || !settings::load(&app).io.background_analysis { continue; }
+                let Ok(dbfile) = db::db_path(&app) else { continue };
+                let conn = match db::open_or_create(&dbfile) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log::warn!("background: {e:#}");
+                        continue;
+                    }
+                };
+                let sig = match signature(&conn, dbfile) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::warn!("background: {e:#}");
+                        continue;
+                    }
+                };
+                if done.as_ref() == Some(&sig) { continue; }
+                status.lock().running = true;
+                // Cancelled from the progress callback the moment a job shows up
+                let cancel = Cancel::default();
+                let on_progress = |p: &Progress| {
+                    if !scans.is_idle() { cancel.request(); }
+                    let mut s = status.lock();
+                    s.stage.clone_from(&p.stage);
+                    s.processed = p.processed;
+                    s.total = p.total;
+                };
+                match run_pass(&app, &conn, &on_progress, &cancel) {
+                    Ok(()) => {
+                        // Measured after the pass: filling in what was missing doesn't count as a change
+                        done = signature(&conn, sig.0.clone()).ok();
+                        *status.lock() = BackgroundStatus::default();
+                    }
+                    Err(e) if cancel.is_requested() => log::info!("background: paused for a job ({e})"),
+                    Err(e) => {
+                        log::warn!("background: {e:#}");
+                        done = Some(sig);
+                        *status.lock() = BackgroundStatus::default();
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn signature(conn: &Connection, dbfile: PathBuf) -> Result<Signature> {
+    let (n, max_id, mtimes) = conn.query_row("SELECT COUNT(*), COALESCE(MAX(id), 0), COALESCE(SUM(mtime), 0) FROM files", [], |r| {
+        Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+    })?;
+    Ok((dbfile, n, max_id, mtimes))
+}
+
+fn run_pass(app: &AppHandle, conn: &Connection, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<()> {
+    let headers = fill_headers(conn, on_progress, cancel)?;
+    events::metadata_changed(app, &headers);
+    fill_hashes(conn, on_progress, cancel)?;
+    fill_waveforms(conn, on_progress, cancel)?;
+    for kind in Analysis::ALL {
+        let (ids, failed) = kind.run(conn, false, on_progress, cancel)?;
+        if failed > 0 { log::info!("background: {}: {failed} files couldn't be decoded", kind.label()); }
+        events::metadata_changed(app, &ids);
+    }
+    Ok(())
+}
+
+fn todo(conn: &Connection, sql: &str) -> Result<Vec<(i64, PathBuf)>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get::<_, db::StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Runs `f` over each file, reporting progress as `stage` and stopping on `cancel`.
+fn each(
+    files: Vec<(i64, PathBuf)>,
+    stage: &str,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+    mut f: impl FnMut(i64, &PathBuf) -> Result<()>,
+) -> Result<()> {
+    let total = files.len();
+    for (i, (id, path)) in files.iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: stage.into(), processed: i, total, batch: None });
+        f(*id, path)?;
+    }
+    Ok(())
+}
+
+/// Duration and format for files the scan couldn't read a header from; returns the ids filled.
+fn fill_headers(conn: &Connection, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<Vec<i64>> {
+    let files = todo(conn, "SELECT id, path FROM files WHERE duration IS NULL ORDER BY id")?;
+    let mut filled = Vec::new();
+    each(files, "durations", on_progress, cancel, |id, path| {
+        let Ok(h) = scan::wav_header(&paths::extended(path)) else { return Ok(()) };
+        conn.execute(
+            "UPDATE files SET duration = ?, sample_rate = COALESCE(sample_rate, ?), bits_per_sample = COALESCE(bits_per_sample, ?), \
+             channels = COALESCE(channels, ?) WHERE id = ?",
+            params![h.duration, h.sample_rate, h.bits_per_sample, h.channels, id],
+        )?;
+        filled.push(id);
+        Ok(())
+    })?;
+    Ok(filled)
+}
+
+fn fill_hashes(conn: &Connection, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<()> {
+    let files = todo(conn, "SELECT id, path FROM files WHERE content_hash IS NULL ORDER BY id")?;
+    each(files, "hashing", on_progress, cancel, |id, path| {
+        match scan::content_hash(path) {
+            Ok(h) => { conn.execute("UPDATE files SET content_hash = ? WHERE id = ?", params![h, id])?; }
+            Err(e) => log::debug!("background: hash {}: {e:#}", path.display()),
+        }
+        Ok(())
+    })
+}
+
+/// Thumbnails missing or older than their file; `thumbnails` computes and stores them.
+fn fill_waveforms(conn: &Connection, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<()> {
+    let files = todo(
+        conn,
+        "SELECT f.id, f.path FROM files f LEFT JOIN waveforms w ON w.file_id = f.id WHERE w.file_id IS NULL OR w.mtime <> f.mtime ORDER BY f.id",
+    )?;
+    each(files, "waveforms", on_progress, cancel, |id, _| waveform::thumbnails(conn, &[id]).map(drop))
+}
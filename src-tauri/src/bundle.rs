@@ -0,0 +1,142 @@
+//! Shareable kits: one zip holding the samples under `samples/` and `library.sqlite`, a cut-down
+//! library with their metadata, tags, embeddings and map coords. Importing unpacks the samples
+//! into a folder and merges the rest like [`merge::import_library`], so a kit arrives already
+//! placed and tagged.
+
+use crate::{
+    db::{self, SqlPath, StoredPath},
+    export,
+    merge::{self, ImportSummary, MergeStrategy},
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, Connection};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+const LIBRARY_ENTRY: &str = "library.sqlite";
+const SAMPLES_DIR: &str = "samples";
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleReport {
+    pub path: PathBuf,
+    pub files: usize,
+}
+
+/// Writes the files still on disk, and what the library knows about them, to the zip at `path`.
+pub fn export_bundle(conn: &Connection, file_ids: &[i64], path: &Path) -> Result<BundleReport> {
+    let mut entries = Vec::with_capacity(file_ids.len());
+    let mut taken = HashSet::new();
+    for &id in file_ids {
+        let Some(src) = db::file_path(conn, id)? else { continue };
+        if !src.is_file() { continue; }
+        let name = src.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut entry = format!("{SAMPLES_DIR}/{name}");
+        if !taken.insert(entry.clone()) {
+            let p = Path::new(&name);
+            let stem = p.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+            let ext = p.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+            entry = format!("{SAMPLES_DIR}/{stem} ({id}){ext}");
+            taken.insert(entry.clone());
+        }
+        entries.push((id, src, entry));
+    }
+    if entries.is_empty() { bail!("none of the selected files exist on disk"); }
+
+    let db_path = temp_library();
+    let res = write_library(conn, &db_path, &entries).and_then(|()| {
+        let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+        let mut zip = ZipWriter::new(BufWriter::new(file));
+        // Audio barely deflates; only the library is worth compressing
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored).large_file(true);
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file(LIBRARY_ENTRY, deflated)?;
+        std::io::copy(&mut File::open(&db_path)?, &mut zip)?;
+        for (_, src, entry) in &entries {
+            zip.start_file(entry.as_str(), stored)?;
+            std::io::copy(&mut File::open(src).with_context(|| format!("read {}", src.display()))?, &mut zip)?;
+        }
+        zip.finish()?;
+        Ok(())
+    });
+    let _ = std::fs::remove_file(&db_path);
+    res?;
+    Ok(BundleReport { path: path.to_path_buf(), files: entries.len() })
+}
+
+/// Copies the rows for `entries` into a fresh database at `db_path`, with each file's path
+/// replaced by its entry in the zip.
+fn write_library(conn: &Connection, db_path: &Path, entries: &[(i64, PathBuf, String)]) -> Result<()> {
+    let Some(name) = db_path.to_str() else { bail!("temp path is not valid UTF-8: {}", db_path.display()) };
+    conn.execute("ATTACH DATABASE ? AS bundle", params![name])?;
+    let res = (|| -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE bundle.files AS SELECT id, path, name, size_bytes, duration, mtime, favorite, rating, content_hash, \
+                 sample_rate, bits_per_sample, channels FROM main.files WHERE 0;
+             CREATE TABLE bundle.embeddings AS SELECT file_id, model_id, dim, vec, dtype FROM main.embeddings WHERE 0;
+             CREATE TABLE bundle.coords AS SELECT file_id, x, y FROM main.coords WHERE 0;
+             CREATE TABLE bundle.file_tags AS SELECT file_id, tag FROM main.file_tags WHERE 0;",
+        )?;
+        for (id, _, entry) in entries {
+            conn.execute(
+                "INSERT INTO bundle.files SELECT id, ?2, name, size_bytes, duration, mtime, favorite, rating, content_hash, \
+                     sample_rate, bits_per_sample, channels FROM main.files WHERE id = ?1",
+                params![id, entry],
+            )?;
+            conn.execute("INSERT INTO bundle.embeddings SELECT file_id, model_id, dim, vec, dtype FROM main.embeddings WHERE file_id = ?", [id])?;
+            conn.execute("INSERT INTO bundle.coords SELECT file_id, x, y FROM main.coords WHERE file_id = ?", [id])?;
+            conn.execute("INSERT INTO bundle.file_tags SELECT file_id, tag FROM main.file_tags WHERE file_id = ? AND source = 'user'", [id])?;
+        }
+        Ok(())
+    })();
+    let _ = conn.execute("DETACH DATABASE bundle", []);
+    res
+}
+
+/// Unpacks the bundle's samples into `dest` (renaming around files already there) and merges
+/// its library into `conn`.
+pub fn import_bundle(conn: &mut Connection, bundle: &Path, dest: &Path, strategy: MergeStrategy) -> Result<ImportSummary> {
+    let file = File::open(bundle).with_context(|| format!("open {}", bundle.display()))?;
+    let mut zip = ZipArchive::new(BufReader::new(file)).context("not a sample bundle")?;
+    std::fs::create_dir_all(dest).with_context(|| format!("create {}", dest.display()))?;
+
+    let db_path = temp_library();
+    let res = (|| -> Result<ImportSummary> {
+        {
+            let mut entry = zip.by_name(LIBRARY_ENTRY).context("not a sample bundle (no library)")?;
+            std::io::copy(&mut entry, &mut File::create(&db_path)?)?;
+        }
+        let lib = Connection::open(&db_path)?;
+        let rows: Vec<(i64, PathBuf)> = {
+            let mut stmt = lib.prepare("SELECT id, path FROM files")?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+            rows
+        };
+        let mut taken = HashSet::new();
+        for (id, entry_name) in rows {
+            // Only plain names under samples/; anything else in the zip is ignored
+            let Some(file_name) = entry_name.strip_prefix(SAMPLES_DIR).ok().filter(|p| p.components().count() == 1) else { continue };
+            let mut target = dest.join(file_name);
+            if target.exists() || taken.contains(&target) { target = export::free_name(&target, &taken); }
+            let mut entry = zip.by_name(&entry_name.to_string_lossy()).with_context(|| format!("bundle is missing {}", entry_name.display()))?;
+            std::io::copy(&mut entry, &mut File::create(&target).with_context(|| format!("create {}", target.display()))?)?;
+            lib.execute("UPDATE files SET path = ? WHERE id = ?", params![SqlPath(&target), id])?;
+            taken.insert(target);
+        }
+        // Rows whose sample didn't unpack would point nowhere
+        lib.execute("DELETE FROM files WHERE path LIKE 'samples/%'", [])?;
+        drop(lib);
+        merge::import_library(conn, &db_path, strategy)
+    })();
+    let _ = std::fs::remove_file(&db_path);
+    res
+}
+
+fn temp_library() -> PathBuf {
+    std::env::temp_dir().join(format!("sample-map-bundle-{}.sqlite", uuid::Uuid::new_v4()))
+}
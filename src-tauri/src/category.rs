@@ -0,0 +1,116 @@
+//! One-shot / loop / long-form labels from cheap features: duration, onset density, how much
+//! of the level is left at the end, and the tempo pass's periodicity. One-shots are short or
+//! die away with at most a couple of hits; loops keep going with repeated onsets or a clear
+//! pulse; anything past [`LONG_FORM_SECONDS`] is a recording rather than a sample.
+
+use crate::{
+    db::StoredPath,
+    playback,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+use std::path::PathBuf;
+
+pub const LONG_FORM_SECONDS: f64 = 30.0;
+/// Shorter than this is a one-shot whatever it sounds like.
+const MAX_ONE_SHOT_SECONDS: f64 = 1.0;
+/// Tempo confidence that alone makes a file a loop.
+const PULSE_CONFIDENCE: f64 = 0.3;
+const FRAME_SECONDS: f64 = 0.01;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Category {
+    OneShot,
+    Loop,
+    LongForm,
+}
+
+impl Category {
+    /// Stored spelling, the same as the serialized one.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::OneShot => "oneShot",
+            Self::Loop => "loop",
+            Self::LongForm => "longForm",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [Self::OneShot, Self::Loop, Self::LongForm].into_iter().find(|c| c.as_str() == s)
+    }
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CategorySummary {
+    pub analyzed: usize,
+    pub failed: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// Labels files without a category; with `force`, all of them. Runs after tempo detection,
+/// whose confidence it uses where present.
+pub fn analyze(conn: &Connection, force: bool, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<CategorySummary> {
+    let todo: Vec<(i64, PathBuf, Option<f64>, Option<f64>)> = {
+        let mut stmt = conn.prepare("SELECT id, path, duration, bpm_confidence FROM files WHERE category IS NULL OR ?1 ORDER BY id")?;
+        let rows = stmt
+            .query_map(params![force], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0, r.get(2)?, r.get(3)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let total = todo.len();
+    let mut summary = CategorySummary::default();
+    for (i, (id, path, duration, pulse)) in todo.into_iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "classifying".into(), processed: i, total, batch: None });
+        // Long files are labelled from the header alone
+        let category = match duration {
+            Some(d) if d >= LONG_FORM_SECONDS => Category::LongForm,
+            _ => match playback::decode_samples(&path) {
+                Ok((channels, rate, data)) => classify(&data, channels, rate, pulse),
+                Err(e) => {
+                    log::warn!("classify: {}: {e:#}", path.display());
+                    summary.failed += 1;
+                    continue;
+                }
+            },
+        };
+        conn.execute("UPDATE files SET category = ? WHERE id = ?", params![category.as_str(), id])?;
+        summary.analyzed += 1;
+        summary.file_ids.push(id);
+    }
+    on_progress(&Progress { stage: "classifying".into(), processed: total, total, batch: None });
+    Ok(summary)
+}
+
+/// `pulse` is the tempo pass's confidence, if it ran on this file.
+pub fn classify(data: &[f32], channels: u16, rate: u32, pulse: Option<f64>) -> Category {
+    let ch = usize::from(channels.max(1));
+    let duration = data.len() as f64 / ch as f64 / f64::from(rate.max(1));
+    if duration >= LONG_FORM_SECONDS { return Category::LongForm; }
+    if duration < MAX_ONE_SHOT_SECONDS { return Category::OneShot; }
+    if pulse.is_some_and(|c| c >= PULSE_CONFIDENCE) { return Category::Loop; }
+
+    // RMS per 10 ms frame of the mono mix
+    let frame = ((f64::from(rate) * FRAME_SECONDS) as usize).max(1) * ch;
+    let envelope: Vec<f64> = data
+        .chunks(frame)
+        .map(|c| (c.iter().map(|&v| f64::from(v).powi(2)).sum::<f64>() / c.len() as f64).sqrt())
+        .collect();
+    let peak = envelope.iter().copied().fold(0.0, f64::max);
+    if peak <= 0.0 { return Category::OneShot; }
+
+    // Onsets: frames at least 6 dB over the one before that clear a tenth of the peak
+    let onsets = envelope.windows(2).filter(|w| w[1] > 2.0 * w[0].max(1e-6) && w[1] > 0.1 * peak).count();
+    let density = onsets as f64 / duration;
+    let quarter = (envelope.len() / 4).max(1);
+    let mean = |s: &[f64]| s.iter().sum::<f64>() / s.len().max(1) as f64;
+    let tail = mean(&envelope[envelope.len() - quarter..]) / mean(&envelope[..quarter]).max(1e-9);
+
+    if onsets <= 2 && tail < 0.1 { return Category::OneShot; }
+    if density >= 1.5 && tail >= 0.25 { return Category::Loop; }
+    if density < 0.5 { Category::OneShot } else { Category::Loop }
+}
@@ -0,0 +1,267 @@
+use crate::{db::StoredPath, quant};
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    thread,
+};
+
+const MAX_ITER: usize = 25;
+/// Stop once fewer than this fraction of files change cluster in an iteration.
+const CONVERGED: f64 = 0.001;
+
+/// Tiny xorshift64* so runs are reproducible without pulling in a rand crate.
+struct Rng(u64);
+
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Roughly √(n/2), kept within a range the map can still color distinctly.
+pub fn default_k(n: usize) -> usize {
+    ((n as f64 / 2.0).sqrt() as usize).clamp(2, 64)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalize(v: &mut [f32]) {
+    let n = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if n > 0.0 { v.iter_mut().for_each(|x| *x /= n); }
+}
+
+/// Spherical k-means (cosine) over unit vectors laid out row-major in `data`, seeded with
+/// k-means++. Returns the cluster of each row.
+pub fn kmeans(data: &[f32], dim: usize, k: usize, seed: u64) -> Vec<u32> {
+    let n = data.len() / dim;
+    if n == 0 || k == 0 { return Vec::new(); }
+    let row = |i: usize| &data[i * dim..(i + 1) * dim];
+    let mut rng = Rng(seed | 1);
+
+    // k-means++: each next centroid is drawn proportionally to its distance from the chosen ones
+    let mut centroids: Vec<f32> = row((rng.next_f64() * n as f64) as usize % n).to_vec();
+    let mut far: Vec<f32> = (0..n).map(|i| (1.0 - dot(row(i), &centroids[..dim])).max(0.0)).collect();
+    for _ in 1..k {
+        let total: f64 = far.iter().map(|&d| d as f64).sum();
+        let mut target = rng.next_f64() * total;
+        let mut pick = n - 1;
+        for (i, &d) in far.iter().enumerate() {
+            target -= d as f64;
+            if target <= 0.0 {
+                pick = i;
+                break;
+            }
+        }
+        let c = row(pick).to_vec();
+        for (i, f) in far.iter_mut().enumerate() { *f = f.min((1.0 - dot(row(i), &c)).max(0.0)); }
+        centroids.extend_from_slice(&c);
+    }
+
+    let mut labels = vec![u32::MAX; n];
+    for _ in 0..MAX_ITER {
+        let changed = assign(data, dim, &centroids, &mut labels);
+        let mut sums = vec![0f32; k * dim];
+        for (i, &l) in labels.iter().enumerate() {
+            let s = &mut sums[l as usize * dim..(l as usize + 1) * dim];
+            s.iter_mut().zip(row(i)).for_each(|(a, b)| *a += b);
+        }
+        for (c, s) in centroids.chunks_mut(dim).zip(sums.chunks_mut(dim)) {
+            // An emptied cluster keeps its previous centroid
+            if s.iter().any(|&x| x != 0.0) {
+                normalize(s);
+                c.copy_from_slice(s);
+            }
+        }
+        if (changed as f64) < CONVERGED * n as f64 { break; }
+    }
+    labels
+}
+
+/// Points every row at its nearest centroid, split across threads. Returns how many moved.
+fn assign(data: &[f32], dim: usize, centroids: &[f32], labels: &mut [u32]) -> usize {
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let chunk = labels.len().div_ceil(threads).max(1);
+    thread::scope(|sc| {
+        let handles: Vec<_> = labels
+            .chunks_mut(chunk)
+            .enumerate()
+            .map(|(ci, part)| {
+                sc.spawn(move || {
+                    let mut changed = 0;
+                    for (j, l) in part.iter_mut().enumerate() {
+                        let i = ci * chunk + j;
+                        let v = &data[i * dim..(i + 1) * dim];
+                        let best = centroids
+                            .chunks(dim)
+                            .map(|c| dot(v, c))
+                            .enumerate()
+                            .max_by(|a, b| a.1.total_cmp(&b.1))
+                            .map_or(0, |(c, _)| c as u32);
+                        if best != *l {
+                            *l = best;
+                            changed += 1;
+                        }
+                    }
+                    changed
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap_or(0)).sum()
+    })
+}
+
+/// Clusters every embedded file and stores `files.cluster_id`; files without an embedding get
+/// NULL. `k = None` picks [`default_k`]. Returns the number of clusters used.
+pub fn recluster(conn: &mut Connection, k: Option<usize>) -> Result<usize> {
+    let (ids, data, dim) = {
+        let mut stmt = conn.prepare("SELECT file_id, vec, dtype FROM active_embeddings ORDER BY file_id")?;
+        let mut rows = stmt.query([])?;
+        let (mut ids, mut data, mut dim) = (Vec::new(), Vec::new(), 0);
+        while let Some(r) = rows.next()? {
+            let mut v = quant::decode_row(r.get_ref(1)?.as_blob()?, r.get_ref(2)?.as_str()?);
+            // Vectors from another model dimension can't share centroids
+            if ids.is_empty() { dim = v.len(); }
+            if v.len() != dim || dim == 0 { continue; }
+            normalize(&mut v);
+            ids.push(r.get::<_, i64>(0)?);
+            data.extend_from_slice(&v);
+        }
+        (ids, data, dim)
+    };
+    let k = k.unwrap_or_else(|| default_k(ids.len())).min(ids.len());
+    let labels = if k == 0 { Vec::new() } else { kmeans(&data, dim, k, 42) };
+
+    let tx = conn.transaction()?;
+    tx.execute("UPDATE files SET cluster_id = NULL", [])?;
+    {
+        let mut stmt = tx.prepare("UPDATE files SET cluster_id = ? WHERE id = ?")?;
+        for (id, l) in ids.iter().zip(&labels) { stmt.execute(params![l, id])?; }
+    }
+    tx.execute("DELETE FROM clusters", [])?;
+    {
+        let names = name_clusters(&tx)?;
+        let mut stmt = tx.prepare("INSERT INTO clusters(cluster_id, label) VALUES(?, ?)")?;
+        for (id, label) in names { stmt.execute(params![id, label])?; }
+    }
+    tx.commit()?;
+    Ok(k)
+}
+
+/// Tokens too generic to tell regions apart.
+const STOP_WORDS: &[&str] = &[
+    "wav", "wave", "aif", "aiff", "sample", "samples", "sound", "sounds", "audio", "the", "and", "of", "my", "new", "copy",
+    "final", "pack", "kit", "vol", "free", "sfx",
+];
+
+/// Lowercase words of a file or folder name, split on punctuation, case changes and digit runs.
+/// Digits and 1-letter fragments are dropped.
+pub fn tokens(s: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut prev: Option<char> = None;
+    for c in s.chars() {
+        let boundary = match prev {
+            _ if !c.is_alphanumeric() => true,
+            Some(p) => (p.is_lowercase() && c.is_uppercase()) || (p.is_alphabetic() != c.is_alphabetic()),
+            None => false,
+        };
+        if boundary && !cur.is_empty() { out.push(std::mem::take(&mut cur)); }
+        if c.is_alphanumeric() { cur.extend(c.to_lowercase()); }
+        prev = Some(c);
+    }
+    if !cur.is_empty() { out.push(cur); }
+    out.retain(|t| t.chars().count() > 1 && !t.chars().all(|c| c.is_ascii_digit()) && !STOP_WORDS.contains(&t.as_str()));
+    out
+}
+
+/// Words describing a file: its name plus the two folders above it, each word counted once.
+fn file_words(path: &Path) -> BTreeSet<String> {
+    let mut words = BTreeSet::new();
+    if let Some(stem) = path.file_stem() { words.extend(tokens(&stem.to_string_lossy())); }
+    for dir in path.ancestors().skip(1).take(2) {
+        if let Some(name) = dir.file_name() { words.extend(tokens(&name.to_string_lossy())); }
+    }
+    words
+}
+
+/// A caption per cluster from the words most distinctive of its members (frequent inside, rare
+/// outside, tf-idf style), e.g. "kick" or "vinyl_fx". Falls back to "cluster N".
+fn name_clusters(conn: &Connection) -> Result<Vec<(i64, String)>> {
+    let mut stmt = conn.prepare("SELECT cluster_id, path FROM files WHERE cluster_id IS NOT NULL")?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, StoredPath>(1)?.0)))?;
+    let mut df: HashMap<String, usize> = HashMap::new();
+    let mut per_cluster: HashMap<i64, (usize, HashMap<String, usize>)> = HashMap::new();
+    let mut total = 0usize;
+    for row in rows {
+        let (cluster, path) = row?;
+        let entry = per_cluster.entry(cluster).or_default();
+        entry.0 += 1;
+        total += 1;
+        for w in file_words(&path) {
+            *df.entry(w.clone()).or_default() += 1;
+            *entry.1.entry(w).or_default() += 1;
+        }
+    }
+    let mut names: Vec<(i64, String)> = per_cluster
+        .into_iter()
+        .map(|(cluster, (size, counts))| {
+            // A word must cover a real share of the cluster, not one odd file
+            let min_support = 2.max(size / 10).min(size);
+            let mut scored: Vec<(f64, String)> = counts
+                .into_iter()
+                .filter(|(_, n)| *n >= min_support)
+                .map(|(w, n)| ((n as f64 / size as f64) * (total as f64 / df[&w] as f64).ln(), w))
+                .filter(|(score, _)| *score > 0.0)
+                .collect();
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+            let label = match scored.as_slice() {
+                [] => format!("cluster {cluster}"),
+                // Second word only when it is nearly as characteristic as the first
+                [(s1, w1), (s2, w2), ..] if *s2 >= s1 * 0.6 => format!("{w1}_{w2}"),
+                [(_, w1), ..] => w1.clone(),
+            };
+            (cluster, label)
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterInfo {
+    pub cluster_id: i64,
+    /// Caption derived from member file and folder names.
+    pub label: Option<String>,
+    pub size: i64,
+    /// Mean map position of the cluster's projected members, for captions.
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+pub fn list_clusters(conn: &Connection, include_hidden: bool) -> Result<Vec<ClusterInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT f.cluster_id, COUNT(*), AVG(c.x), AVG(c.y), l.label FROM files f \
+         LEFT JOIN coords c ON c.file_id = f.id LEFT JOIN clusters l ON l.cluster_id = f.cluster_id \
+         WHERE f.cluster_id IS NOT NULL AND (?1 OR f.hidden = 0) GROUP BY f.cluster_id ORDER BY f.cluster_id",
+    )?;
+    let rows = stmt
+        .query_map(params![include_hidden], |r| {
+            Ok(ClusterInfo {
+                cluster_id: r.get(0)?,
+                label: r.get(4)?,
+                size: r.get(1)?,
+                x: r.get::<_, Option<f64>>(2)?.map(|v| v as f32),
+                y: r.get::<_, Option<f64>>(3)?.map(|v| v as f32),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
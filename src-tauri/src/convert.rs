@@ -0,0 +1,269 @@
+//! Offline conversion to a fixed WAV format, for hardware samplers that only take e.g.
+//! 44.1 kHz / 16-bit. Decoding goes through the playback chain, so anything that plays converts.
+
+use crate::{
+    db, export, playback,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Context, Result};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rubato::{FftFixedIn, Resampler};
+use rusqlite::Connection;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Frames per resampler chunk.
+const CHUNK: usize = 1024;
+
+/// Target format; `None` keeps the source's value.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ConvertOptions {
+    pub sample_rate: Option<u32>,
+    /// 8, 16 or 24 for integer PCM, 32 for float.
+    pub bits: Option<u16>,
+    /// 1 mixes down to mono; more than the source has repeats its channels.
+    pub channels: Option<u16>,
+    /// Cuts leading and trailing audio quieter than this many dBFS (e.g. -60) on every channel.
+    pub trim_silence_db: Option<f32>,
+    pub normalize: Option<Normalize>,
+}
+
+/// Level target applied to the finished buffer, as `{"peak": -1}` or `{"lufs": -14}`.
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Normalize {
+    /// Sample peak in dBFS.
+    Peak(f32),
+    /// Integrated loudness (ITU-R BS.1770), capped so the peak stays at or below 0 dBFS.
+    Lufs(f32),
+}
+
+impl ConvertOptions {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(b) = self.bits {
+            if !matches!(b, 8 | 16 | 24 | 32) { bail!("unsupported bit depth {b}; use 8, 16, 24 or 32"); }
+        }
+        if let Some(sr) = self.sample_rate {
+            if !(8_000..=384_000).contains(&sr) { bail!("unsupported sample rate {sr}"); }
+        }
+        if self.channels == Some(0) { bail!("channels must be at least 1"); }
+        if self.trim_silence_db.is_some_and(|db| !(-120.0..=0.0).contains(&db)) { bail!("silence threshold must be between -120 and 0 dBFS"); }
+        match self.normalize {
+            Some(Normalize::Peak(db)) if !(-60.0..=0.0).contains(&db) => bail!("peak target must be between -60 and 0 dBFS"),
+            Some(Normalize::Lufs(l)) if !(-70.0..=0.0).contains(&l) => bail!("loudness target must be between -70 and 0 LUFS"),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConvertReport {
+    pub converted: usize,
+    pub failed: usize,
+}
+
+/// Converts each file into `dest` as `<name>.wav`, numbering clashes. Files that fail to decode
+/// or write are logged and counted.
+pub fn convert_files(
+    conn: &Connection,
+    file_ids: &[i64],
+    dest: &Path,
+    opts: &ConvertOptions,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+) -> Result<ConvertReport> {
+    opts.validate()?;
+    std::fs::create_dir_all(dest).with_context(|| format!("create {}", dest.display()))?;
+    let mut sources = Vec::with_capacity(file_ids.len());
+    for &id in file_ids {
+        if let Some(p) = db::file_path(conn, id)? { sources.push(p); }
+    }
+    let mut report = ConvertReport { failed: file_ids.len() - sources.len(), ..Default::default() };
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    let total = sources.len();
+    for (i, src) in sources.iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "converting".into(), processed: i, total, batch: None });
+        let stem = src.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut target = dest.join(format!("{stem}.wav"));
+        if target.exists() || taken.contains(&target) { target = export::free_name(&target, &taken); }
+        match convert_file(src, &target, opts) {
+            Ok(()) => {
+                report.converted += 1;
+                taken.insert(target);
+            }
+            Err(e) => {
+                log::warn!("convert: {}: {e:#}", src.display());
+                let _ = std::fs::remove_file(&target);
+                report.failed += 1;
+            }
+        }
+    }
+    on_progress(&Progress { stage: "converting".into(), processed: total, total, batch: None });
+    Ok(report)
+}
+
+pub fn convert_file(src: &Path, target: &Path, opts: &ConvertOptions) -> Result<()> {
+    let (channels, rate, data) = playback::decode_samples(src)?;
+    let mut planes = deinterleave(&data, usize::from(channels.max(1)));
+    if let Some(db) = opts.trim_silence_db { trim_silence(&mut planes, db); }
+    if let Some(n) = opts.channels { planes = remix(planes, usize::from(n)); }
+    let out_rate = opts.sample_rate.unwrap_or(rate);
+    if out_rate != rate { planes = resample(&planes, rate, out_rate)?; }
+    if let Some(target) = opts.normalize { normalize(&mut planes, out_rate, target); }
+    let bits = opts.bits.unwrap_or(16);
+    write_wav(target, &planes, out_rate, bits)
+}
+
+pub fn deinterleave(data: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    (0..channels).map(|c| data.iter().skip(c).step_by(channels).copied().collect()).collect()
+}
+
+/// Drops the frames before the first and after the last one where any channel reaches
+/// `threshold_db`. All-silent audio is left alone rather than written as an empty file.
+fn trim_silence(planes: &mut [Vec<f32>], threshold_db: f32) {
+    let threshold = 10f32.powf(threshold_db / 20.0);
+    let frames = planes.first().map_or(0, Vec::len);
+    let loud = |f: usize| planes.iter().any(|p| p[f].abs() >= threshold);
+    let Some(start) = (0..frames).find(|&f| loud(f)) else { return };
+    let end = (start..frames).rev().find(|&f| loud(f)).map_or(frames, |f| f + 1);
+    for p in planes.iter_mut() {
+        p.truncate(end);
+        p.drain(..start);
+    }
+}
+
+fn remix(planes: Vec<Vec<f32>>, channels: usize) -> Vec<Vec<f32>> {
+    if planes.len() == channels || planes.is_empty() { return planes; }
+    if channels == 1 {
+        let n = planes.len() as f32;
+        let frames = planes[0].len();
+        return vec![(0..frames).map(|f| planes.iter().map(|p| p[f]).sum::<f32>() / n).collect()];
+    }
+    (0..channels).map(|c| planes[c % planes.len()].clone()).collect()
+}
+
+/// Band-limited FFT resampling; output length is the input's scaled by the rate ratio.
+fn resample(planes: &[Vec<f32>], from: u32, to: u32) -> Result<Vec<Vec<f32>>> {
+    let frames = planes.first().map_or(0, Vec::len);
+    let mut rs = FftFixedIn::<f32>::new(from as usize, to as usize, CHUNK, 2, planes.len())?;
+    let expected = (frames as u64 * u64::from(to) / u64::from(from)) as usize;
+    let delay = rs.output_delay();
+    let mut out: Vec<Vec<f32>> = vec![Vec::with_capacity(expected + delay); planes.len()];
+    let mut pos = 0;
+    while pos + rs.input_frames_next() <= frames {
+        let n = rs.input_frames_next();
+        let chunk: Vec<&[f32]> = planes.iter().map(|p| &p[pos..pos + n]).collect();
+        for (o, r) in out.iter_mut().zip(rs.process(&chunk, None)?) { o.extend(r); }
+        pos += n;
+    }
+    let rest: Vec<&[f32]> = planes.iter().map(|p| &p[pos..]).collect();
+    for (o, r) in out.iter_mut().zip(rs.process_partial(Some(&rest), None)?) { o.extend(r); }
+    // Flush what's still inside the filter until the delayed tail is out
+    while out[0].len() < expected + delay {
+        let flushed = rs.process_partial::<&[f32]>(None, None)?;
+        if flushed[0].is_empty() { break; }
+        for (o, r) in out.iter_mut().zip(flushed) { o.extend(r); }
+    }
+    for o in &mut out {
+        o.drain(..delay.min(o.len()));
+        o.truncate(expected);
+    }
+    Ok(out)
+}
+
+fn normalize(planes: &mut [Vec<f32>], rate: u32, target: Normalize) {
+    let peak = planes.iter().flatten().fold(0f32, |m, v| m.max(v.abs()));
+    if peak == 0.0 { return; }
+    let gain_db = match target {
+        Normalize::Peak(db) => db - 20.0 * peak.log10(),
+        Normalize::Lufs(lufs) => {
+            // Too short or too quiet to gate: leave the level as it is
+            let Some(measured) = integrated_loudness(planes, rate) else { return };
+            (lufs - measured).min(-20.0 * peak.log10())
+        }
+    };
+    let gain = 10f32.powf(gain_db / 20.0);
+    for v in planes.iter_mut().flatten() { *v *= gain; }
+}
+
+/// Gated integrated loudness per BS.1770-4 over 400 ms blocks with 75% overlap, all channels
+/// weighted equally. `None` when no block clears the absolute gate.
+pub fn integrated_loudness(planes: &[Vec<f32>], rate: u32) -> Option<f32> {
+    let filtered: Vec<Vec<f64>> = planes.iter().map(|p| k_weight(p, f64::from(rate))).collect();
+    let frames = filtered.first().map_or(0, Vec::len);
+    let (block, hop) = (rate as usize * 2 / 5, rate as usize / 10);
+    if block == 0 || frames < block { return None; }
+    let powers: Vec<f64> = (0..=(frames - block) / hop)
+        .map(|b| filtered.iter().map(|ch| ch[b * hop..b * hop + block].iter().map(|v| v * v).sum::<f64>() / block as f64).sum())
+        .collect();
+    let loudness = |p: f64| -0.691 + 10.0 * p.log10();
+    let gated_mean = |gate: f64| {
+        let kept: Vec<f64> = powers.iter().copied().filter(|&p| loudness(p) > gate).collect();
+        (!kept.is_empty()).then(|| kept.iter().sum::<f64>() / kept.len() as f64)
+    };
+    let relative_gate = loudness(gated_mean(-70.0)?) - 10.0;
+    gated_mean(relative_gate.max(-70.0)).map(|p| loudness(p) as f32)
+}
+
+/// The BS.1770 pre-filter (high shelf, then high-pass), with coefficients derived for `fs`.
+fn k_weight(x: &[f32], fs: f64) -> Vec<f64> {
+    use std::f64::consts::PI;
+    let shelf = {
+        let (f0, g, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / fs).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        ([(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0])
+    };
+    let highpass = {
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / fs).tan();
+        let a0 = 1.0 + k / q + k * k;
+        ([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0])
+    };
+    let y = biquad(x.iter().map(|&v| f64::from(v)), shelf);
+    biquad(y.into_iter(), highpass)
+}
+
+/// Direct form I; `a` holds a1 and a2 with a0 normalised to 1.
+fn biquad(x: impl Iterator<Item = f64>, (b, a): ([f64; 3], [f64; 2])) -> Vec<f64> {
+    let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+    x.map(|x0| {
+        let y0 = b[0] * x0 + b[1] * x1 + b[2] * x2 - a[0] * y1 - a[1] * y2;
+        (x2, x1, y2, y1) = (x1, x0, y1, y0);
+        y0
+    })
+    .collect()
+}
+
+pub fn write_wav(path: &Path, planes: &[Vec<f32>], sample_rate: u32, bits: u16) -> Result<()> {
+    let spec = WavSpec {
+        channels: planes.len() as u16,
+        sample_rate,
+        bits_per_sample: bits,
+        sample_format: if bits == 32 { SampleFormat::Float } else { SampleFormat::Int },
+    };
+    let mut w = WavWriter::create(path, spec).with_context(|| format!("create {}", path.display()))?;
+    let frames = planes.first().map_or(0, Vec::len);
+    let scale = ((1i64 << (bits.min(31) - 1)) - 1) as f32;
+    for f in 0..frames {
+        for p in planes {
+            let v = p[f].clamp(-1.0, 1.0);
+            match bits {
+                32 => w.write_sample(v)?,
+                8 => w.write_sample((v * scale).round() as i8)?,
+                16 => w.write_sample((v * scale).round() as i16)?,
+                _ => w.write_sample((v * scale).round() as i32)?,
+            }
+        }
+    }
+    w.finalize()?;
+    Ok(())
+}
@@ -1,9 +1,67 @@
-use anyhow::{Context, Result};
-use rusqlite::{params, Connection};
-use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, ErrorCode, OptionalExtension, ToSql,
+};
+use parking_lot::Mutex;
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tauri::Manager;
 
+/// Database file of the active library (see `settings.active_library`).
 pub fn db_path(app: &tauri::AppHandle) -> Result<PathBuf> {
+    match crate::settings::load(app).active_library {
+        Some(name) if name != DEFAULT_LIBRARY => library_path(app, &name),
+        _ => default_db_path(app),
+    }
+}
+
+pub const DEFAULT_LIBRARY: &str = "Default";
+
+/// Named libraries live in `<data_dir>/libraries/<name>.sqlite`; the default library keeps the
+/// original location so existing installs are unaffected.
+pub fn library_path(app: &tauri::AppHandle, name: &str) -> Result<PathBuf> {
+    if name == DEFAULT_LIBRARY { return default_db_path(app); }
+    validate_library_name(name)?;
+    let dir = libraries_dir(app)?;
+    Ok(dir.join(format!("{name}.sqlite")))
+}
+
+pub fn libraries_dir(app: &tauri::AppHandle) -> Result<PathBuf> {
+    let dir = data_dir(app)?.join("libraries");
+    std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Names become file names, so keep them to a portable character set.
+pub fn validate_library_name(name: &str) -> Result<()> {
+    let ok = !name.trim().is_empty()
+        && name.len() <= 64
+        && name == name.trim()
+        && name.chars().all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'));
+    if !ok { bail!("invalid library name {name:?}: use letters, digits, spaces, '-' or '_'"); }
+    Ok(())
+}
+
+/// All known library names, default first.
+pub fn list_libraries(app: &tauri::AppHandle) -> Result<Vec<String>> {
+    let mut names: Vec<String> = std::fs::read_dir(libraries_dir(app)?)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|x| x.to_str()) == Some("sqlite"))
+        .filter_map(|p| p.file_stem().and_then(|s| s.to_str()).map(str::to_string))
+        .filter(|n| validate_library_name(n).is_ok())
+        .collect();
+    names.sort_by_key(|n| n.to_lowercase());
+    names.insert(0, DEFAULT_LIBRARY.to_string());
+    Ok(names)
+}
+
+fn default_db_path(app: &tauri::AppHandle) -> Result<PathBuf> {
     // Unified location to match Python worker venv/cache on Windows
     #[cfg(target_os = "windows")]
     {
@@ -51,15 +109,151 @@ pub fn db_path(app: &tauri::AppHandle) -> Result<PathBuf> {
     Ok(base.join("samplemap.sqlite"))
 }
 
+/// Base directory for app-owned files (database, settings, worker venv), shared with worker.py's `appdata_dir()`.
+pub fn data_dir(app: &tauri::AppHandle) -> Result<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let base = std::env::var("LOCALAPPDATA").ok().map(|local| PathBuf::from(local).join("SampleMap"));
+    #[cfg(not(target_os = "windows"))]
+    let base = std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".local").join("share").join("samplemap"));
+    let base = match base {
+        Some(b) => b,
+        None => app.path().app_data_dir().context("no app_data_dir path available")?,
+    };
+    std::fs::create_dir_all(&base).ok();
+    Ok(base)
+}
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// True if the error chain bottoms out in SQLITE_BUSY/SQLITE_LOCKED.
+pub fn is_busy(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        matches!(
+            c.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(f, _)) if matches!(f.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        )
+    })
+}
+
+/// Retries `f` with backoff while it fails with a busy/locked error. `busy_timeout` covers most
+/// contention; this catches the cases SQLite won't wait on (e.g. lock upgrades in WAL mode).
+pub fn retry_busy<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = Duration::from_millis(50);
+    for _ in 0..5 {
+        match f() {
+            Err(e) if is_busy(&e) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            res => return res,
+        }
+    }
+    f()
+}
+
+/// Moves WAL frames back into the main file without blocking readers, so the WAL doesn't grow
+/// unbounded during long write-heavy scans.
+pub fn checkpoint(conn: &Connection) -> Result<()> {
+    conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+/// One long-lived connection to the active library, shared by all commands so migrations run
+/// once per open rather than per call. Background scans keep their own connection; WAL lets
+/// them write while this one reads.
+#[derive(Default)]
+pub struct Db {
+    conn: Mutex<Option<Connection>>,
+}
+
+impl Db {
+    pub fn with<T>(&self, app: &tauri::AppHandle, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut guard = self.conn.lock();
+        if guard.is_none() {
+            let p = db_path(app)?;
+            *guard = Some(retry_busy(|| open_or_create(&p))?);
+        }
+        let conn = guard.as_mut().expect("opened above");
+        f(conn)
+    }
+
+    /// Drops the connection; the next call reopens whatever library is active then.
+    pub fn close(&self) {
+        *self.conn.lock() = None;
+    }
+}
+
 pub fn open_or_create(path: &Path) -> Result<Connection> {
     let mut conn = Connection::open(path).with_context(|| format!("open db at {}", path.display()))?;
+    // Wait out a concurrent writer (scan thread, Python worker) instead of failing with SQLITE_BUSY
+    conn.busy_timeout(BUSY_TIMEOUT)?;
     conn.pragma_update(None, "journal_mode", &"WAL")?;
     conn.pragma_update(None, "synchronous", &"NORMAL")?;
     migrate(&mut conn)?;
     Ok(conn)
 }
 
+struct Migration {
+    version: i64,
+    description: &'static str,
+    up: fn(&Connection) -> Result<()>,
+}
+
+/// Ordered schema history. Each entry upgrades the schema to `version`; append new entries,
+/// never edit shipped ones. Versions 2 and 3 tolerate DBs that already had their changes while
+/// still stamped as version 1.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "base tables", up: m001_base },
+    Migration { version: 2, description: "favorite and rating columns", up: m002_favorite_rating },
+    Migration { version: 3, description: "files_fts full-text index", up: m003_fts },
+    Migration { version: 4, description: "content_hash column", up: m004_content_hash },
+    Migration { version: 5, description: "play_count and last_played_at columns", up: m005_plays },
+    Migration { version: 6, description: "note column, indexed in files_fts", up: m006_notes },
+    Migration { version: 7, description: "color label column", up: m007_color },
+    Migration { version: 8, description: "hidden flag", up: m008_hidden },
+    Migration { version: 9, description: "sample_rate, bits_per_sample and channels columns", up: m009_format },
+    Migration { version: 10, description: "file_tags table", up: m010_tags },
+    Migration { version: 11, description: "layout_coords table for per-method projections", up: m011_layouts },
+    Migration { version: 12, description: "optional z coordinate for 3D layouts", up: m012_z },
+    Migration { version: 13, description: "cluster_id column", up: m013_cluster },
+    Migration { version: 14, description: "clusters table with derived labels", up: m014_cluster_labels },
+    Migration { version: 15, description: "coords_rtree spatial index", up: m015_coords_rtree },
+    Migration { version: 16, description: "named layouts with history", up: m016_named_layouts },
+    Migration { version: 17, description: "per-row embedding dtype", up: m017_embedding_dtype },
+    Migration { version: 18, description: "pins table for anchored samples", up: m018_pins },
+    Migration { version: 19, description: "embedding outlier score", up: m019_outlier_score },
+    Migration { version: 20, description: "models table; embeddings keyed by (file_id, model_id)", up: m020_models },
+    Migration { version: 21, description: "waveforms table for peak thumbnails", up: m021_waveforms },
+    Migration { version: 22, description: "scan_history table", up: m022_scan_history },
+    Migration { version: 23, description: "bpm and musical_key columns", up: m023_tempo_key },
+    Migration { version: 24, description: "bpm_confidence column for detected tempos", up: m024_bpm_confidence },
+    Migration { version: 25, description: "pitch and tonal_confidence columns", up: m025_pitch },
+    Migration { version: 26, description: "peak_db, rms_db and lufs columns", up: m026_loudness },
+    Migration { version: 27, description: "category column (one-shot / loop / long-form)", up: m027_category },
+    Migration { version: 28, description: "is_silent flag", up: m028_silent },
+    Migration { version: 29, description: "file_tags source and confidence for machine tags", up: m029_tag_source },
+    Migration { version: 30, description: "parent_id and slice offsets for loop slices", up: m030_slices },
+    Migration { version: 31, description: "spectral descriptors", up: m031_spectral },
+    Migration { version: 32, description: "coords_lod quadtree for level-of-detail maps", up: m032_coords_lod },
+    Migration { version: 33, description: "canonical stored paths", up: m033_canonical_paths },
+    Migration { version: 34, description: "library snapshots", up: m034_snapshots },
+    Migration { version: 35, description: "operations journal for undo", up: m035_operations },
+    Migration { version: 36, description: "embedding change log for the ANN index", up: m036_embedding_changes },
+];
+
+pub fn latest_schema_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    let v: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = 'schema_version'", [], |r| r.get(0))
+        .optional()?;
+    Ok(v.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
 fn migrate(conn: &mut Connection) -> Result<()> {
+    // Bookkeeping tables live outside the numbered migrations so the version can always be read
     conn.execute_batch(
         r#"
         CREATE TABLE IF NOT EXISTS meta (
@@ -67,6 +261,38 @@ fn migrate(conn: &mut Connection) -> Result<()> {
             value TEXT
         );
 
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );
+        "#,
+    )?;
+
+    let current = schema_version(conn)?;
+    let latest = latest_schema_version();
+    if current > latest {
+        bail!("database schema v{current} is newer than this app supports (v{latest})");
+    }
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        (m.up)(&tx).with_context(|| format!("migration {} ({})", m.version, m.description))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO meta(key, value) VALUES('schema_version', ?)",
+            params![m.version.to_string()],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO schema_migrations(version, description, applied_at) VALUES(?, ?, CAST(strftime('%s','now') AS INTEGER))",
+            params![m.version, m.description],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn m001_base(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
         CREATE TABLE IF NOT EXISTS files (
             id INTEGER PRIMARY KEY,
             path TEXT UNIQUE NOT NULL,
@@ -95,42 +321,1020 @@ fn migrate(conn: &mut Connection) -> Result<()> {
         CREATE INDEX IF NOT EXISTS idx_coords_file ON coords(file_id);
         "#,
     )?;
+    Ok(())
+}
 
-    conn.execute(
-        "INSERT OR REPLACE INTO meta(key, value) VALUES('schema_version','1')",
+fn m002_favorite_rating(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "favorite", "INTEGER NOT NULL DEFAULT 0")?;
+    // NULL = unrated, otherwise 1..=5
+    add_column_if_missing(conn, "files", "rating", "INTEGER")?;
+    Ok(())
+}
+
+fn m003_fts(conn: &Connection) -> Result<()> {
+    ensure_fts(conn)
+}
+
+fn m004_content_hash(conn: &Connection) -> Result<()> {
+    // Hex SHA-256 of the file bytes; NULL until something has needed to hash the file
+    add_column_if_missing(conn, "files", "content_hash", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_content_hash ON files(content_hash);")?;
+    Ok(())
+}
+
+fn m005_plays(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "play_count", "INTEGER NOT NULL DEFAULT 0")?;
+    // Unix seconds of the most recent audition
+    add_column_if_missing(conn, "files", "last_played_at", "INTEGER")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_last_played ON files(last_played_at);")?;
+    Ok(())
+}
+
+fn m006_notes(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "note", "TEXT")?;
+    // FTS5 columns are fixed at creation; rebuild the index with the extra column
+    conn.execute_batch(
+        r#"
+        DROP TRIGGER IF EXISTS files_fts_ai;
+        DROP TRIGGER IF EXISTS files_fts_ad;
+        DROP TRIGGER IF EXISTS files_fts_au;
+        DROP TABLE IF EXISTS files_fts;
+
+        CREATE VIRTUAL TABLE files_fts USING fts5(
+            name, path, note,
+            content='files', content_rowid='id',
+            tokenize='unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER files_fts_ai AFTER INSERT ON files BEGIN
+            INSERT INTO files_fts(rowid, name, path, note) VALUES (new.id, new.name, new.path, new.note);
+        END;
+        CREATE TRIGGER files_fts_ad AFTER DELETE ON files BEGIN
+            INSERT INTO files_fts(files_fts, rowid, name, path, note) VALUES ('delete', old.id, old.name, old.path, old.note);
+        END;
+        CREATE TRIGGER files_fts_au AFTER UPDATE OF name, path, note ON files BEGIN
+            INSERT INTO files_fts(files_fts, rowid, name, path, note) VALUES ('delete', old.id, old.name, old.path, old.note);
+            INSERT INTO files_fts(rowid, name, path, note) VALUES (new.id, new.name, new.path, new.note);
+        END;
+
+        INSERT INTO files_fts(files_fts) VALUES ('rebuild');
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m007_color(conn: &Connection) -> Result<()> {
+    // Lowercase "#rrggbb", NULL = no label
+    add_column_if_missing(conn, "files", "color", "TEXT")?;
+    Ok(())
+}
+
+fn m008_hidden(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "hidden", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn m009_format(conn: &Connection) -> Result<()> {
+    // From the WAV header; NULL where the header couldn't be read
+    add_column_if_missing(conn, "files", "sample_rate", "INTEGER")?;
+    add_column_if_missing(conn, "files", "bits_per_sample", "INTEGER")?;
+    add_column_if_missing(conn, "files", "channels", "INTEGER")?;
+    Ok(())
+}
+
+fn m010_tags(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_tags (
+            file_id INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY(file_id, tag),
+            FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_file_tags_tag ON file_tags(tag);
+        "#,
+    )?;
+    Ok(())
+}
+
+/// `coords` stays the active layout; every projection method also keeps its own copy here so
+/// the map can switch between them without recomputing. Existing coords came from UMAP.
+fn m011_layouts(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS layout_coords (
+            method TEXT NOT NULL,
+            file_id INTEGER NOT NULL,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            PRIMARY KEY(method, file_id),
+            FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE
+        );
+        INSERT OR IGNORE INTO layout_coords(method, file_id, x, y) SELECT 'umap', file_id, x, y FROM coords;
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m012_z(conn: &Connection) -> Result<()> {
+    // NULL for 2D layouts
+    add_column_if_missing(conn, "coords", "z", "REAL")?;
+    add_column_if_missing(conn, "layout_coords", "z", "REAL")?;
+    Ok(())
+}
+
+fn m013_cluster(conn: &Connection) -> Result<()> {
+    // k-means label over embeddings; NULL until the file is embedded and clustered
+    add_column_if_missing(conn, "files", "cluster_id", "INTEGER")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_cluster ON files(cluster_id);")?;
+    Ok(())
+}
+
+fn m014_cluster_labels(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS clusters (
+            cluster_id INTEGER PRIMARY KEY,
+            label TEXT NOT NULL
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+/// R*Tree over the active layout for viewport queries, kept in step by triggers. The worker
+/// writes coords with INSERT OR REPLACE, which doesn't fire delete triggers, so inserts replace too.
+fn m015_coords_rtree(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS coords_rtree USING rtree(id, min_x, max_x, min_y, max_y);
+        CREATE TRIGGER IF NOT EXISTS coords_rtree_ai AFTER INSERT ON coords BEGIN
+            INSERT OR REPLACE INTO coords_rtree(id, min_x, max_x, min_y, max_y) VALUES (new.file_id, new.x, new.x, new.y, new.y);
+        END;
+        CREATE TRIGGER IF NOT EXISTS coords_rtree_au AFTER UPDATE ON coords BEGIN
+            DELETE FROM coords_rtree WHERE id = old.file_id;
+            INSERT OR REPLACE INTO coords_rtree(id, min_x, max_x, min_y, max_y) VALUES (new.file_id, new.x, new.x, new.y, new.y);
+        END;
+        CREATE TRIGGER IF NOT EXISTS coords_rtree_ad AFTER DELETE ON coords BEGIN
+            DELETE FROM coords_rtree WHERE id = old.file_id;
+        END;
+        INSERT OR REPLACE INTO coords_rtree(id, min_x, max_x, min_y, max_y) SELECT file_id, x, x, y, y FROM coords;
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m016_named_layouts(conn: &Connection) -> Result<()> {
+    // layout_coords was keyed by method, keeping only the latest run of each; every run now gets
+    // its own layout row. Existing per-method results become the first entries of the history.
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS layouts (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            method TEXT NOT NULL,
+            params TEXT,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE layout_coords_new (
+            layout_id INTEGER NOT NULL,
+            file_id INTEGER NOT NULL,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            z REAL,
+            PRIMARY KEY(layout_id, file_id),
+            FOREIGN KEY(layout_id) REFERENCES layouts(id) ON DELETE CASCADE,
+            FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE
+        );
+        INSERT INTO layouts(name, method, created_at)
+            SELECT upper(method), method, CAST(strftime('%s','now') AS INTEGER) FROM layout_coords GROUP BY method ORDER BY method;
+        INSERT INTO layout_coords_new(layout_id, file_id, x, y, z)
+            SELECT l.id, lc.file_id, lc.x, lc.y, lc.z FROM layout_coords lc JOIN layouts l ON l.method = lc.method;
+        DROP TABLE layout_coords;
+        ALTER TABLE layout_coords_new RENAME TO layout_coords;
+        "#,
+    )?;
+    // coords stays the active layout (the R*Tree and every map query read it); this records which
+    add_column_if_missing(conn, "coords", "layout_id", "INTEGER")?;
+    conn.execute("UPDATE coords SET layout_id = (SELECT id FROM layouts ORDER BY method = 'umap' DESC, id DESC LIMIT 1)", [])?;
+    Ok(())
+}
+
+fn m017_embedding_dtype(conn: &Connection) -> Result<()> {
+    // Encoding of `vec` (see quant.rs); everything written so far is f32
+    add_column_if_missing(conn, "embeddings", "dtype", "TEXT NOT NULL DEFAULT 'f32'")?;
+    Ok(())
+}
+
+fn m018_pins(conn: &Connection) -> Result<()> {
+    // Positions in layout space that re-projection keeps these files at
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS pins (
+            file_id INTEGER PRIMARY KEY,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m019_outlier_score(conn: &Connection) -> Result<()> {
+    // Distance to the k-th nearest neighbour; NULL until scored after an embedding run
+    add_column_if_missing(conn, "embeddings", "outlier_score", "REAL")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_embeddings_outlier ON embeddings(outlier_score)")?;
+    Ok(())
+}
+
+fn m020_models(conn: &Connection) -> Result<()> {
+    // Model 1 is the built-in CLAP that produced every existing vector. Readers go through
+    // `active_embeddings`; writers name the model explicitly.
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS models (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            path TEXT,
+            created_at INTEGER NOT NULL
+        );
+        INSERT OR IGNORE INTO models(id, name, created_at) VALUES (1, 'clap', CAST(strftime('%s','now') AS INTEGER));
+        CREATE TABLE embeddings_new (
+            file_id INTEGER NOT NULL,
+            model_id INTEGER NOT NULL DEFAULT 1,
+            dim INTEGER NOT NULL,
+            vec BLOB NOT NULL,
+            dtype TEXT NOT NULL DEFAULT 'f32',
+            outlier_score REAL,
+            PRIMARY KEY(file_id, model_id),
+            FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE,
+            FOREIGN KEY(model_id) REFERENCES models(id) ON DELETE CASCADE
+        );
+        INSERT INTO embeddings_new(file_id, model_id, dim, vec, dtype, outlier_score)
+            SELECT file_id, 1, dim, vec, dtype, outlier_score FROM embeddings;
+        DROP TABLE embeddings;
+        ALTER TABLE embeddings_new RENAME TO embeddings;
+        CREATE INDEX IF NOT EXISTS idx_embeddings_model ON embeddings(model_id, file_id);
+        CREATE INDEX IF NOT EXISTS idx_embeddings_outlier ON embeddings(model_id, outlier_score);
+        INSERT OR IGNORE INTO meta(key, value) VALUES ('active_model', '1');
+        CREATE VIEW IF NOT EXISTS active_embeddings AS
+            SELECT * FROM embeddings WHERE model_id = (SELECT CAST(value AS INTEGER) FROM meta WHERE key = 'active_model');
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m021_waveforms(conn: &Connection) -> Result<()> {
+    // `mtime` is the file's when the peaks were taken; a newer file means recompute
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS waveforms (
+            file_id INTEGER PRIMARY KEY,
+            mtime INTEGER NOT NULL,
+            peaks BLOB NOT NULL,
+            FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m022_scan_history(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS scan_history (
+            id INTEGER PRIMARY KEY,
+            root TEXT NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER NOT NULL,
+            total INTEGER NOT NULL,
+            processed INTEGER NOT NULL,
+            skipped INTEGER NOT NULL,
+            error TEXT,
+            cancelled INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS idx_scan_history_root ON scan_history(root);
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m023_tempo_key(conn: &Connection) -> Result<()> {
+    // From other tools or tempo detection; NULL when unknown. Keys are spelled "F#m", "Bb"
+    add_column_if_missing(conn, "files", "bpm", "REAL")?;
+    add_column_if_missing(conn, "files", "musical_key", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_key ON files(musical_key);")?;
+    Ok(())
+}
+
+fn m024_bpm_confidence(conn: &Connection) -> Result<()> {
+    // Set by tempo detection, 0 when it found nothing; NULL for imported or unanalysed BPMs
+    add_column_if_missing(conn, "files", "bpm_confidence", "REAL")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_bpm ON files(bpm);")?;
+    Ok(())
+}
+
+fn m025_pitch(conn: &Connection) -> Result<()> {
+    // Fractional MIDI note of a one-shot. `tonal_confidence` is set by key/pitch detection and
+    // stays NULL for imported keys, like `bpm_confidence`
+    add_column_if_missing(conn, "files", "pitch", "REAL")?;
+    add_column_if_missing(conn, "files", "tonal_confidence", "REAL")?;
+    Ok(())
+}
+
+fn m026_loudness(conn: &Connection) -> Result<()> {
+    // NULL peak_db = not measured yet; lufs stays NULL for files too short to gate
+    add_column_if_missing(conn, "files", "peak_db", "REAL")?;
+    add_column_if_missing(conn, "files", "rms_db", "REAL")?;
+    add_column_if_missing(conn, "files", "lufs", "REAL")?;
+    Ok(())
+}
+
+fn m027_category(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "category", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_category ON files(category);")?;
+    Ok(())
+}
+
+fn m028_silent(conn: &Connection) -> Result<()> {
+    // Set with the loudness measurement; files measured before this only get the peak check
+    add_column_if_missing(conn, "files", "is_silent", "INTEGER")?;
+    conn.execute_batch("UPDATE files SET is_silent = (peak_db < -60.0) WHERE peak_db IS NOT NULL AND is_silent IS NULL;")?;
+    Ok(())
+}
+
+fn m029_tag_source(conn: &Connection) -> Result<()> {
+    // 'user' or 'auto'; only machine tags carry a confidence
+    add_column_if_missing(conn, "file_tags", "source", "TEXT NOT NULL DEFAULT 'user'")?;
+    add_column_if_missing(conn, "file_tags", "confidence", "REAL")?;
+    Ok(())
+}
+
+fn m030_slices(conn: &Connection) -> Result<()> {
+    // Offsets are seconds into the parent; all three are NULL for ordinary files
+    add_column_if_missing(conn, "files", "parent_id", "INTEGER")?;
+    add_column_if_missing(conn, "files", "slice_start", "REAL")?;
+    add_column_if_missing(conn, "files", "slice_end", "REAL")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_files_parent ON files(parent_id);")?;
+    Ok(())
+}
+
+fn m031_spectral(conn: &Connection) -> Result<()> {
+    // Centroid and rolloff in Hz, flatness 0-1, zero crossings per second
+    add_column_if_missing(conn, "files", "spectral_centroid", "REAL")?;
+    add_column_if_missing(conn, "files", "spectral_rolloff", "REAL")?;
+    add_column_if_missing(conn, "files", "spectral_flatness", "REAL")?;
+    add_column_if_missing(conn, "files", "zero_crossing_rate", "REAL")?;
+    Ok(())
+}
+
+/// Per-level cell aggregates of the active layout (see `lod`). Any change to coords or to which
+/// files are hidden empties `coords_lod_bounds`, marking the aggregates stale.
+fn m032_coords_lod(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS coords_lod(
+            level INTEGER NOT NULL,
+            cell_x INTEGER NOT NULL,
+            cell_y INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            file_id INTEGER NOT NULL,
+            PRIMARY KEY(level, cell_x, cell_y)
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS coords_lod_bounds(min_x REAL NOT NULL, min_y REAL NOT NULL, size REAL NOT NULL);
+        CREATE TRIGGER IF NOT EXISTS coords_lod_ai AFTER INSERT ON coords BEGIN DELETE FROM coords_lod_bounds; END;
+        CREATE TRIGGER IF NOT EXISTS coords_lod_au AFTER UPDATE ON coords BEGIN DELETE FROM coords_lod_bounds; END;
+        CREATE TRIGGER IF NOT EXISTS coords_lod_ad AFTER DELETE ON coords BEGIN DELETE FROM coords_lod_bounds; END;
+        CREATE TRIGGER IF NOT EXISTS coords_lod_hidden AFTER UPDATE OF hidden ON files BEGIN DELETE FROM coords_lod_bounds; END;
+        "#,
+    )?;
+    Ok(())
+}
+
+/// Rewrites paths stored before scans canonicalized them, so a rescan matches the old rows
+/// instead of adding the files again. Files that are gone keep their path; where both spellings
+/// were already stored, the rows are left for merge to sort out.
+fn m033_canonical_paths(conn: &Connection) -> Result<()> {
+    let rows: Vec<(i64, PathBuf)> = {
+        let mut stmt = conn.prepare("SELECT id, path FROM files")?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let mut update = conn.prepare("UPDATE OR IGNORE files SET path = ? WHERE id = ?")?;
+    for (id, path) in rows {
+        let canonical = crate::paths::canonical(&path);
+        if canonical != path { update.execute(params![SqlPath(&canonical), id])?; }
+    }
+    Ok(())
+}
+
+fn m034_snapshots(conn: &Connection) -> Result<()> {
+    // Copies rather than references, so they survive the files being pruned
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS snapshots (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS snapshot_files (
+            snapshot_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            name TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            content_hash TEXT,
+            FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_snapshot_files_snapshot ON snapshot_files(snapshot_id);
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m035_operations(conn: &Connection) -> Result<()> {
+    // `payload` is the JSON `undo::Operation`; the rows it deleted live in `undo_*` tables
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS operations (
+            id INTEGER PRIMARY KEY,
+            kind TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            payload TEXT NOT NULL
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+fn m036_embedding_changes(conn: &Connection) -> Result<()> {
+    // The newest write per (file, model), so `ann` can tell a replaced vector from an unchanged
+    // one even when a deleted file's id is reused. Delete-then-insert rather than OR REPLACE,
+    // which an outer OR IGNORE would override.
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS embedding_changes (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_id INTEGER NOT NULL,
+            model_id INTEGER NOT NULL,
+            UNIQUE(file_id, model_id)
+        );
+        CREATE TRIGGER IF NOT EXISTS embedding_changes_ai AFTER INSERT ON embeddings BEGIN
+            DELETE FROM embedding_changes WHERE file_id = new.file_id AND model_id = new.model_id;
+            INSERT INTO embedding_changes(file_id, model_id) VALUES (new.file_id, new.model_id);
+        END;
+        CREATE TRIGGER IF NOT EXISTS embedding_changes_au AFTER UPDATE OF vec ON embeddings BEGIN
+            DELETE FROM embedding_changes WHERE file_id = new.file_id AND model_id = new.model_id;
+            INSERT INTO embedding_changes(file_id, model_id) VALUES (new.file_id, new.model_id);
+        END;
+        CREATE TRIGGER IF NOT EXISTS embedding_changes_ad AFTER DELETE ON embeddings BEGIN
+            DELETE FROM embedding_changes WHERE file_id = old.file_id AND model_id = old.model_id;
+            INSERT INTO embedding_changes(file_id, model_id) VALUES (old.file_id, old.model_id);
+        END;
+        "#,
+    )?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationInfo {
+    pub version: i64,
+    pub description: &'static str,
+    pub applied_at: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaInfo {
+    pub version: i64,
+    pub latest_version: i64,
+    pub sqlite_version: String,
+    pub migrations: Vec<MigrationInfo>,
+}
+
+pub fn schema_info(conn: &Connection) -> Result<SchemaInfo> {
+    let mut stmt = conn.prepare("SELECT applied_at FROM schema_migrations WHERE version = ?")?;
+    let mut migrations = Vec::new();
+    for m in MIGRATIONS {
+        let applied_at: Option<i64> = stmt.query_row(params![m.version], |r| r.get(0)).optional()?;
+        migrations.push(MigrationInfo { version: m.version, description: m.description, applied_at });
+    }
+    Ok(SchemaInfo {
+        version: schema_version(conn)?,
+        latest_version: latest_schema_version(),
+        sqlite_version: rusqlite::version().to_string(),
+        migrations,
+    })
+}
+
+/// `ALTER TABLE .. ADD COLUMN` has no IF NOT EXISTS; check the table first so reopening is a no-op.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |r| r.get::<_, String>(1))?
+        .filter_map(|r| r.ok())
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))?;
+    }
+    Ok(())
+}
+
+/// External-content FTS5 index over `files`, kept in sync by triggers so writes from the
+/// Python worker are indexed too. Existing rows are backfilled when the index is first created.
+/// Schema v6 replaces this index with one that also covers notes.
+fn ensure_fts(conn: &Connection) -> Result<()> {
+    let existed: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'files_fts')",
         [],
+        |r| r.get(0),
     )?;
+    conn.execute_batch(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS files_fts USING fts5(
+            name, path,
+            content='files', content_rowid='id',
+            tokenize='unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS files_fts_ai AFTER INSERT ON files BEGIN
+            INSERT INTO files_fts(rowid, name, path) VALUES (new.id, new.name, new.path);
+        END;
+        CREATE TRIGGER IF NOT EXISTS files_fts_ad AFTER DELETE ON files BEGIN
+            INSERT INTO files_fts(files_fts, rowid, name, path) VALUES ('delete', old.id, old.name, old.path);
+        END;
+        CREATE TRIGGER IF NOT EXISTS files_fts_au AFTER UPDATE OF name, path ON files BEGIN
+            INSERT INTO files_fts(files_fts, rowid, name, path) VALUES ('delete', old.id, old.name, old.path);
+            INSERT INTO files_fts(rowid, name, path) VALUES (new.id, new.name, new.path);
+        END;
+        "#,
+    )?;
+    if !existed {
+        conn.execute("INSERT INTO files_fts(files_fts) VALUES ('rebuild')", [])?;
+    }
     Ok(())
 }
 
+/// Turns free user input into an FTS5 MATCH expression: every whitespace-separated term
+/// becomes a quoted prefix query, ANDed together. Returns `None` if there's nothing to search.
+pub fn fts_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .map(|t| format!("\"{}\"*", t.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() { None } else { Some(terms.join(" ")) }
+}
+
+/// Binds a file path losslessly: TEXT when it is valid UTF-8 (nearly always, and what older
+/// libraries hold), otherwise a BLOB of the raw OS-string bytes so invalid names survive intact.
+pub struct SqlPath<'a>(pub &'a Path);
+
+impl ToSql for SqlPath<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self.0.to_str() {
+            Some(s) => ToSqlOutput::Borrowed(ValueRef::Text(s.as_bytes())),
+            None => ToSqlOutput::Owned(Value::Blob(os_bytes(self.0.as_os_str()))),
+        })
+    }
+}
+
+/// Reads a path column written through [`SqlPath`].
+pub struct StoredPath(pub PathBuf);
+
+impl FromSql for StoredPath {
+    fn column_result(v: ValueRef<'_>) -> FromSqlResult<Self> {
+        match v {
+            ValueRef::Text(t) => std::str::from_utf8(t).map(|s| StoredPath(s.into())).map_err(|e| FromSqlError::Other(Box::new(e))),
+            ValueRef::Blob(b) => os_from_bytes(b).map(|s| StoredPath(s.into())).ok_or(FromSqlError::InvalidType),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn os_from_bytes(b: &[u8]) -> Option<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(OsStr::from_bytes(b).to_os_string())
+}
+
+// Windows paths are UTF-16 that may contain unpaired surrogates; store the code units little-endian
+#[cfg(windows)]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    s.encode_wide().flat_map(u16::to_le_bytes).collect()
+}
+
+#[cfg(windows)]
+fn os_from_bytes(b: &[u8]) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+    if b.len() % 2 != 0 { return None; }
+    let wide: Vec<u16> = b.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    Some(OsString::from_wide(&wide))
+}
+
 pub struct FileRow<'a> {
-    pub path: &'a str,
+    pub path: &'a Path,
     pub name: &'a str,
     pub size_bytes: i64,
     pub duration: Option<f64>,
     pub mtime: i64,
+    pub sample_rate: Option<u32>,
+    pub bits_per_sample: Option<u16>,
+    pub channels: Option<u16>,
 }
 
 pub fn upsert_file(conn: &Connection, f: &FileRow) -> Result<()> {
-    // Update only if new or modified by mtime
+    // Update only if new or modified by mtime, or still missing header fields from older scans
     conn.execute(
         r#"
-        INSERT INTO files(path, name, size_bytes, duration, mtime)
-        VALUES(?, ?, ?, ?, ?)
+        INSERT INTO files(path, name, size_bytes, duration, mtime, sample_rate, bits_per_sample, channels)
+        VALUES(?, ?, ?, ?, ?, ?, ?, ?)
         ON CONFLICT(path) DO UPDATE SET
             name=excluded.name,
             size_bytes=excluded.size_bytes,
             duration=excluded.duration,
-            mtime=excluded.mtime
-        WHERE files.mtime <> excluded.mtime;
+            mtime=excluded.mtime,
+            sample_rate=excluded.sample_rate,
+            bits_per_sample=excluded.bits_per_sample,
+            channels=excluded.channels,
+            -- Analyses are redone for the new audio; imported tempos and keys are kept
+            bpm=CASE WHEN files.bpm_confidence IS NULL THEN files.bpm END,
+            bpm_confidence=NULL,
+            musical_key=CASE WHEN files.tonal_confidence IS NULL THEN files.musical_key END,
+            pitch=NULL,
+            tonal_confidence=NULL,
+            peak_db=NULL,
+            rms_db=NULL,
+            lufs=NULL,
+            category=NULL,
+            is_silent=NULL,
+            spectral_centroid=NULL,
+            spectral_rolloff=NULL,
+            spectral_flatness=NULL,
+            zero_crossing_rate=NULL
+        WHERE files.mtime <> excluded.mtime
+           OR (files.sample_rate IS NULL AND excluded.sample_rate IS NOT NULL);
         "#,
-        params![f.path, f.name, f.size_bytes, f.duration, f.mtime],
+        params![SqlPath(f.path), f.name, f.size_bytes, f.duration, f.mtime, f.sample_rate, f.bits_per_sample, f.channels],
     )?;
     Ok(())
 }
 
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryStats {
+    pub file_count: i64,
+    pub embedding_count: i64,
+    pub coord_count: i64,
+    pub total_bytes: i64,
+    /// Sum of known durations; files whose header couldn't be read don't contribute.
+    pub total_duration_hours: f64,
+    /// Lowercased extension (without the dot) → file count.
+    pub counts_by_extension: std::collections::BTreeMap<String, i64>,
+    pub missing_embeddings: i64,
+    pub missing_coords: i64,
+}
+
+pub fn library_stats(conn: &Connection) -> Result<LibraryStats> {
+    let count = |sql: &str| -> Result<i64> { Ok(conn.query_row(sql, [], |r| r.get(0))?) };
+    let (total_bytes, total_seconds): (i64, f64) = conn.query_row(
+        "SELECT COALESCE(SUM(size_bytes), 0), COALESCE(SUM(duration), 0.0) FROM files",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    // Text after the last '.': rtrim strips the extension's characters, replace removes the rest
+    let mut stmt = conn.prepare(
+        "SELECT lower(replace(name, rtrim(name, replace(name, '.', '')), '')) AS ext, COUNT(*) FROM files GROUP BY ext",
+    )?;
+    let counts_by_extension = stmt
+        .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(LibraryStats {
+        file_count: file_count(conn)?,
+        embedding_count: count("SELECT COUNT(*) FROM active_embeddings")?,
+        coord_count: count("SELECT COUNT(*) FROM coords")?,
+        total_bytes,
+        total_duration_hours: total_seconds / 3600.0,
+        counts_by_extension,
+        missing_embeddings: count("SELECT COUNT(*) FROM files WHERE id NOT IN (SELECT file_id FROM active_embeddings)")?,
+        missing_coords: count("SELECT COUNT(*) FROM files WHERE id NOT IN (SELECT file_id FROM coords)")?,
+    })
+}
+
 pub fn file_count(conn: &Connection) -> Result<i64> {
     let mut stmt = conn.prepare("SELECT COUNT(*) FROM files")?;
     let cnt: i64 = stmt.query_row([], |r| r.get(0))?;
     Ok(cnt)
 }
+
+/// Returns false if no file has this id.
+pub fn set_favorite(conn: &Connection, file_id: i64, favorite: bool) -> Result<bool> {
+    let n = conn.execute("UPDATE files SET favorite = ? WHERE id = ?", params![favorite, file_id])?;
+    Ok(n > 0)
+}
+
+/// `None` clears the rating. Returns false if no file has this id.
+pub fn set_rating(conn: &Connection, file_id: i64, rating: Option<u8>) -> Result<bool> {
+    if let Some(r) = rating {
+        anyhow::ensure!((1..=5).contains(&r), "rating must be 1-5, got {r}");
+    }
+    let n = conn.execute("UPDATE files SET rating = ? WHERE id = ?", params![rating, file_id])?;
+    Ok(n > 0)
+}
+
+/// Counts an audition of `path` if it belongs to the library. Returns the file id when it does.
+pub fn record_play(conn: &Connection, path: &Path, played_at: i64) -> Result<Option<i64>> {
+    let id = file_id(conn, path)?;
+    if let Some(id) = id {
+        conn.execute(
+            "UPDATE files SET play_count = play_count + 1, last_played_at = ? WHERE id = ?",
+            params![played_at, id],
+        )?;
+    }
+    Ok(id)
+}
+
+/// Id of the library file at `path`, or `None` if it isn't in the library.
+pub fn file_id(conn: &Connection, path: &Path) -> Result<Option<i64>> {
+    let path = crate::paths::canonical(path);
+    Ok(conn.query_row("SELECT id FROM files WHERE path = ?", params![SqlPath(&path)], |r| r.get(0)).optional()?)
+}
+
+/// Stored path of a file, or `None` if no file has this id.
+pub fn file_path(conn: &Connection, file_id: i64) -> Result<Option<PathBuf>> {
+    let p = conn.query_row("SELECT path FROM files WHERE id = ?", params![file_id], |r| r.get::<_, StoredPath>(0)).optional()?;
+    Ok(p.map(|p| p.0))
+}
+
+/// Empty or whitespace-only text clears the note. Returns false if no file has this id.
+pub fn set_note(conn: &Connection, file_id: i64, note: Option<&str>) -> Result<bool> {
+    let note = note.map(str::trim).filter(|n| !n.is_empty());
+    let n = conn.execute("UPDATE files SET note = ? WHERE id = ?", params![note, file_id])?;
+    Ok(n > 0)
+}
+
+/// Accepts `#rgb` or `#rrggbb` (case-insensitive) and stores the long lowercase form; `None`
+/// clears the label. Returns false if no file has this id.
+pub fn set_color(conn: &Connection, file_id: i64, color: Option<&str>) -> Result<bool> {
+    let color = color.map(normalize_color).transpose()?;
+    let n = conn.execute("UPDATE files SET color = ? WHERE id = ?", params![color, file_id])?;
+    Ok(n > 0)
+}
+
+pub fn normalize_color(input: &str) -> Result<String> {
+    let hex = input.trim().trim_start_matches('#').to_ascii_lowercase();
+    let valid = matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit());
+    if !valid { bail!("invalid color {input:?}, expected #rrggbb"); }
+    if hex.len() == 3 {
+        return Ok(format!("#{}", hex.chars().flat_map(|c| [c, c]).collect::<String>()));
+    }
+    Ok(format!("#{hex}"))
+}
+
+/// Soft-deletes (or restores) files: rows, embeddings and coords are kept. Returns how many rows changed.
+pub fn set_hidden(conn: &mut Connection, file_ids: &[i64], hidden: bool) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    {
+        let mut stmt = tx.prepare("UPDATE files SET hidden = ? WHERE id = ? AND hidden <> ?")?;
+        for id in file_ids { n += stmt.execute(params![hidden, id, hidden])?; }
+    }
+    tx.commit()?;
+    Ok(n)
+}
+
+/// Edits for `bulk_update`. Absent fields are left alone; for `rating` and `color`, an explicit
+/// `null` clears the value.
+#[derive(Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BulkChanges {
+    pub add_tags: Vec<String>,
+    pub remove_tags: Vec<String>,
+    #[serde(deserialize_with = "present")]
+    pub rating: Option<Option<u8>>,
+    #[serde(deserialize_with = "present")]
+    pub color: Option<Option<String>>,
+    pub hidden: Option<bool>,
+    pub favorite: Option<bool>,
+}
+
+/// Maps a present field (even `null`) to `Some`, so it can be told apart from a missing one.
+fn present<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(d: D) -> std::result::Result<Option<Option<T>>, D::Error> {
+    <Option<T> as serde::Deserialize>::deserialize(d).map(Some)
+}
+
+/// Applies `changes` to every file in one transaction; nothing is written if any part fails.
+/// Returns how many of the files exist.
+pub fn bulk_update(conn: &mut Connection, file_ids: &[i64], changes: &BulkChanges) -> Result<usize> {
+    if let Some(Some(r)) = changes.rating {
+        anyhow::ensure!((1..=5).contains(&r), "rating must be 1-5, got {r}");
+    }
+    let color = match &changes.color {
+        Some(c) => Some(c.as_deref().map(normalize_color).transpose()?),
+        None => None,
+    };
+    let add: Vec<String> = changes.add_tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let remove: Vec<String> = changes.remove_tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    {
+        let mut exists = tx.prepare("SELECT 1 FROM files WHERE id = ?")?;
+        // As in `add_tags`: a user tag takes over a machine tag of the same name
+        let mut tag = tx.prepare(
+            "INSERT INTO file_tags(file_id, tag) VALUES(?, ?) \
+             ON CONFLICT(file_id, tag) DO UPDATE SET source = 'user', confidence = NULL WHERE source = 'auto'",
+        )?;
+        let mut untag = tx.prepare("DELETE FROM file_tags WHERE file_id = ? AND tag = ?")?;
+        for &id in file_ids {
+            if !exists.exists(params![id])? { continue; }
+            n += 1;
+            for t in &add { tag.execute(params![id, t])?; }
+            for t in &remove { untag.execute(params![id, t])?; }
+            if let Some(r) = changes.rating { tx.execute("UPDATE files SET rating = ? WHERE id = ?", params![r, id])?; }
+            if let Some(c) = &color { tx.execute("UPDATE files SET color = ? WHERE id = ?", params![c, id])?; }
+            if let Some(h) = changes.hidden { tx.execute("UPDATE files SET hidden = ? WHERE id = ?", params![h, id])?; }
+            if let Some(f) = changes.favorite { tx.execute("UPDATE files SET favorite = ? WHERE id = ?", params![f, id])?; }
+        }
+    }
+    tx.commit()?;
+    Ok(n)
+}
+
+/// Ids of the files at or below `root`. Compared component-wise in Rust, since stored paths
+/// may be blobs.
+pub fn files_under(conn: &Connection, root: &Path) -> Result<Vec<i64>> {
+    let root = crate::paths::canonical(root);
+    let mut stmt = conn.prepare("SELECT id, path FROM files")?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, StoredPath>(1)?.0)))?;
+    let mut ids = Vec::new();
+    for row in rows {
+        let (id, path) = row?;
+        if path.starts_with(&root) { ids.push(id); }
+    }
+    Ok(ids)
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovalSummary {
+    pub files: usize,
+    pub embeddings: usize,
+    pub coords: usize,
+    pub tags: usize,
+}
+
+/// What `delete_files` would take with it.
+pub fn removal_summary(conn: &Connection, file_ids: &[i64]) -> Result<RemovalSummary> {
+    let mut s = RemovalSummary { files: file_ids.len(), ..Default::default() };
+    let count = |table: &str, id: i64| -> Result<usize> {
+        Ok(conn.query_row(&format!("SELECT COUNT(*) FROM {table} WHERE file_id = ?"), params![id], |r| r.get::<_, i64>(0))? as usize)
+    };
+    for &id in file_ids {
+        s.embeddings += count("embeddings", id)?;
+        s.coords += count("coords", id)?;
+        s.tags += count("file_tags", id)?;
+    }
+    Ok(s)
+}
+
+/// Tables with a `file_id` column whose rows go with the file.
+pub const FILE_DEPENDENTS: &[&str] = &["embeddings", "coords", "layout_coords", "file_tags", "pins", "waveforms"];
+
+/// Removes files along with everything keyed on them. Foreign keys aren't enforced on these
+/// connections, so the dependent rows are deleted explicitly. Returns how many files went.
+pub fn delete_files(conn: &mut Connection, file_ids: &[i64]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    for id in file_ids {
+        for table in FILE_DEPENDENTS {
+            tx.execute(&format!("DELETE FROM {table} WHERE file_id = ?"), params![id])?;
+        }
+        n += tx.execute("DELETE FROM files WHERE id = ?", params![id])?;
+    }
+    tx.commit()?;
+    Ok(n)
+}
+
+/// Moves the file on disk and points its row at `to`, keeping the id and so everything attached
+/// to it.
+pub fn relocate_file(conn: &mut Connection, file_id: i64, to: &Path) -> Result<()> {
+    relocate_files(conn, &[(file_id, to.to_path_buf())])
+}
+
+/// [`relocate_file`] for several files, all or nothing. Rows are updated first, so a clashing
+/// path fails before anything moves; if a move or the commit fails, the files already moved
+/// are moved back.
+pub fn relocate_files(conn: &mut Connection, moves: &[(i64, PathBuf)]) -> Result<()> {
+    let tx = conn.transaction()?;
+    let mut plan = Vec::with_capacity(moves.len());
+    for (id, to) in moves {
+        let from = file_path(&tx, *id)?.with_context(|| format!("file {id} not found"))?;
+        if to.exists() { bail!("{} already exists", to.display()); }
+        let name = to.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        tx.execute("UPDATE files SET path = ?, name = ? WHERE id = ?", params![SqlPath(to), name, id])?;
+        plan.push((from, to));
+    }
+    let mut done = 0;
+    let res = plan
+        .iter()
+        .try_for_each(|(from, to)| {
+            move_path(from, to).with_context(|| format!("move {} to {}", from.display(), to.display()))?;
+            done += 1;
+            Ok(())
+        })
+        .and_then(|_| tx.commit().map_err(anyhow::Error::from));
+    if res.is_err() {
+        for (from, to) in plan[..done].iter().rev() { let _ = move_path(to, from); }
+    }
+    res
+}
+
+/// `rename`, or copy-then-delete when the two paths are on different volumes.
+fn move_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() { return Ok(()); }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from).inspect_err(|_| { let _ = std::fs::remove_file(to); })
+}
+
+/// On-disk footprint of the database including its WAL and shared-memory sidecars.
+pub fn db_size_bytes(path: &Path) -> u64 {
+    let sidecar = |suffix: &str| {
+        let mut p = path.as_os_str().to_owned();
+        p.push(suffix);
+        PathBuf::from(p)
+    };
+    [path.to_path_buf(), sidecar("-wal"), sidecar("-shm")]
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OptimizeReport {
+    pub size_before: u64,
+    pub size_after: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// Merges FTS segments, refreshes planner statistics, rewrites the file without free pages and
+/// truncates the WAL. Blocks writers for the duration; meant to be run on demand.
+pub fn optimize(conn: &Connection, path: &Path) -> Result<OptimizeReport> {
+    let size_before = db_size_bytes(path);
+    conn.execute("INSERT INTO files_fts(files_fts) VALUES ('optimize')", [])?;
+    conn.execute_batch("ANALYZE; VACUUM;")?;
+    // Returns (busy, log frames, checkpointed frames); only the side effect matters here
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    let size_after = db_size_bytes(path);
+    Ok(OptimizeReport { size_before, size_after, reclaimed_bytes: size_before.saturating_sub(size_after) })
+}
+
+/// Tags are stored trimmed and lowercased so "Kick" and "kick " are the same tag.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let t = tag.trim().to_lowercase();
+    if t.is_empty() { None } else { Some(t) }
+}
+
+/// Adds every tag to every file, taking over machine tags of the same name. Returns how many
+/// (file, tag) pairs were new or taken over.
+pub fn add_tags(conn: &mut Connection, file_ids: &[i64], tags: &[String]) -> Result<usize> {
+    let tags: Vec<String> = tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO file_tags(file_id, tag) SELECT id, ? FROM files WHERE id = ? \
+             ON CONFLICT(file_id, tag) DO UPDATE SET source = 'user', confidence = NULL WHERE source = 'auto'",
+        )?;
+        for id in file_ids {
+            for t in &tags { n += stmt.execute(params![t, id])?; }
+        }
+    }
+    tx.commit()?;
+    Ok(n)
+}
+
+pub fn remove_tags(conn: &mut Connection, file_ids: &[i64], tags: &[String]) -> Result<usize> {
+    let tags: Vec<String> = tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    {
+        let mut stmt = tx.prepare("DELETE FROM file_tags WHERE file_id = ? AND tag = ?")?;
+        for id in file_ids {
+            for t in &tags { n += stmt.execute(params![id, t])?; }
+        }
+    }
+    tx.commit()?;
+    Ok(n)
+}
@@ -0,0 +1,326 @@
+use crate::{
+    db::{self, StoredPath},
+    models, playback,
+    quant,
+    pyenv,
+    settings::{self, Device, EmbeddingBackend, EmbeddingSettings},
+    worker::{self, Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+
+/// CLAP's audio encoder works on 48 kHz mono.
+pub const SAMPLE_RATE: u32 = 48_000;
+/// Embedding width stored in the `embeddings` table, matching the Python worker.
+pub const DIM: usize = 512;
+
+/// Token sequence length the CLAP text encoder was exported with.
+const TEXT_LEN: usize = 77;
+
+fn model_file(app: &tauri::AppHandle, configured: &Option<PathBuf>, default_name: &str) -> Result<PathBuf> {
+    match configured {
+        Some(p) => Ok(p.clone()),
+        None => Ok(db::data_dir(app)?.join("models").join(default_name)),
+    }
+}
+
+fn model_path(app: &tauri::AppHandle, cfg: &EmbeddingSettings) -> Result<PathBuf> {
+    model_file(app, &cfg.model_path, "clap-audio.onnx")
+}
+
+/// Embeds every file that has no embedding from the active model yet, then projects the map.
+/// Native inference is used when available (see `settings.embedding.backend`); registered models
+/// other than the built-in CLAP are native only. Projection still runs in the worker.
+pub fn run_pipeline(app: &tauri::AppHandle, dbp: &Path, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<()> {
+    let s = settings::load(app);
+    let (cfg, proj) = (s.embedding, s.projection);
+    let conn = db::open_or_create(dbp)?;
+    let active = models::get(&conn, models::active(&conn)?)?;
+    if let Some(path) = &active.path {
+        if !cfg!(feature = "onnx") { bail!("model {:?} needs native embedding support, which this build lacks", active.name); }
+        embed_missing(&conn, active.id, path, &cfg, on_progress, cancel)?;
+        return worker::run_pipeline(app, dbp, "umap", &proj, on_progress, cancel);
+    }
+    let model = model_path(app, &cfg)?;
+    let native = cfg!(feature = "onnx") && model.is_file();
+    match cfg.backend {
+        EmbeddingBackend::Python => return worker::run_pipeline(app, dbp, "all", &proj, on_progress, cancel),
+        EmbeddingBackend::Auto if !native => return worker::run_pipeline(app, dbp, "all", &proj, on_progress, cancel),
+        EmbeddingBackend::Native if !cfg!(feature = "onnx") => bail!("this build has no native embedding support"),
+        EmbeddingBackend::Native if !native => bail!("embedding model not found at {}", model.display()),
+        _ => {}
+    }
+    embed_missing(&conn, active.id, &model, &cfg, on_progress, cancel)?;
+    worker::run_pipeline(app, dbp, "umap", &proj, on_progress, cancel)
+}
+
+fn embed_missing(
+    conn: &Connection,
+    model_id: i64,
+    model: &Path,
+    cfg: &EmbeddingSettings,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+) -> Result<()> {
+    let (clip_seconds, storage) = (cfg.clip_seconds, cfg.storage);
+    let encoder = onnx::Encoder::open(model, cfg.device, cfg.threads())?;
+    let todo: Vec<(i64, PathBuf)> = {
+        let mut stmt = conn.prepare("SELECT id, path FROM files WHERE id NOT IN (SELECT file_id FROM embeddings WHERE model_id = ?) ORDER BY id")?;
+        let rows = stmt.query_map(params![model_id], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let clip_len = (clip_seconds.max(0.1) * SAMPLE_RATE as f64) as usize;
+    let (total, batch_size) = (todo.len(), cfg.batch_size());
+    for (b, chunk) in todo.chunks(batch_size).enumerate() {
+        // Rows written so far are complete; the rest wait for the next scan
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "embedding".into(), processed: b * batch_size, total, batch: Some(b) });
+        // A bad file shouldn't stop the batch; it stays unembedded and is retried next scan
+        let mut ids = Vec::with_capacity(chunk.len());
+        let mut clips = Vec::with_capacity(chunk.len());
+        for (id, path) in chunk {
+            match load_clip(path, clip_len) {
+                Ok(clip) => {
+                    ids.push(*id);
+                    clips.push(clip);
+                }
+                Err(e) => log::warn!("embed: {}: {e}", path.display()),
+            }
+        }
+        if clips.is_empty() { continue; }
+        let vecs = match encoder.embed_batch(&clips) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("embed: batch of {} failed: {e}", clips.len());
+                continue;
+            }
+        };
+        db::retry_busy(|| {
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut stmt = tx.prepare_cached("INSERT OR REPLACE INTO embeddings(file_id, model_id, dim, vec, dtype) VALUES(?, ?, ?, ?, ?)")?;
+                for (id, v) in ids.iter().zip(&vecs) {
+                    stmt.execute(params![id, model_id, v.len() as i64, quant::encode(v, storage), storage.as_str()])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })?;
+    }
+    on_progress(&Progress { stage: "embedding".into(), processed: total, total, batch: None });
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub device: Device,
+    /// Usable by in-process ONNX inference in this build.
+    pub native: bool,
+    /// Usable by the worker's torch; false until its venv is installed.
+    pub python: bool,
+}
+
+/// Which devices each backend can embed on. The Python side is probed in the worker's venv,
+/// which takes a few seconds while torch loads.
+pub fn available_devices(app: &tauri::AppHandle) -> Vec<DeviceInfo> {
+    let python = pyenv::torch_devices(app);
+    Device::ALL
+        .into_iter()
+        .filter(|&d| d != Device::Auto)
+        .map(|device| DeviceInfo { device, native: onnx::available(device), python: python.iter().any(|p| p == device.as_str()) })
+        .collect()
+}
+
+/// The CLAP text encoder, loaded on the first prompt and reloaded if its configured files change.
+#[derive(Default)]
+pub struct TextModel {
+    loaded: Mutex<Option<((PathBuf, PathBuf), onnx::TextEncoder)>>,
+}
+
+impl TextModel {
+    /// Embeds `prompt` into the same space as the audio embeddings.
+    pub fn embed(&self, app: &tauri::AppHandle, prompt: &str) -> Result<Vec<f32>> {
+        let cfg = settings::load(app).embedding;
+        let files = (
+            model_file(app, &cfg.text_model_path, "clap-text.onnx")?,
+            model_file(app, &cfg.tokenizer_path, "clap-tokenizer.json")?,
+        );
+        let mut guard = self.loaded.lock();
+        if !matches!(&*guard, Some((f, _)) if *f == files) {
+            for p in [&files.0, &files.1] {
+                if !p.is_file() { bail!("text search needs {}", p.display()); }
+            }
+            *guard = Some((files.clone(), onnx::TextEncoder::open(&files.0, &files.1)?));
+        }
+        let (_, encoder) = guard.as_ref().expect("encoder loaded above");
+        encoder.embed(prompt)
+    }
+}
+
+/// The first `len` samples of `path` as 48 kHz mono, repeated to fill short files the way
+/// CLAP's "repeatpad" does.
+pub fn load_clip(path: &Path, len: usize) -> Result<Vec<f32>> {
+    let (channels, rate, data) = playback::decode_samples(path)?;
+    let channels = channels.max(1) as usize;
+    let mono: Vec<f32> = data.chunks(channels).map(|f| f.iter().sum::<f32>() / f.len() as f32).collect();
+    let mut clip = resample(&mono, rate, SAMPLE_RATE);
+    if clip.is_empty() { bail!("empty audio"); }
+    clip.truncate(len);
+    let n = clip.len();
+    while clip.len() < len {
+        let take = n.min(len - clip.len());
+        clip.extend_from_within(..take);
+    }
+    Ok(clip)
+}
+
+/// Linear-interpolation resampler. Not studio grade, but CLAP is trained on far worse.
+pub fn resample(x: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || x.is_empty() { return x.to_vec(); }
+    let ratio = from as f64 / to as f64;
+    let n = ((x.len() as f64) / ratio).floor() as usize;
+    (0..n)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let j = pos as usize;
+            let frac = (pos - j as f64) as f32;
+            let a = x[j];
+            let b = x.get(j + 1).copied().unwrap_or(a);
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+/// Brings model output to the stored width (same reduction as the worker) and unit length.
+#[cfg_attr(not(feature = "onnx"), allow(dead_code))]
+fn to_embedding(mut v: Vec<f32>) -> Vec<f32> {
+    if v.len() == 2 * DIM {
+        v = (0..DIM).map(|i| (v[i] + v[i + DIM]) / 2.0).collect();
+    }
+    v.truncate(DIM);
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 { v.iter_mut().for_each(|x| *x /= norm); }
+    v
+}
+
+#[cfg(feature = "onnx")]
+mod onnx {
+    use crate::settings::Device;
+    use anyhow::{anyhow, bail, Context, Result};
+    use ort::{
+        execution_providers::{CUDAExecutionProvider, DirectMLExecutionProvider, ExecutionProvider, ExecutionProviderDispatch},
+        session::Session,
+        value::Tensor,
+    };
+    use std::path::Path;
+    use tokenizers::Tokenizer;
+
+    /// Whether ONNX Runtime can run on `device`; CUDA and DirectML need the matching cargo
+    /// feature and the GPU's runtime libraries.
+    pub fn available(device: Device) -> bool {
+        match device {
+            Device::Auto | Device::Cpu => true,
+            Device::Cuda => CUDAExecutionProvider::default().is_available().unwrap_or(false),
+            Device::DirectMl => DirectMLExecutionProvider::default().is_available().unwrap_or(false),
+        }
+    }
+
+    /// Execution providers for `device`. An explicit GPU must register or the session fails;
+    /// `Auto` lists every GPU provider and lets ONNX Runtime skip the ones that don't load.
+    fn providers(device: Device) -> Result<Vec<ExecutionProviderDispatch>> {
+        Ok(match device {
+            Device::Cpu => Vec::new(),
+            Device::Auto => vec![CUDAExecutionProvider::default().build(), DirectMLExecutionProvider::default().build()],
+            Device::Cuda | Device::DirectMl if !available(device) => bail!("{} isn't available to this build", device.as_str()),
+            Device::Cuda => vec![CUDAExecutionProvider::default().build().error_on_failure()],
+            Device::DirectMl => vec![DirectMLExecutionProvider::default().build().error_on_failure()],
+        })
+    }
+
+    /// CLAP audio encoder: `[batch, samples]` waveform in, `[batch, dim]` embedding out.
+    pub struct Encoder {
+        session: Session,
+    }
+
+    impl Encoder {
+        pub fn open(model: &Path, device: Device, threads: usize) -> Result<Self> {
+            let session = Session::builder()?
+                .with_execution_providers(providers(device)?)?
+                .with_intra_threads(threads)?
+                .commit_from_file(model)
+                .with_context(|| format!("load embedding model {}", model.display()))?;
+            Ok(Self { session })
+        }
+
+        /// Embeds equal-length clips in one run.
+        pub fn embed_batch(&self, clips: &[Vec<f32>]) -> Result<Vec<Vec<f32>>> {
+            let len = clips.first().map_or(0, Vec::len);
+            let flat: Vec<f32> = clips.iter().flatten().copied().collect();
+            let input = Tensor::from_array(([clips.len(), len], flat.into_boxed_slice()))?;
+            let outputs = self.session.run(ort::inputs![input]?)?;
+            let (_, data) = outputs[0].try_extract_raw_tensor::<f32>()?;
+            let width = data.len() / clips.len();
+            Ok(data.chunks(width).map(|v| super::to_embedding(v.to_vec())).collect())
+        }
+    }
+
+    /// CLAP text encoder: `input_ids` and `attention_mask` of `[batch, TEXT_LEN]` in, `[batch, dim]` out.
+    pub struct TextEncoder {
+        session: Session,
+        tokenizer: Tokenizer,
+    }
+
+    impl TextEncoder {
+        pub fn open(model: &Path, tokenizer: &Path) -> Result<Self> {
+            let tokenizer = Tokenizer::from_file(tokenizer).map_err(|e| anyhow!("load tokenizer {}: {e}", tokenizer.display()))?;
+            let session = Session::builder()?
+                .commit_from_file(model)
+                .with_context(|| format!("load text model {}", model.display()))?;
+            Ok(Self { session, tokenizer })
+        }
+
+        pub fn embed(&self, prompt: &str) -> Result<Vec<f32>> {
+            let enc = self.tokenizer.encode(prompt, true).map_err(|e| anyhow!("tokenize prompt: {e}"))?;
+            let mut ids: Vec<i64> = enc.get_ids().iter().map(|&t| t as i64).take(super::TEXT_LEN).collect();
+            let mut mask = vec![1i64; ids.len()];
+            // RoBERTa's <pad> is 1; prefer whatever the tokenizer says
+            let pad = self.tokenizer.token_to_id("<pad>").unwrap_or(1) as i64;
+            ids.resize(super::TEXT_LEN, pad);
+            mask.resize(super::TEXT_LEN, 0);
+            let shape = [1usize, super::TEXT_LEN];
+            let ids = Tensor::from_array((shape, ids.into_boxed_slice()))?;
+            let mask = Tensor::from_array((shape, mask.into_boxed_slice()))?;
+            let outputs = self.session.run(ort::inputs!["input_ids" => ids, "attention_mask" => mask]?)?;
+            let (_, data) = outputs[0].try_extract_raw_tensor::<f32>()?;
+            Ok(super::to_embedding(data.to_vec()))
+        }
+    }
+}
+
+#[cfg(not(feature = "onnx"))]
+mod onnx {
+    use crate::settings::Device;
+    use anyhow::{bail, Result};
+    use std::path::Path;
+
+    pub fn available(_device: Device) -> bool { false }
+
+    pub struct Encoder;
+
+    impl Encoder {
+        pub fn open(_model: &Path, _device: Device, _threads: usize) -> Result<Self> { bail!("this build has no native embedding support") }
+
+        pub fn embed_batch(&self, _clips: &[Vec<f32>]) -> Result<Vec<Vec<f32>>> { bail!("this build has no native embedding support") }
+    }
+
+    pub struct TextEncoder;
+
+    impl TextEncoder {
+        pub fn open(_model: &Path, _tokenizer: &Path) -> Result<Self> { bail!("this build has no text search support") }
+
+        pub fn embed(&self, _prompt: &str) -> Result<Vec<f32>> { bail!("this build has no text search support") }
+    }
+}
@@ -0,0 +1,54 @@
+//! Library change notifications, so the frontend can patch what it shows instead of
+//! refetching every coordinate after each operation. Emitting is best-effort.
+
+use crate::{db, layouts};
+use tauri::{AppHandle, Emitter};
+
+pub const FILES_ADDED: &str = "library://files_added";
+pub const FILES_REMOVED: &str = "library://files_removed";
+pub const COORDS_UPDATED: &str = "library://coords_updated";
+pub const METADATA_CHANGED: &str = "library://metadata_changed";
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilesChanged {
+    pub file_ids: Vec<i64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoordsUpdated {
+    /// The layout now on the map; `None` if coords came from a run outside the layout history.
+    pub layout_id: Option<i64>,
+}
+
+fn files(app: &AppHandle, event: &str, file_ids: &[i64]) {
+    if file_ids.is_empty() { return; }
+    if let Err(e) = app.emit(event, FilesChanged { file_ids: file_ids.to_vec() }) { log::warn!("events: {event}: {e}"); }
+}
+
+pub fn files_added(app: &AppHandle, file_ids: &[i64]) { files(app, FILES_ADDED, file_ids) }
+pub fn files_removed(app: &AppHandle, file_ids: &[i64]) { files(app, FILES_REMOVED, file_ids) }
+pub fn metadata_changed(app: &AppHandle, file_ids: &[i64]) { files(app, METADATA_CHANGED, file_ids) }
+
+pub fn coords_updated(app: &AppHandle, layout_id: Option<i64>) {
+    if let Err(e) = app.emit(COORDS_UPDATED, CoordsUpdated { layout_id }) { log::warn!("events: {COORDS_UPDATED}: {e}"); }
+}
+
+/// [`coords_updated`] after a projection into `dbfile`, naming whichever layout it left active.
+pub fn projected(app: &AppHandle, dbfile: &std::path::Path) {
+    coords_updated(app, db::open_or_create(dbfile).ok().and_then(|c| layouts::active(&c).ok().flatten()));
+}
+
+/// Ids above `after`, i.e. the rows inserted since it was read with [`max_file_id`].
+pub fn new_file_ids(conn: &rusqlite::Connection, after: i64) -> Vec<i64> {
+    let ids = conn.prepare("SELECT id FROM files WHERE id > ? ORDER BY id").and_then(|mut s| {
+        let ids = s.query_map([after], |r| r.get(0))?.collect::<rusqlite::Result<Vec<i64>>>();
+        ids
+    });
+    ids.unwrap_or_default()
+}
+
+pub fn max_file_id(conn: &rusqlite::Connection) -> i64 {
+    conn.query_row("SELECT COALESCE(MAX(id), 0) FROM files", [], |r| r.get(0)).unwrap_or(0)
+}
@@ -0,0 +1,413 @@
+use crate::{
+    db,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Svg,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExportOptions {
+    /// `None` = from the file extension.
+    pub format: Option<ImageFormat>,
+    pub width: u32,
+    pub height: u32,
+    /// Point radius in pixels.
+    pub point_radius: f32,
+    pub background: String,
+    /// Cluster captions at each cluster's centre. SVG only; the PNG writer has no font.
+    pub labels: bool,
+    pub include_hidden: bool,
+    /// Stored layout to draw; `None` = the active one.
+    pub layout: Option<i64>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: None,
+            width: 4096,
+            height: 4096,
+            point_radius: 2.0,
+            background: "#111111".into(),
+            labels: true,
+            include_hidden: false,
+            layout: None,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportReport {
+    pub points: usize,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+}
+
+/// Grey for files with neither a color label nor a cluster.
+const DEFAULT_COLOR: [u8; 3] = [0x9a, 0xa4, 0xb0];
+/// Keeps points off the image edge.
+const MARGIN: f32 = 0.03;
+
+struct MapPoint {
+    x: f32,
+    y: f32,
+    color: [u8; 3],
+    cluster: Option<i64>,
+}
+
+/// Renders the layout to `path` as a poster: points take their color label, else a per-cluster
+/// hue. Layout space ([-1, 1], y up) is fit into the image with a small margin.
+pub fn export_map_image(conn: &Connection, path: &Path, opts: &ExportOptions) -> Result<ExportReport> {
+    let format = match opts.format {
+        Some(f) => f,
+        None => match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("png") => ImageFormat::Png,
+            Some("svg") => ImageFormat::Svg,
+            _ => bail!("can't tell the image format from {}; use .png or .svg", path.display()),
+        },
+    };
+    if opts.width == 0 || opts.height == 0 || opts.width > 16_384 || opts.height > 16_384 {
+        bail!("image size must be between 1 and 16384 pixels per side");
+    }
+    let background = parse_hex(&crate::db::normalize_color(&opts.background)?);
+    let points = load_points(conn, opts)?;
+    match format {
+        ImageFormat::Png => write_png(path, opts, background, &points)?,
+        ImageFormat::Svg => write_svg(conn, path, opts, background, &points)?,
+    }
+    let bytes = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    Ok(ExportReport { points: points.len(), width: opts.width, height: opts.height, bytes })
+}
+
+fn load_points(conn: &Connection, opts: &ExportOptions) -> Result<Vec<MapPoint>> {
+    let mut stmt = conn.prepare(
+        "SELECT c.x, c.y, f.color, f.cluster_id \
+         FROM (SELECT file_id, x, y FROM coords WHERE ?2 IS NULL \
+               UNION ALL SELECT file_id, x, y FROM layout_coords WHERE layout_id = ?2) c \
+         JOIN files f ON f.id = c.file_id WHERE ?1 OR f.hidden = 0 ORDER BY c.file_id",
+    )?;
+    let rows = stmt
+        .query_map(params![opts.include_hidden, opts.layout], |r| {
+            let color: Option<String> = r.get(2)?;
+            let cluster: Option<i64> = r.get(3)?;
+            Ok(MapPoint {
+                x: r.get::<_, f64>(0)? as f32,
+                y: r.get::<_, f64>(1)? as f32,
+                color: color.as_deref().map(parse_hex).or(cluster.map(cluster_color)).unwrap_or(DEFAULT_COLOR),
+                cluster,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// `#rrggbb` as stored by `db::normalize_color`.
+fn parse_hex(s: &str) -> [u8; 3] {
+    let v = u32::from_str_radix(s.trim_start_matches('#'), 16).unwrap_or(0);
+    [(v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+/// Evenly spread hues (golden angle) so neighbouring cluster ids don't look alike.
+fn cluster_color(id: i64) -> [u8; 3] {
+    let h = (id as f32 * 137.508).rem_euclid(360.0) / 60.0;
+    let (s, v) = (0.65f32, 0.95f32);
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [((r + m) * 255.0) as u8, ((g + m) * 255.0) as u8, ((b + m) * 255.0) as u8]
+}
+
+/// Layout position to pixel centre, flipping y (image rows grow downwards).
+fn to_pixel(opts: &ExportOptions, x: f32, y: f32) -> (f32, f32) {
+    let scale = 1.0 - 2.0 * MARGIN;
+    let px = ((x * scale + 1.0) / 2.0) * opts.width as f32;
+    let py = ((1.0 - y * scale) / 2.0) * opts.height as f32;
+    (px, py)
+}
+
+fn write_png(path: &Path, opts: &ExportOptions, background: [u8; 3], points: &[MapPoint]) -> Result<()> {
+    let (w, h) = (opts.width as usize, opts.height as usize);
+    let mut rgb: Vec<u8> = background.iter().copied().cycle().take(w * h * 3).collect();
+    let r = opts.point_radius.max(0.5);
+    for p in points {
+        let (cx, cy) = to_pixel(opts, p.x, p.y);
+        let (x0, x1) = ((cx - r - 1.0).floor().max(0.0) as usize, ((cx + r + 1.0).ceil() as usize).min(w));
+        let (y0, y1) = ((cy - r - 1.0).floor().max(0.0) as usize, ((cy + r + 1.0).ceil() as usize).min(h));
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let d = ((px as f32 + 0.5 - cx).powi(2) + (py as f32 + 0.5 - cy).powi(2)).sqrt();
+                // One pixel of falloff at the rim, at 85% opacity so overlaps read as density
+                let a = (r + 0.5 - d).clamp(0.0, 1.0) * 0.85;
+                if a <= 0.0 { continue; }
+                let i = (py * w + px) * 3;
+                for (dst, &src) in rgb[i..i + 3].iter_mut().zip(&p.color) {
+                    *dst = (*dst as f32 * (1.0 - a) + src as f32 * a).round() as u8;
+                }
+            }
+        }
+    }
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut enc = png::Encoder::new(BufWriter::new(file), opts.width, opts.height);
+    enc.set_color(png::ColorType::Rgb);
+    enc.set_depth(png::BitDepth::Eight);
+    let mut writer = enc.write_header()?;
+    writer.write_image_data(&rgb)?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_svg(conn: &Connection, path: &Path, opts: &ExportOptions, background: [u8; 3], points: &[MapPoint]) -> Result<()> {
+    let hex = |c: [u8; 3]| format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+    let mut out = String::with_capacity(points.len() * 48 + 512);
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = opts.width,
+        h = opts.height
+    )?;
+    writeln!(out, r#"<rect width="100%" height="100%" fill="{}"/>"#, hex(background))?;
+    // One group per color keeps the file small: each circle only carries its position
+    let mut by_color: HashMap<[u8; 3], Vec<&MapPoint>> = HashMap::new();
+    for p in points { by_color.entry(p.color).or_default().push(p); }
+    let mut groups: Vec<_> = by_color.into_iter().collect();
+    groups.sort_by_key(|(c, _)| *c);
+    for (color, pts) in groups {
+        writeln!(out, r#"<g fill="{}" fill-opacity="0.85">"#, hex(color))?;
+        for p in pts {
+            let (x, y) = to_pixel(opts, p.x, p.y);
+            writeln!(out, r#"<circle cx="{x:.1}" cy="{y:.1}" r="{}"/>"#, opts.point_radius)?;
+        }
+        out.push_str("</g>\n");
+    }
+    if opts.labels {
+        let labels: HashMap<i64, String> = {
+            let mut stmt = conn.prepare("SELECT cluster_id, label FROM clusters WHERE label IS NOT NULL")?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<rusqlite::Result<_>>()?;
+            rows
+        };
+        let mut centres: HashMap<i64, (f32, f32, usize)> = HashMap::new();
+        for p in points {
+            if let Some(c) = p.cluster {
+                let e = centres.entry(c).or_default();
+                e.0 += p.x;
+                e.1 += p.y;
+                e.2 += 1;
+            }
+        }
+        let mut centres: Vec<_> = centres.into_iter().collect();
+        centres.sort_by_key(|(c, _)| *c);
+        let size = (opts.width.min(opts.height) as f32 / 80.0).max(10.0);
+        writeln!(
+            out,
+            r##"<g font-family="sans-serif" font-size="{size:.0}" fill="#ffffff" stroke="#000000" stroke-width="{:.1}" paint-order="stroke" text-anchor="middle">"##,
+            size / 6.0
+        )?;
+        for (cluster, (sx, sy, n)) in centres {
+            let Some(label) = labels.get(&cluster) else { continue };
+            let (x, y) = to_pixel(opts, sx / n as f32, sy / n as f32);
+            writeln!(out, r#"<text x="{x:.1}" y="{y:.1}">{}</text>"#, escape_xml(label))?;
+        }
+        out.push_str("</g>\n");
+    }
+    out.push_str("</svg>\n");
+    let mut file = BufWriter::new(File::create(path).with_context(|| format!("create {}", path.display()))?);
+    file.write_all(out.as_bytes())?;
+    file.flush()?;
+    Ok(())
+}
+
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[derive(Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CopyMode {
+    #[default]
+    Copy,
+    /// No extra disk space; falls back to copying across volumes.
+    Hardlink,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FileExportOptions {
+    pub mode: CopyMode,
+    /// Everything straight into the destination; otherwise the folders below the selection's
+    /// common parent are recreated.
+    pub flatten: bool,
+    /// Name clashes get " (2)", " (3)" ..; off = files whose name is taken are skipped.
+    pub dedupe_names: bool,
+}
+
+impl Default for FileExportOptions {
+    fn default() -> Self { Self { mode: CopyMode::Copy, flatten: true, dedupe_names: true } }
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileExportReport {
+    pub exported: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Copies or hard-links the files into `dest`, reporting each one to `on_progress`. Missing
+/// sources and I/O errors are logged and counted rather than stopping the export.
+pub fn export_files(
+    conn: &Connection,
+    file_ids: &[i64],
+    dest: &Path,
+    opts: &FileExportOptions,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+) -> Result<FileExportReport> {
+    std::fs::create_dir_all(dest).with_context(|| format!("create {}", dest.display()))?;
+    let mut sources = Vec::with_capacity(file_ids.len());
+    for &id in file_ids {
+        if let Some(p) = db::file_path(conn, id)? { sources.push(p); }
+    }
+    let base = if opts.flatten { None } else { common_parent(&sources) };
+    let mut report = FileExportReport { skipped: file_ids.len() - sources.len(), ..Default::default() };
+    // Targets claimed by this export, so two sources never land on one name
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    let total = sources.len();
+    for (i, src) in sources.iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "exporting".into(), processed: i, total, batch: None });
+        let rel = match &base {
+            Some(b) => src.strip_prefix(b).map(Path::to_path_buf).unwrap_or_else(|_| src.file_name().map(PathBuf::from).unwrap_or_default()),
+            None => src.file_name().map(PathBuf::from).unwrap_or_default(),
+        };
+        let mut target = dest.join(rel);
+        if target.exists() || taken.contains(&target) {
+            if !opts.dedupe_names {
+                report.skipped += 1;
+                continue;
+            }
+            target = free_name(&target, &taken);
+        }
+        let res = target
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| place(src, &target, opts.mode));
+        match res {
+            Ok(()) => {
+                report.exported += 1;
+                taken.insert(target);
+            }
+            Err(e) => {
+                log::warn!("export: {} -> {}: {e}", src.display(), target.display());
+                report.failed += 1;
+            }
+        }
+    }
+    on_progress(&Progress { stage: "exporting".into(), processed: total, total, batch: None });
+    Ok(report)
+}
+
+/// Copies, or hard-links when asked and possible.
+pub fn place(src: &Path, target: &Path, mode: CopyMode) -> std::io::Result<()> {
+    if mode == CopyMode::Hardlink && std::fs::hard_link(src, target).is_ok() { return Ok(()); }
+    std::fs::copy(src, target).map(|_| ())
+}
+
+/// `kick.wav` -> `kick (2).wav`, `kick (3).wav` .. until one is free on disk and in `taken`.
+pub fn free_name(target: &Path, taken: &HashSet<PathBuf>) -> PathBuf {
+    let stem = target.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let ext = target.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|n| target.with_file_name(format!("{stem} ({n}){ext}")))
+        .find(|p| !p.exists() && !taken.contains(p))
+        .expect("unbounded range")
+}
+
+/// Deepest folder containing every file in `paths`.
+fn common_parent(paths: &[PathBuf]) -> Option<PathBuf> {
+    let mut base = paths.first()?.parent()?.to_path_buf();
+    for p in &paths[1..] {
+        while !p.starts_with(&base) {
+            if !base.pop() { return None; }
+        }
+    }
+    Some(base)
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    /// UTF-8 extended M3U.
+    #[serde(alias = "m3u")]
+    M3u8,
+    Csv,
+}
+
+/// Writes the files as a playlist in the given order, with absolute paths and durations.
+/// Returns how many entries were written; unknown ids are left out.
+pub fn export_playlist(conn: &Connection, file_ids: &[i64], format: PlaylistFormat, path: &Path) -> Result<usize> {
+    let mut entries = Vec::with_capacity(file_ids.len());
+    for &id in file_ids {
+        let row = conn
+            .query_row("SELECT path, name, duration FROM files WHERE id = ?", params![id], |r| {
+                Ok((r.get::<_, db::StoredPath>(0)?.0, r.get::<_, String>(1)?, r.get::<_, Option<f64>>(2)?))
+            })
+            .optional()?;
+        if let Some((p, name, duration)) = row {
+            // Scanned roots are absolute already; this covers a relative root passed by hand
+            let p = if p.is_relative() { std::env::current_dir().map(|d| d.join(&p)).unwrap_or(p) } else { p };
+            entries.push((p, name, duration));
+        }
+    }
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut w = BufWriter::new(file);
+    match format {
+        PlaylistFormat::M3u8 => {
+            writeln!(w, "#EXTM3U")?;
+            for (p, name, duration) in &entries {
+                // -1 is the spec's "unknown length"
+                let secs = duration.map_or(-1, |d| d.round() as i64);
+                writeln!(w, "#EXTINF:{secs},{name}")?;
+                writeln!(w, "{}", p.display())?;
+            }
+        }
+        PlaylistFormat::Csv => {
+            writeln!(w, "path,name,duration_seconds")?;
+            for (p, name, duration) in &entries {
+                let duration = duration.map(|d| format!("{d:.3}")).unwrap_or_default();
+                writeln!(w, "{},{},{duration}", csv_field(&p.to_string_lossy()), csv_field(name))?;
+            }
+        }
+    }
+    w.flush()?;
+    Ok(entries.len())
+}
+
+/// Quotes a field if it holds a comma, quote or line break, doubling inner quotes.
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) { format!("\"{}\"", s.replace('"', "\"\"")).into() } else { s.into() }
+}
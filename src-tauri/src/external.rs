@@ -0,0 +1,201 @@
+//! Metadata from other tools, so a library that was curated elsewhere doesn't start from zero.
+//! rekordbox's XML export gives BPM, key, rating and genre per track; Splice's `sounds.db`
+//! gives BPM, key, sample type and tags, plus the pack from the folder layout. Entries are
+//! matched to local files by path, then by content hash for files hashed on both sides.
+//! As with library merges, local values win: BPM, key and rating are only filled where unset,
+//! and tags are unioned.
+
+use crate::{db::{self, SqlPath}, scan, tonal::normalize_key};
+use anyhow::{bail, Context, Result};
+use quick_xml::events::Event;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Source {
+    /// A `rekordbox.xml` collection export.
+    Rekordbox,
+    /// Splice's `sounds.db`, or a folder containing it.
+    Splice,
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataImportSummary {
+    pub entries: usize,
+    pub matched: usize,
+    pub tags_added: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// What one tool knows about one file.
+#[derive(Default)]
+struct Entry {
+    path: PathBuf,
+    bpm: Option<f64>,
+    key: Option<String>,
+    rating: Option<u8>,
+    tags: Vec<String>,
+}
+
+pub fn import(conn: &mut Connection, source: Source, path: &Path) -> Result<MetadataImportSummary> {
+    let entries = match source {
+        Source::Rekordbox => read_rekordbox(path)?,
+        Source::Splice => read_splice(path)?,
+    };
+    let tx = conn.transaction()?;
+    let mut summary = MetadataImportSummary { entries: entries.len(), ..Default::default() };
+    for e in &entries {
+        let Some(id) = local_id(&tx, &e.path)? else { continue };
+        tx.execute(
+            "UPDATE files SET bpm = COALESCE(bpm, ?), musical_key = COALESCE(musical_key, ?), rating = COALESCE(rating, ?) WHERE id = ?",
+            params![e.bpm, e.key, e.rating, id],
+        )?;
+        for tag in e.tags.iter().filter_map(|t| db::normalize_tag(t)) {
+            summary.tags_added += tx.execute("INSERT OR IGNORE INTO file_tags(file_id, tag) VALUES(?, ?)", params![id, tag])?;
+        }
+        summary.matched += 1;
+        summary.file_ids.push(id);
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+fn local_id(conn: &Connection, path: &Path) -> Result<Option<i64>> {
+    let canonical = crate::paths::canonical(path);
+    if let Some(id) = conn.query_row("SELECT id FROM files WHERE path = ?", params![SqlPath(&canonical)], |r| r.get(0)).optional()? {
+        return Ok(Some(id));
+    }
+    // Moved or copied since the other tool saw it, if it's still readable where that tool put it
+    let Ok(hash) = scan::content_hash(path) else { return Ok(None) };
+    Ok(conn.query_row("SELECT id FROM files WHERE content_hash = ? LIMIT 1", params![hash], |r| r.get(0)).optional()?)
+}
+
+fn read_rekordbox(path: &Path) -> Result<Vec<Entry>> {
+    let mut reader = quick_xml::Reader::from_file(path).with_context(|| format!("open {}", path.display()))?;
+    let mut buf = Vec::new();
+    let mut out = Vec::new();
+    let mut seen_collection = false;
+    loop {
+        match reader.read_event_into(&mut buf).with_context(|| format!("parse {}", path.display()))? {
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"COLLECTION" => seen_collection = true,
+            // Playlist entries are also TRACK elements, but carry only a Key back into the collection
+            Event::Start(e) | Event::Empty(e) if seen_collection && e.name().as_ref() == b"TRACK" => {
+                let mut entry = Entry::default();
+                for attr in e.attributes().flatten() {
+                    let value = attr.unescape_value()?;
+                    match attr.key.as_ref() {
+                        b"Location" => entry.path = location_path(&value),
+                        b"AverageBpm" => entry.bpm = value.parse().ok().filter(|b: &f64| *b > 0.0),
+                        b"Tonality" => entry.key = normalize_key(&value),
+                        // 0-255 in steps of 51
+                        b"Rating" => entry.rating = value.parse::<u16>().ok().map(|r| (r / 51) as u8).filter(|r| (1..=5).contains(r)),
+                        b"Genre" if !value.trim().is_empty() => entry.tags.push(value.trim().to_string()),
+                        _ => {}
+                    }
+                }
+                if !entry.path.as_os_str().is_empty() { out.push(entry); }
+            }
+            Event::End(e) if e.name().as_ref() == b"COLLECTION" => break,
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    if !seen_collection { bail!("not a rekordbox collection export (no COLLECTION element)"); }
+    Ok(out)
+}
+
+/// `file://localhost/C:/Music/a%20b.wav` -> `C:/Music/a b.wav`; `file://localhost/Users/..` -> `/Users/..`.
+fn location_path(url: &str) -> PathBuf {
+    let rest = url.strip_prefix("file://localhost").or_else(|| url.strip_prefix("file://")).unwrap_or(url);
+    let bytes = rest.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                decoded.push((h * 16 + l) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    let s = String::from_utf8_lossy(&decoded).into_owned();
+    // A drive letter after the leading slash means Windows
+    let drive = s.len() > 2 && s.as_bytes()[0] == b'/' && s.as_bytes()[2] == b':';
+    PathBuf::from(if drive { &s[1..] } else { &s[..] })
+}
+
+fn read_splice(path: &Path) -> Result<Vec<Entry>> {
+    let db_path = if path.is_dir() {
+        walkdir::WalkDir::new(path)
+            .max_depth(6)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_type().is_file() && e.file_name() == "sounds.db")
+            .map(|e| e.into_path())
+            .with_context(|| format!("no Splice sounds.db under {}", path.display()))?
+    } else {
+        path.to_path_buf()
+    };
+    let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY).with_context(|| format!("open {}", db_path.display()))?;
+    let cols: HashSet<String> = {
+        let mut stmt = conn.prepare("PRAGMA table_info(samples)")?;
+        let names = stmt.query_map([], |r| r.get(1))?.collect::<rusqlite::Result<_>>()?;
+        names
+    };
+    if !cols.contains("local_path") { bail!("not a Splice library (no samples.local_path): {}", db_path.display()); }
+    // Columns have shifted between Splice versions; take what's there
+    let col = |name: &str| if cols.contains(name) { name.to_string() } else { "NULL".to_string() };
+    let sql = format!(
+        "SELECT local_path, {}, {}, {}, {}, {} FROM samples WHERE local_path IS NOT NULL AND local_path != ''",
+        col("bpm"),
+        col("audio_key"),
+        col("chord_type"),
+        col("sample_type"),
+        col("tags"),
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |r| {
+        let path: String = r.get(0)?;
+        let bpm: Option<f64> = r.get(1).ok().flatten();
+        let key: Option<String> = r.get(2).ok().flatten();
+        let chord: Option<String> = r.get(3).ok().flatten();
+        let kind: Option<String> = r.get(4).ok().flatten();
+        let tags: Option<String> = r.get(5).ok().flatten();
+        Ok((path, bpm, key, chord, kind, tags))
+    })?;
+    let mut out = Vec::new();
+    for row in rows {
+        let (path, bpm, key, chord, kind, tags) = row?;
+        let path = PathBuf::from(path);
+        let mut entry = Entry {
+            bpm: bpm.filter(|b| *b > 0.0),
+            // "F#" with chord_type "minor" -> "F#m"
+            key: key.and_then(|k| normalize_key(&format!("{k}{}", if chord.as_deref() == Some("minor") { "m" } else { "" }))),
+            tags: tags.unwrap_or_default().split(',').map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect(),
+            ..Default::default()
+        };
+        if let Some(kind) = kind.filter(|k| !k.is_empty()) { entry.tags.push(kind); }
+        if let Some(pack) = splice_pack(&path) { entry.tags.push(pack); }
+        entry.path = path;
+        out.push(entry);
+    }
+    Ok(out)
+}
+
+/// The pack folder of a file under Splice's `sounds/packs/<pack>/...`.
+fn splice_pack(path: &Path) -> Option<String> {
+    let parts: Vec<_> = path.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+    let i = parts.iter().position(|p| p == "packs")?;
+    parts.get(i + 1).filter(|_| i + 2 < parts.len()).cloned()
+}
@@ -0,0 +1,82 @@
+use crate::{
+    db::{SqlPath, StoredPath},
+    scan::ScanStatus,
+};
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanRecord {
+    pub id: i64,
+    pub root: PathBuf,
+    pub started_at: Option<i64>,
+    pub finished_at: i64,
+    pub total: i64,
+    pub processed: i64,
+    pub skipped: i64,
+    pub error: Option<String>,
+    pub cancelled: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentFolder {
+    pub root: PathBuf,
+    pub last_scanned_at: i64,
+    pub scan_count: i64,
+    /// Whether the folder is still there, so the UI can grey out unplugged drives.
+    pub exists: bool,
+}
+
+/// Appends a finished scan of `root`.
+pub fn record(conn: &Connection, root: &Path, status: &ScanStatus) -> Result<()> {
+    conn.execute(
+        "INSERT INTO scan_history(root, started_at, finished_at, total, processed, skipped, error, cancelled) VALUES(?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            SqlPath(root),
+            status.started_at,
+            status.finished_at.unwrap_or_else(crate::scan::now_secs),
+            status.total as i64,
+            status.processed as i64,
+            status.skipped as i64,
+            status.error,
+            status.cancelled,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Scans newest first.
+pub fn list(conn: &Connection, limit: usize) -> Result<Vec<ScanRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, root, started_at, finished_at, total, processed, skipped, error, cancelled FROM scan_history ORDER BY id DESC LIMIT ?",
+    )?;
+    let rows = stmt.query_map(params![limit as i64], |r| {
+        Ok(ScanRecord {
+            id: r.get(0)?,
+            root: r.get::<_, StoredPath>(1)?.0,
+            started_at: r.get(2)?,
+            finished_at: r.get(3)?,
+            total: r.get(4)?,
+            processed: r.get(5)?,
+            skipped: r.get(6)?,
+            error: r.get(7)?,
+            cancelled: r.get(8)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Distinct scanned roots, most recently scanned first.
+pub fn recent_folders(conn: &Connection, limit: usize) -> Result<Vec<RecentFolder>> {
+    let mut stmt = conn.prepare(
+        "SELECT root, MAX(finished_at) AS last, COUNT(*) FROM scan_history GROUP BY root ORDER BY last DESC LIMIT ?",
+    )?;
+    let rows = stmt.query_map(params![limit as i64], |r| {
+        let root = r.get::<_, StoredPath>(0)?.0;
+        Ok(RecentFolder { exists: root.is_dir(), root, last_scanned_at: r.get(1)?, scan_count: r.get(2)? })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
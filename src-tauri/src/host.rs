@@ -0,0 +1,201 @@
+//! The long-lived Python worker (`worker.py serve`). It keeps the CLAP model loaded between
+//! scans and answers one JSON request per line on stdin; progress lines may precede each
+//! response. Requests run one at a time, and a worker that dies or is killed is respawned by
+//! the next call.
+
+use crate::{
+    pyenv,
+    settings,
+    worker::{self, Cancel, Progress, WorkerFailure},
+};
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Lines, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Lines of worker stderr kept for the error report; the full output goes to the log.
+const STDERR_TAIL: usize = 20;
+/// How long `shutdown` waits for the worker to exit on its own.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct WorkerHost {
+    session: Mutex<Option<Session>>,
+}
+
+/// What the running process was started with; a settings change means a respawn.
+#[derive(PartialEq)]
+struct SpawnKey {
+    python: PathBuf,
+    worker: PathBuf,
+    device: settings::Device,
+    threads: usize,
+}
+
+struct Session {
+    key: SpawnKey,
+    child: Arc<Mutex<Child>>,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    /// Last time the worker printed anything, on either stream.
+    activity: Arc<Mutex<Instant>>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    next_id: u64,
+}
+
+impl Session {
+    fn spawn(key: SpawnKey, cache: PathBuf) -> Result<Self> {
+        let mut child = Command::new(&key.python)
+            .arg(&key.worker)
+            .args(["serve", "--device", key.device.as_str(), "--threads", &key.threads.to_string()])
+            .env("SMAP_MANAGED_VENV", "1")
+            .env("HF_HOME", cache)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("failed to spawn python worker")?;
+        let (stdin, stdout, stderr) = match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+            (Some(i), Some(o), Some(e)) => (i, o, e),
+            _ => bail!("python worker started without its pipes"),
+        };
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_TAIL)));
+        // Tracebacks and library warnings, logged as they come and kept for the error; ends when
+        // the process does
+        let (act, tail) = (activity.clone(), stderr_tail.clone());
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                let Ok(line) = line else { break };
+                *act.lock() = Instant::now();
+                log::warn!("worker: {line}");
+                let mut tail = tail.lock();
+                if tail.len() == STDERR_TAIL { tail.pop_front(); }
+                tail.push_back(line);
+            }
+        });
+        log::info!("worker: started persistent process on {}", key.device.as_str());
+        Ok(Self { key, child: Arc::new(Mutex::new(child)), stdin, stdout: BufReader::new(stdout).lines(), activity, stderr_tail, next_id: 1 })
+    }
+
+    /// Sends one request and reads up to its response. `Err` with a `WorkerFailure` means the
+    /// process is gone; any other `Err` is the worker reporting the request failed.
+    fn request(&mut self, method: &str, params: Value, timeout: Duration, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let line = json!({ "id": id, "method": method, "params": params }).to_string();
+        if writeln!(self.stdin, "{line}").and_then(|_| self.stdin.flush()).is_err() {
+            return Err(self.exited().into());
+        }
+        *self.activity.lock() = Instant::now();
+        // Parked where `Cancel::request` can reach it for the length of the request
+        cancel.attach(self.child.clone());
+        let (finished, timed_out) = (AtomicBool::new(false), AtomicBool::new(false));
+        let (child, activity) = (self.child.clone(), self.activity.clone());
+        let reply = thread::scope(|s| {
+            s.spawn(|| {
+                while !finished.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(500));
+                    if activity.lock().elapsed() < timeout { continue; }
+                    timed_out.store(true, Ordering::SeqCst);
+                    let _ = child.lock().kill();
+                    break;
+                }
+            });
+            let reply = self.read_reply(id, on_progress);
+            finished.store(true, Ordering::SeqCst);
+            reply
+        });
+        cancel.detach();
+        match reply {
+            Some(v) => match v.get("error").and_then(Value::as_str) {
+                Some(e) => bail!("worker {method}: {e}"),
+                None => Ok(v.get("result").cloned().unwrap_or(Value::Null)),
+            },
+            None if timed_out.load(Ordering::SeqCst) => Err(WorkerFailure::Timeout { stage: method.to_string(), timeout_secs: timeout.as_secs(), attempts: 1 }.into()),
+            None => Err(self.exited().into()),
+        }
+    }
+
+    /// Reads stdout until the response to `id`; `None` if the process closed it first.
+    fn read_reply(&mut self, id: u64, on_progress: &dyn Fn(&Progress)) -> Option<Value> {
+        for line in self.stdout.by_ref() {
+            let Ok(line) = line else { break };
+            *self.activity.lock() = Instant::now();
+            let msg: Option<Value> = serde_json::from_str(&line).ok();
+            match msg.as_ref().and_then(|m| m.get("type")).and_then(Value::as_str) {
+                Some("progress") => {
+                    if let Ok(p) = serde_json::from_value::<Progress>(msg.clone().unwrap_or_default()) { on_progress(&p); }
+                }
+                Some("response") if msg.as_ref().and_then(|m| m.get("id")).and_then(Value::as_u64) == Some(id) => return msg,
+                _ => log::info!("{line}"),
+            }
+        }
+        None
+    }
+
+    fn close(mut self) {
+        let _ = writeln!(self.stdin, "{}", json!({ "id": 0, "method": "shutdown" })).and_then(|_| self.stdin.flush());
+        drop(self.stdin);
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        let mut child = self.child.lock();
+        while Instant::now() < deadline {
+            if matches!(child.try_wait(), Ok(Some(_))) { return; }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    fn exited(&self) -> WorkerFailure {
+        let mut child = self.child.lock();
+        let _ = child.kill();
+        let code = child.wait().ok().and_then(|s| s.code());
+        WorkerFailure::Exited { code, stderr: self.stderr_tail.lock().iter().cloned().collect() }
+    }
+}
+
+impl WorkerHost {
+    /// Runs `method` on the worker, starting it (or restarting it after a settings change) if
+    /// needed. The process is killed if it prints nothing for `timeout` or `cancel` fires.
+    pub fn call(&self, app: &tauri::AppHandle, method: &str, params: Value, timeout: Duration, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<Value> {
+        let embedding = settings::load(app).embedding;
+        let key = SpawnKey {
+            python: pyenv::ensure(app, false, on_progress, cancel)?,
+            worker: worker::find_worker(app)?,
+            device: embedding.device,
+            threads: embedding.threads(),
+        };
+        let mut guard = self.session.lock();
+        if guard.as_ref().is_some_and(|s| s.key != key) {
+            if let Some(old) = guard.take() { old.close(); }
+        }
+        let session = match guard.as_mut() {
+            Some(s) => s,
+            None => guard.insert(Session::spawn(key, pyenv::model_cache_dir(app)?)?),
+        };
+        let res = session.request(method, params, timeout, on_progress, cancel);
+        if matches!(&res, Err(e) if e.downcast_ref::<WorkerFailure>().is_some()) || cancel.is_requested() {
+            // Whatever the process was doing is lost; the next call starts a fresh one
+            *guard = None;
+        }
+        if cancel.is_requested() { bail!("cancelled"); }
+        res
+    }
+
+    /// Asks the worker to exit, killing it if it doesn't within a moment. For app shutdown.
+    pub fn shutdown(&self) {
+        if let Some(s) = self.session.lock().take() { s.close(); }
+    }
+}
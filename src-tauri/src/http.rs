@@ -0,0 +1,194 @@
+//! Optional HTTP API, so the library on a studio machine can be browsed from another device.
+//! Off unless `settings.http.enabled`, and every request needs the configured token as
+//! `Authorization: Bearer <token>` or `?token=`.
+//!
+//! - `GET /api/search?q=&limit=&offset=` filename search, as `search_files`
+//! - `GET /api/coords?offset=&limit=&layout=` map points, as `get_coords`
+//! - `GET /api/similar/:id?k=` nearest neighbours, as `find_similar`
+//! - `GET /api/files/:id` file info; `GET /api/files/:id/audio` the file itself, with `Range`
+//! - `POST /api/scan {"path": ..}` queues a scan and returns its job id
+
+use crate::{db, scan, settings::HttpSettings, similar, AppState};
+use anyhow::{bail, Context, Result};
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use parking_lot::Mutex;
+use std::{io::SeekFrom, path::PathBuf};
+use tauri::{AppHandle, Manager};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::oneshot,
+};
+use tokio_util::io::ReaderStream;
+
+#[derive(Default)]
+pub struct HttpServer {
+    running: Mutex<Option<(HttpSettings, oneshot::Sender<()>)>>,
+}
+
+impl HttpServer {
+    /// Starts, stops or restarts the server to match `cfg`.
+    pub fn configure(&self, app: &AppHandle, cfg: &HttpSettings) -> Result<()> {
+        let mut running = self.running.lock();
+        if running.as_ref().is_some_and(|(c, _)| c == cfg) { return Ok(()); }
+        if let Some((_, stop)) = running.take() { let _ = stop.send(()); }
+        if !cfg.enabled { return Ok(()); }
+        let Some(token) = cfg.token.clone().filter(|t| !t.is_empty()) else { bail!("the HTTP API needs a token before it can be enabled") };
+        // Bound here so a taken port is reported to the caller rather than only logged
+        let listener = std::net::TcpListener::bind((cfg.bind.as_str(), cfg.port)).with_context(|| format!("bind {}:{}", cfg.bind, cfg.port))?;
+        listener.set_nonblocking(true)?;
+        let ctx = Ctx { app: app.clone(), token };
+        let router = Router::new()
+            .route("/api/search", get(search))
+            .route("/api/coords", get(coords))
+            .route("/api/similar/:id", get(similar))
+            .route("/api/files/:id", get(file_info))
+            .route("/api/files/:id/audio", get(audio))
+            .route("/api/scan", post(start_scan))
+            .layer(middleware::from_fn_with_state(ctx.clone(), auth))
+            .with_state(ctx);
+        let (stop, stopped) = oneshot::channel::<()>();
+        tauri::async_runtime::spawn(async move {
+            let served = match tokio::net::TcpListener::from_std(listener) {
+                Ok(l) => axum::serve(l, router).with_graceful_shutdown(async { let _ = stopped.await; }).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = served { log::warn!("http: server stopped: {e}"); }
+        });
+        log::info!("http: API listening on {}:{}", cfg.bind, cfg.port);
+        *running = Some((cfg.clone(), stop));
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Ctx {
+    app: AppHandle,
+    token: String,
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<String> for ApiError {
+    fn from(e: String) -> Self { Self(StatusCode::INTERNAL_SERVER_ERROR, e) }
+}
+
+#[derive(serde::Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+async fn auth(State(ctx): State<Ctx>, req: Request, next: Next) -> Result<Response, ApiError> {
+    let bearer = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "));
+    // Decoded like any other query parameter, so tokens with reserved characters work there too
+    let query = Query::<TokenQuery>::try_from_uri(req.uri()).ok().and_then(|q| q.0.token);
+    let given = bearer.map(str::to_owned).or(query);
+    if !given.is_some_and(|t| same_token(&t, &ctx.token)) { return Err(ApiError(StatusCode::UNAUTHORIZED, "missing or wrong token".into())); }
+    Ok(next.run(req).await)
+}
+
+/// Compares without stopping at the first differing byte, so response times don't give the
+/// token away a byte at a time.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Runs the sync DB work off the async threads.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, ApiError> {
+    tauri::async_runtime::spawn_blocking(f).await.map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.map_err(ApiError::from)
+}
+
+#[derive(serde::Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn search(State(ctx): State<Ctx>, Query(q): Query<SearchQuery>) -> Result<Response, ApiError> {
+    let hits = blocking(move || crate::search_files(ctx.app, q.q, q.limit, q.offset, None)).await?;
+    Ok(Json(hits).into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct CoordsQuery {
+    offset: Option<i64>,
+    limit: Option<i64>,
+    layout: Option<i64>,
+}
+
+async fn coords(State(ctx): State<Ctx>, Query(q): Query<CoordsQuery>) -> Result<Response, ApiError> {
+    let filter = crate::CoordsFilter { layout: q.layout, ..Default::default() };
+    let points = blocking(move || crate::coords_page(&ctx.app, q.offset, q.limit, &filter)).await?;
+    Ok(Json(points).into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct SimilarQuery {
+    k: Option<usize>,
+}
+
+async fn similar(State(ctx): State<Ctx>, Path(id): Path<i64>, Query(q): Query<SimilarQuery>) -> Result<Response, ApiError> {
+    let hits = blocking(move || {
+        let p = db::db_path(&ctx.app).map_err(|e| e.to_string())?;
+        let ann = &ctx.app.state::<AppState>().ann;
+        crate::with_db(&ctx.app, |conn| similar::find_similar(conn, ann, &p, id, q.k.unwrap_or(20)).map_err(|e| e.to_string()))
+    })
+    .await?;
+    Ok(Json(hits).into_response())
+}
+
+async fn file_info(State(ctx): State<Ctx>, Path(id): Path<i64>) -> Result<Response, ApiError> {
+    let info = blocking(move || crate::get_file_info(ctx.app, id)).await?;
+    Ok(Json(info).into_response())
+}
+
+/// Streamed rather than read whole, honouring `Range` like the `sample://` protocol so players
+/// can seek.
+async fn audio(State(ctx): State<Ctx>, Path(id): Path<i64>, headers: HeaderMap) -> Result<Response, ApiError> {
+    let path = blocking(move || crate::with_db(&ctx.app, |conn| db::file_path(conn, id).map_err(|e| e.to_string()))).await?;
+    let path = path.ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("no file with id {id}")))?;
+    let mut file = tokio::fs::File::open(&path).await.map_err(|e| ApiError(StatusCode::NOT_FOUND, format!("{}: {e}", path.display())))?;
+    let len = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let Some((start, end)) = crate::protocol::byte_range(range, len) else {
+        return Ok((StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, format!("bytes */{len}"))]).into_response());
+    };
+    let count = if len == 0 { 0 } else { end - start + 1 };
+    file.seek(SeekFrom::Start(start)).await.map_err(|e| e.to_string())?;
+    let wav = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("wav"));
+    let mime = if wav { "audio/wav" } else { "application/octet-stream" };
+    let mut res = Response::builder()
+        .header(header::CONTENT_TYPE, mime)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, count);
+    if range.is_some() {
+        res = res.status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+    }
+    res.body(Body::from_stream(ReaderStream::new(file.take(count)))).map_err(|e| ApiError::from(e.to_string()))
+}
+
+#[derive(serde::Deserialize)]
+struct ScanRequest {
+    path: PathBuf,
+}
+
+async fn start_scan(State(ctx): State<Ctx>, Json(req): Json<ScanRequest>) -> Result<Response, ApiError> {
+    if !req.path.is_dir() { return Err(ApiError(StatusCode::BAD_REQUEST, format!("{} is not a folder", req.path.display()))); }
+    let opts = scan::ScanOptions { walk: crate::walk_options(&ctx.app, None, None, None), ..Default::default() };
+    let job_id = scan::start_scan(ctx.app.clone(), req.path, opts, ctx.app.state::<AppState>().scans.clone());
+    Ok(Json(serde_json::json!({ "jobId": job_id })).into_response())
+}
@@ -0,0 +1,32 @@
+//! Scans requested from outside the app: a folder passed on the command line (e.g. from an
+//! Explorer "Open with" entry) or a `samplemap://scan?path=...` link.
+
+use crate::{scan, AppState};
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager, Url};
+
+pub const SCHEME: &str = "samplemap";
+
+/// The folder an argument or link asks to scan, if it names one that exists.
+pub fn scan_target(arg: &str) -> Option<PathBuf> {
+    let path = if arg.starts_with(&format!("{SCHEME}:")) {
+        let url = Url::parse(arg).ok()?;
+        // `samplemap://scan?..` parses "scan" as the host; `samplemap:scan?..` as the path
+        let action = url.host_str().unwrap_or_else(|| url.path().trim_matches('/'));
+        if action != "scan" { return None; }
+        url.query_pairs().find(|(k, _)| k == "path").map(|(_, v)| PathBuf::from(v.into_owned()))?
+    } else {
+        PathBuf::from(arg)
+    };
+    path.is_dir().then_some(path)
+}
+
+/// Queues a scan with the default options for every folder among `args`; others are ignored.
+pub fn scan_requested(app: &AppHandle, args: impl IntoIterator<Item = String>) {
+    for arg in args {
+        let Some(root) = scan_target(&arg) else { continue };
+        log::info!("launch: scanning {}", root.display());
+        let opts = scan::ScanOptions { walk: crate::walk_options(app, None, None, None), ..Default::default() };
+        scan::start_scan(app.clone(), root, opts, app.state::<AppState>().scans.clone());
+    }
+}
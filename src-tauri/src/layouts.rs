@@ -0,0 +1,149 @@
+use crate::{scan::now_secs, settings::ProjectionSettings};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayoutInfo {
+    pub id: i64,
+    pub name: String,
+    pub method: String,
+    /// The projection settings the layout was computed with.
+    pub params: Option<serde_json::Value>,
+    pub created_at: i64,
+    pub point_count: i64,
+    /// Whether this is the layout currently shown on the map.
+    pub active: bool,
+}
+
+/// Adds an empty layout for a projection run about to start, named after its method and
+/// position in the history ("UMAP 3").
+pub fn create(conn: &Connection, proj: &ProjectionSettings) -> Result<i64> {
+    let method = proj.method.as_str();
+    let n: i64 = conn.query_row("SELECT COUNT(*) FROM layouts WHERE method = ?", params![method], |r| r.get(0))?;
+    conn.execute(
+        "INSERT INTO layouts(name, method, params, created_at) VALUES(?, ?, ?, ?)",
+        params![format!("{} {}", method.to_uppercase(), n + 1), method, serde_json::to_string(proj)?, now_secs()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// The layout `coords` currently holds, if any.
+pub fn active(conn: &Connection) -> Result<Option<i64>> {
+    Ok(conn.query_row("SELECT layout_id FROM coords WHERE layout_id IS NOT NULL LIMIT 1", [], |r| r.get(0)).optional()?)
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<LayoutInfo>> {
+    let active = active(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT l.id, l.name, l.method, l.params, l.created_at, (SELECT COUNT(*) FROM layout_coords c WHERE c.layout_id = l.id) \
+         FROM layouts l ORDER BY l.id DESC",
+    )?;
+    let rows = stmt
+        .query_map([], |r| {
+            let id: i64 = r.get(0)?;
+            let params: Option<String> = r.get(3)?;
+            Ok(LayoutInfo {
+                id,
+                name: r.get(1)?,
+                method: r.get(2)?,
+                params: params.and_then(|p| serde_json::from_str(&p).ok()),
+                created_at: r.get(4)?,
+                point_count: r.get(5)?,
+                active: Some(id) == active,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Makes `id` the map's layout by copying its points into `coords`.
+pub fn switch(conn: &mut Connection, id: i64) -> Result<()> {
+    if !exists(conn, id)? { bail!("layout {id} not found"); }
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM coords", [])?;
+    tx.execute("INSERT INTO coords(file_id, x, y, z, layout_id) SELECT file_id, x, y, z, layout_id FROM layout_coords WHERE layout_id = ?", params![id])?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn rename(conn: &Connection, id: i64, name: &str) -> Result<()> {
+    let name = name.trim();
+    if name.is_empty() { bail!("layout name can't be empty"); }
+    if conn.execute("UPDATE layouts SET name = ? WHERE id = ?", params![name, id])? == 0 { bail!("layout {id} not found"); }
+    Ok(())
+}
+
+/// Drops a layout from the history. The one on the map can't go; switch away first.
+pub fn delete(conn: &mut Connection, id: i64) -> Result<()> {
+    if active(conn)? == Some(id) { bail!("layout {id} is the active layout"); }
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM layout_coords WHERE layout_id = ?", params![id])?;
+    if tx.execute("DELETE FROM layouts WHERE id = ?", params![id])? == 0 { bail!("layout {id} not found"); }
+    tx.commit()?;
+    Ok(())
+}
+
+fn exists(conn: &Connection, id: i64) -> Result<bool> {
+    Ok(conn.query_row("SELECT 1 FROM layouts WHERE id = ?", params![id], |_| Ok(())).optional()?.is_some())
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pin {
+    pub file_id: i64,
+    /// Omitted = wherever the file sits on the active layout now.
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+}
+
+/// Anchors files at fixed map positions. The worker aligns each new projection to the pins and
+/// then places pinned files exactly, so landmarks survive rebuilds. The active layout moves the
+/// files straight away. Returns how many were pinned.
+pub fn pin(conn: &mut Connection, pins: &[Pin]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    {
+        let mut here = tx.prepare("SELECT x, y FROM coords WHERE file_id = ?")?;
+        let mut save = tx.prepare("INSERT OR REPLACE INTO pins(file_id, x, y) VALUES(?, ?, ?)")?;
+        let mut place = tx.prepare("UPDATE coords SET x = ?, y = ? WHERE file_id = ?")?;
+        let mut place_stored = tx.prepare("UPDATE layout_coords SET x = ?, y = ? WHERE file_id = ? AND layout_id = (SELECT layout_id FROM coords WHERE file_id = ?)")?;
+        for p in pins {
+            let (x, y) = match (p.x, p.y) {
+                (Some(x), Some(y)) => (x, y),
+                (None, None) => match here.query_row(params![p.file_id], |r| Ok((r.get(0)?, r.get(1)?))).optional()? {
+                    Some(xy) => xy,
+                    None => bail!("file {} has no map position to pin at", p.file_id),
+                },
+                _ => bail!("pin for file {} needs both x and y, or neither", p.file_id),
+            };
+            if !x.is_finite() || !y.is_finite() { bail!("pin position must be finite"); }
+            save.execute(params![p.file_id, x, y])?;
+            place_stored.execute(params![x, y, p.file_id, p.file_id])?;
+            place.execute(params![x, y, p.file_id])?;
+            n += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(n)
+}
+
+/// Releases pins; the files keep their current position until the next projection.
+pub fn unpin(conn: &mut Connection, file_ids: &[i64]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut n = 0;
+    {
+        let mut stmt = tx.prepare("DELETE FROM pins WHERE file_id = ?")?;
+        for id in file_ids { n += stmt.execute(params![id])?; }
+    }
+    tx.commit()?;
+    Ok(n)
+}
+
+pub fn list_pins(conn: &Connection) -> Result<Vec<Pin>> {
+    let mut stmt = conn.prepare("SELECT file_id, x, y FROM pins ORDER BY file_id")?;
+    let rows = stmt
+        .query_map([], |r| Ok(Pin { file_id: r.get(0)?, x: r.get(1)?, y: r.get(2)? }))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
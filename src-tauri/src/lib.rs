@@ -1,11 +1,55 @@
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
 use std::{path::PathBuf, process::Command};
 use tauri::Manager;
+use tauri_plugin_deep_link::DeepLinkExt;
 
 mod playback;
+mod ableton;
+mod ann;
+mod autotag;
+mod background;
+mod category;
+mod bundle;
+mod cluster;
+mod convert;
 mod db;
+mod embed;
+mod events;
+mod export;
+mod external;
+mod history;
+mod host;
+mod launch;
+mod listing;
+mod http;
+mod layouts;
+mod lod;
+mod loudness;
+mod merge;
+mod models;
+mod osc;
+mod outlier;
+mod paths;
+mod project;
+mod protocol;
+mod pyenv;
+mod quant;
+mod query;
 mod scan;
+mod settings;
+mod shortcuts;
+mod sfz;
+mod slices;
+mod similar;
+mod snapshots;
+mod spectral;
+mod tempo;
+mod tonal;
+mod undo;
+mod waveform;
+mod webmap;
 mod worker;
+mod workspace;
 
 use std::sync::Arc;
 use parking_lot::Mutex;
@@ -13,15 +57,55 @@ use parking_lot::Mutex;
 struct AppState {
     audio: playback::AudioHandle,
     scans: Arc<scan::ScanManager>,
+    db: db::Db,
+    ann: ann::AnnCache,
+    text: embed::TextModel,
+    worker: host::WorkerHost,
+    osc: osc::OscServer,
+    http: http::HttpServer,
+    listings: listing::Listings,
+    background: background::Enricher,
 }
 
 impl AppState {
-    fn new() -> anyhow::Result<Self> { Ok(Self { audio: playback::AudioHandle::new()?, scans: Arc::new(Default::default()) }) }
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self { audio: playback::AudioHandle::new()?, scans: Arc::new(Default::default()), db: db::Db::default(), ann: Default::default(), text: Default::default(), worker: Default::default(), osc: Default::default(), http: Default::default(), listings: Default::default(), background: Default::default() })
+    }
 }
 
+/// Commands that act on a file take its library id or a raw path. Prefer the id: paths that
+/// aren't valid UTF-8 can't round-trip through the frontend, but the stored path is exact.
+fn resolve_path(app: &tauri::AppHandle, file_id: Option<i64>, path: Option<PathBuf>) -> Result<PathBuf, String> {
+    match (file_id, path) {
+        (Some(id), _) => with_db(app, |conn| db::file_path(conn, id).map_err(|e| e.to_string()))?.ok_or_else(|| format!("no file with id {id}")),
+        (None, Some(p)) => Ok(p),
+        (None, None) => Err("expected a fileId or path".into()),
+    }
+}
+
+/// Plays a library file by id, or any file by path.
 #[tauri::command]
-fn play_file(state: tauri::State<AppState>, path: String) -> Result<(), String> {
-    state.audio.play_path(PathBuf::from(path)).map_err(|e| e.to_string())
+fn play_file(app: tauri::AppHandle, file_id: Option<i64>, path: Option<PathBuf>) -> Result<(), String> {
+    let path = resolve_path(&app, file_id, path)?;
+    audition(&app, file_id, path)
+}
+
+/// Plays `path`, counts the play and announces it over OSC. Shared by `play_file` and remote
+/// control.
+fn audition(app: &tauri::AppHandle, file_id: Option<i64>, path: PathBuf) -> Result<(), String> {
+    let state = app.state::<AppState>();
+    state.audio.play_path(path.clone()).map_err(|e| e.to_string())?;
+    // Play stats are best-effort; never fail an audition over them
+    let recorded = with_db(app, |conn| db::record_play(conn, &path, scan::now_secs()).map_err(|e| e.to_string()));
+    let file_id = match recorded {
+        Ok(id) => id.or(file_id),
+        Err(e) => {
+            log::warn!("play stats not recorded for {}: {e}", path.display());
+            file_id
+        }
+    };
+    state.osc.now_playing(file_id, &path);
+    Ok(())
 }
 
 #[tauri::command]
@@ -30,11 +114,43 @@ fn stop_playback(state: tauri::State<AppState>) -> Result<(), String> {
     Ok(())
 }
 
+/// Starts an OS file drag of the given samples, so they can be dropped into a DAW or file
+/// manager. Call it from the frontend's `mousedown`/`dragstart`; it runs on the main thread,
+/// as the platform drag APIs require, and returns once the drop has finished or was cancelled.
+/// With `ableton` set, drags Live clips of the samples instead, so names survive the drop.
+#[tauri::command]
+fn start_drag(app: tauri::AppHandle, window: tauri::WebviewWindow, file_ids: Vec<i64>, ableton: Option<bool>) -> Result<(), String> {
+    if ableton.unwrap_or(false) {
+        let dir = std::env::temp_dir().join("sample-map-clips");
+        let _ = std::fs::remove_dir_all(&dir);
+        let report = with_db(&app, |conn| {
+            ableton::export_clips(conn, &file_ids, &dir, "clips", &Default::default()).map_err(|e| format!("{e:#}"))
+        })?;
+        let icon = drag::Image::Raw(include_bytes!("../icons/32x32.png").to_vec());
+        return drag::start_drag(&window, drag::DragItem::Files(report.paths), icon, |_, _| {}, Default::default()).map_err(|e| e.to_string());
+    }
+    let paths: Vec<PathBuf> = with_db(&app, |conn| {
+        let mut out = Vec::with_capacity(file_ids.len());
+        for id in &file_ids {
+            if let Some(p) = db::file_path(conn, *id).map_err(|e| e.to_string())? { out.push(p); }
+        }
+        Ok(out)
+    })?;
+    let paths: Vec<PathBuf> = paths.into_iter().filter(|p| p.is_file()).collect();
+    if paths.is_empty() { return Err("none of the selected files exist on disk".into()); }
+    let icon = drag::Image::Raw(include_bytes!("../icons/32x32.png").to_vec());
+    drag::start_drag(&window, drag::DragItem::Files(paths), icon, |_, _| {}, Default::default()).map_err(|e| e.to_string())
+}
+
 #[tauri::command]
-fn reveal_in_explorer(path: String) -> Result<(), String> {
-    // Windows-specific: open Explorer with the file selected
+fn reveal_in_explorer(app: tauri::AppHandle, file_id: Option<i64>, path: Option<PathBuf>) -> Result<(), String> {
+    let path = resolve_path(&app, file_id, path)?;
+    // Windows-specific: open Explorer with the file selected; it takes neither the `\\?\` prefix
+    // nor forward slashes
+    let path = paths::strip_verbatim(&path).to_string_lossy().replace('/', "\\");
     Command::new("explorer")
-        .args(["/select,", &path])
+        .arg("/select,")
+        .arg(&path)
         .spawn()
         .map_err(|e| e.to_string())?;
     Ok(())
@@ -50,36 +166,97 @@ fn copy_to_clipboard(app: tauri::AppHandle, text: String) -> Result<(), String>
 }
 
 #[derive(serde::Serialize)]
-struct FileEntry {
-    path: String,
-    name: String,
+#[serde(rename_all = "camelCase")]
+struct ListingStart {
+    listing_id: String,
 }
 
+/// Starts listing the audio files under `root_path` (up to `limit`, default 1000) for a folder
+/// preview; they arrive as `listing://batch` events carrying the returned id.
 #[tauri::command]
-fn list_wavs(root_path: String, limit: Option<usize>) -> Result<Vec<FileEntry>, String> {
-    let mut out = Vec::new();
-    let lim = limit.unwrap_or(1000);
-    for entry in walkdir::WalkDir::new(&root_path)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-    {
-        let p = entry.path();
-        if let Some(ext) = p.extension().and_then(|s| s.to_str()) {
-            if ext.eq_ignore_ascii_case("wav") {
-                let name = p.file_name().and_then(|s| s.to_str()).unwrap_or("").to_string();
-                out.push(FileEntry { path: p.to_string_lossy().to_string(), name });
-                if out.len() >= lim { break; }
-            }
+async fn list_wavs(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+    root_path: PathBuf,
+    limit: Option<usize>,
+    max_depth: Option<usize>,
+    follow_symlinks: Option<bool>,
+    same_file_system: Option<bool>,
+) -> Result<ListingStart, String> {
+    let walk = walk_options(&app, max_depth, follow_symlinks, same_file_system);
+    Ok(ListingStart { listing_id: state.listings.start(app, root_path, walk, limit.unwrap_or(1000)) })
+}
+
+#[tauri::command]
+fn cancel_list_wavs(state: tauri::State<AppState>, listing_id: String) -> Result<(), String> {
+    if state.listings.cancel(&listing_id) { Ok(()) } else { Err("listing not found or already finished".into()) }
+}
+
+fn walk_options(app: &tauri::AppHandle, max_depth: Option<usize>, follow_symlinks: Option<bool>, same_file_system: Option<bool>) -> scan::WalkOptions {
+    let d = scan::WalkOptions::default();
+    scan::WalkOptions {
+        max_depth,
+        follow_symlinks: follow_symlinks.unwrap_or(d.follow_symlinks),
+        same_file_system: same_file_system.unwrap_or(d.same_file_system),
+        excludes: settings::load(app).scan.excludes,
+    }
+}
+
+#[tauri::command]
+fn get_settings(app: tauri::AppHandle) -> settings::Settings {
+    settings::load(&app)
+}
+
+/// Replaces all preferences at once; the frontend sends back what `get_settings` gave it with
+/// its edits. Audio and shortcut changes apply immediately, the rest from the next job that
+/// reads them.
+#[tauri::command]
+fn set_settings(app: tauri::AppHandle, state: tauri::State<AppState>, settings: settings::Settings) -> Result<settings::Settings, String> {
+    settings::validate(&settings).map_err(|e| e.to_string())?;
+    let previous = settings::load(&app);
+    if settings.worker.python != previous.worker.python {
+        // Same check as `set_python_path`, so a bad interpreter isn't saved this way either
+        if let Some(p) = &settings.worker.python { pyenv::validate(p).map_err(|e| e.to_string())?; }
+    }
+    if settings.shortcuts != previous.shortcuts {
+        if let Err(e) = shortcuts::apply(&app, &settings.shortcuts) {
+            // Put back what worked before rather than leave half the bindings registered
+            let _ = shortcuts::apply(&app, &previous.shortcuts);
+            return Err(e.to_string());
         }
     }
-    Ok(out)
+    if settings.osc != previous.osc {
+        state.osc.configure(&app, &settings.osc).map_err(|e| format!("{e:#}"))?;
+    }
+    if settings.http != previous.http {
+        state.http.configure(&app, &settings.http).map_err(|e| format!("{e:#}"))?;
+    }
+    settings::save(&app, &settings).map_err(|e| e.to_string())?;
+    state.audio.configure(settings.audio.clone());
+    scan::set_io_rate(settings.io.max_files_per_sec);
+    Ok(settings)
+}
+
+#[tauri::command]
+fn list_audio_devices() -> Vec<String> {
+    playback::output_devices()
+}
+
+/// The output previews play on; follows the system default while `outputDevice` is unset or
+/// unplugged. Changes arrive as `audio://device_changed`.
+#[tauri::command]
+fn get_audio_device(state: tauri::State<AppState>) -> Option<String> {
+    state.audio.device()
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
+        .register_asynchronous_uri_scheme_protocol(protocol::SCHEME, |ctx, req, responder| {
+            // Decoding and reading happen off the webview's thread
+            let app = ctx.app_handle().clone();
+            std::thread::spawn(move || responder.respond(protocol::handle(&app, &req)));
+        })
         .setup(|app| {
             if cfg!(debug_assertions) {
                 app.handle().plugin(
@@ -91,93 +268,1517 @@ pub fn run() {
             // Clipboard plugin
             let _ = app.handle().plugin(tauri_plugin_clipboard_manager::init());
             let _ = app.handle().plugin(tauri_plugin_dialog::init());
+            let _ = app.handle().plugin(tauri_plugin_global_shortcut::Builder::new().build());
+            let prefs = settings::load(app.handle());
+            app.state::<AppState>().audio.configure(prefs.audio);
+            scan::set_io_rate(prefs.io.max_files_per_sec);
+            app.state::<AppState>().audio.attach(app.handle().clone());
+            // A binding another app already holds shouldn't stop startup
+            if let Err(e) = shortcuts::apply(app.handle(), &prefs.shortcuts) { log::warn!("shortcuts: {e}"); }
+            if let Err(e) = app.state::<AppState>().osc.configure(app.handle(), &prefs.osc) { log::warn!("osc: {e:#}"); }
+            if let Err(e) = app.state::<AppState>().http.configure(app.handle(), &prefs.http) { log::warn!("http: {e:#}"); }
+            let state = app.state::<AppState>();
+            state.background.start(app.handle().clone(), state.scans.clone());
+            let _ = app.handle().plugin(tauri_plugin_deep_link::init());
+            // Installers register the scheme; dev builds have to do it themselves
+            #[cfg(all(debug_assertions, any(windows, target_os = "linux")))]
+            let _ = app.deep_link().register(launch::SCHEME);
+            let handle = app.handle().clone();
+            app.deep_link().on_open_url(move |event| {
+                launch::scan_requested(&handle, event.urls().into_iter().map(String::from));
+            });
+            // A folder (or link, on Windows and Linux) given on the command line
+            launch::scan_requested(app.handle(), std::env::args().skip(1));
             Ok(())
         })
         .manage(AppState::new().expect("audio init"))
         .invoke_handler(tauri::generate_handler![
             play_file,
             stop_playback,
+            get_settings,
+            set_settings,
+            list_audio_devices,
+            get_audio_device,
             reveal_in_explorer,
+            start_drag,
             copy_to_clipboard,
             list_wavs,
+            cancel_list_wavs,
             start_scan,
+            reproject,
+            recluster,
+            detect_tempo,
+            detect_key,
+            measure_loudness,
+            classify_files,
+            analyze_spectrum,
+            get_silent_files,
+            auto_tag,
+            slice_file,
+            remove_slices,
+            list_devices,
+            python_env_status,
+            set_python_path,
+            check_worker_environment,
+            setup_python_env,
+            get_clusters,
             scan_status,
+            cancel_scan,
+            list_scan_jobs,
             get_stats,
+            optimize_database,
             get_coords,
-            get_file_info
+            get_coords_in_bbox,
+            get_coords_lod,
+            get_coords_binary,
+            compress_embeddings,
+            list_models,
+            register_model,
+            set_active_model,
+            delete_model,
+            export_map_image,
+            export_files,
+            export_playlist,
+            export_web_map,
+            export_sfz,
+            export_ableton,
+            convert_files,
+            list_layouts,
+            snapshot_library,
+            list_snapshots,
+            delete_snapshot,
+            diff_snapshots,
+            switch_layout,
+            rename_layout,
+            delete_layout,
+            pin_samples,
+            unpin_samples,
+            list_pins,
+            get_file_info,
+            get_file_infos,
+            get_schema_info,
+            set_favorite,
+            list_favorites,
+            set_rating,
+            get_ratings,
+            set_note,
+            set_color,
+            hide_files,
+            unhide_files,
+            trash_files,
+            bulk_update,
+            rename_file,
+            move_files,
+            undo_last_operation,
+            list_operations,
+            remove_root,
+            get_waveform_thumbnail,
+            get_scan_history,
+            get_recent_folders,
+            save_workspace,
+            load_workspace,
+            search,
+            search_files,
+            import_library,
+            export_bundle,
+            import_bundle,
+            import_external_metadata,
+            most_played,
+            recently_played,
+            query_files,
+            get_random_files,
+            list_files,
+            find_similar,
+            locate_file,
+            get_nearest_to_point,
+            interpolate_path,
+            list_outliers,
+            search_by_text,
+            add_tags,
+            remove_tags,
+            list_tags,
+            list_libraries,
+            create_library,
+            switch_library,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event { app.state::<AppState>().worker.shutdown(); }
+        });
 }
 #[derive(serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ScanStart { job_id: String }
 
 #[tauri::command]
-fn start_scan(app: tauri::AppHandle, state: tauri::State<AppState>, root_path: String) -> Result<ScanStart, String> {
-    let id = scan::start_scan(app, root_path, state.scans.clone());
+fn start_scan(
+    app: tauri::AppHandle,
+    state: tauri::State<AppState>,
+    root_path: PathBuf,
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+    max_size_bytes: Option<u64>,
+    max_depth: Option<usize>,
+    follow_symlinks: Option<bool>,
+    same_file_system: Option<bool>,
+) -> Result<ScanStart, String> {
+    let walk = walk_options(&app, max_depth, follow_symlinks, same_file_system);
+    let opts = scan::ScanOptions { walk, min_duration, max_duration, max_size_bytes };
+    let id = scan::start_scan(app, root_path, opts, state.scans.clone());
     Ok(ScanStart { job_id: id })
 }
 
+/// Queues a new layout of the current embeddings. Given params become the defaults for later
+/// scans too, so the map keeps its character.
+#[tauri::command]
+fn reproject(app: tauri::AppHandle, state: tauri::State<AppState>, params: Option<settings::ProjectionSettings>) -> Result<ScanStart, String> {
+    let given = params.is_some();
+    let params = params.unwrap_or_else(|| settings::load(&app).projection);
+    if params.n_neighbors < 2 { return Err("nNeighbors must be at least 2".into()); }
+    if !(0.0..=1.0).contains(&params.min_dist) { return Err("minDist must be between 0 and 1".into()); }
+    if !(2..=3).contains(&params.components) { return Err("components must be 2 or 3".into()); }
+    if given {
+        let mut s = settings::load(&app);
+        s.projection = params.clone();
+        settings::save(&app, &s).map_err(|e| e.to_string())?;
+    }
+    Ok(ScanStart { job_id: scan::start_reproject(app, params, state.scans.clone()) })
+}
+
+/// CPU/GPU options for embedding and which backend can use each; `settings.embedding.device` picks one.
+#[tauri::command]
+fn list_devices(app: tauri::AppHandle) -> Result<Vec<embed::DeviceInfo>, String> {
+    Ok(embed::available_devices(&app))
+}
+
+/// Python, worker script, packages, model cache and GPUs in one report, for setup screens and
+/// support before a scan fails at the embedding stage.
+#[tauri::command]
+fn check_worker_environment(app: tauri::AppHandle) -> Result<pyenv::Diagnostics, String> {
+    pyenv::diagnose(&app).map_err(|e| e.to_string())
+}
+
+/// Whether the worker's venv is installed, and which Python it would be built from.
+#[tauri::command]
+fn python_env_status(app: tauri::AppHandle) -> Result<pyenv::EnvStatus, String> {
+    pyenv::status(&app).map_err(|e| e.to_string())
+}
+
+/// Points the worker at a specific interpreter or environment directory, or back at the managed
+/// venv with `None`. The path is checked before it's saved.
+#[tauri::command]
+fn set_python_path(app: tauri::AppHandle, path: Option<PathBuf>) -> Result<pyenv::EnvStatus, String> {
+    if let Some(p) = &path { pyenv::validate(p).map_err(|e| e.to_string())?; }
+    let mut s = settings::load(&app);
+    s.worker.python = path;
+    settings::save(&app, &s).map_err(|e| e.to_string())?;
+    pyenv::status(&app).map_err(|e| e.to_string())
+}
+
+/// Queues creating/updating the worker's venv; progress shows under the returned job id.
+#[tauri::command]
+fn setup_python_env(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_python_setup(app, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Queues a k-means pass; `k` defaults to `settings.clustering.k`.
+#[tauri::command]
+fn recluster(app: tauri::AppHandle, state: tauri::State<AppState>, k: Option<usize>) -> Result<ScanStart, String> {
+    if k == Some(0) { return Err("k must be at least 1".into()); }
+    let k = k.or(settings::load(&app).clustering.k);
+    Ok(ScanStart { job_id: scan::start_recluster(app, k, state.scans.clone()) })
+}
+
+/// Queues tempo estimation for loops without a BPM; `force` redoes earlier estimates.
+#[tauri::command]
+fn detect_tempo(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_analysis(app, scan::Analysis::Tempo, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Queues key detection for loops and pitch detection for one-shots that have neither;
+/// `force` redoes earlier estimates.
+#[tauri::command]
+fn detect_key(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_analysis(app, scan::Analysis::Key, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Queues measuring peak, RMS and integrated loudness for files not measured yet; `force`
+/// remeasures all of them.
+#[tauri::command]
+fn measure_loudness(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_analysis(app, scan::Analysis::Loudness, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Queues labelling files as one-shots, loops or long-form recordings; `force` relabels all.
+#[tauri::command]
+fn classify_files(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_analysis(app, scan::Analysis::Category, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Queues computing spectral centroid, rolloff, flatness and zero-crossing rate; `force`
+/// recomputes all of them.
+#[tauri::command]
+fn analyze_spectrum(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_analysis(app, scan::Analysis::Spectral, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Files whose content is silence or a DC offset, for cleanup; covers measured files only.
+#[tauri::command]
+fn get_silent_files(app: tauri::AppHandle) -> Result<loudness::SilenceReport, String> {
+    with_db(&app, |conn| loudness::silence_report(conn).map_err(|e| e.to_string()))
+}
+
+/// Queues zero-shot tagging of embedded files with `settings.autotag.labels`; `force`
+/// replaces existing machine tags.
+#[tauri::command]
+fn auto_tag(app: tauri::AppHandle, state: tauri::State<AppState>, force: Option<bool>) -> Result<ScanStart, String> {
+    Ok(ScanStart { job_id: scan::start_auto_tag(app, force.unwrap_or(false), state.scans.clone()) })
+}
+
+/// Queues cutting a loop at its transients into slices, which are then embedded and placed
+/// on the map like new files. Re-slicing replaces the file's earlier slices.
+#[tauri::command]
+fn slice_file(app: tauri::AppHandle, state: tauri::State<AppState>, file_id: i64, options: Option<slices::SliceOptions>) -> Result<ScanStart, String> {
+    let options = options.unwrap_or_default();
+    options.validate().map_err(|e| e.to_string())?;
+    Ok(ScanStart { job_id: scan::start_slice(app, file_id, options, state.scans.clone()) })
+}
+
+/// Deletes a file's slices and their WAVs. Returns the removed ids.
+#[tauri::command]
+fn remove_slices(app: tauri::AppHandle, file_id: i64) -> Result<Vec<i64>, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    let removed = with_db(&app, |conn| slices::remove_slices(conn, &p, file_id).map_err(|e| format!("{e:#}")))?;
+    events::files_removed(&app, &removed);
+    Ok(removed)
+}
+
+#[tauri::command]
+fn get_clusters(app: tauri::AppHandle, include_hidden: Option<bool>) -> Result<Vec<cluster::ClusterInfo>, String> {
+    with_db(&app, |conn| cluster::list_clusters(conn, include_hidden.unwrap_or(false)).map_err(|e| e.to_string()))
+}
+
 #[derive(serde::Serialize)]
 #[serde(rename_all = "camelCase")]
-struct ScanStatusResp { stage: String, processed: usize, total: usize, done: bool, error: Option<String> }
+struct ScanStatusResp {
+    job_id: String,
+    root: String,
+    stage: String,
+    processed: usize,
+    total: usize,
+    skipped: usize,
+    done: bool,
+    error: Option<String>,
+    cancelled: bool,
+    failure: Option<worker::WorkerFailure>,
+    queue_position: Option<usize>,
+    queued_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+}
+
+fn scan_status_resp(mgr: &scan::ScanManager, job_id: &str, st: scan::ScanStatus) -> ScanStatusResp {
+    ScanStatusResp {
+        job_id: job_id.to_string(),
+        root: st.root,
+        stage: st.stage,
+        processed: st.processed,
+        total: st.total,
+        skipped: st.skipped,
+        done: st.done,
+        error: st.error,
+        cancelled: st.cancelled,
+        failure: st.failure,
+        queue_position: mgr.queue_position(job_id),
+        queued_at: st.queued_at,
+        started_at: st.started_at,
+        finished_at: st.finished_at,
+    }
+}
+
+/// Stops a queued or running job, killing the Python worker if it's mid-embedding.
+#[tauri::command]
+fn cancel_scan(state: tauri::State<AppState>, job_id: String) -> Result<(), String> {
+    if state.scans.cancel(&job_id) { Ok(()) } else { Err("job not found or already finished".into()) }
+}
 
 #[tauri::command]
 fn scan_status(state: tauri::State<AppState>, job_id: String) -> Result<ScanStatusResp, String> {
-    let jobs = state.scans.jobs.lock();
-    let st = jobs.get(&job_id).ok_or_else(|| "job not found".to_string())?.lock().clone();
-    Ok(ScanStatusResp { stage: st.stage, processed: st.processed, total: st.total, done: st.done, error: st.error })
+    let st = state.scans.jobs.lock().get(&job_id).ok_or_else(|| "job not found".to_string())?.lock().clone();
+    Ok(scan_status_resp(&state.scans, &job_id, st))
+}
+
+/// All jobs this session, oldest first: queued, running and finished.
+#[tauri::command]
+fn list_scan_jobs(state: tauri::State<AppState>) -> Result<Vec<ScanStatusResp>, String> {
+    let ids = state.scans.order.lock().clone();
+    let snapshot: Vec<(String, scan::ScanStatus)> = {
+        let jobs = state.scans.jobs.lock();
+        ids.into_iter().filter_map(|id| jobs.get(&id).map(|s| (id, s.lock().clone()))).collect()
+    };
+    Ok(snapshot.into_iter().map(|(id, st)| scan_status_resp(&state.scans, &id, st)).collect())
 }
 
 #[derive(serde::Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Stats { file_count: i64, embedding_count: i64, coord_count: i64, db_path: String }
+struct Stats {
+    #[serde(flatten)]
+    library: db::LibraryStats,
+    db_path: String,
+    db_size_bytes: u64,
+    /// Idle-time enrichment of already indexed files.
+    background: background::BackgroundStatus,
+}
+
+/// Runs `f` on the shared connection to the active library.
+fn with_db<T>(app: &tauri::AppHandle, f: impl FnOnce(&mut rusqlite::Connection) -> Result<T, String>) -> Result<T, String> {
+    let state = app.state::<AppState>();
+    state.db.with(app, |conn| Ok(f(conn))).map_err(|e| e.to_string())?
+}
 
 #[tauri::command]
-fn get_stats(app: tauri::AppHandle) -> Result<Stats, String> {
+fn get_stats(app: tauri::AppHandle, state: tauri::State<AppState>) -> Result<Stats, String> {
     let p = db::db_path(&app).map_err(|e| e.to_string())?;
-    let conn = db::open_or_create(&p).map_err(|e| e.to_string())?;
-    let files = db::file_count(&conn).map_err(|e| e.to_string())?;
-    let emb: i64 = conn.prepare("SELECT COUNT(*) FROM embeddings").map_err(|e| e.to_string())?
-        .query_row([], |r| r.get(0)).map_err(|e| e.to_string())?;
-    let coords: i64 = conn.prepare("SELECT COUNT(*) FROM coords").map_err(|e| e.to_string())?
-        .query_row([], |r| r.get(0)).map_err(|e| e.to_string())?;
-    Ok(Stats { file_count: files, embedding_count: emb, coord_count: coords, db_path: p.to_string_lossy().to_string() })
+    let library = with_db(&app, |conn| db::library_stats(conn).map_err(|e| e.to_string()))?;
+    Ok(Stats { library, db_path: p.to_string_lossy().to_string(), db_size_bytes: db::db_size_bytes(&p), background: state.background.status() })
+}
+
+#[tauri::command]
+fn optimize_database(app: tauri::AppHandle) -> Result<db::OptimizeReport, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    with_db(&app, |conn| db::optimize(conn, &p).map_err(|e| e.to_string()))
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Point {
+    file_id: i64,
+    x: f32,
+    y: f32,
+    z: Option<f32>,
+    favorite: bool,
+    rating: Option<u8>,
+    color: Option<String>,
+    /// The `feature` asked for, when the file has it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<f32>,
+}
+
+#[tauri::command]
+fn get_coords(
+    app: tauri::AppHandle,
+    offset: Option<i64>,
+    limit: Option<i64>,
+    min_rating: Option<u8>,
+    include_hidden: Option<bool>,
+    sample_rate: Option<u32>,
+    bits_per_sample: Option<u16>,
+    channels: Option<u16>,
+    method: Option<settings::ProjectionMethod>,
+    cluster_id: Option<i64>,
+    layout: Option<i64>,
+    category: Option<category::Category>,
+    feature: Option<spectral::Feature>,
+) -> Result<Vec<Point>, String> {
+    let filter = CoordsFilter { min_rating, include_hidden, sample_rate, bits_per_sample, channels, method, cluster_id, layout, category, feature };
+    coords_page(&app, offset, limit, &filter)
+}
+
+/// `get_coords` arguments besides paging, for callers that only set a few.
+#[derive(Default)]
+struct CoordsFilter {
+    min_rating: Option<u8>,
+    include_hidden: Option<bool>,
+    sample_rate: Option<u32>,
+    bits_per_sample: Option<u16>,
+    channels: Option<u16>,
+    method: Option<settings::ProjectionMethod>,
+    cluster_id: Option<i64>,
+    layout: Option<i64>,
+    category: Option<category::Category>,
+    feature: Option<spectral::Feature>,
+}
+
+fn coords_page(app: &tauri::AppHandle, offset: Option<i64>, limit: Option<i64>, filter: &CoordsFilter) -> Result<Vec<Point>, String> {
+    let CoordsFilter { min_rating, include_hidden, sample_rate, bits_per_sample, channels, method, cluster_id, layout, category, feature } = *filter;
+    with_db(app, |conn| {
+        let off = offset.unwrap_or(0);
+        let lim = limit.unwrap_or(10000);
+        let sql = format!(
+            // Neither layout nor method = the active layout; a method alone = its newest layout
+            "SELECT c.file_id, c.x, c.y, f.favorite, f.rating, f.color, c.z, {} \
+             FROM (SELECT file_id, x, y, z FROM coords WHERE ?8 IS NULL AND ?10 IS NULL \
+                   UNION ALL SELECT file_id, x, y, z FROM layout_coords \
+                   WHERE layout_id = COALESCE(?10, (SELECT MAX(id) FROM layouts WHERE method = ?8))) c \
+             JOIN files f ON f.id = c.file_id \
+             WHERE (?1 IS NULL OR f.rating >= ?1) AND (?4 OR f.hidden = 0) \
+               AND (?5 IS NULL OR f.sample_rate = ?5) AND (?6 IS NULL OR f.bits_per_sample = ?6) AND (?7 IS NULL OR f.channels = ?7) \
+               AND (?9 IS NULL OR f.cluster_id = ?9) AND (?11 IS NULL OR f.category = ?11) \
+             ORDER BY c.file_id LIMIT ?2 OFFSET ?3",
+            feature.map_or("NULL", |f| f.column()),
+        );
+        let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+        let hidden = include_hidden.unwrap_or(false);
+        let rows = stmt
+            .query_map(rusqlite::params![min_rating, lim, off, hidden, sample_rate, bits_per_sample, channels, method.map(|m| m.as_str()), cluster_id, layout, category.map(|c| c.as_str())], |r| {
+                Ok(Point {
+                    file_id: r.get::<_, i64>(0)?,
+                    x: r.get::<_, f64>(1)? as f32,
+                    y: r.get::<_, f64>(2)? as f32,
+                    z: r.get::<_, Option<f64>>(6)?.map(|v| v as f32),
+                    favorite: r.get(3)?,
+                    rating: r.get(4)?,
+                    color: r.get(5)?,
+                    value: r.get::<_, Option<f64>>(7)?.map(|v| v as f32),
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        let mut out = Vec::new();
+        for r in rows { out.push(r.map_err(|e| e.to_string())?); }
+        Ok(out)
+    })
+}
+
+/// Bytes per record in `get_coords_binary`.
+const COORD_RECORD: usize = 16;
+
+/// Same rows as `get_coords` (active or given layout, visible files, by file id) as one raw buffer of
+/// little-endian `file_id: i64, x: f32, y: f32` records, so the frontend can view it as a
+/// `BigInt64Array` (stride 2) and `Float32Array` (stride 4) instead of parsing JSON.
+#[tauri::command]
+fn get_coords_binary(
+    app: tauri::AppHandle,
+    offset: Option<i64>,
+    limit: Option<i64>,
+    include_hidden: Option<bool>,
+    layout: Option<i64>,
+) -> Result<tauri::ipc::Response, String> {
+    let buf = with_db(&app, |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT c.file_id, c.x, c.y \
+                 FROM (SELECT file_id, x, y FROM coords WHERE ?4 IS NULL \
+                       UNION ALL SELECT file_id, x, y FROM layout_coords WHERE layout_id = ?4) c \
+                 JOIN files f ON f.id = c.file_id \
+                 WHERE (?3 OR f.hidden = 0) ORDER BY c.file_id LIMIT ?1 OFFSET ?2",
+            )
+            .map_err(|e| e.to_string())?;
+        let mut rows = stmt
+            .query(rusqlite::params![limit.unwrap_or(-1), offset.unwrap_or(0), include_hidden.unwrap_or(false), layout])
+            .map_err(|e| e.to_string())?;
+        let mut buf = Vec::new();
+        while let Some(r) = rows.next().map_err(|e| e.to_string())? {
+            let id: i64 = r.get(0).map_err(|e| e.to_string())?;
+            let x: f64 = r.get(1).map_err(|e| e.to_string())?;
+            let y: f64 = r.get(2).map_err(|e| e.to_string())?;
+            buf.reserve(COORD_RECORD);
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&(x as f32).to_le_bytes());
+            buf.extend_from_slice(&(y as f32).to_le_bytes());
+        }
+        Ok(buf)
+    })?;
+    Ok(tauri::ipc::Response::new(buf))
 }
 
 #[derive(serde::Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Point { file_id: i64, x: f32, y: f32 }
+struct LodView {
+    /// Aggregated cells, up to zoom `lod::MAX_LEVEL`.
+    cells: Vec<lod::LodCell>,
+    /// Raw points once zoomed in past the finest level.
+    points: Vec<Point>,
+}
 
+/// The active layout inside a viewport at quadtree level `zoom` (the layout's extent split into
+/// 2^zoom cells per side): cell centroids with counts while zoomed out, raw points past
+/// `lod::MAX_LEVEL`.
 #[tauri::command]
-fn get_coords(app: tauri::AppHandle, offset: Option<i64>, limit: Option<i64>) -> Result<Vec<Point>, String> {
+fn get_coords_lod(
+    app: tauri::AppHandle,
+    zoom: u32,
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+    limit: Option<i64>,
+) -> Result<LodView, String> {
+    if zoom > lod::MAX_LEVEL {
+        let points = get_coords_in_bbox(app, min_x, min_y, max_x, max_y, limit, None, None)?;
+        return Ok(LodView { cells: Vec::new(), points });
+    }
+    let cells = with_db(&app, |conn| lod::cells(conn, zoom, min_x, min_y, max_x, max_y).map_err(|e| format!("{e:#}")))?;
+    Ok(LodView { cells, points: Vec::new() })
+}
+
+/// Points inside a viewport, so panning only fetches what is visible. The active layout goes
+/// through the R*Tree; other layouts (`layout`) are filtered by range.
+#[tauri::command]
+fn get_coords_in_bbox(
+    app: tauri::AppHandle,
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+    limit: Option<i64>,
+    include_hidden: Option<bool>,
+    layout: Option<i64>,
+) -> Result<Vec<Point>, String> {
+    with_db(&app, |conn| {
+        let (lim, hidden) = (limit.unwrap_or(50_000), include_hidden.unwrap_or(false));
+        let (sql, params) = match &layout {
+            None => (
+                "SELECT c.file_id, c.x, c.y, f.favorite, f.rating, f.color, c.z \
+                 FROM coords_rtree r JOIN coords c ON c.file_id = r.id JOIN files f ON f.id = c.file_id \
+                 WHERE r.min_x <= ?3 AND r.max_x >= ?1 AND r.min_y <= ?4 AND r.max_y >= ?2 AND (?6 OR f.hidden = 0) \
+                 LIMIT ?5",
+                vec![&min_x as &dyn rusqlite::ToSql, &min_y, &max_x, &max_y, &lim, &hidden],
+            ),
+            Some(layout_id) => (
+                "SELECT c.file_id, c.x, c.y, f.favorite, f.rating, f.color, c.z \
+                 FROM layout_coords c JOIN files f ON f.id = c.file_id \
+                 WHERE c.layout_id = ?7 AND c.x BETWEEN ?1 AND ?3 AND c.y BETWEEN ?2 AND ?4 AND (?6 OR f.hidden = 0) \
+                 LIMIT ?5",
+                vec![&min_x as &dyn rusqlite::ToSql, &min_y, &max_x, &max_y, &lim, &hidden, layout_id],
+            ),
+        };
+        let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params.as_slice(), |r| {
+                Ok(Point {
+                    file_id: r.get(0)?,
+                    x: r.get::<_, f64>(1)? as f32,
+                    y: r.get::<_, f64>(2)? as f32,
+                    z: r.get::<_, Option<f64>>(6)?.map(|v| v as f32),
+                    favorite: r.get(3)?,
+                    rating: r.get(4)?,
+                    color: r.get(5)?,
+                    value: None,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(|e| e.to_string())
+    })
+}
+
+/// Re-encodes all stored embeddings as `dtype` ("f32", "f16" or "int8") and makes it the
+/// encoding for new ones. Run `optimize_database` afterwards to hand the space back to the OS.
+#[tauri::command]
+fn compress_embeddings(app: tauri::AppHandle, dtype: quant::Dtype) -> Result<quant::CompressReport, String> {
+    let report = with_db(&app, |conn| quant::compress(conn, dtype).map_err(|e| e.to_string()))?;
+    let mut s = settings::load(&app);
+    s.embedding.storage = dtype;
+    settings::save(&app, &s).map_err(|e| e.to_string())?;
+    // The index keeps its own f32 copies, so it needs no rebuild
+    Ok(report)
+}
+
+/// Queues converting the files into `dest_dir` as WAVs in the given format; progress shows
+/// under the job id.
+#[tauri::command]
+fn convert_files(
+    app: tauri::AppHandle,
+    state: tauri::State<AppState>,
+    file_ids: Vec<i64>,
+    dest_dir: PathBuf,
+    options: Option<convert::ConvertOptions>,
+) -> Result<ScanStart, String> {
+    if file_ids.is_empty() { return Err("no files selected".into()); }
+    let options = options.unwrap_or_default();
+    options.validate().map_err(|e| e.to_string())?;
+    Ok(ScanStart { job_id: scan::start_convert(app, file_ids, dest_dir, options, state.scans.clone()) })
+}
+
+/// Queues writing a browser-viewable copy of the map (points, thumbnails, optional previews)
+/// into `path`; progress shows under the job id.
+#[tauri::command]
+fn export_web_map(app: tauri::AppHandle, state: tauri::State<AppState>, path: PathBuf, options: Option<webmap::WebMapOptions>) -> Result<ScanStart, String> {
+    if path.is_file() { return Err(format!("{} is a file", path.display())); }
+    Ok(ScanStart { job_id: scan::start_export_web_map(app, path, options.unwrap_or_default(), state.scans.clone()) })
+}
+
+/// Builds a playable SFZ instrument from the files in `dest`, named after the folder.
+#[tauri::command]
+fn export_sfz(app: tauri::AppHandle, file_ids: Vec<i64>, dest: PathBuf, options: Option<sfz::SfzOptions>) -> Result<sfz::SfzReport, String> {
+    let name = dest.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_else(|| "kit".into());
+    with_db(&app, |conn| sfz::export_sfz(conn, &file_ids, &dest, &name, &options.unwrap_or_default()).map_err(|e| format!("{e:#}")))
+}
+
+/// Writes Ableton Live clips for the files into `dest`: an `.alc` each, or with `set` one
+/// `.als` named after the folder with a track per cluster.
+#[tauri::command]
+fn export_ableton(app: tauri::AppHandle, file_ids: Vec<i64>, dest: PathBuf, options: Option<ableton::AbletonOptions>) -> Result<ableton::AbletonReport, String> {
+    let name = dest.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_else(|| "samples".into());
+    with_db(&app, |conn| ableton::export_clips(conn, &file_ids, &dest, &name, &options.unwrap_or_default()).map_err(|e| format!("{e:#}")))
+}
+
+/// Saves the files as an M3U8 or CSV playlist at `path`, in the given order. Returns the
+/// number of entries written.
+#[tauri::command]
+fn export_playlist(app: tauri::AppHandle, file_ids: Vec<i64>, format: export::PlaylistFormat, path: PathBuf) -> Result<usize, String> {
+    with_db(&app, |conn| export::export_playlist(conn, &file_ids, format, &path).map_err(|e| format!("{e:#}")))
+}
+
+/// Renders the map to a PNG or SVG poster at `path`.
+#[tauri::command]
+fn export_map_image(app: tauri::AppHandle, path: PathBuf, options: Option<export::ExportOptions>) -> Result<export::ExportReport, String> {
+    let options = options.unwrap_or_default();
+    with_db(&app, |conn| export::export_map_image(conn, &path, &options).map_err(|e| e.to_string()))
+}
+
+/// Queues copying (or hard-linking) the files into `dest_dir`; progress shows under the job id.
+#[tauri::command]
+fn export_files(
+    app: tauri::AppHandle,
+    state: tauri::State<AppState>,
+    file_ids: Vec<i64>,
+    dest_dir: PathBuf,
+    options: Option<export::FileExportOptions>,
+) -> Result<ScanStart, String> {
+    if file_ids.is_empty() { return Err("no files selected".into()); }
+    if dest_dir.is_file() { return Err(format!("{} is a file", dest_dir.display())); }
+    Ok(ScanStart { job_id: scan::start_export_files(app, file_ids, dest_dir, options.unwrap_or_default(), state.scans.clone()) })
+}
+
+#[tauri::command]
+fn pin_samples(app: tauri::AppHandle, pins: Vec<layouts::Pin>) -> Result<usize, String> {
+    with_db(&app, |conn| layouts::pin(conn, &pins).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+fn unpin_samples(app: tauri::AppHandle, file_ids: Vec<i64>) -> Result<usize, String> {
+    with_db(&app, |conn| layouts::unpin(conn, &file_ids).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+fn list_pins(app: tauri::AppHandle) -> Result<Vec<layouts::Pin>, String> {
+    with_db(&app, |conn| layouts::list_pins(conn).map_err(|e| e.to_string()))
+}
+
+/// Embedding models, the built-in CLAP first.
+#[tauri::command]
+fn list_models(app: tauri::AppHandle) -> Result<Vec<models::ModelInfo>, String> {
+    with_db(&app, |conn| models::list(conn).map_err(|e| e.to_string()))
+}
+
+/// Adds an ONNX audio encoder; make it active and rescan to embed the library with it.
+#[tauri::command]
+fn register_model(app: tauri::AppHandle, name: String, path: PathBuf) -> Result<i64, String> {
+    with_db(&app, |conn| models::register(conn, &name, &path).map_err(|e| e.to_string()))
+}
+
+/// Chooses the model behind the map, clusters and similarity search.
+#[tauri::command]
+fn set_active_model(app: tauri::AppHandle, model_id: i64) -> Result<(), String> {
+    with_db(&app, |conn| models::set_active(conn, model_id).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+fn delete_model(app: tauri::AppHandle, model_id: i64) -> Result<(), String> {
+    with_db(&app, |conn| models::delete(conn, model_id).map_err(|e| e.to_string()))?;
     let p = db::db_path(&app).map_err(|e| e.to_string())?;
-    let conn = db::open_or_create(&p).map_err(|e| e.to_string())?;
-    let off = offset.unwrap_or(0);
-    let lim = limit.unwrap_or(10000);
-    let mut stmt = conn.prepare("SELECT file_id, x, y FROM coords ORDER BY file_id LIMIT ? OFFSET ?").map_err(|e| e.to_string())?;
-    let rows = stmt
-        .query_map(rusqlite::params![lim, off], |r| {
-            Ok(Point { file_id: r.get::<_, i64>(0)?, x: r.get::<_, f64>(1)? as f32, y: r.get::<_, f64>(2)? as f32 })
-        })
+    let _ = std::fs::remove_file(ann::index_path(&p, model_id));
+    Ok(())
+}
+
+/// Records the library's current file list under `name`, for comparing later.
+#[tauri::command]
+fn snapshot_library(app: tauri::AppHandle, name: String) -> Result<snapshots::Snapshot, String> {
+    with_db(&app, |conn| snapshots::create(conn, &name).map_err(|e| format!("{e:#}")))
+}
+
+#[tauri::command]
+fn list_snapshots(app: tauri::AppHandle) -> Result<Vec<snapshots::Snapshot>, String> {
+    with_db(&app, |conn| snapshots::list(conn).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+fn delete_snapshot(app: tauri::AppHandle, snapshot_id: i64) -> Result<bool, String> {
+    with_db(&app, |conn| snapshots::delete(conn, snapshot_id).map_err(|e| e.to_string()))
+}
+
+/// Files added, removed, moved and changed from snapshot `a` to snapshot `b`.
+#[tauri::command]
+fn diff_snapshots(app: tauri::AppHandle, a: i64, b: i64) -> Result<snapshots::SnapshotDiff, String> {
+    with_db(&app, |conn| snapshots::diff(conn, a, b).map_err(|e| format!("{e:#}")))
+}
+
+/// Every projection run, newest first.
+#[tauri::command]
+fn list_layouts(app: tauri::AppHandle) -> Result<Vec<layouts::LayoutInfo>, String> {
+    with_db(&app, |conn| layouts::list(conn).map_err(|e| e.to_string()))
+}
+
+/// Puts a stored layout back on the map.
+#[tauri::command]
+fn switch_layout(app: tauri::AppHandle, layout_id: i64) -> Result<(), String> {
+    with_db(&app, |conn| layouts::switch(conn, layout_id).map_err(|e| e.to_string()))?;
+    events::coords_updated(&app, Some(layout_id));
+    Ok(())
+}
+
+#[tauri::command]
+fn rename_layout(app: tauri::AppHandle, layout_id: i64, name: String) -> Result<(), String> {
+    with_db(&app, |conn| layouts::rename(conn, layout_id, &name).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+fn delete_layout(app: tauri::AppHandle, layout_id: i64) -> Result<(), String> {
+    with_db(&app, |conn| layouts::delete(conn, layout_id).map_err(|e| e.to_string()))
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileInfo {
+    file_id: i64,
+    path: String,
+    name: String,
+    size_bytes: i64,
+    duration: Option<f64>,
+    favorite: bool,
+    rating: Option<u8>,
+    play_count: i64,
+    last_played_at: Option<i64>,
+    note: Option<String>,
+    color: Option<String>,
+    hidden: bool,
+    sample_rate: Option<u32>,
+    bits_per_sample: Option<u16>,
+    channels: Option<u16>,
+    /// User tags; machine tags are in `auto_tags`.
+    tags: Vec<String>,
+    auto_tags: Vec<AutoTag>,
+    bpm: Option<f64>,
+    /// 0-1 for detected tempos; absent when the BPM came from another tool.
+    bpm_confidence: Option<f64>,
+    musical_key: Option<String>,
+    /// Fractional MIDI note (69.0 = A4) detected for a one-shot.
+    pitch: Option<f64>,
+    /// 0-1 for a detected key or pitch; absent when the key came from another tool.
+    tonal_confidence: Option<f64>,
+    peak_db: Option<f64>,
+    rms_db: Option<f64>,
+    lufs: Option<f64>,
+    category: Option<category::Category>,
+    /// Silence or DC only; absent until measured.
+    is_silent: Option<bool>,
+    /// For slices: the loop they were cut from, and where (seconds).
+    parent_id: Option<i64>,
+    slice_start: Option<f64>,
+    slice_end: Option<f64>,
+    /// Hz; the centroid is the "brightness".
+    spectral_centroid: Option<f64>,
+    spectral_rolloff: Option<f64>,
+    spectral_flatness: Option<f64>,
+    /// Per second.
+    zero_crossing_rate: Option<f64>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AutoTag { tag: String, confidence: Option<f64> }
+
+const FILE_INFO_COLUMNS: &str = "id, path, name, size_bytes, duration, favorite, rating, play_count, last_played_at, note, color, hidden, \
+     sample_rate, bits_per_sample, channels, (SELECT group_concat(tag, char(31)) FROM file_tags WHERE file_id = files.id AND source = 'user'), bpm, musical_key, bpm_confidence, pitch, tonal_confidence, peak_db, rms_db, lufs, category, is_silent, \
+     (SELECT group_concat(tag || char(30) || IFNULL(confidence, ''), char(31)) FROM file_tags WHERE file_id = files.id AND source = 'auto'), \
+     parent_id, slice_start, slice_end, spectral_centroid, spectral_rolloff, spectral_flatness, zero_crossing_rate";
+
+fn file_info_from_row(r: &rusqlite::Row) -> rusqlite::Result<FileInfo> {
+    Ok(FileInfo {
+        file_id: r.get(0)?,
+        path: r.get::<_, db::StoredPath>(1)?.0.to_string_lossy().into_owned(),
+        name: r.get(2)?,
+        size_bytes: r.get(3)?,
+        duration: r.get(4)?,
+        favorite: r.get(5)?,
+        rating: r.get(6)?,
+        play_count: r.get(7)?,
+        last_played_at: r.get(8)?,
+        note: r.get(9)?,
+        color: r.get(10)?,
+        hidden: r.get(11)?,
+        sample_rate: r.get(12)?,
+        bits_per_sample: r.get(13)?,
+        channels: r.get(14)?,
+        // Unit-separator joined so tags may contain commas
+        tags: r
+            .get::<_, Option<String>>(15)?
+            .map(|t| t.split('\u{1f}').map(str::to_string).collect())
+            .unwrap_or_default(),
+        bpm: r.get(16)?,
+        musical_key: r.get(17)?,
+        bpm_confidence: r.get(18)?,
+        pitch: r.get(19)?,
+        tonal_confidence: r.get(20)?,
+        peak_db: r.get(21)?,
+        rms_db: r.get(22)?,
+        lufs: r.get(23)?,
+        category: r.get::<_, Option<String>>(24)?.as_deref().and_then(category::Category::parse),
+        is_silent: r.get(25)?,
+        parent_id: r.get(27)?,
+        slice_start: r.get(28)?,
+        slice_end: r.get(29)?,
+        spectral_centroid: r.get(30)?,
+        spectral_rolloff: r.get(31)?,
+        spectral_flatness: r.get(32)?,
+        zero_crossing_rate: r.get(33)?,
+        auto_tags: r
+            .get::<_, Option<String>>(26)?
+            .map(|t| {
+                t.split('\u{1f}')
+                    .map(|e| {
+                        let (tag, confidence) = e.split_once('\u{1e}').unwrap_or((e, ""));
+                        AutoTag { tag: tag.to_string(), confidence: confidence.parse().ok() }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    })
+}
+
+#[tauri::command]
+fn get_file_info(app: tauri::AppHandle, file_id: i64) -> Result<FileInfo, String> {
+    with_db(&app, |conn| {
+        let mut stmt = conn.prepare(&format!("SELECT {FILE_INFO_COLUMNS} FROM files WHERE id = ?")).map_err(|e| e.to_string())?;
+        let r = stmt.query_row(rusqlite::params![file_id], file_info_from_row).map_err(|e| e.to_string())?;
+        Ok(r)
+    })
+}
+
+/// Ids per `IN (..)` list, well under SQLite's bound-parameter limit.
+const ID_CHUNK: usize = 500;
+
+/// `get_file_info` for many files in one call and one connection, e.g. a lasso selection. Results
+/// follow the order of `file_ids`; unknown ids are left out.
+#[tauri::command]
+fn get_file_infos(app: tauri::AppHandle, file_ids: Vec<i64>) -> Result<Vec<FileInfo>, String> {
+    with_db(&app, |conn| {
+        let mut found = std::collections::HashMap::with_capacity(file_ids.len());
+        for chunk in file_ids.chunks(ID_CHUNK) {
+            let marks = vec!["?"; chunk.len()].join(", ");
+            for info in query_file_infos(conn, &format!("WHERE id IN ({marks})"), rusqlite::params_from_iter(chunk))? {
+                found.insert(info.file_id, info);
+            }
+        }
+        Ok(file_ids.iter().filter_map(|id| found.remove(id)).collect())
+    })
+}
+
+#[tauri::command]
+fn get_schema_info(app: tauri::AppHandle) -> Result<db::SchemaInfo, String> {
+    with_db(&app, |conn| {
+        db::schema_info(conn).map_err(|e| e.to_string())
+    })
+}
+
+#[tauri::command]
+fn set_favorite(app: tauri::AppHandle, file_id: i64, favorite: bool) -> Result<(), String> {
+    with_db(&app, |conn| {
+        if !db::set_favorite(conn, file_id, favorite).map_err(|e| e.to_string())? {
+            return Err("file not found".into());
+        }
+        Ok(())
+    })?;
+    events::metadata_changed(&app, &[file_id]);
+    Ok(())
+}
+
+#[tauri::command]
+fn list_favorites(app: tauri::AppHandle) -> Result<Vec<FileInfo>, String> {
+    with_db(&app, |conn| {
+        query_file_infos(conn, "WHERE favorite = 1 ORDER BY name", [])
+    })
+}
+
+#[tauri::command]
+fn set_rating(app: tauri::AppHandle, file_id: i64, rating: Option<u8>) -> Result<(), String> {
+    with_db(&app, |conn| {
+        if !db::set_rating(conn, file_id, rating).map_err(|e| e.to_string())? {
+            return Err("file not found".into());
+        }
+        Ok(())
+    })?;
+    events::metadata_changed(&app, &[file_id]);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_note(app: tauri::AppHandle, file_id: i64, note: Option<String>) -> Result<(), String> {
+    with_db(&app, |conn| {
+        if !db::set_note(conn, file_id, note.as_deref()).map_err(|e| e.to_string())? {
+            return Err("file not found".into());
+        }
+        Ok(())
+    })?;
+    events::metadata_changed(&app, &[file_id]);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_color(app: tauri::AppHandle, file_id: i64, color: Option<String>) -> Result<(), String> {
+    with_db(&app, |conn| {
+        if !db::set_color(conn, file_id, color.as_deref()).map_err(|e| e.to_string())? {
+            return Err("file not found".into());
+        }
+        Ok(())
+    })?;
+    events::metadata_changed(&app, &[file_id]);
+    Ok(())
+}
+
+/// Hidden files stay in the DB with their embeddings but drop out of the map and search.
+#[tauri::command]
+fn hide_files(app: tauri::AppHandle, file_ids: Vec<i64>) -> Result<usize, String> {
+    let n = with_db(&app, |conn| {
+        db::set_hidden(conn, &file_ids, true).map_err(|e| e.to_string())
+    })?;
+    if n > 0 { events::metadata_changed(&app, &file_ids); }
+    Ok(n)
+}
+
+#[tauri::command]
+fn unhide_files(app: tauri::AppHandle, file_ids: Vec<i64>) -> Result<usize, String> {
+    let n = with_db(&app, |conn| {
+        db::set_hidden(conn, &file_ids, false).map_err(|e| e.to_string())
+    })?;
+    if n > 0 { events::metadata_changed(&app, &file_ids); }
+    Ok(n)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TrashFailure { file_id: i64, error: String }
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TrashReport { removed: Vec<i64>, failed: Vec<TrashFailure> }
+
+/// Moves the files to the OS trash and drops them from the library; ones already gone from
+/// disk are just dropped, and slices of trashed files are deleted with them.
+#[tauri::command]
+fn trash_files(app: tauri::AppHandle, file_ids: Vec<i64>) -> Result<TrashReport, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    let paths = with_db(&app, |conn| {
+        let mut paths = Vec::new();
+        for id in file_ids {
+            if let Some(path) = db::file_path(conn, id).map_err(|e| e.to_string())? { paths.push((id, path)); }
+        }
+        Ok(paths)
+    })?;
+    // Outside the DB lock: the OS trash can take seconds per file on Windows
+    let mut report = TrashReport { removed: Vec::new(), failed: Vec::new() };
+    let mut trashed = Vec::new();
+    for (id, path) in paths {
+        if path.exists() {
+            if let Err(e) = trash::delete(&path) {
+                report.failed.push(TrashFailure { file_id: id, error: e.to_string() });
+                continue;
+            }
+            trashed.push((id, path));
+        }
+        report.removed.push(id);
+    }
+    let sliced = with_db(&app, |conn| {
+        let op = undo::record_trash(conn, trashed, &report.removed);
+        journaled(conn, op, |conn| db::delete_files(conn, &report.removed).map_err(|e| e.to_string()))?;
+        Ok(slices::remove_slices_of(conn, &p, &report.removed).unwrap_or_else(|e| {
+            log::warn!("slices of trashed files not removed: {e:#}");
+            Vec::new()
+        }))
+    })?;
+    events::files_removed(&app, &report.removed);
+    if !sliced.is_empty() { events::files_removed(&app, &sliced); }
+    Ok(report)
+}
+
+/// Renames the file in place. A name without an extension keeps the current one. Returns the
+/// new path.
+#[tauri::command]
+fn rename_file(app: tauri::AppHandle, file_id: i64, new_name: String) -> Result<PathBuf, String> {
+    let new_name = new_name.trim();
+    if new_name.is_empty() || new_name.contains(['/', '\\']) || new_name == "." || new_name == ".." {
+        return Err(format!("invalid file name {new_name:?}"));
+    }
+    let to = with_db(&app, |conn| {
+        let from = db::file_path(conn, file_id).map_err(|e| e.to_string())?.ok_or_else(|| format!("file {file_id} not found"))?;
+        let mut to = from.with_file_name(new_name);
+        if to.extension().is_none() {
+            if let Some(ext) = from.extension() { to.set_extension(ext); }
+        }
+        if to == from { return Ok(to); }
+        db::relocate_file(conn, file_id, &to).map_err(|e| format!("{e:#}"))?;
+        if let Err(e) = undo::record_move(conn, vec![(file_id, from, to.clone())]) { log::warn!("undo: {e:#}"); }
+        Ok(to)
+    })?;
+    events::metadata_changed(&app, &[file_id]);
+    Ok(to)
+}
+
+/// Takes a scanned folder out of the library. With `delete_data` its files and everything
+/// attached to them are deleted; without, they're only hidden, keeping embeddings, tags and
+/// ratings for if they're unhidden. Either way it leaves `libraryRoots`, and the slices of its
+/// files go the same way: deleted with their WAVs, or hidden.
+#[tauri::command]
+fn remove_root(app: tauri::AppHandle, path: PathBuf, delete_data: bool) -> Result<db::RemovalSummary, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    let (summary, ids) = with_db(&app, |conn| {
+        let mut ids = db::files_under(conn, &path).map_err(|e| e.to_string())?;
+        // Slices live outside every root; hidden along with their parents so undo unhides both
+        if !delete_data { ids.extend(slices::slices_of(conn, &ids).map_err(|e| e.to_string())?); }
+        let summary = db::removal_summary(conn, &ids).map_err(|e| e.to_string())?;
+        let op = undo::record_remove_root(conn, path.clone(), delete_data, &ids);
+        journaled(conn, op, |conn| {
+            let res = if delete_data { db::delete_files(conn, &ids) } else { db::set_hidden(conn, &ids, true) };
+            res.map_err(|e| e.to_string())
+        })?;
+        if delete_data {
+            match slices::remove_slices_of(conn, &p, &ids) {
+                Ok(sliced) => ids.extend(sliced),
+                Err(e) => log::warn!("slices under {} not removed: {e:#}", path.display()),
+            }
+        }
+        Ok((summary, ids))
+    })?;
+    let mut prefs = settings::load(&app);
+    if prefs.library_roots.iter().any(|r| r == &path) {
+        prefs.library_roots.retain(|r| r != &path);
+        settings::save(&app, &prefs).map_err(|e| e.to_string())?;
+    }
+    if delete_data { events::files_removed(&app, &ids); } else { events::metadata_changed(&app, &ids); }
+    Ok(summary)
+}
+
+/// Runs `edit` journaled as `op` (from `undo::record_*`), dropping the entry again if the edit
+/// fails. Journaling is best-effort; its errors are only logged.
+fn journaled<T>(conn: &mut rusqlite::Connection, op: anyhow::Result<Option<i64>>, edit: impl FnOnce(&mut rusqlite::Connection) -> Result<T, String>) -> Result<T, String> {
+    let op = op.unwrap_or_else(|e| {
+        log::warn!("undo: {e:#}");
+        None
+    });
+    let res = edit(conn);
+    if let (Err(_), Some(op)) = (&res, op) {
+        if let Err(e) = undo::forget_op(conn, op) { log::warn!("undo: {e:#}"); }
+    }
+    res
+}
+
+/// Reverts the newest rename, move, trash, tag or bulk edit or folder removal, files and rows both
+/// where possible; what couldn't be restored is listed in `failed`.
+#[tauri::command]
+fn undo_last_operation(app: tauri::AppHandle) -> Result<undo::UndoReport, String> {
+    let report = with_db(&app, |conn| undo::undo_last(conn).map_err(|e| format!("{e:#}")))?;
+    if let Some(root) = &report.root {
+        let mut prefs = settings::load(&app);
+        if !prefs.library_roots.contains(root) {
+            prefs.library_roots.push(root.clone());
+            settings::save(&app, &prefs).map_err(|e| e.to_string())?;
+        }
+    }
+    if report.readded { events::files_added(&app, &report.file_ids); } else { events::metadata_changed(&app, &report.file_ids); }
+    Ok(report)
+}
+
+/// The undo journal, newest first.
+#[tauri::command]
+fn list_operations(app: tauri::AppHandle) -> Result<Vec<undo::OperationInfo>, String> {
+    with_db(&app, |conn| undo::list(conn).map_err(|e| e.to_string()))
+}
+
+/// Moves the files into `dest_dir`, keeping their names and library data. All of them move or
+/// none do.
+#[tauri::command]
+fn move_files(app: tauri::AppHandle, file_ids: Vec<i64>, dest_dir: PathBuf) -> Result<usize, String> {
+    if !dest_dir.is_dir() { return Err(format!("{} is not a folder", dest_dir.display())); }
+    let moved = with_db(&app, |conn| {
+        let mut moves = Vec::with_capacity(file_ids.len());
+        let mut targets = std::collections::HashSet::new();
+        for id in file_ids {
+            let from = db::file_path(conn, id).map_err(|e| e.to_string())?.ok_or_else(|| format!("file {id} not found"))?;
+            let Some(name) = from.file_name() else { continue };
+            let to = dest_dir.join(name);
+            if to == from { continue; }
+            if !targets.insert(to.clone()) { return Err(format!("more than one selected file is named {}", name.to_string_lossy())); }
+            moves.push((id, from, to));
+        }
+        let plan: Vec<(i64, PathBuf)> = moves.iter().map(|(id, _, to)| (*id, to.clone())).collect();
+        db::relocate_files(conn, &plan).map_err(|e| format!("{e:#}"))?;
+        let ids = moves.iter().map(|m| m.0).collect::<Vec<_>>();
+        if let Err(e) = undo::record_move(conn, moves) { log::warn!("undo: {e:#}"); }
+        Ok(ids)
+    })?;
+    events::metadata_changed(&app, &moved);
+    Ok(moved.len())
+}
+
+/// Peak thumbnails for hover previews, computed on first request and cached in the library.
+#[tauri::command]
+fn get_waveform_thumbnail(app: tauri::AppHandle, file_ids: Vec<i64>) -> Result<Vec<waveform::Thumbnail>, String> {
+    with_db(&app, |conn| waveform::thumbnails(conn, &file_ids).map_err(|e| e.to_string()))
+}
+
+/// Finished scans of the open library, newest first. Unlike `list_scan_jobs` this survives restarts.
+#[tauri::command]
+fn get_scan_history(app: tauri::AppHandle, limit: Option<usize>) -> Result<Vec<history::ScanRecord>, String> {
+    with_db(&app, |conn| history::list(conn, limit.unwrap_or(100)).map_err(|e| e.to_string()))
+}
+
+/// Previously scanned folders, most recent first, for one-click rescans.
+#[tauri::command]
+fn get_recent_folders(app: tauri::AppHandle, limit: Option<usize>) -> Result<Vec<history::RecentFolder>, String> {
+    with_db(&app, |conn| history::recent_folders(conn, limit.unwrap_or(10)).map_err(|e| e.to_string()))
+}
+
+/// Stores the map view (camera, filters, selection, layout) in the open library.
+#[tauri::command]
+fn save_workspace(app: tauri::AppHandle, state: workspace::Workspace) -> Result<(), String> {
+    with_db(&app, |conn| workspace::save(conn, &state).map_err(|e| e.to_string()))
+}
+
+/// The view saved by `save_workspace`, or `None` for a library that has never saved one.
+#[tauri::command]
+fn load_workspace(app: tauri::AppHandle) -> Result<Option<workspace::Workspace>, String> {
+    with_db(&app, |conn| workspace::load(conn).map_err(|e| e.to_string()))
+}
+
+/// Applies tag, rating, color, hidden and favorite edits to many files at once, then emits a
+/// single change event for all of them. Returns how many of the files exist.
+#[tauri::command]
+fn bulk_update(app: tauri::AppHandle, file_ids: Vec<i64>, changes: db::BulkChanges) -> Result<usize, String> {
+    let n = with_db(&app, |conn| {
+        let op = undo::record_bulk_update(conn, &file_ids, &changes);
+        journaled(conn, op, |conn| db::bulk_update(conn, &file_ids, &changes).map_err(|e| e.to_string()))
+    })?;
+    if n > 0 { events::metadata_changed(&app, &file_ids); }
+    Ok(n)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RatingEntry { file_id: i64, rating: u8 }
+
+/// Rated files only, best first.
+#[tauri::command]
+fn get_ratings(app: tauri::AppHandle, min_rating: Option<u8>) -> Result<Vec<RatingEntry>, String> {
+    with_db(&app, |conn| {
+        let mut stmt = conn
+            .prepare("SELECT id, rating FROM files WHERE rating IS NOT NULL AND rating >= ? ORDER BY rating DESC, id")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(rusqlite::params![min_rating.unwrap_or(1)], |r| Ok(RatingEntry { file_id: r.get(0)?, rating: r.get(1)? }))
+            .map_err(|e| e.to_string())?;
+        let mut out = Vec::new();
+        for r in rows { out.push(r.map_err(|e| e.to_string())?); }
+        Ok(out)
+    })
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchHit { file_id: i64, name: String, path: String, x: Option<f32>, y: Option<f32>, score: f64 }
+
+/// Ranked full-text matches over file names, paths and notes; names weigh most, then notes.
+/// `x`/`y` are absent for files that haven't been projected yet.
+#[tauri::command]
+fn search(app: tauri::AppHandle, query: String, limit: Option<i64>, include_hidden: Option<bool>) -> Result<Vec<SearchHit>, String> {
+    let Some(q) = db::fts_query(&query) else { return Ok(Vec::new()) };
+    with_db(&app, |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT f.id, f.name, f.path, c.x, c.y, -bm25(files_fts, 10.0, 1.0, 4.0) AS score \
+                 FROM files_fts JOIN files f ON f.id = files_fts.rowid LEFT JOIN coords c ON c.file_id = f.id \
+                 WHERE files_fts MATCH ?1 AND (?3 OR f.hidden = 0) ORDER BY score DESC LIMIT ?2",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(rusqlite::params![q, limit.unwrap_or(100), include_hidden.unwrap_or(false)], |r| {
+                Ok(SearchHit {
+                    file_id: r.get(0)?,
+                    name: r.get(1)?,
+                    path: r.get::<_, db::StoredPath>(2)?.0.to_string_lossy().into_owned(),
+                    x: r.get::<_, Option<f64>>(3)?.map(|v| v as f32),
+                    y: r.get::<_, Option<f64>>(4)?.map(|v| v as f32),
+                    score: r.get(5)?,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        let mut out = Vec::new();
+        for r in rows { out.push(r.map_err(|e| e.to_string())?); }
+        Ok(out)
+    })
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileHit { file_id: i64, name: String, path: String, x: Option<f32>, y: Option<f32> }
+
+/// Files whose name or path contains `query`, case-insensitively (ASCII), with `*`/`?`
+/// wildcards. Name matches come first, then by name. `x`/`y` are absent for unprojected files.
+#[tauri::command]
+fn search_files(app: tauri::AppHandle, query: String, limit: Option<i64>, offset: Option<i64>, include_hidden: Option<bool>) -> Result<Vec<FileHit>, String> {
+    let query = query.trim();
+    if query.is_empty() { return Ok(Vec::new()); }
+    let pattern = query::like_pattern(query);
+    with_db(&app, |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT f.id, f.name, f.path, c.x, c.y FROM files f LEFT JOIN coords c ON c.file_id = f.id \
+                 WHERE (f.name LIKE ?1 ESCAPE '\\' OR f.path LIKE ?1 ESCAPE '\\') AND (?4 OR f.hidden = 0) \
+                 ORDER BY f.name LIKE ?1 ESCAPE '\\' DESC, f.name, f.id LIMIT ?2 OFFSET ?3",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(rusqlite::params![pattern, limit.unwrap_or(100), offset.unwrap_or(0), include_hidden.unwrap_or(false)], |r| {
+                Ok(FileHit {
+                    file_id: r.get(0)?,
+                    name: r.get(1)?,
+                    path: r.get::<_, db::StoredPath>(2)?.0.to_string_lossy().into_owned(),
+                    x: r.get::<_, Option<f64>>(3)?.map(|v| v as f32),
+                    y: r.get::<_, Option<f64>>(4)?.map(|v| v as f32),
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        let mut out = Vec::new();
+        for r in rows { out.push(r.map_err(|e| e.to_string())?); }
+        Ok(out)
+    })
+}
+
+#[tauri::command]
+fn import_library(app: tauri::AppHandle, path: PathBuf, strategy: Option<merge::MergeStrategy>) -> Result<merge::ImportSummary, String> {
+    let (summary, added) = with_db(&app, |conn| {
+        let strategy = strategy.unwrap_or(merge::MergeStrategy::Path);
+        let last_id = events::max_file_id(conn);
+        let summary = merge::import_library(conn, &path, strategy).map_err(|e| e.to_string())?;
+        Ok((summary, events::new_file_ids(conn, last_id)))
+    })?;
+    events::files_added(&app, &added);
+    Ok(summary)
+}
+
+/// Packs the files with their tags, embeddings and map coords into one zip at `path`.
+#[tauri::command]
+fn export_bundle(app: tauri::AppHandle, file_ids: Vec<i64>, path: PathBuf) -> Result<bundle::BundleReport, String> {
+    with_db(&app, |conn| bundle::export_bundle(conn, &file_ids, &path).map_err(|e| format!("{e:#}")))
+}
+
+/// Unpacks a bundle's samples into `dest` and merges its library into the active one.
+#[tauri::command]
+fn import_bundle(app: tauri::AppHandle, path: PathBuf, dest: PathBuf, strategy: Option<merge::MergeStrategy>) -> Result<merge::ImportSummary, String> {
+    let (summary, added) = with_db(&app, |conn| {
+        let last_id = events::max_file_id(conn);
+        let summary = bundle::import_bundle(conn, &path, &dest, strategy.unwrap_or(merge::MergeStrategy::Path)).map_err(|e| format!("{e:#}"))?;
+        Ok((summary, events::new_file_ids(conn, last_id)))
+    })?;
+    events::files_added(&app, &added);
+    Ok(summary)
+}
+
+/// Fills BPM, key, rating and tags from a rekordbox XML export or Splice's library, for files
+/// that are already in this one.
+#[tauri::command]
+fn import_external_metadata(app: tauri::AppHandle, source: external::Source, path: PathBuf) -> Result<external::MetadataImportSummary, String> {
+    let summary = with_db(&app, |conn| external::import(conn, source, &path).map_err(|e| format!("{e:#}")))?;
+    events::metadata_changed(&app, &summary.file_ids);
+    Ok(summary)
+}
+
+fn query_file_infos(conn: &rusqlite::Connection, filter_and_order: &str, params: impl rusqlite::Params) -> Result<Vec<FileInfo>, String> {
+    let mut stmt = conn
+        .prepare(&format!("SELECT {FILE_INFO_COLUMNS} FROM files {filter_and_order}"))
         .map_err(|e| e.to_string())?;
+    let rows = stmt.query_map(params, file_info_from_row).map_err(|e| e.to_string())?;
     let mut out = Vec::new();
     for r in rows { out.push(r.map_err(|e| e.to_string())?); }
     Ok(out)
 }
 
+#[tauri::command]
+fn most_played(app: tauri::AppHandle, limit: Option<i64>) -> Result<Vec<FileInfo>, String> {
+    with_db(&app, |conn| {
+        query_file_infos(conn, "WHERE play_count > 0 ORDER BY play_count DESC, last_played_at DESC LIMIT ?", rusqlite::params![limit.unwrap_or(50)])
+    })
+}
+
+#[tauri::command]
+fn recently_played(app: tauri::AppHandle, limit: Option<i64>) -> Result<Vec<FileInfo>, String> {
+    with_db(&app, |conn| {
+        query_file_infos(conn, "WHERE last_played_at IS NOT NULL ORDER BY last_played_at DESC LIMIT ?", rusqlite::params![limit.unwrap_or(50)])
+    })
+}
+
+#[tauri::command]
+fn query_files(
+    app: tauri::AppHandle,
+    filters: Option<query::FileFilter>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Vec<query::QueryMatch>, String> {
+    with_db(&app, |conn| {
+        let filters = filters.unwrap_or_default();
+        query::query_files(conn, &filters, limit.unwrap_or(1_000_000), offset.unwrap_or(0)).map_err(|e| e.to_string())
+    })
+}
+
+/// "Surprise me": `k` random files matching `filters`, with their coords.
+/// `favorUnplayed` makes rarely or never auditioned files more likely.
+#[tauri::command]
+fn get_random_files(
+    app: tauri::AppHandle,
+    k: Option<usize>,
+    filters: Option<query::FileFilter>,
+    favor_unplayed: Option<bool>,
+) -> Result<Vec<query::QueryMatch>, String> {
+    let filters = filters.unwrap_or_default();
+    with_db(&app, |conn| query::random_files(conn, &filters, k.unwrap_or(10), favor_unplayed.unwrap_or(false)).map_err(|e| e.to_string()))
+}
+
+/// One page of the library for the list view, sorted by `sort_by` and narrowed by `filters`
+/// (hidden files excluded unless the filter says otherwise).
+#[tauri::command]
+fn list_files(
+    app: tauri::AppHandle,
+    sort_by: Option<query::SortKey>,
+    order: Option<query::SortOrder>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    filters: Option<query::FileFilter>,
+) -> Result<Vec<FileInfo>, String> {
+    let filters = filters.unwrap_or_default();
+    let (clause, params) = query::list_clause(&filters, sort_by.unwrap_or_default(), order.unwrap_or_default(), limit.unwrap_or(200), offset.unwrap_or(0));
+    with_db(&app, |conn| query_file_infos(conn, &clause, rusqlite::params_from_iter(params)))
+}
+
+/// Where a file (by id, or a path dropped or pasted from the OS) lives in the library and its `k`
+/// nearest neighbours; `None` if neither it nor a copy of it is in the library.
+#[tauri::command]
+fn locate_file(
+    app: tauri::AppHandle,
+    state: tauri::State<AppState>,
+    file_id: Option<i64>,
+    path: Option<PathBuf>,
+    k: Option<usize>,
+) -> Result<Option<similar::Location>, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    with_db(&app, |conn| similar::locate(conn, &state.ann, &p, file_id, path.as_deref(), k.unwrap_or(10)).map_err(|e| format!("{e:#}")))
+}
+
+/// "More like this": the `k` nearest files to `file_id` in embedding space.
+#[tauri::command]
+fn find_similar(app: tauri::AppHandle, state: tauri::State<AppState>, file_id: i64, k: Option<usize>) -> Result<Vec<similar::Neighbor>, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    with_db(&app, |conn| similar::find_similar(conn, &state.ann, &p, file_id, k.unwrap_or(20)).map_err(|e| e.to_string()))
+}
+
+/// Samples along the embedding-space line from `file_a` to `file_b`, e.g. "clean clap" to
+/// "noisy snare", for building evolving sequences.
+#[tauri::command]
+fn interpolate_path(
+    app: tauri::AppHandle,
+    state: tauri::State<AppState>,
+    file_a: i64,
+    file_b: i64,
+    steps: Option<usize>,
+) -> Result<Vec<similar::Neighbor>, String> {
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    with_db(&app, |conn| similar::interpolate_path(conn, &state.ann, &p, file_a, file_b, steps.unwrap_or(16)).map_err(|e| e.to_string()))
+}
+
+/// The most isolated samples by embedding distance to their 10th nearest neighbour, scored
+/// after each scan. Often broken files, speech or mislabeled content.
+#[tauri::command]
+fn list_outliers(app: tauri::AppHandle, limit: Option<i64>, include_hidden: Option<bool>) -> Result<Vec<similar::Neighbor>, String> {
+    with_db(&app, |conn| outlier::list(conn, limit.unwrap_or(100), include_hidden.unwrap_or(false)).map_err(|e| e.to_string()))
+}
+
+/// "What's around here?": the `k` samples nearest a map position, e.g. a click on empty space.
+#[tauri::command]
+fn get_nearest_to_point(app: tauri::AppHandle, x: f64, y: f64, k: Option<usize>, include_hidden: Option<bool>) -> Result<Vec<similar::Neighbor>, String> {
+    with_db(&app, |conn| similar::nearest_to_point(conn, x, y, k.unwrap_or(20), include_hidden.unwrap_or(false)).map_err(|e| e.to_string()))
+}
+
+/// Ranks samples against a text prompt ("metallic hit with long tail") via the CLAP text encoder.
+#[tauri::command]
+fn search_by_text(app: tauri::AppHandle, state: tauri::State<AppState>, prompt: String, k: Option<usize>) -> Result<Vec<similar::Neighbor>, String> {
+    // Text vectors only mean something next to CLAP's audio vectors
+    let model = with_db(&app, |conn| models::active(conn).map_err(|e| e.to_string()))?;
+    if model != models::BUILTIN { return Err("text search needs the built-in CLAP model to be active".into()); }
+    let query = state.text.embed(&app, &prompt).map_err(|e| e.to_string())?;
+    let p = db::db_path(&app).map_err(|e| e.to_string())?;
+    with_db(&app, |conn| similar::nearest(conn, &state.ann, &p, &query, None, k.unwrap_or(50)).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+fn add_tags(app: tauri::AppHandle, file_ids: Vec<i64>, tags: Vec<String>) -> Result<usize, String> {
+    let n = with_db(&app, |conn| {
+        let op = undo::record_add_tags(conn, &file_ids, &tags);
+        journaled(conn, op, |conn| db::add_tags(conn, &file_ids, &tags).map_err(|e| e.to_string()))
+    })?;
+    if n > 0 { events::metadata_changed(&app, &file_ids); }
+    Ok(n)
+}
+
+#[tauri::command]
+fn remove_tags(app: tauri::AppHandle, file_ids: Vec<i64>, tags: Vec<String>) -> Result<usize, String> {
+    let n = with_db(&app, |conn| {
+        let op = undo::record_remove_tags(conn, &file_ids, &tags);
+        journaled(conn, op, |conn| db::remove_tags(conn, &file_ids, &tags).map_err(|e| e.to_string()))
+    })?;
+    if n > 0 { events::metadata_changed(&app, &file_ids); }
+    Ok(n)
+}
+
 #[derive(serde::Serialize)]
 #[serde(rename_all = "camelCase")]
-struct FileInfo { path: String, name: String, size_bytes: i64, duration: Option<f64> }
+struct TagCount { tag: String, count: i64, auto_count: i64 }
 
 #[tauri::command]
-fn get_file_info(app: tauri::AppHandle, file_id: i64) -> Result<FileInfo, String> {
-    let p = db::db_path(&app).map_err(|e| e.to_string())?;
-    let conn = db::open_or_create(&p).map_err(|e| e.to_string())?;
-    let mut stmt = conn.prepare("SELECT path, name, size_bytes, duration FROM files WHERE id = ?").map_err(|e| e.to_string())?;
-    let r = stmt.query_row(rusqlite::params![file_id], |r| {
-        Ok(FileInfo { path: r.get(0)?, name: r.get(1)?, size_bytes: r.get(2)?, duration: r.get(3)? })
-    }).map_err(|e| e.to_string())?;
-    Ok(r)
+fn list_tags(app: tauri::AppHandle) -> Result<Vec<TagCount>, String> {
+    with_db(&app, |conn| {
+        let mut stmt = conn
+            .prepare("SELECT tag, COUNT(*), SUM(source = 'auto') FROM file_tags GROUP BY tag ORDER BY COUNT(*) DESC, tag")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt.query_map([], |r| Ok(TagCount { tag: r.get(0)?, count: r.get(1)?, auto_count: r.get(2)? })).map_err(|e| e.to_string())?;
+        let mut out = Vec::new();
+        for r in rows { out.push(r.map_err(|e| e.to_string())?); }
+        Ok(out)
+    })
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LibraryInfo { name: String, path: String, active: bool }
+
+#[tauri::command]
+fn list_libraries(app: tauri::AppHandle) -> Result<Vec<LibraryInfo>, String> {
+    let active = settings::load(&app).active_library.unwrap_or_else(|| db::DEFAULT_LIBRARY.to_string());
+    let names = db::list_libraries(&app).map_err(|e| e.to_string())?;
+    let mut out = Vec::new();
+    for name in names {
+        let path = db::library_path(&app, &name).map_err(|e| e.to_string())?;
+        out.push(LibraryInfo { active: name == active, path: path.to_string_lossy().to_string(), name });
+    }
+    Ok(out)
+}
+
+/// Creates an empty library database. Does not switch to it.
+#[tauri::command]
+fn create_library(app: tauri::AppHandle, name: String) -> Result<LibraryInfo, String> {
+    let path = db::library_path(&app, &name).map_err(|e| e.to_string())?;
+    if path.exists() { return Err(format!("library {name:?} already exists")); }
+    db::open_or_create(&path).map_err(|e| e.to_string())?;
+    Ok(LibraryInfo { name, path: path.to_string_lossy().to_string(), active: false })
+}
+
+/// Makes `name` the library every command and new scan uses. Scans already running finish
+/// against the library they started on.
+#[tauri::command]
+fn switch_library(app: tauri::AppHandle, name: String) -> Result<(), String> {
+    let path = db::library_path(&app, &name).map_err(|e| e.to_string())?;
+    if name != db::DEFAULT_LIBRARY && !path.exists() { return Err(format!("library {name:?} not found")); }
+    let mut s = settings::load(&app);
+    s.active_library = if name == db::DEFAULT_LIBRARY { None } else { Some(name) };
+    settings::save(&app, &s).map_err(|e| e.to_string())?;
+    app.state::<AppState>().db.close();
+    Ok(())
 }
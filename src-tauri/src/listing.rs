@@ -0,0 +1,84 @@
+//! Folder previews for the import dialog. Walking a huge tree, possibly on a network share,
+//! takes a while, so the walk runs on its own thread and sends what it finds in batches as
+//! [`BATCH`] events; the last one has `done` set.
+
+use crate::{scan, worker::Cancel};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use tauri::{AppHandle, Emitter};
+use uuid::Uuid;
+
+pub const BATCH: &str = "listing://batch";
+/// A batch goes out at this size, or sooner when the walk is slow.
+const BATCH_SIZE: usize = 256;
+const BATCH_INTERVAL: Duration = Duration::from_millis(150);
+
+#[derive(Clone, serde::Serialize)]
+pub struct FileEntry {
+    pub path: String,
+    pub name: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Batch {
+    pub listing_id: String,
+    pub files: Vec<FileEntry>,
+    /// Last batch: the walk finished, hit its limit or was cancelled.
+    pub done: bool,
+    pub cancelled: bool,
+}
+
+/// Walks in progress, by id, for cancelling.
+#[derive(Default)]
+pub struct Listings {
+    running: Arc<Mutex<HashMap<String, Arc<Cancel>>>>,
+}
+
+impl Listings {
+    /// Starts walking `root` for up to `limit` audio files; returns the id batches carry.
+    pub fn start(&self, app: AppHandle, root: PathBuf, walk: scan::WalkOptions, limit: usize) -> String {
+        let id = Uuid::new_v4().to_string();
+        let cancel = Arc::new(Cancel::default());
+        self.running.lock().insert(id.clone(), cancel.clone());
+        let running = self.running.clone();
+        let listing_id = id.clone();
+        thread::spawn(move || {
+            let send = |files: Vec<FileEntry>, done: bool| {
+                let batch = Batch { listing_id: listing_id.clone(), files, done, cancelled: done && cancel.is_requested() };
+                if let Err(e) = app.emit(BATCH, batch) { log::warn!("listing: {BATCH}: {e}"); }
+            };
+            let mut pending = Vec::new();
+            let mut last = Instant::now();
+            for p in scan::walk_wavs(&root, &walk).take(limit) {
+                if cancel.is_requested() { break; }
+                let name = p.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+                pending.push(FileEntry { path: p.to_string_lossy().to_string(), name });
+                if pending.len() >= BATCH_SIZE || last.elapsed() >= BATCH_INTERVAL {
+                    send(std::mem::take(&mut pending), false);
+                    last = Instant::now();
+                }
+            }
+            running.lock().remove(&listing_id);
+            send(pending, true);
+        });
+        id
+    }
+
+    /// Stops a walk; false if it already finished.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.running.lock().get(id) {
+            Some(c) => {
+                c.request();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Level-of-detail view of the active layout for maps too large to ship whole. Level `z` splits
+//! the layout's bounding square into 2^z × 2^z cells; `coords_lod` holds each occupied cell's
+//! point count and centroid per level, built after projection. Triggers on `coords` (and on
+//! hiding files) drop `coords_lod_bounds`, which marks the table stale until the next rebuild.
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Finest aggregated level; zooming past it returns raw points.
+pub const MAX_LEVEL: u32 = 10;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LodCell {
+    pub x: f32,
+    pub y: f32,
+    pub count: i64,
+    /// The file itself when the cell holds only one.
+    pub file_id: Option<i64>,
+}
+
+#[derive(Clone, Copy)]
+struct Bounds {
+    min_x: f64,
+    min_y: f64,
+    /// Side of the bounding square.
+    size: f64,
+}
+
+impl Bounds {
+    /// Cell index along one axis at `level`, clamped into the grid.
+    fn cell(&self, v: f64, origin: f64, level: u32) -> i64 {
+        let n = 1i64 << level;
+        (((v - origin) / self.size * n as f64).floor() as i64).clamp(0, n - 1)
+    }
+}
+
+fn bounds(conn: &Connection) -> Result<Option<Bounds>> {
+    Ok(conn
+        .query_row("SELECT min_x, min_y, size FROM coords_lod_bounds", [], |r| Ok(Bounds { min_x: r.get(0)?, min_y: r.get(1)?, size: r.get(2)? }))
+        .optional()?)
+}
+
+/// Re-aggregates every level from the visible files in `coords`.
+pub fn rebuild(conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM coords_lod", [])?;
+    tx.execute("DELETE FROM coords_lod_bounds", [])?;
+    let extent: (Option<f64>, Option<f64>, Option<f64>, Option<f64>) = tx.query_row(
+        "SELECT MIN(c.x), MIN(c.y), MAX(c.x), MAX(c.y) FROM coords c JOIN files f ON f.id = c.file_id WHERE f.hidden = 0",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+    )?;
+    if let (Some(min_x), Some(min_y), Some(max_x), Some(max_y)) = extent {
+        // Square cells, with a little slack so the maximum falls inside the last one
+        let size = (max_x - min_x).max(max_y - min_y).max(1e-9) * (1.0 + 1e-9);
+        for level in 0..=MAX_LEVEL {
+            let n = 1i64 << level;
+            tx.execute(
+                "INSERT INTO coords_lod(level, cell_x, cell_y, count, x, y, file_id) \
+                 SELECT ?1, cx, cy, COUNT(*), AVG(x), AVG(y), MIN(file_id) FROM ( \
+                     SELECT c.file_id, c.x, c.y, \
+                            MIN(?5 - 1, CAST((c.x - ?2) / ?4 * ?5 AS INTEGER)) AS cx, \
+                            MIN(?5 - 1, CAST((c.y - ?3) / ?4 * ?5 AS INTEGER)) AS cy \
+                     FROM coords c JOIN files f ON f.id = c.file_id WHERE f.hidden = 0) \
+                 GROUP BY cx, cy",
+                params![level, min_x, min_y, size, n],
+            )?;
+        }
+        // Written last: the triggers on coords clear it, so it only survives an up-to-date build
+        tx.execute("INSERT INTO coords_lod_bounds(min_x, min_y, size) VALUES(?, ?, ?)", params![min_x, min_y, size])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Cells at `level` overlapping the given viewport, rebuilding first if coords moved since
+/// the last build. `level` is clamped to [`MAX_LEVEL`].
+pub fn cells(conn: &mut Connection, level: u32, min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> Result<Vec<LodCell>> {
+    let b = match bounds(conn)? {
+        Some(b) => b,
+        None => {
+            rebuild(conn)?;
+            match bounds(conn)? {
+                Some(b) => b,
+                None => return Ok(Vec::new()),
+            }
+        }
+    };
+    let level = level.min(MAX_LEVEL);
+    let (x0, x1) = (b.cell(min_x, b.min_x, level), b.cell(max_x, b.min_x, level));
+    let (y0, y1) = (b.cell(min_y, b.min_y, level), b.cell(max_y, b.min_y, level));
+    let mut stmt = conn.prepare(
+        "SELECT x, y, count, file_id FROM coords_lod WHERE level = ?1 AND cell_x BETWEEN ?2 AND ?3 AND cell_y BETWEEN ?4 AND ?5",
+    )?;
+    let rows = stmt
+        .query_map(params![level, x0, x1, y0, y1], |r| {
+            let count: i64 = r.get(2)?;
+            Ok(LodCell {
+                x: r.get::<_, f64>(0)? as f32,
+                y: r.get::<_, f64>(1)? as f32,
+                count,
+                file_id: if count == 1 { Some(r.get(3)?) } else { None },
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
@@ -0,0 +1,129 @@
+//! Per-file levels: sample peak and RMS in dBFS, and integrated loudness in LUFS through the
+//! same BS.1770 measurement the converter normalises with. Files that are silence, or only a
+//! DC offset, are flagged on the way so they can be listed for cleanup.
+
+use crate::{
+    convert,
+    db::StoredPath,
+    playback,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+use std::path::PathBuf;
+
+/// Stored for digital silence instead of -inf.
+pub const FLOOR_DB: f64 = -150.0;
+/// A peak below this is silence.
+pub const SILENT_PEAK_DB: f64 = -60.0;
+/// RMS around the mean below this is silence even when the signal sits at a DC offset.
+const SILENT_AC_RMS_DB: f64 = -70.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Levels {
+    pub peak_db: f64,
+    pub rms_db: f64,
+    /// `None` for files too short or too quiet to gate (under 400 ms, or below -70 LUFS).
+    pub lufs: Option<f64>,
+    pub silent: bool,
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessSummary {
+    pub analyzed: usize,
+    pub failed: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// Measures files not measured yet; with `force`, all of them.
+pub fn analyze(conn: &Connection, force: bool, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<LoudnessSummary> {
+    let todo: Vec<(i64, PathBuf)> = {
+        let mut stmt = conn.prepare("SELECT id, path FROM files WHERE peak_db IS NULL OR ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![force], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let total = todo.len();
+    let mut summary = LoudnessSummary::default();
+    for (i, (id, path)) in todo.into_iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "loudness".into(), processed: i, total, batch: None });
+        let levels = match playback::decode_samples(&path) {
+            Ok((channels, rate, data)) => measure(&data, channels, rate),
+            Err(e) => {
+                log::warn!("loudness: {}: {e:#}", path.display());
+                summary.failed += 1;
+                continue;
+            }
+        };
+        conn.execute(
+            "UPDATE files SET peak_db = ?, rms_db = ?, lufs = ?, is_silent = ? WHERE id = ?",
+            params![levels.peak_db, levels.rms_db, levels.lufs, levels.silent, id],
+        )?;
+        summary.analyzed += 1;
+        summary.file_ids.push(id);
+    }
+    on_progress(&Progress { stage: "loudness".into(), processed: total, total, batch: None });
+    Ok(summary)
+}
+
+pub fn measure(data: &[f32], channels: u16, rate: u32) -> Levels {
+    let db = |amplitude: f64| if amplitude > 0.0 { (20.0 * amplitude.log10()).max(FLOOR_DB) } else { FLOOR_DB };
+    let peak = data.iter().fold(0f32, |m, v| m.max(v.abs()));
+    let mean_square = if data.is_empty() { 0.0 } else { data.iter().map(|&v| f64::from(v).powi(2)).sum::<f64>() / data.len() as f64 };
+    let planes = convert::deinterleave(data, usize::from(channels.max(1)));
+    // Loudest channel's deviation from its own mean
+    let ac_rms = planes
+        .iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let mean = p.iter().map(|&v| f64::from(v)).sum::<f64>() / p.len() as f64;
+            (p.iter().map(|&v| (f64::from(v) - mean).powi(2)).sum::<f64>() / p.len() as f64).sqrt()
+        })
+        .fold(0.0, f64::max);
+    let peak_db = db(f64::from(peak));
+    Levels {
+        peak_db,
+        rms_db: db(mean_square.sqrt()),
+        lufs: convert::integrated_loudness(&planes, rate).map(f64::from),
+        silent: peak_db < SILENT_PEAK_DB || db(ac_rms) < SILENT_AC_RMS_DB,
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SilentFile {
+    pub file_id: i64,
+    pub path: String,
+    pub size_bytes: i64,
+    pub peak_db: Option<f64>,
+    pub hidden: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SilenceReport {
+    pub files: Vec<SilentFile>,
+    pub total_bytes: i64,
+    /// Files not measured yet, so not covered by the report.
+    pub unmeasured: i64,
+}
+
+/// Every file flagged as silent, largest first.
+pub fn silence_report(conn: &Connection) -> Result<SilenceReport> {
+    let mut stmt = conn.prepare("SELECT id, path, size_bytes, peak_db, hidden FROM files WHERE is_silent = 1 ORDER BY size_bytes DESC, id")?;
+    let files = stmt
+        .query_map([], |r| {
+            Ok(SilentFile {
+                file_id: r.get(0)?,
+                path: r.get::<_, StoredPath>(1)?.0.to_string_lossy().into_owned(),
+                size_bytes: r.get(2)?,
+                peak_db: r.get(3)?,
+                hidden: r.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let unmeasured = conn.query_row("SELECT COUNT(*) FROM files WHERE peak_db IS NULL", [], |r| r.get(0))?;
+    Ok(SilenceReport { total_bytes: files.iter().map(|f| f.size_bytes).sum(), files, unmeasured })
+}
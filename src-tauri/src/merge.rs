@@ -0,0 +1,197 @@
+use crate::{
+    db::{SqlPath, StoredPath},
+    scan,
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// How rows from the other library are matched to local ones.
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeStrategy {
+    /// Same absolute path = same file.
+    Path,
+    /// Same content hash = same file, falling back to path. Hashes missing on either side are
+    /// computed from disk where the file is reachable.
+    Hash,
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+    pub files_added: usize,
+    pub files_matched: usize,
+    pub embeddings_added: usize,
+    pub coords_added: usize,
+    pub tags_added: usize,
+}
+
+struct SrcFile {
+    id: i64,
+    path: PathBuf,
+    name: String,
+    size_bytes: i64,
+    duration: Option<f64>,
+    mtime: i64,
+    favorite: bool,
+    rating: Option<u8>,
+    content_hash: Option<String>,
+    sample_rate: Option<u32>,
+    bits_per_sample: Option<u16>,
+    channels: Option<u16>,
+}
+
+/// Merges another samplemap database into `conn` in one transaction. Local data wins: existing
+/// embeddings and coords are never overwritten, ratings are only filled where unset, and
+/// favorites are OR-ed and tags unioned. Imported coords come from a different projection run, so a re-projection
+/// is needed before they line up with the local map.
+pub fn import_library(conn: &mut Connection, src_path: &Path, strategy: MergeStrategy) -> Result<ImportSummary> {
+    if !src_path.is_file() { bail!("library not found: {}", src_path.display()); }
+    // SQLite takes the filename as UTF-8
+    let Some(src_name) = src_path.to_str() else { bail!("library path is not valid UTF-8: {}", src_path.display()) };
+    conn.execute("ATTACH DATABASE ? AS src", params![src_name])?;
+    let res = merge_attached(conn, strategy);
+    let _ = conn.execute("DETACH DATABASE src", []);
+    res
+}
+
+fn merge_attached(conn: &mut Connection, strategy: MergeStrategy) -> Result<ImportSummary> {
+    let src_tables: HashSet<String> = {
+        let mut stmt = conn.prepare("SELECT name FROM src.sqlite_master WHERE type = 'table'")?;
+        let names = stmt.query_map([], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+        names
+    };
+    if !src_tables.contains("files") { bail!("not a samplemap library (no files table)"); }
+    let src_cols: HashSet<String> = {
+        let mut stmt = conn.prepare("PRAGMA src.table_info(files)")?;
+        let names = stmt.query_map([], |r| r.get(1))?.collect::<rusqlite::Result<_>>()?;
+        names
+    };
+    // Older libraries predate some columns; substitute neutral values
+    let col = |name: &str, fallback: &'static str| if src_cols.contains(name) { name.to_string() } else { fallback.to_string() };
+    let sql = format!(
+        "SELECT id, path, name, size_bytes, duration, mtime, {}, {}, {}, {}, {}, {} FROM src.files ORDER BY id",
+        col("favorite", "0"),
+        col("rating", "NULL"),
+        col("content_hash", "NULL"),
+        col("sample_rate", "NULL"),
+        col("bits_per_sample", "NULL"),
+        col("channels", "NULL"),
+    );
+    let src_files: Vec<SrcFile> = {
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt
+            .query_map([], |r| {
+                Ok(SrcFile {
+                    id: r.get(0)?,
+                    path: r.get::<_, StoredPath>(1)?.0,
+                    name: r.get(2)?,
+                    size_bytes: r.get(3)?,
+                    duration: r.get(4)?,
+                    mtime: r.get(5)?,
+                    favorite: r.get(6)?,
+                    rating: r.get(7)?,
+                    content_hash: r.get(8)?,
+                    sample_rate: r.get(9)?,
+                    bits_per_sample: r.get(10)?,
+                    channels: r.get(11)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let has_embeddings = src_tables.contains("embeddings");
+    let emb_cols: HashSet<String> = if has_embeddings {
+        let mut stmt = conn.prepare("PRAGMA src.table_info(embeddings)")?;
+        let names = stmt.query_map([], |r| r.get(1))?.collect::<rusqlite::Result<_>>()?;
+        names
+    } else {
+        HashSet::new()
+    };
+    // Libraries from before per-row dtypes hold f32 only; only the built-in model's vectors are
+    // comparable across libraries
+    let src_dtype = if emb_cols.contains("dtype") { "dtype" } else { "'f32'" };
+    let src_model = if emb_cols.contains("model_id") { "AND model_id = 1" } else { "" };
+    let has_coords = src_tables.contains("coords");
+    let has_tags = src_tables.contains("file_tags");
+
+    let tx = conn.transaction()?;
+    let by_hash = match strategy {
+        MergeStrategy::Hash => local_hashes(&tx)?,
+        MergeStrategy::Path => HashMap::new(),
+    };
+    let mut summary = ImportSummary::default();
+    for f in &src_files {
+        let hash = match strategy {
+            MergeStrategy::Hash => f.content_hash.clone().or_else(|| scan::content_hash(&f.path).ok()),
+            MergeStrategy::Path => f.content_hash.clone(),
+        };
+        let mut local = hash.as_ref().and_then(|h| by_hash.get(h).copied());
+        if local.is_none() {
+            local = tx.query_row("SELECT id FROM main.files WHERE path = ?", params![SqlPath(&f.path)], |r| r.get(0)).optional()?;
+        }
+        let local_id = match local {
+            Some(id) => {
+                tx.execute(
+                    "UPDATE main.files SET favorite = MAX(favorite, ?), rating = COALESCE(rating, ?) WHERE id = ?",
+                    params![f.favorite, f.rating, id],
+                )?;
+                summary.files_matched += 1;
+                id
+            }
+            None => {
+                tx.execute(
+                    "INSERT INTO main.files(path, name, size_bytes, duration, mtime, favorite, rating, content_hash, sample_rate, bits_per_sample, channels) \
+                     VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    params![SqlPath(&f.path), f.name, f.size_bytes, f.duration, f.mtime, f.favorite, f.rating, hash, f.sample_rate, f.bits_per_sample, f.channels],
+                )?;
+                summary.files_added += 1;
+                tx.last_insert_rowid()
+            }
+        };
+        if has_embeddings {
+            summary.embeddings_added += tx.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO main.embeddings(file_id, model_id, dim, vec, dtype) \
+                     SELECT ?, 1, dim, vec, {src_dtype} FROM src.embeddings WHERE file_id = ? {src_model}"
+                ),
+                params![local_id, f.id],
+            )?;
+        }
+        if has_coords {
+            summary.coords_added += tx.execute(
+                "INSERT OR IGNORE INTO main.coords(file_id, x, y) SELECT ?, x, y FROM src.coords WHERE file_id = ?",
+                params![local_id, f.id],
+            )?;
+        }
+        if has_tags {
+            summary.tags_added += tx.execute(
+                "INSERT OR IGNORE INTO main.file_tags(file_id, tag) SELECT ?, tag FROM src.file_tags WHERE file_id = ?",
+                params![local_id, f.id],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+/// content_hash → file id for the local library, hashing (and storing) any files not yet hashed.
+fn local_hashes(conn: &Connection) -> Result<HashMap<String, i64>> {
+    let missing: Vec<(i64, PathBuf)> = {
+        let mut stmt = conn.prepare("SELECT id, path FROM main.files WHERE content_hash IS NULL")?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    for (id, path) in missing {
+        if let Ok(h) = scan::content_hash(&path) {
+            conn.execute("UPDATE main.files SET content_hash = ? WHERE id = ?", params![h, id])?;
+        }
+    }
+    let mut stmt = conn.prepare("SELECT content_hash, id FROM main.files WHERE content_hash IS NOT NULL")?;
+    let map = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<rusqlite::Result<_>>()?;
+    Ok(map)
+}
@@ -0,0 +1,85 @@
+use crate::{
+    db::{SqlPath, StoredPath},
+    scan::now_secs,
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+
+/// The CLAP model every library starts with, computed by the worker or the configured ONNX export.
+pub const BUILTIN: i64 = 1;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    pub id: i64,
+    pub name: String,
+    /// ONNX audio encoder; `None` for the built-in CLAP.
+    pub path: Option<PathBuf>,
+    pub created_at: i64,
+    pub embedding_count: i64,
+    /// Whether this model drives the map and similarity search.
+    pub active: bool,
+}
+
+/// The model `active_embeddings` currently selects.
+pub fn active(conn: &Connection) -> Result<i64> {
+    let v: Option<String> = conn.query_row("SELECT value FROM meta WHERE key = 'active_model'", [], |r| r.get(0)).optional()?;
+    Ok(v.and_then(|v| v.parse().ok()).unwrap_or(BUILTIN))
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<ModelInfo> {
+    list(conn)?.into_iter().find(|m| m.id == id).ok_or_else(|| anyhow::anyhow!("model {id} not found"))
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<ModelInfo>> {
+    let active = active(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.name, m.path, m.created_at, (SELECT COUNT(*) FROM embeddings e WHERE e.model_id = m.id) FROM models m ORDER BY m.id",
+    )?;
+    let rows = stmt
+        .query_map([], |r| {
+            let id: i64 = r.get(0)?;
+            Ok(ModelInfo {
+                id,
+                name: r.get(1)?,
+                path: r.get::<_, Option<StoredPath>>(2)?.map(|p| p.0),
+                created_at: r.get(3)?,
+                embedding_count: r.get(4)?,
+                active: id == active,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Adds an ONNX audio encoder (same input/output contract as the CLAP export) under `name`.
+/// Its embeddings are computed by the next scan while it's active.
+pub fn register(conn: &Connection, name: &str, path: &Path) -> Result<i64> {
+    let name = name.trim();
+    if name.is_empty() { bail!("model name can't be empty"); }
+    if !path.is_file() { bail!("model file not found: {}", path.display()); }
+    let taken = conn.query_row("SELECT 1 FROM models WHERE name = ?", params![name], |_| Ok(())).optional()?.is_some();
+    if taken { bail!("a model named {name:?} already exists"); }
+    conn.execute("INSERT INTO models(name, path, created_at) VALUES(?, ?, ?)", params![name, SqlPath(path), now_secs()])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Switches which model's embeddings the map, clustering and search read. Coords are left as
+/// they are until the next projection.
+pub fn set_active(conn: &Connection, id: i64) -> Result<()> {
+    get(conn, id)?;
+    conn.execute("INSERT OR REPLACE INTO meta(key, value) VALUES('active_model', ?)", params![id.to_string()])?;
+    Ok(())
+}
+
+/// Removes a model and all of its embeddings. The built-in and the active model stay.
+pub fn delete(conn: &mut Connection, id: i64) -> Result<()> {
+    if id == BUILTIN { bail!("the built-in model can't be removed"); }
+    if id == active(conn)? { bail!("model {id} is active; switch to another first"); }
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM embeddings WHERE model_id = ?", params![id])?;
+    if tx.execute("DELETE FROM models WHERE id = ?", params![id])? == 0 { bail!("model {id} not found"); }
+    tx.commit()?;
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Optional OSC remote control, for driving the map from TouchOSC, Max and the like:
+//!
+//! - `/samplemap/play <path | id>` and `/samplemap/stop`
+//! - `/samplemap/similar <id> [k]`, answered to the sender as `/samplemap/similar/result <id>..`
+//!
+//! Every audition, from the UI or OSC, is announced as `/samplemap/now_playing <id> <path>` to
+//! `settings.osc.broadcast` (if set) and to whoever last sent a message.
+
+use crate::{db, settings::OscSettings, similar, AppState};
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use rosc::{OscMessage, OscPacket, OscType};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tauri::{AppHandle, Manager};
+
+#[derive(Default)]
+pub struct OscServer {
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    cfg: OscSettings,
+    socket: Arc<UdpSocket>,
+    stop: Arc<AtomicBool>,
+    /// Sender of the most recent message, which also gets now-playing updates.
+    peer: Arc<Mutex<Option<SocketAddr>>>,
+    listener: thread::JoinHandle<()>,
+}
+
+impl OscServer {
+    /// Starts, stops or rebinds the listener to match `cfg`.
+    pub fn configure(&self, app: &AppHandle, cfg: &OscSettings) -> Result<()> {
+        let mut running = self.running.lock();
+        if let Some(r) = running.as_mut() {
+            if cfg.enabled && r.cfg.bind == cfg.bind && r.cfg.port == cfg.port {
+                // Same address: keep the socket, only the broadcast list can have changed
+                r.cfg = cfg.clone();
+                return Ok(());
+            }
+        }
+        if let Some(old) = running.take() {
+            // The old thread must let go of the port before it's bound again. Unlocked while
+            // waiting, since a message being handled may announce itself through `now_playing`.
+            drop(running);
+            old.stop.store(true, Ordering::SeqCst);
+            let _ = old.listener.join();
+            running = self.running.lock();
+        }
+        if !cfg.enabled { return Ok(()); }
+        let socket = Arc::new(UdpSocket::bind((cfg.bind.as_str(), cfg.port)).with_context(|| format!("bind OSC {}:{}", cfg.bind, cfg.port))?);
+        // Lets the thread notice `stop` without a message arriving
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        let (stop, peer) = (Arc::<AtomicBool>::default(), Arc::<Mutex<Option<SocketAddr>>>::default());
+        let listener = {
+            let (app, socket, stop, peer) = (app.clone(), socket.clone(), stop.clone(), peer.clone());
+            thread::spawn(move || listen(&app, &socket, &stop, &peer))
+        };
+        log::info!("osc: listening on {}:{}", cfg.bind, cfg.port);
+        *running = Some(Running { cfg: cfg.clone(), socket, stop, peer, listener });
+        Ok(())
+    }
+
+    /// Announces an audition; a no-op while the server is off.
+    pub fn now_playing(&self, file_id: Option<i64>, path: &Path) {
+        let running = self.running.lock();
+        let Some(r) = running.as_ref() else { return };
+        let msg = OscMessage {
+            addr: "/samplemap/now_playing".into(),
+            args: vec![OscType::Long(file_id.unwrap_or(-1)), OscType::String(path.to_string_lossy().into_owned())],
+        };
+        let mut targets: Vec<SocketAddr> = r.cfg.broadcast.iter().filter_map(|a| a.parse().ok()).collect();
+        if let Some(p) = *r.peer.lock() { if !targets.contains(&p) { targets.push(p); } }
+        for t in targets { send(&r.socket, t, msg.clone()); }
+    }
+}
+
+fn listen(app: &AppHandle, socket: &UdpSocket, stop: &AtomicBool, peer: &Mutex<Option<SocketAddr>>) {
+    let mut buf = [0u8; rosc::decoder::MTU];
+    while !stop.load(Ordering::SeqCst) {
+        let Ok((n, from)) = socket.recv_from(&mut buf) else { continue };
+        *peer.lock() = Some(from);
+        match rosc::decoder::decode_udp(&buf[..n]) {
+            Ok((_, packet)) => handle(app, socket, from, packet),
+            Err(e) => log::warn!("osc: undecodable packet from {from}: {e:?}"),
+        }
+    }
+}
+
+fn handle(app: &AppHandle, socket: &UdpSocket, from: SocketAddr, packet: OscPacket) {
+    let msg = match packet {
+        OscPacket::Message(m) => m,
+        OscPacket::Bundle(b) => {
+            for p in b.content { handle(app, socket, from, p); }
+            return;
+        }
+    };
+    let res = match msg.addr.as_str() {
+        "/samplemap/play" => match msg.args.first() {
+            // Only library files; a controller on the network shouldn't get to open arbitrary paths
+            Some(OscType::String(p)) => {
+                let id = crate::with_db(app, |conn| db::file_id(conn, Path::new(p)).map_err(|e| e.to_string()));
+                id.and_then(|id| id.ok_or_else(|| format!("{p} is not in the library"))).and_then(|id| crate::audition(app, Some(id), PathBuf::from(p)))
+            }
+            Some(arg) => match id_arg(arg) {
+                Some(id) => {
+                    let path = crate::with_db(app, |conn| db::file_path(conn, id).map_err(|e| e.to_string()));
+                    path.and_then(|p| p.ok_or_else(|| format!("no file with id {id}"))).and_then(|p| crate::audition(app, Some(id), p))
+                }
+                None => Err("expected a path or file id".into()),
+            },
+            None => Err("expected a path or file id".into()),
+        },
+        "/samplemap/stop" => {
+            app.state::<AppState>().audio.stop();
+            Ok(())
+        }
+        "/samplemap/similar" => match msg.args.first().and_then(id_arg) {
+            Some(id) => {
+                let k = msg.args.get(1).and_then(id_arg).map_or(20, |k| k.max(1) as usize);
+                similar_ids(app, id, k).map(|ids| {
+                    let args = ids.into_iter().map(OscType::Long).collect();
+                    send(socket, from, OscMessage { addr: "/samplemap/similar/result".into(), args });
+                })
+            }
+            None => Err("expected a file id".into()),
+        },
+        other => Err(format!("unknown address {other}")),
+    };
+    if let Err(e) = res { log::warn!("osc: {}: {e}", msg.addr); }
+}
+
+fn similar_ids(app: &AppHandle, file_id: i64, k: usize) -> Result<Vec<i64>, String> {
+    let p = db::db_path(app).map_err(|e| e.to_string())?;
+    let ann = &app.state::<AppState>().ann;
+    let hits = crate::with_db(app, |conn| similar::find_similar(conn, ann, &p, file_id, k).map_err(|e| e.to_string()))?;
+    Ok(hits.into_iter().map(|n| n.file_id).collect())
+}
+
+/// Controllers send ids as whichever numeric type they like.
+fn id_arg(arg: &OscType) -> Option<i64> {
+    match *arg {
+        OscType::Int(i) => Some(i64::from(i)),
+        OscType::Long(l) => Some(l),
+        OscType::Float(f) => Some(f as i64),
+        OscType::Double(d) => Some(d as i64),
+        _ => None,
+    }
+}
+
+fn send(socket: &UdpSocket, to: SocketAddr, msg: OscMessage) {
+    match rosc::encoder::encode(&OscPacket::Message(msg)) {
+        Ok(bytes) => { let _ = socket.send_to(&bytes, to); }
+        Err(e) => log::warn!("osc: encode failed: {e:?}"),
+    }
+}
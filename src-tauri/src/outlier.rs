@@ -0,0 +1,72 @@
+use crate::{ann::AnnCache, models, quant, similar::Neighbor};
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use std::{path::Path, thread};
+
+/// Neighbours considered; isolation is the distance to the k-th one, so a pair of near-identical
+/// broken files still stands out from the crowd.
+pub const DEFAULT_K: usize = 10;
+
+/// Stores `embeddings.outlier_score` = cosine distance from each embedding of the active model
+/// to its `k`-th nearest neighbour (via the HNSW index). Returns how many rows were scored.
+pub fn score(conn: &mut Connection, ann: &AnnCache, db: &Path, k: usize) -> Result<usize> {
+    let rows: Vec<(i64, Vec<f32>)> = {
+        let mut stmt = conn.prepare("SELECT file_id, vec, dtype FROM active_embeddings")?;
+        let rows = stmt
+            .query_map([], |r| Ok((r.get(0)?, quant::decode_row(r.get_ref(1)?.as_blob()?, r.get_ref(2)?.as_str()?))))?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let k = k.max(1);
+    let scores: Vec<(i64, Option<f32>)> = ann.with(conn, db, |idx| {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk = rows.len().div_ceil(threads).max(1);
+        thread::scope(|sc| {
+            let handles: Vec<_> = rows
+                .chunks(chunk)
+                .map(|part| {
+                    sc.spawn(move || {
+                        part.iter()
+                            .map(|(id, v)| {
+                                let hits = idx.search(v, k + 1, (4 * k).max(64));
+                                // The file finds itself first; with too few others the score is unknown
+                                let d = hits.iter().filter(|(h, _)| h != id).nth(k - 1).map(|&(_, d)| d);
+                                (*id, d)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap_or_default()).collect()
+        })
+    })?;
+
+    let model = models::active(conn)?;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE embeddings SET outlier_score = ? WHERE file_id = ? AND model_id = ?")?;
+        for (id, d) in &scores { stmt.execute(params![d, id, model])?; }
+    }
+    tx.commit()?;
+    Ok(scores.len())
+}
+
+/// The most isolated samples first; `distance` is their outlier score.
+pub fn list(conn: &Connection, limit: i64, include_hidden: bool) -> Result<Vec<Neighbor>> {
+    let mut stmt = conn.prepare(
+        "SELECT e.file_id, e.outlier_score, c.x, c.y FROM active_embeddings e JOIN files f ON f.id = e.file_id \
+         LEFT JOIN coords c ON c.file_id = e.file_id \
+         WHERE e.outlier_score IS NOT NULL AND (?2 OR f.hidden = 0) ORDER BY e.outlier_score DESC LIMIT ?1",
+    )?;
+    let rows = stmt
+        .query_map(params![limit, include_hidden], |r| {
+            Ok(Neighbor {
+                file_id: r.get(0)?,
+                distance: r.get::<_, f64>(1)? as f32,
+                x: r.get::<_, Option<f64>>(2)?.map(|v| v as f32),
+                y: r.get::<_, Option<f64>>(3)?.map(|v| v as f32),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
@@ -0,0 +1,54 @@
+//! Windows caps ordinary paths at MAX_PATH (260 UTF-16 units) unless they carry the `\\?\`
+//! verbatim prefix, and deeply nested sample packs go past that. The library stores paths
+//! canonical and without the prefix, so they read like Explorer shows them and compare equal
+//! however a folder was typed; [`extended`] adds the prefix back right before the filesystem
+//! is touched. Elsewhere these are no-ops, apart from canonicalizing.
+
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Absolute, symlink-free form for storing and looking up; `p` unchanged if it can't be resolved
+/// (e.g. the file is gone).
+pub fn canonical(p: &Path) -> PathBuf {
+    fs::canonicalize(extended(p)).map(|c| strip_verbatim(&c).into_owned()).unwrap_or_else(|_| p.to_path_buf())
+}
+
+/// `p` in a form the Windows file APIs accept at any length. Left alone when it's relative or
+/// has `.`/`..` components, which verbatim paths don't resolve.
+#[cfg(windows)]
+pub fn extended(p: &Path) -> Cow<'_, Path> {
+    use std::path::Component;
+    let Some(s) = p.to_str() else { return Cow::Borrowed(p) };
+    let dotted = p.components().any(|c| matches!(c, Component::CurDir | Component::ParentDir));
+    if s.starts_with(r"\\?\") || !p.is_absolute() || dotted { return Cow::Borrowed(p); }
+    let s = s.replace('/', "\\");
+    let verbatim = match s.strip_prefix(r"\\") {
+        Some(unc) => format!(r"\\?\UNC\{unc}"),
+        None => format!(r"\\?\{s}"),
+    };
+    Cow::Owned(verbatim.into())
+}
+
+#[cfg(not(windows))]
+pub fn extended(p: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(p)
+}
+
+/// Undoes [`extended`]: `\\?\C:\a` -> `C:\a`, `\\?\UNC\host\share` -> `\\host\share`.
+#[cfg(windows)]
+pub fn strip_verbatim(p: &Path) -> Cow<'_, Path> {
+    let Some(s) = p.to_str() else { return Cow::Borrowed(p) };
+    match (s.strip_prefix(r"\\?\UNC\"), s.strip_prefix(r"\\?\")) {
+        (Some(unc), _) => Cow::Owned(format!(r"\\{unc}").into()),
+        (None, Some(local)) => Cow::Owned(local.into()),
+        (None, None) => Cow::Borrowed(p),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn strip_verbatim(p: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(p)
+}
@@ -1,59 +1,235 @@
 use anyhow::{bail, Context, Result};
 use rodio::{buffer::SamplesBuffer, Decoder, OutputStream, Sink, Source};
-use std::{fs::File, io::BufReader, path::PathBuf, sync::mpsc, thread};
+use parking_lot::Mutex;
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}, sync::{mpsc, Arc}, thread, time::Duration};
 use hound::{SampleFormat, WavReader};
 use symphonia::core::{audio::SampleBuffer, codecs::DecoderOptions, formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint};
 use symphonia::default::get_probe;
+use crate::settings::AudioSettings;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use tauri::Emitter;
+
+/// Emitted with a [`DeviceChanged`] whenever playback moves to another output.
+pub const DEVICE_CHANGED: &str = "audio://device_changed";
+/// How often the playback thread checks whether the system default output has changed.
+const DEVICE_POLL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceChanged {
+    /// `None` while no output could be opened.
+    pub device: Option<String>,
+}
 
 pub enum Msg {
     Play(PathBuf),
     Stop,
+    Configure(AudioSettings),
+    /// Where to send [`DEVICE_CHANGED`]; the app handle exists only after setup.
+    Attach(tauri::AppHandle),
 }
 
 #[derive(Clone)]
 pub struct AudioHandle {
     tx: mpsc::Sender<Msg>,
+    /// Most recently played file, for `replay`.
+    last: Arc<Mutex<Option<PathBuf>>>,
+    /// Name of the output playback currently goes to.
+    device: Arc<Mutex<Option<String>>>,
 }
 
 impl AudioHandle {
     pub fn new() -> Result<Self> {
         let (tx, rx) = mpsc::channel::<Msg>();
+        let device: Arc<Mutex<Option<String>>> = Default::default();
+        let active = device.clone();
         thread::spawn(move || {
             // This thread owns the non-Send audio objects.
-            let (_stream, handle) = match OutputStream::try_default() {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("audio: failed to open output: {e}");
-                    return;
+            let mut config = AudioSettings::default();
+            let mut app: Option<tauri::AppHandle> = None;
+            let mut output: Option<Output> = None;
+            let mut sink: Option<Sink> = None;
+            // Opens whichever output should be in use if it isn't already: the configured device
+            // while it's plugged in, otherwise the system default. A stream whose device went away
+            // (unplugged, dock or headphones switched) gets replaced the same way, once the poll
+            // notices or a sink can't be made on it.
+            let reconcile = |config: &AudioSettings, output: &mut Option<Output>, sink: &mut Option<Sink>, app: &Option<tauri::AppHandle>| {
+                let want = wanted_output(config.output_device.as_deref());
+                if output.as_ref().map(|o| &o.name) == want.as_ref() { return; }
+                if let Some(s) = sink.take() { s.stop(); }
+                *output = None;
+                match open_output(want.as_deref()) {
+                    Ok(o) => *output = Some(o),
+                    Err(e) => log::warn!("audio: failed to open output: {e:#}"),
+                }
+                let name = output.as_ref().map(|o| o.name.clone());
+                *active.lock() = name.clone();
+                if let Some(app) = app {
+                    if let Err(e) = app.emit(DEVICE_CHANGED, DeviceChanged { device: name }) { log::warn!("audio: {DEVICE_CHANGED}: {e}"); }
                 }
             };
-            let mut sink: Option<Sink> = None;
-            while let Ok(msg) = rx.recv() {
+            reconcile(&config, &mut output, &mut sink, &app);
+            let mut default = default_output_name();
+            loop {
+                let msg = match rx.recv_timeout(DEVICE_POLL) {
+                    Ok(m) => m,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        // Only the default's name is polled; listing every device is slow on some hosts,
+                        // so that's left to while a configured non-default device is in use, which can
+                        // be unplugged without the default changing
+                        let now = default_output_name();
+                        let lost = output.as_ref().is_some_and(|o| Some(&o.name) != now.as_ref() && !is_listed(&o.name));
+                        if lost { output = None; }
+                        if lost || now != default {
+                            default = now;
+                            reconcile(&config, &mut output, &mut sink, &app);
+                        }
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
                 match msg {
                     Msg::Stop => {
                         if let Some(s) = sink.take() { s.stop(); }
                     }
                     Msg::Play(path) => {
                         if let Some(s) = sink.take() { s.stop(); }
-                        match decode_wav_to_source(&path) {
-                            Ok(source) => match Sink::try_new(&handle) {
-                                Ok(s) => { s.append(source); s.play(); sink = Some(s); }
-                                Err(e) => eprintln!("audio: sink error: {e}"),
-                            },
-                            Err(e) => eprintln!("audio: wav decode error for {}: {e}", path.display()),
+                        let source = match decode_wav_to_source(&path) {
+                            Ok(s) => s,
+                            Err(e) => {
+                                log::warn!("audio: wav decode error for {}: {e}", path.display());
+                                continue;
+                            }
+                        };
+                        // On the fallback output, see whether the configured device is back
+                        if config.output_device.is_some() && output.as_ref().map(|o| &o.name) != config.output_device.as_ref() {
+                            reconcile(&config, &mut output, &mut sink, &app);
+                        }
+                        // A failing sink usually means the device vanished since the last check
+                        let mut s = output.as_ref().map(|o| Sink::try_new(&o.handle));
+                        if !matches!(s, Some(Ok(_))) {
+                            output = None;
+                            reconcile(&config, &mut output, &mut sink, &app);
+                            s = output.as_ref().map(|o| Sink::try_new(&o.handle));
                         }
+                        match s {
+                            Some(Ok(s)) => { s.set_volume(config.preview_gain); s.append(source); s.play(); sink = Some(s); }
+                            Some(Err(e)) => log::warn!("audio: sink error: {e}"),
+                            None => log::warn!("audio: no output device"),
+                        }
+                    }
+                    Msg::Configure(next) => {
+                        if let Some(s) = &sink { s.set_volume(next.preview_gain); }
+                        config = next;
+                        reconcile(&config, &mut output, &mut sink, &app);
+                    }
+                    Msg::Attach(handle) => {
+                        // Report the device opened before the app was up
+                        let device = output.as_ref().map(|o| o.name.clone());
+                        if let Err(e) = handle.emit(DEVICE_CHANGED, DeviceChanged { device }) { log::warn!("audio: {DEVICE_CHANGED}: {e}"); }
+                        app = Some(handle);
                     }
                 }
             }
         });
-        Ok(Self { tx })
+        Ok(Self { tx, last: Default::default(), device })
+    }
+
+    pub fn play_path(&self, path: PathBuf) -> Result<()> {
+        *self.last.lock() = Some(path.clone());
+        self.tx.send(Msg::Play(path)).context("send play")
+    }
+
+    /// Plays the last file again; does nothing if nothing has played yet.
+    pub fn replay(&self) -> Result<()> {
+        let last = self.last.lock().clone();
+        match last {
+            Some(p) => self.play_path(p),
+            None => Ok(()),
+        }
     }
 
-    pub fn play_path(&self, path: PathBuf) -> Result<()> { self.tx.send(Msg::Play(path)).context("send play") }
     pub fn stop(&self) { let _ = self.tx.send(Msg::Stop); }
+    pub fn attach(&self, app: tauri::AppHandle) { let _ = self.tx.send(Msg::Attach(app)); }
+    /// The output in use, `None` if none could be opened.
+    pub fn device(&self) -> Option<String> { self.device.lock().clone() }
+    pub fn configure(&self, settings: AudioSettings) {
+        *FFMPEG.lock() = settings.ffmpeg.clone();
+        let _ = self.tx.send(Msg::Configure(settings));
+    }
+}
+
+/// Names of the output devices on the default host, for `AudioSettings::output_device`.
+pub fn output_devices() -> Vec<String> {
+    let host = rodio::cpal::default_host();
+    host.output_devices().map(|ds| ds.filter_map(|d| d.name().ok()).collect()).unwrap_or_default()
+}
+
+/// An open output stream; dropping it closes the device.
+struct Output {
+    _stream: OutputStream,
+    handle: rodio::OutputStreamHandle,
+    name: String,
+}
+
+/// Name of the output that should be open: `configured` while it's listed, else the default.
+fn wanted_output(configured: Option<&str>) -> Option<String> {
+    if let Some(name) = configured {
+        if is_listed(name) { return Some(name.to_string()); }
+    }
+    default_output_name()
+}
+
+fn is_listed(name: &str) -> bool {
+    rodio::cpal::default_host().output_devices().is_ok_and(|mut ds| ds.any(|d| d.name().is_ok_and(|n| n == name)))
+}
+
+fn default_output_name() -> Option<String> {
+    rodio::cpal::default_host().default_output_device().and_then(|d| d.name().ok())
+}
+
+/// The named device, or the default one for `None`.
+fn open_output(name: Option<&str>) -> Result<Output> {
+    let host = rodio::cpal::default_host();
+    let device = match name {
+        None => host.default_output_device().context("no default output device")?,
+        Some(name) => host
+            .output_devices()
+            .context("list output devices")?
+            .find(|d| d.name().is_ok_and(|n| n == name))
+            .with_context(|| format!("no output device named {name:?}"))?,
+    };
+    let name = device.name().unwrap_or_default();
+    let (stream, handle) = OutputStream::try_from_device(&device).with_context(|| format!("open {name}"))?;
+    Ok(Output { _stream: stream, handle, name })
+}
+
+/// Interleaved f32 samples with (channels, sample rate), through the same decoder chain as playback.
+pub fn decode_samples(path: &Path) -> Result<(u16, u32, Vec<f32>)> {
+    let buf = decode_wav_to_source(&path.to_path_buf())?;
+    let (channels, rate) = (buf.channels(), buf.sample_rate());
+    Ok((channels, rate, buf.collect()))
+}
+
+/// ffmpeg for files none of the built-in decoders take; set from `AudioSettings::ffmpeg`.
+static FFMPEG: Mutex<Option<PathBuf>> = parking_lot::const_mutex(None);
+
+/// The configured ffmpeg, or one on PATH.
+fn ffmpeg() -> Option<PathBuf> {
+    if let Some(p) = FFMPEG.lock().clone() { return Some(p); }
+    let exe = if cfg!(windows) { "ffmpeg.exe" } else { "ffmpeg" };
+    std::env::var_os("PATH").and_then(|paths| std::env::split_paths(&paths).map(|d| d.join(exe)).find(|p| p.is_file()))
 }
 
 fn decode_wav_to_source(path: &PathBuf) -> Result<SamplesBuffer<f32>> {
+    let path = &crate::paths::extended(path).into_owned();
+    decode_native(path).or_else(|e| match ffmpeg() {
+        Some(ff) => decode_via_ffmpeg(&ff, path).with_context(|| format!("{e:#}; ffmpeg also failed")),
+        None => Err(e),
+    })
+}
+
+fn decode_native(path: &PathBuf) -> Result<SamplesBuffer<f32>> {
     // Try fast path (hound). If open fails, fall back to symphonia, then rodio.
     let mut reader = match WavReader::open(path) {
         Ok(r) => r,
@@ -163,6 +339,30 @@ fn decode_via_symphonia(path: &PathBuf) -> Result<SamplesBuffer<f32>> {
     Ok(SamplesBuffer::new(spec.channels.count() as u16, spec.rate, out))
 }
 
+/// Has ffmpeg write float PCM to a temporary WAV (a pipe would leave the header's sizes unset),
+/// then reads that back.
+fn decode_via_ffmpeg(ffmpeg: &Path, path: &Path) -> Result<SamplesBuffer<f32>> {
+    let tmp = std::env::temp_dir().join(format!("samplemap-{}.wav", uuid::Uuid::new_v4()));
+    let out = std::process::Command::new(ffmpeg)
+        .args(["-v", "error", "-nostdin", "-y", "-i"])
+        .arg(path)
+        .args(["-vn", "-acodec", "pcm_f32le"])
+        .arg(&tmp)
+        .output()
+        .with_context(|| format!("run {}", ffmpeg.display()))?;
+    let res = if out.status.success() {
+        WavReader::open(&tmp).context("read ffmpeg output").and_then(|mut r| {
+            let spec = r.spec();
+            let data = r.samples::<f32>().collect::<Result<Vec<_>, _>>()?;
+            Ok(SamplesBuffer::new(spec.channels, spec.sample_rate, data))
+        })
+    } else {
+        Err(anyhow::anyhow!("ffmpeg: {}", String::from_utf8_lossy(&out.stderr).trim()))
+    };
+    let _ = std::fs::remove_file(&tmp);
+    res
+}
+
 fn decode_via_rodio(path: &PathBuf) -> Result<SamplesBuffer<f32>> {
     // As a last resort, let rodio decode and collect to f32 buffer.
     let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
@@ -0,0 +1,160 @@
+//! Native PCA projection, used when the Python worker can't run. Coarser than UMAP (it only
+//! keeps the directions of largest variance) but needs nothing beyond the embeddings.
+
+use crate::{
+    layouts,
+    quant,
+    settings::{ProjectionMethod, ProjectionSettings},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+/// Cap per component; iteration stops earlier once the direction settles.
+const POWER_ITERATIONS: usize = 50;
+
+/// Projects the active model's embeddings onto their top principal components into a new layout,
+/// fitted onto any pins the same way the worker does, and makes it the active one. Returns the
+/// number of points placed.
+pub fn run(conn: &mut Connection, proj: &ProjectionSettings) -> Result<usize> {
+    let (ids, data, dim) = {
+        let mut stmt = conn.prepare("SELECT file_id, vec, dtype FROM active_embeddings ORDER BY file_id")?;
+        let mut rows = stmt.query([])?;
+        let (mut ids, mut data, mut dim) = (Vec::new(), Vec::new(), 0);
+        while let Some(r) = rows.next()? {
+            let v = quant::decode_row(r.get_ref(1)?.as_blob()?, r.get_ref(2)?.as_str()?);
+            if ids.is_empty() { dim = v.len(); }
+            if v.len() != dim || dim == 0 { continue; }
+            ids.push(r.get::<_, i64>(0)?);
+            data.extend(v.iter().map(|&x| x as f64));
+        }
+        (ids, data, dim)
+    };
+    if ids.is_empty() { bail!("no embeddings to project"); }
+    let k = (proj.components as usize).clamp(2, 3);
+    let mut ys = pca(data, dim, k, proj.seed);
+    normalize(&mut ys, k);
+    let pins = load_pins(conn)?;
+    apply_pins(&mut ys, k, &ids, &pins);
+
+    let record = ProjectionSettings { method: ProjectionMethod::Pca, components: k as u8, ..proj.clone() };
+    let tx = conn.transaction()?;
+    let layout = layouts::create(&tx, &record)?;
+    {
+        let mut stored = tx.prepare("INSERT INTO layout_coords(layout_id, file_id, x, y, z) VALUES(?, ?, ?, ?, ?)")?;
+        let mut active = tx.prepare("INSERT INTO coords(file_id, x, y, z, layout_id) VALUES(?, ?, ?, ?, ?)")?;
+        tx.execute("DELETE FROM coords", [])?;
+        for (id, p) in ids.iter().zip(ys.chunks(k)) {
+            let z = p.get(2).copied();
+            stored.execute(params![layout, id, p[0], p[1], z])?;
+            active.execute(params![id, p[0], p[1], z, layout])?;
+        }
+    }
+    tx.commit()?;
+    Ok(ids.len())
+}
+
+/// Top-`k` principal component scores of `data` (row-major, `dim` wide), by power iteration
+/// with deflation. Centers in place and never forms the covariance matrix, so memory stays at
+/// the data itself.
+fn pca(mut centered: Vec<f64>, dim: usize, k: usize, seed: u64) -> Vec<f64> {
+    let n = centered.len() / dim;
+    let mut mean = vec![0f64; dim];
+    for row in centered.chunks(dim) { mean.iter_mut().zip(row).for_each(|(m, x)| *m += x); }
+    mean.iter_mut().for_each(|m| *m /= n as f64);
+    for row in centered.chunks_mut(dim) { row.iter_mut().zip(&mean).for_each(|(x, m)| *x -= m); }
+
+    let mut state = seed | 1;
+    let mut components: Vec<Vec<f64>> = Vec::with_capacity(k);
+    for _ in 0..k {
+        // Deterministic start so the same seed gives the same map
+        let mut v: Vec<f64> = (0..dim)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+            })
+            .collect();
+        for _ in 0..POWER_ITERATIONS {
+            // v ← Xᵀ(X v), kept orthogonal to the components already found
+            let xv: Vec<f64> = centered.chunks(dim).map(|row| dot(row, &v)).collect();
+            let mut next = vec![0f64; dim];
+            for (row, s) in centered.chunks(dim).zip(&xv) { next.iter_mut().zip(row).for_each(|(a, x)| *a += x * s); }
+            for c in &components {
+                let d = dot(&next, c);
+                next.iter_mut().zip(c).for_each(|(a, x)| *a -= d * x);
+            }
+            let norm = dot(&next, &next).sqrt();
+            if norm < 1e-12 { break; }
+            next.iter_mut().for_each(|a| *a /= norm);
+            let settled = dot(&next, &v).abs() > 1.0 - 1e-9;
+            v = next;
+            if settled { break; }
+        }
+        components.push(v);
+    }
+    centered.chunks(dim).flat_map(|row| components.iter().map(|c| dot(row, c)).collect::<Vec<_>>()).collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Scales each axis to [-1, 1], as the worker does.
+fn normalize(ys: &mut [f64], k: usize) {
+    for axis in 0..k {
+        let (lo, hi) = ys.iter().skip(axis).step_by(k).fold((f64::MAX, f64::MIN), |(lo, hi), &y| (lo.min(y), hi.max(y)));
+        let range = (hi - lo).max(1e-6);
+        ys.iter_mut().skip(axis).step_by(k).for_each(|y| *y = (*y - lo) / range * 2.0 - 1.0);
+    }
+}
+
+fn load_pins(conn: &Connection) -> Result<HashMap<i64, (f64, f64)>> {
+    let mut stmt = conn.prepare("SELECT file_id, x, y FROM pins")?;
+    let pins = stmt.query_map([], |r| Ok((r.get(0)?, (r.get(1)?, r.get(2)?))))?.collect::<rusqlite::Result<_>>()?;
+    Ok(pins)
+}
+
+/// 2D counterpart of the worker's `apply_pins`: the best similarity transform (rotation, optional
+/// reflection, uniform scale, translation) from the pinned files' new positions onto their pins
+/// moves every point, then pinned files sit exactly on their pins.
+fn apply_pins(ys: &mut [f64], k: usize, ids: &[i64], pins: &HashMap<i64, (f64, f64)>) {
+    let idx: Vec<usize> = (0..ids.len()).filter(|&i| pins.contains_key(&ids[i])).collect();
+    if idx.is_empty() { return; }
+    let mean = |pts: &mut dyn Iterator<Item = (f64, f64)>| {
+        let (sx, sy, n) = pts.fold((0.0, 0.0, 0.0), |(sx, sy, n), (x, y)| (sx + x, sy + y, n + 1.0));
+        (sx / n, sy / n)
+    };
+    let src = mean(&mut idx.iter().map(|&i| (ys[i * k], ys[i * k + 1])));
+    let dst = mean(&mut idx.iter().map(|&i| pins[&ids[i]]));
+    // Σ p·q and Σ p×q for centred pairs give the optimal rotation; repeat with p's y flipped for
+    // the reflected fit and keep whichever matches better
+    let (mut a, mut b, mut ar, mut br, mut spread) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for &i in &idx {
+        let (px, py) = (ys[i * k] - src.0, ys[i * k + 1] - src.1);
+        let (qx, qy) = (pins[&ids[i]].0 - dst.0, pins[&ids[i]].1 - dst.1);
+        a += px * qx + py * qy;
+        b += px * qy - py * qx;
+        ar += px * qx - py * qy;
+        br += px * qy + py * qx;
+        spread += px * px + py * py;
+    }
+    let (flip, a, b) = if ar.hypot(br) > a.hypot(b) { (-1.0, ar, br) } else { (1.0, a, b) };
+    let h = a.hypot(b);
+    let (scale, cos, sin) = if idx.len() >= 2 && spread > 1e-12 && h > 1e-12 {
+        (h / spread, a / h, b / h)
+    } else {
+        (1.0, 1.0, 0.0)
+    };
+    for p in ys.chunks_mut(k) {
+        let (px, py) = (p[0] - src.0, (p[1] - src.1) * flip);
+        p[0] = scale * (cos * px - sin * py) + dst.0;
+        p[1] = scale * (sin * px + cos * py) + dst.1;
+    }
+    for &i in &idx {
+        let (x, y) = pins[&ids[i]];
+        ys[i * k] = x;
+        ys[i * k + 1] = y;
+    }
+}
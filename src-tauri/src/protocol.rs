@@ -0,0 +1,149 @@
+//! The `sample://` scheme, so the webview can load library audio into `<audio>` or WebAudio
+//! directly: `sample://localhost/<file_id>` (on Windows `http://sample.localhost/<file_id>`)
+//! serves the file as stored, and `?decoded=1` a 16-bit WAV of it through the playback decoder
+//! chain, for formats the webview can't play. Both honour `Range` requests, for scrubbing.
+
+use crate::{db, playback};
+use parking_lot::Mutex;
+use std::{
+    borrow::Cow,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+use tauri::{
+    http::{header, Request, Response, StatusCode},
+    AppHandle,
+};
+
+pub const SCHEME: &str = "sample";
+
+/// Most bytes of a file served per `Range` request; longer and open-ended ranges (`<audio>`
+/// starts with `bytes=0-`) get this much and the player asks for the rest, so playing a long
+/// file doesn't read it into memory whole. Requests without `Range` (`fetch` for WebAudio)
+/// still get the whole file, which they couldn't complete from a 206.
+const MAX_CHUNK: u64 = 1 << 20;
+
+/// The last decoded WAV, by file id and modification time: a player scrubbing one file asks
+/// for many ranges of it, and each would otherwise decode the whole file again.
+static DECODED: Mutex<Option<(i64, Option<SystemTime>, Arc<Vec<u8>>)>> = parking_lot::const_mutex(None);
+
+pub fn handle(app: &AppHandle, req: &Request<Vec<u8>>) -> Response<Cow<'static, [u8]>> {
+    let uri = req.uri();
+    // The id is the last path segment, or the host for `sample://<id>`
+    let segment = uri.path().trim_matches('/').rsplit('/').next().filter(|s| !s.is_empty()).or(uri.host());
+    let Some(id) = segment.and_then(|s| s.parse::<i64>().ok()) else { return error(StatusCode::BAD_REQUEST, "expected sample://localhost/<file id>") };
+    let path = match crate::with_db(app, |conn| db::file_path(conn, id).map_err(|e| e.to_string())) {
+        Ok(Some(p)) => p,
+        Ok(None) => return error(StatusCode::NOT_FOUND, &format!("no file with id {id}")),
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+    };
+    let range = req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+    let decoded = uri.query().is_some_and(|q| q.split('&').any(|kv| kv == "decoded=1" || kv == "decoded=true"));
+    let res = if decoded { serve_decoded(id, &path, range.as_deref()) } else { serve_raw(&path, range.as_deref()) };
+    res.unwrap_or_else(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}: {e:#}", path.display())))
+}
+
+fn serve_raw(path: &Path, range: Option<&str>) -> anyhow::Result<Response<Cow<'static, [u8]>>> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let Some((start, end)) = byte_range(range, len) else { return Ok(unsatisfiable(len)) };
+    let end = if range.is_some() { end.min(start + MAX_CHUNK - 1) } else { end };
+    file.seek(SeekFrom::Start(start))?;
+    let mut body = Vec::with_capacity((end - start + 1) as usize);
+    file.take(end - start + 1).read_to_end(&mut body)?;
+    Ok(respond(body, start, end, len, range.is_some(), mime(path)))
+}
+
+fn serve_decoded(file_id: i64, path: &Path, range: Option<&str>) -> anyhow::Result<Response<Cow<'static, [u8]>>> {
+    let wav = decoded_wav(file_id, path)?;
+    let len = wav.len() as u64;
+    let Some((start, end)) = byte_range(range, len) else { return Ok(unsatisfiable(len)) };
+    let body = wav[start as usize..=end as usize].to_vec();
+    Ok(respond(body, start, end, len, range.is_some(), "audio/wav"))
+}
+
+/// Inclusive byte bounds for a `bytes=a-b`, `bytes=a-` or `bytes=-n` header, the whole file
+/// without one; `None` if it can't be satisfied. Only the first range of a list is served.
+pub fn byte_range(header: Option<&str>, len: u64) -> Option<(u64, u64)> {
+    if len == 0 { return header.is_none().then_some((0, 0)); }
+    let Some(spec) = header.and_then(|h| h.strip_prefix("bytes=")) else { return Some((0, len - 1)) };
+    let (a, b) = spec.split(',').next()?.trim().split_once('-')?;
+    let (start, end) = match (a.parse::<u64>().ok(), b.parse::<u64>().ok()) {
+        (Some(s), Some(e)) => (s, e.min(len - 1)),
+        (Some(s), None) => (s, len - 1),
+        (None, Some(n)) => (len.saturating_sub(n), len - 1),
+        (None, None) => return None,
+    };
+    (start <= end && start < len).then_some((start, end))
+}
+
+fn respond(body: Vec<u8>, start: u64, end: u64, len: u64, partial: bool, mime: &str) -> Response<Cow<'static, [u8]>> {
+    let mut res = Response::builder()
+        .header(header::CONTENT_TYPE, mime)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, body.len());
+    if partial {
+        res = res.status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+    }
+    res.body(Cow::Owned(body)).unwrap_or_else(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "bad response"))
+}
+
+fn decoded_wav(file_id: i64, path: &Path) -> anyhow::Result<Arc<Vec<u8>>> {
+    let mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    if let Some((id, t, wav)) = DECODED.lock().as_ref() {
+        if *id == file_id && *t == mtime { return Ok(wav.clone()); }
+    }
+    // Decoded unlocked, so one slow file doesn't hold up requests for another
+    let (channels, rate, data) = playback::decode_samples(path)?;
+    let wav = Arc::new(pcm16_wav(channels, rate, &data));
+    *DECODED.lock() = Some((file_id, mtime, wav.clone()));
+    Ok(wav)
+}
+
+fn unsatisfiable(len: u64) -> Response<Cow<'static, [u8]>> {
+    Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+        .body(Cow::Borrowed(&[][..]))
+        .unwrap_or_else(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "bad response"))
+}
+
+fn error(status: StatusCode, msg: &str) -> Response<Cow<'static, [u8]>> {
+    let mut res = Response::new(Cow::Owned(msg.as_bytes().to_vec()));
+    *res.status_mut() = status;
+    res
+}
+
+fn mime(path: &Path) -> &'static str {
+    match path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).as_deref() {
+        Some("wav") => "audio/wav",
+        Some("flac") => "audio/flac",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("aif" | "aiff") => "audio/aiff",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A complete 16-bit PCM WAV file in memory.
+fn pcm16_wav(channels: u16, rate: u32, data: &[f32]) -> Vec<u8> {
+    let bytes = (data.len() * 2) as u32;
+    let block = channels * 2;
+    let mut out = Vec::with_capacity(44 + bytes as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + bytes).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&rate.to_le_bytes());
+    out.extend_from_slice(&(rate * u32::from(block)).to_le_bytes());
+    out.extend_from_slice(&block.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&bytes.to_le_bytes());
+    for v in data { out.extend_from_slice(&((v.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_le_bytes()); }
+    out
+}
@@ -0,0 +1,301 @@
+//! The worker's Python environment: a dedicated venv under the data dir (the one worker.py's
+//! `venv_python()` looks for) with the worker's requirements installed. Setting it up from here
+//! means a missing launcher or package shows up as a clear error instead of a worker traceback.
+//! An interpreter configured by the user (a conda env, a portable install) replaces the managed
+//! venv entirely.
+
+use crate::{
+    db,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// What worker.py imports; keep in step with its `ensure_venv_and_deps`.
+pub const REQUIREMENTS: &[&str] = &["numpy", "soundfile", "librosa", "umap-learn", "torch", "torchvision", "laion-clap"];
+/// Oldest interpreter the requirements still ship wheels for.
+const MIN_VERSION: (u32, u32) = (3, 9);
+/// Written into the venv once every requirement installed; a different list means reinstall.
+const MARKER: &str = ".samplemap-requirements";
+
+/// Overrides `settings.worker.python` when set.
+const PYTHON_ENV: &str = "SMAP_PYTHON";
+
+/// Serialises setup so parallel jobs don't run pip over the same venv.
+static SETUP: Mutex<()> = parking_lot::const_mutex(());
+
+/// The configured interpreter or environment last validated, and the executable it resolved to,
+/// so worker calls don't start a Python process to check the version every batch.
+static VALIDATED: Mutex<Option<(PathBuf, PathBuf)>> = parking_lot::const_mutex(None);
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvStatus {
+    /// User-chosen interpreter in use instead of the managed venv.
+    pub custom: Option<PathBuf>,
+    /// What the worker runs with, once there is one.
+    pub interpreter: Option<PathBuf>,
+    /// Interpreter the venv is (or would be) created from, e.g. "py -3".
+    pub base_python: Option<String>,
+    pub base_version: Option<String>,
+    pub venv: PathBuf,
+    pub venv_exists: bool,
+    /// Venv present with the current requirements installed, or the custom interpreter usable.
+    pub ready: bool,
+    pub error: Option<String>,
+}
+
+struct BasePython {
+    program: &'static str,
+    args: &'static [&'static str],
+    version: (u32, u32),
+}
+
+impl BasePython {
+    fn label(&self) -> String {
+        std::iter::once(self.program).chain(self.args.iter().copied()).collect::<Vec<_>>().join(" ")
+    }
+}
+
+pub fn venv_dir(app: &tauri::AppHandle) -> Result<PathBuf> {
+    Ok(db::data_dir(app)?.join("venv"))
+}
+
+fn venv_python(venv: &Path) -> PathBuf {
+    if cfg!(target_os = "windows") { venv.join("Scripts").join("python.exe") } else { venv.join("bin").join("python") }
+}
+
+/// The interpreter from `SMAP_PYTHON` or the settings, before validation.
+fn configured(app: &tauri::AppHandle) -> Option<PathBuf> {
+    std::env::var_os(PYTHON_ENV).filter(|v| !v.is_empty()).map(PathBuf::from).or_else(|| crate::settings::load(app).worker.python)
+}
+
+/// Accepts an interpreter, or an environment directory (venv or conda) holding one, as long as
+/// it runs and is new enough. Returns the executable and its version.
+pub fn validate(path: &Path) -> Result<(PathBuf, (u32, u32))> {
+    let exe = if path.is_dir() {
+        let candidates = [venv_python(path), path.join("python.exe"), path.join("bin").join("python3")];
+        match candidates.into_iter().find(|c| c.is_file()) {
+            Some(c) => c,
+            None => bail!("no Python interpreter in {}", path.display()),
+        }
+    } else if path.is_file() {
+        path.to_path_buf()
+    } else {
+        bail!("{} doesn't exist", path.display());
+    };
+    let Some(version) = probe_version(Command::new(&exe)) else { bail!("{} didn't run as Python", exe.display()) };
+    if version < MIN_VERSION {
+        bail!("{} is Python {}.{}; the worker needs {}.{} or newer", exe.display(), version.0, version.1, MIN_VERSION.0, MIN_VERSION.1);
+    }
+    Ok((exe, version))
+}
+
+/// The interpreter the worker would run with right now, if any is ready.
+fn interpreter(app: &tauri::AppHandle) -> Option<PathBuf> {
+    match configured(app) {
+        Some(p) => validate(&p).ok().map(|(exe, _)| exe),
+        None => venv_dir(app).ok().filter(|v| installed(v)).map(|v| venv_python(&v)),
+    }
+}
+
+fn probe_version(mut cmd: Command) -> Option<(u32, u32)> {
+    let out = cmd.args(["-c", "import sys; print('%d.%d' % sys.version_info[:2])"]).output().ok()?;
+    if !out.status.success() { return None; }
+    let text = String::from_utf8_lossy(&out.stdout);
+    let (major, minor) = text.trim().split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn installed(venv: &Path) -> bool {
+    venv_python(venv).exists() && std::fs::read_to_string(venv.join(MARKER)).is_ok_and(|m| m == REQUIREMENTS.join("\n"))
+}
+
+/// First interpreter on PATH new enough for the worker. Windows prefers the `py` launcher; a bare
+/// `python` there may be the Store stub, which fails the version probe and is skipped.
+fn find_base() -> Result<BasePython> {
+    let candidates: &[(&'static str, &'static [&'static str])] =
+        if cfg!(target_os = "windows") { &[("py", &["-3"]), ("python", &[]), ("python3", &[])] } else { &[("python3", &[]), ("python", &[])] };
+    let mut too_old = None;
+    for &(program, args) in candidates {
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        let Some(version) = probe_version(cmd) else { continue };
+        let base = BasePython { program, args, version };
+        if base.version >= MIN_VERSION { return Ok(base); }
+        too_old.get_or_insert(base);
+    }
+    match too_old {
+        Some(b) => bail!(
+            "{} is Python {}.{}; the worker needs {}.{} or newer",
+            b.label(),
+            b.version.0,
+            b.version.1,
+            MIN_VERSION.0,
+            MIN_VERSION.1
+        ),
+        None if cfg!(target_os = "windows") => bail!("no Python found (tried py -3, python, python3); install Python 3 from python.org with the py launcher"),
+        None => bail!("no Python found (tried python3, python); install Python {}.{} or newer", MIN_VERSION.0, MIN_VERSION.1),
+    }
+}
+
+pub fn status(app: &tauri::AppHandle) -> Result<EnvStatus> {
+    let venv = venv_dir(app)?;
+    let venv_exists = venv_python(&venv).exists();
+    if let Some(custom) = configured(app) {
+        let (interpreter, base_version, error) = match validate(&custom) {
+            Ok((exe, v)) => (Some(exe), Some(format!("{}.{}", v.0, v.1)), None),
+            Err(e) => (None, None, Some(e.to_string())),
+        };
+        let base_python = interpreter.as_ref().map(|p| p.display().to_string());
+        return Ok(EnvStatus { ready: interpreter.is_some(), custom: Some(custom), interpreter, base_python, base_version, venv, venv_exists, error });
+    }
+    let (base_python, base_version, error) = match find_base() {
+        Ok(b) => (Some(b.label()), Some(format!("{}.{}", b.version.0, b.version.1)), None),
+        Err(e) => (None, None, Some(e.to_string())),
+    };
+    let ready = installed(&venv);
+    let interpreter = ready.then(|| venv_python(&venv));
+    Ok(EnvStatus { custom: None, interpreter, base_python, base_version, venv_exists, ready, venv, error })
+}
+
+/// Makes sure the venv exists with every requirement installed and returns its interpreter.
+/// Installs one package at a time so `on_progress` can count them; `force` reinstalls anyway.
+/// A custom interpreter is only validated, and gets the packages installed into it on `force`.
+pub fn ensure(app: &tauri::AppHandle, force: bool, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<PathBuf> {
+    let _guard = SETUP.lock();
+    if let Some(custom) = configured(app) {
+        let cached = VALIDATED.lock().as_ref().filter(|(c, exe)| c == &custom && exe.is_file()).map(|(_, exe)| exe.clone());
+        let python = match cached {
+            Some(exe) if !force => return Ok(exe),
+            Some(exe) => exe,
+            None => validate(&custom).with_context(|| format!("configured Python ({PYTHON_ENV} or settings.worker.python)"))?.0,
+        };
+        *VALIDATED.lock() = Some((custom, python.clone()));
+        if force { install(&python, on_progress, cancel)?; }
+        return Ok(python);
+    }
+    let venv = venv_dir(app)?;
+    let python = venv_python(&venv);
+    if !force && installed(&venv) { return Ok(python); }
+
+    on_progress(&Progress { stage: "installing".into(), processed: 0, total: REQUIREMENTS.len(), batch: None });
+    if !python.exists() {
+        let base = find_base()?;
+        log::info!("pyenv: creating {} with {}", venv.display(), base.label());
+        let out = Command::new(base.program).args(base.args).arg("-m").arg("venv").arg(&venv).output().context("failed to run python -m venv")?;
+        check(&out, "creating the venv")?;
+    }
+    let _ = std::fs::remove_file(venv.join(MARKER));
+    install(&python, on_progress, cancel)?;
+    std::fs::write(venv.join(MARKER), REQUIREMENTS.join("\n"))?;
+    Ok(python)
+}
+
+fn install(python: &Path, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<()> {
+    let total = REQUIREMENTS.len();
+    let report = |processed| on_progress(&Progress { stage: "installing".into(), processed, total, batch: None });
+    report(0);
+    check(&pip(python, &["install", "--upgrade", "pip"])?, "upgrading pip")?;
+    for (i, req) in REQUIREMENTS.iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        log::info!("pyenv: installing {req} into {}", python.display());
+        check(&pip(python, &["install", req])?, &format!("installing {req}"))?;
+        report(i + 1);
+    }
+    Ok(())
+}
+
+/// Devices the worker's torch can use ("cpu", "cuda", "directml"); empty if there's no
+/// interpreter ready yet.
+pub fn torch_devices(app: &tauri::AppHandle) -> Vec<String> {
+    let Some(python) = interpreter(app) else { return Vec::new() };
+    let probe = "import torch\nd = ['cpu']\nif torch.cuda.is_available(): d.append('cuda')\n\
+                 try:\n    import torch_directml\n    if torch_directml.device_count() > 0: d.append('directml')\nexcept ImportError:\n    pass\n\
+                 print(' '.join(d))";
+    match Command::new(python).args(["-c", probe]).output() {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).split_whitespace().map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Where the worker's Hugging Face downloads go; worker.py sets the same when it re-execs.
+pub fn model_cache_dir(app: &tauri::AppHandle) -> Result<PathBuf> {
+    Ok(db::data_dir(app)?.join("hf_cache"))
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageInfo {
+    pub name: String,
+    /// `None` = not installed in the venv.
+    pub version: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelCache {
+    pub dir: PathBuf,
+    pub bytes: u64,
+    /// The CLAP checkpoint laion-clap downloads on first use, if it's there yet.
+    pub checkpoint: Option<PathBuf>,
+}
+
+/// Everything a first run needs, checked without starting a scan.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostics {
+    pub python: EnvStatus,
+    pub worker_path: Option<PathBuf>,
+    pub worker_error: Option<String>,
+    pub packages: Vec<PackageInfo>,
+    pub model_cache: ModelCache,
+    pub devices: Vec<crate::embed::DeviceInfo>,
+}
+
+pub fn diagnose(app: &tauri::AppHandle) -> Result<Diagnostics> {
+    let python = status(app)?;
+    let (worker_path, worker_error) = match crate::worker::find_worker(app) {
+        Ok(p) => (Some(p), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    // One interpreter start for both the package versions and the checkpoint lookup
+    let probe = "import json, importlib.metadata as md, importlib.util as iu, pathlib\n\
+                 v = {}\n\
+                 for n in NAMES:\n    try:\n        v[n] = md.version(n)\n    except md.PackageNotFoundError:\n        v[n] = None\n\
+                 ck = None\n\
+                 spec = iu.find_spec('laion_clap')\n\
+                 if spec and spec.origin:\n    pts = sorted(pathlib.Path(spec.origin).parent.rglob('*.pt'))\n    ck = str(pts[0]) if pts else None\n\
+                 print(json.dumps({'packages': v, 'checkpoint': ck}))";
+    let probe = probe.replace("NAMES", &serde_json::to_string(REQUIREMENTS)?);
+    let exe = if python.custom.is_some() { python.interpreter.clone() } else { python.venv_exists.then(|| venv_python(&python.venv)) };
+    let found: serde_json::Value = match exe.map(|exe| Command::new(exe).args(["-c", &probe]).output()) {
+        Some(Ok(out)) if out.status.success() => serde_json::from_slice(&out.stdout).unwrap_or_default(),
+        _ => serde_json::Value::Null,
+    };
+    let packages = REQUIREMENTS
+        .iter()
+        .map(|&name| PackageInfo { name: name.to_string(), version: found["packages"][name].as_str().map(str::to_string) })
+        .collect();
+    let dir = model_cache_dir(app)?;
+    let bytes = walkdir::WalkDir::new(&dir).into_iter().filter_map(|e| e.ok()).filter_map(|e| e.metadata().ok()).filter(|m| m.is_file()).map(|m| m.len()).sum();
+    let model_cache = ModelCache { dir, bytes, checkpoint: found["checkpoint"].as_str().map(PathBuf::from) };
+    Ok(Diagnostics { python, worker_path, worker_error, packages, model_cache, devices: crate::embed::available_devices(app) })
+}
+
+fn pip(python: &Path, args: &[&str]) -> Result<Output> {
+    Command::new(python).args(["-m", "pip", "--disable-pip-version-check"]).args(args).output().context("failed to run pip")
+}
+
+/// Logs the command's output and fails with the tail of stderr if it didn't succeed.
+fn check(out: &Output, what: &str) -> Result<()> {
+    for line in String::from_utf8_lossy(&out.stdout).lines() { log::info!("{line}"); }
+    if out.status.success() { return Ok(()); }
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let tail: Vec<&str> = stderr.lines().rev().take(5).collect();
+    bail!("{what} failed ({}): {}", out.status, tail.into_iter().rev().collect::<Vec<_>>().join("\n"))
+}
@@ -0,0 +1,158 @@
+//! Storage encodings for embedding blobs. `embeddings.dtype` says how each row's `vec` is packed:
+//!
+//! - `f32`: little-endian f32 per component (what the worker has always written)
+//! - `f16`: little-endian IEEE half per component
+//! - `int8`: a little-endian f32 scale, then one i8 per component; value = i8 × scale
+
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dtype {
+    #[default]
+    F32,
+    F16,
+    Int8,
+}
+
+impl Dtype {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Dtype::F32 => "f32",
+            Dtype::F16 => "f16",
+            Dtype::Int8 => "int8",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "f32" => Dtype::F32,
+            "f16" => Dtype::F16,
+            "int8" => Dtype::Int8,
+            other => bail!("unknown embedding dtype {other:?}"),
+        })
+    }
+}
+
+pub fn encode(v: &[f32], dtype: Dtype) -> Vec<u8> {
+    match dtype {
+        Dtype::F32 => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        Dtype::F16 => v.iter().flat_map(|&x| f32_to_f16(x).to_le_bytes()).collect(),
+        Dtype::Int8 => {
+            // Symmetric per-vector scale; cosine only cares about direction, so this loses little
+            let max = v.iter().fold(0f32, |m, x| m.max(x.abs()));
+            let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+            let mut out = Vec::with_capacity(4 + v.len());
+            out.extend_from_slice(&scale.to_le_bytes());
+            out.extend(v.iter().map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8 as u8));
+            out
+        }
+    }
+}
+
+pub fn decode(blob: &[u8], dtype: Dtype) -> Vec<f32> {
+    match dtype {
+        Dtype::F32 => blob.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect(),
+        Dtype::F16 => blob.chunks_exact(2).map(|c| f16_to_f32(u16::from_le_bytes([c[0], c[1]]))).collect(),
+        Dtype::Int8 => {
+            if blob.len() < 4 { return Vec::new(); }
+            let scale = f32::from_le_bytes([blob[0], blob[1], blob[2], blob[3]]);
+            blob[4..].iter().map(|&b| b as i8 as f32 * scale).collect()
+        }
+    }
+}
+
+/// Decodes a row given its `dtype` column text; unknown encodings decode to nothing, which every
+/// reader already skips as a dimension mismatch.
+pub fn decode_row(blob: &[u8], dtype: &str) -> Vec<f32> {
+    match Dtype::parse(dtype) {
+        Ok(d) => decode(blob, d),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Round-to-nearest-even f32 → IEEE 754 binary16, saturating to ±inf.
+fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let man = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // inf / NaN (keep NaN quiet)
+        return sign | 0x7c00 | if man != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f { return sign | 0x7c00; }
+    if e <= 0 {
+        // Subnormal half, or too small for one
+        if e < -10 { return sign; }
+        let m = man | 0x80_0000;
+        let shift = (14 - e) as u32;
+        let half = 1 << (shift - 1);
+        let rounded = (m + half - 1 + ((m >> shift) & 1)) >> shift;
+        return sign | rounded as u16;
+    }
+    let rounded = man + 0xfff + ((man >> 13) & 1);
+    // A carry out of the mantissa correctly bumps the exponent
+    ((sign as u32) | (((e as u32) << 10) + (rounded >> 13))) as u16
+}
+
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let man = (h & 0x3ff) as u32;
+    let bits = match (exp, man) {
+        (0, 0) => sign,
+        (0, _) => {
+            // Subnormal: renormalize
+            let mut e = 127 - 15 + 1;
+            let mut m = man;
+            while m & 0x400 == 0 {
+                m <<= 1;
+                e -= 1;
+            }
+            sign | (e << 23) | ((m & 0x3ff) << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (man << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (man << 13),
+    };
+    f32::from_bits(bits)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressReport {
+    pub rows: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Re-encodes every stored embedding, of every model, as `dtype`. Going back up to f32 works but can't recover
+/// precision already dropped. The file only shrinks after a VACUUM (`optimize_database`).
+pub fn compress(conn: &mut Connection, dtype: Dtype) -> Result<CompressReport> {
+    let tx = conn.transaction()?;
+    let mut report = CompressReport { rows: 0, bytes_before: 0, bytes_after: 0 };
+    // Row ids first (one per file and model): rewriting rows under an open cursor over the same
+    // table isn't well defined
+    let ids: Vec<i64> = {
+        let mut stmt = tx.prepare("SELECT rowid FROM embeddings WHERE dtype <> ? ORDER BY rowid")?;
+        let ids = stmt.query_map(params![dtype.as_str()], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+        ids
+    };
+    {
+        let mut read = tx.prepare("SELECT vec, dtype FROM embeddings WHERE rowid = ?")?;
+        let mut write = tx.prepare("UPDATE embeddings SET vec = ?, dtype = ? WHERE rowid = ?")?;
+        for id in ids {
+            let (blob, from): (Vec<u8>, String) = read.query_row(params![id], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            let Ok(from) = Dtype::parse(&from) else { continue };
+            let out = encode(&decode(&blob, from), dtype);
+            report.bytes_before += blob.len() as u64;
+            report.bytes_after += out.len() as u64;
+            write.execute(params![out, dtype.as_str(), id])?;
+            report.rows += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(report)
+}
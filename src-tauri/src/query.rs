@@ -0,0 +1,267 @@
+use anyhow::Result;
+use rusqlite::{types::Value, Connection};
+
+/// Combinable predicates over the library. Every field is optional and all set fields must
+/// match; list fields match any of their values, except `tags`, where a file needs all of them.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FileFilter {
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub min_size_bytes: Option<i64>,
+    pub max_size_bytes: Option<i64>,
+    pub sample_rates: Option<Vec<u32>>,
+    pub bits_per_sample: Option<Vec<u16>>,
+    pub channels: Option<Vec<u16>>,
+    pub tags: Option<Vec<String>>,
+    pub clusters: Option<Vec<i64>>,
+    pub min_rating: Option<u8>,
+    pub max_rating: Option<u8>,
+    pub min_bpm: Option<f64>,
+    pub max_bpm: Option<f64>,
+    /// Key names in any common spelling ("F minor", "Fm", "4A").
+    pub keys: Option<Vec<String>>,
+    /// Fractional MIDI note bounds for one-shots.
+    pub min_pitch: Option<f64>,
+    pub max_pitch: Option<f64>,
+    pub min_lufs: Option<f64>,
+    pub max_lufs: Option<f64>,
+    pub min_peak_db: Option<f64>,
+    pub max_peak_db: Option<f64>,
+    pub categories: Option<Vec<crate::category::Category>>,
+    /// Spectral centroid bounds in Hz ("brightness").
+    pub min_centroid: Option<f64>,
+    pub max_centroid: Option<f64>,
+    /// 0 = tonal, 1 = noise-like.
+    pub min_flatness: Option<f64>,
+    pub max_flatness: Option<f64>,
+    /// Zero crossings per second.
+    pub min_zcr: Option<f64>,
+    pub max_zcr: Option<f64>,
+    /// Flagged as silence or DC by the loudness pass.
+    pub silent: Option<bool>,
+    pub favorite: Option<bool>,
+    /// Only files under this folder.
+    pub root: Option<String>,
+    /// Full-text query over names, paths and notes (same syntax as `search`).
+    pub text: Option<String>,
+    pub include_hidden: bool,
+}
+
+#[derive(Default)]
+struct Where {
+    conds: Vec<String>,
+    params: Vec<Value>,
+}
+
+impl Where {
+    fn push(&mut self, cond: impl Into<String>, params: impl IntoIterator<Item = Value>) {
+        self.conds.push(cond.into());
+        self.params.extend(params);
+    }
+
+    fn opt(&mut self, cond: &str, v: Option<impl Into<Value>>) {
+        if let Some(v) = v { self.push(cond, [v.into()]); }
+    }
+
+    fn any_of<T: Into<Value> + Copy>(&mut self, col: &str, vals: Option<&Vec<T>>) {
+        let Some(vals) = vals.filter(|v| !v.is_empty()) else { return };
+        let marks = vec!["?"; vals.len()].join(", ");
+        self.push(format!("{col} IN ({marks})"), vals.iter().map(|v| (*v).into()));
+    }
+}
+
+impl FileFilter {
+    /// Builds a WHERE clause over `files f` with positional parameters.
+    pub fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut w = Where::default();
+        w.opt("f.duration >= ?", self.min_duration);
+        w.opt("f.duration <= ?", self.max_duration);
+        w.opt("f.size_bytes >= ?", self.min_size_bytes);
+        w.opt("f.size_bytes <= ?", self.max_size_bytes);
+        w.opt("f.rating >= ?", self.min_rating);
+        w.opt("f.rating <= ?", self.max_rating);
+        w.opt("f.bpm >= ?", self.min_bpm);
+        w.opt("f.bpm <= ?", self.max_bpm);
+        let keys: Vec<String> = self.keys.iter().flatten().filter_map(|k| crate::tonal::normalize_key(k)).collect();
+        if !keys.is_empty() {
+            let marks = vec!["?"; keys.len()].join(", ");
+            w.push(format!("f.musical_key IN ({marks})"), keys.into_iter().map(Value::from));
+        }
+        w.opt("f.pitch >= ?", self.min_pitch);
+        w.opt("f.pitch <= ?", self.max_pitch);
+        w.opt("f.lufs >= ?", self.min_lufs);
+        w.opt("f.lufs <= ?", self.max_lufs);
+        w.opt("f.peak_db >= ?", self.min_peak_db);
+        w.opt("f.peak_db <= ?", self.max_peak_db);
+        w.opt("f.spectral_centroid >= ?", self.min_centroid);
+        w.opt("f.spectral_centroid <= ?", self.max_centroid);
+        w.opt("f.spectral_flatness >= ?", self.min_flatness);
+        w.opt("f.spectral_flatness <= ?", self.max_flatness);
+        w.opt("f.zero_crossing_rate >= ?", self.min_zcr);
+        w.opt("f.zero_crossing_rate <= ?", self.max_zcr);
+        w.opt("f.is_silent = ?", self.silent);
+        if let Some(cats) = self.categories.as_ref().filter(|c| !c.is_empty()) {
+            let marks = vec!["?"; cats.len()].join(", ");
+            w.push(format!("f.category IN ({marks})"), cats.iter().map(|c| Value::from(c.as_str().to_string())));
+        }
+        w.opt("f.favorite = ?", self.favorite);
+        w.any_of("f.sample_rate", self.sample_rates.as_ref());
+        w.any_of("f.bits_per_sample", self.bits_per_sample.as_ref());
+        w.any_of("f.channels", self.channels.as_ref());
+        w.any_of("f.cluster_id", self.clusters.as_ref());
+        for tag in self.tags.iter().flatten().filter_map(|t| crate::db::normalize_tag(t)) {
+            w.push("EXISTS (SELECT 1 FROM file_tags t WHERE t.file_id = f.id AND t.tag = ?)", [tag.into()]);
+        }
+        // Resolved the way scans store paths, so any spelling of the folder matches
+        let root = self.root.as_deref().map(|r| crate::paths::canonical(std::path::Path::new(r)).to_string_lossy().into_owned());
+        if let Some(root) = root.as_deref().map(|r| r.trim_end_matches(['/', '\\'])).filter(|r| !r.is_empty()) {
+            // Anything below the folder, with either separator, without LIKE escaping
+            let n = root.chars().count() as i64 + 1;
+            w.push("substr(f.path, 1, ?) IN (?, ?)", [n.into(), format!("{root}/").into(), format!("{root}\\").into()]);
+        }
+        w.opt("f.id IN (SELECT rowid FROM files_fts WHERE files_fts MATCH ?)", self.text.as_deref().and_then(crate::db::fts_query));
+        if !self.include_hidden { w.push("f.hidden = 0", []); }
+
+        let clause = if w.conds.is_empty() { "1".to_string() } else { w.conds.join(" AND ") };
+        (clause, w.params)
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryMatch {
+    pub file_id: i64,
+    /// Absent for files that haven't been projected yet.
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+pub fn query_files(conn: &Connection, filter: &FileFilter, limit: i64, offset: i64) -> Result<Vec<QueryMatch>> {
+    let (clause, mut params) = filter.to_sql();
+    params.push(limit.into());
+    params.push(offset.into());
+    let sql = format!(
+        "SELECT f.id, c.x, c.y FROM files f LEFT JOIN coords c ON c.file_id = f.id WHERE {clause} ORDER BY f.id LIMIT ? OFFSET ?"
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(params), |r| {
+            Ok(QueryMatch {
+                file_id: r.get(0)?,
+                x: r.get::<_, Option<f64>>(1)?.map(|v| v as f32),
+                y: r.get::<_, Option<f64>>(2)?.map(|v| v as f32),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// `k` files drawn at random from those matching `filter`. With `favor_unplayed`, a file's
+/// chance falls with its play count (weight `1 / (1 + plays)`), so never-played ones come up
+/// most.
+pub fn random_files(conn: &Connection, filter: &FileFilter, k: usize, favor_unplayed: bool) -> Result<Vec<QueryMatch>> {
+    let (clause, mut params) = filter.to_sql();
+    let row = |r: &rusqlite::Row| {
+        Ok(QueryMatch {
+            file_id: r.get(0)?,
+            x: r.get::<_, Option<f64>>(1)?.map(|v| v as f32),
+            y: r.get::<_, Option<f64>>(2)?.map(|v| v as f32),
+        })
+    };
+    if !favor_unplayed {
+        params.push((k as i64).into());
+        let sql = format!("SELECT f.id, c.x, c.y FROM files f LEFT JOIN coords c ON c.file_id = f.id WHERE {clause} ORDER BY random() LIMIT ?");
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(params), row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+        return Ok(rows);
+    }
+    // Weighted sampling without replacement: keep the k largest ln(u) / w, u uniform in (0, 1]
+    let sql = format!("SELECT f.id, c.x, c.y, f.play_count, random() FROM files f LEFT JOIN coords c ON c.file_id = f.id WHERE {clause}");
+    let mut stmt = conn.prepare(&sql)?;
+    let mut keyed = stmt
+        .query_map(rusqlite::params_from_iter(params), |r| {
+            let plays = r.get::<_, Option<i64>>(3)?.unwrap_or(0);
+            let u = ((r.get::<_, i64>(4)? as u64 >> 11) + 1) as f64 / (1u64 << 53) as f64;
+            Ok((u.ln() * (1 + plays.max(0)) as f64, row(r)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(keyed.into_iter().take(k).map(|(_, m)| m).collect())
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Duration,
+    Size,
+    Mtime,
+    Rating,
+    PlayCount,
+    Bpm,
+    Loudness,
+    Peak,
+    Brightness,
+}
+
+impl SortKey {
+    /// Column on `files`; the column list is fixed, so it's safe to format into SQL.
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Name => "name COLLATE NOCASE",
+            Self::Duration => "duration",
+            Self::Size => "size_bytes",
+            Self::Mtime => "mtime",
+            Self::Rating => "rating",
+            Self::PlayCount => "play_count",
+            Self::Bpm => "bpm",
+            Self::Loudness => "lufs",
+            Self::Peak => "peak_db",
+            Self::Brightness => "spectral_centroid",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// `WHERE .. ORDER BY .. LIMIT ? OFFSET ?` over `files` for a table view: `filter` picks the
+/// rows, unknown values (no duration, unrated) sort last either way, and ties go by id.
+pub fn list_clause(filter: &FileFilter, sort: SortKey, order: SortOrder, limit: i64, offset: i64) -> (String, Vec<Value>) {
+    let (clause, mut params) = filter.to_sql();
+    params.push(limit.into());
+    params.push(offset.into());
+    let dir = match order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    let col = sort.column();
+    let sql = format!("WHERE id IN (SELECT f.id FROM files f WHERE {clause}) ORDER BY {col} IS NULL, {col} {dir}, id LIMIT ? OFFSET ?");
+    (sql, params)
+}
+
+/// A LIKE pattern (escape `\\`) for a filename search: `*` and `?` are wildcards, anything else
+/// is literal, and a query without wildcards matches anywhere in the text.
+pub fn like_pattern(query: &str) -> String {
+    let mut out = String::with_capacity(query.len() + 2);
+    for ch in query.chars() {
+        match ch {
+            '*' => out.push('%'),
+            '?' => out.push('_'),
+            '%' | '_' | '\\' => {
+                out.push('\\');
+                out.push(ch);
+            }
+            c => out.push(c),
+        }
+    }
+    if !query.contains(['*', '?']) { out = format!("%{out}%"); }
+    out
+}
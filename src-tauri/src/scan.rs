@@ -1,9 +1,30 @@
-use crate::db::{db_path, open_or_create, upsert_file, FileRow};
-use crate::worker;
+use crate::db::{self, db_path, open_or_create, upsert_file, FileRow};
+use crate::{
+    autotag, category, cluster,
+    convert::{self, ConvertOptions},
+    embed, events,
+    export::{self, FileExportOptions},
+    history, lod, outlier, paths, pyenv,
+    settings::{self, ProjectionSettings},
+    slices::{self, SliceOptions},
+    loudness, spectral, tempo, tonal,
+    webmap::{self, WebMapOptions},
+    worker::{self, Cancel},
+};
 use anyhow::Result;
 use hound::WavReader;
 use rusqlite::Connection;
-use std::{fs, path::Path, sync::Arc, thread, time::SystemTime};
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 use tauri::Manager;
 
 use parking_lot::Mutex;
@@ -12,95 +33,678 @@ use walkdir::WalkDir;
 
 #[derive(Clone, serde::Serialize)]
 pub struct ScanStatus {
+    pub root: String,
     pub stage: String,
     pub processed: usize,
     pub total: usize,
+    pub skipped: usize,
     pub done: bool,
     pub error: Option<String>,
+    /// Stopped by `cancel_scan`; `error` then reads "cancelled".
+    pub cancelled: bool,
+    /// Set when the worker failed in a recognisable way (e.g. timed out), alongside `error`.
+    pub failure: Option<worker::WorkerFailure>,
+    pub queued_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
 }
 
 impl Default for ScanStatus {
     fn default() -> Self {
-        Self { stage: "idle".into(), processed: 0, total: 0, done: false, error: None }
+        Self {
+            root: String::new(),
+            stage: "idle".into(),
+            processed: 0,
+            total: 0,
+            skipped: 0,
+            done: false,
+            error: None,
+            cancelled: false,
+            failure: None,
+            queued_at: 0,
+            started_at: None,
+            finished_at: None,
+        }
+    }
+}
+
+impl ScanStatus {
+    fn cancel(&mut self) {
+        self.finish(Some("cancelled".into()));
+        self.cancelled = true;
+    }
+
+    /// Finishes with `e` under `what`, keeping a structured worker failure if that's the cause.
+    fn fail(&mut self, what: &str, e: &anyhow::Error) {
+        self.failure = e.downcast_ref::<worker::WorkerFailure>().cloned();
+        self.finish(Some(format!("{what}: {e}")));
+    }
+
+    fn finish(&mut self, error: Option<String>) {
+        self.stage = "done".into();
+        self.error = error;
+        self.done = true;
+        self.finished_at = Some(now_secs());
+    }
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// How the directory tree is traversed; shared by scans and `list_wavs`.
+#[derive(Clone)]
+pub struct WalkOptions {
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    pub same_file_system: bool,
+    /// Name patterns from `settings.scan.excludes`; a matching folder is skipped whole.
+    pub excludes: Vec<String>,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self { Self { max_depth: None, follow_symlinks: true, same_file_system: false, excludes: Vec::new() } }
+}
+
+/// Optional per-scan filters. Files outside the limits are counted as skipped and never upserted.
+#[derive(Clone, Default)]
+pub struct ScanOptions {
+    pub walk: WalkOptions,
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub max_size_bytes: Option<u64>,
+}
+
+impl ScanOptions {
+    fn accepts_size(&self, size: u64) -> bool {
+        self.max_size_bytes.map(|m| size <= m).unwrap_or(true)
+    }
+
+    // Files whose header can't be read have no duration; keep them rather than guess.
+    fn accepts_duration(&self, duration: Option<f64>) -> bool {
+        let Some(d) = duration else { return true };
+        if let Some(min) = self.min_duration { if d < min { return false; } }
+        if let Some(max) = self.max_duration { if d > max { return false; } }
+        true
     }
 }
 
 pub struct ScanManager {
     pub jobs: Mutex<std::collections::HashMap<String, Arc<Mutex<ScanStatus>>>>,
+    /// Job ids in submission order, for history listings.
+    pub order: Mutex<Vec<String>>,
+    queue: Mutex<ScanQueue>,
+    cancels: Mutex<std::collections::HashMap<String, Arc<Cancel>>>,
 }
 
 impl Default for ScanManager {
-    fn default() -> Self { Self { jobs: Mutex::new(Default::default()) } }
+    fn default() -> Self {
+        Self {
+            jobs: Mutex::new(Default::default()),
+            order: Mutex::new(Vec::new()),
+            queue: Mutex::new(Default::default()),
+            cancels: Mutex::new(Default::default()),
+        }
+    }
 }
 
-pub fn start_scan(app: tauri::AppHandle, root: String, mgr: Arc<ScanManager>) -> String {
-    let job_id = Uuid::new_v4().to_string();
-    let status = Arc::new(Mutex::new(ScanStatus::default()));
-    mgr.jobs.lock().insert(job_id.clone(), status.clone());
+struct PendingJob {
+    id: String,
+    app: tauri::AppHandle,
+    task: Task,
+    status: Arc<Mutex<ScanStatus>>,
+    cancel: Arc<Cancel>,
+}
 
-    thread::spawn(move || {
-        let res = do_scan(&app, &root, &status);
-        if let Err(e) = res {
-            let mut s = status.lock();
-            s.error = Some(e.to_string());
-            s.done = true;
+/// What a queued job does. Re-projection shares the queue so it never races a scan's pipeline.
+enum Task {
+    Scan { root: PathBuf, opts: ScanOptions },
+    Reproject(ProjectionSettings),
+    Recluster(Option<usize>),
+    /// (Re)installs the worker's Python environment; `true` = even if it looks complete.
+    SetupPython(bool),
+    ExportFiles { file_ids: Vec<i64>, dest: PathBuf, opts: FileExportOptions },
+    Convert { file_ids: Vec<i64>, dest: PathBuf, opts: ConvertOptions },
+    ExportWebMap { dest: PathBuf, opts: WebMapOptions },
+    /// Cuts a loop into slices, then indexes them like scanned files.
+    Slice { file_id: i64, opts: SliceOptions },
+    /// Zero-shot machine tags; `true` = replace existing ones.
+    AutoTag(bool),
+    /// One analysis pass over the library; `force` = redo files already analysed.
+    Analyze { kind: Analysis, force: bool },
+}
+
+/// Native per-file analyses; each runs after a scan's embeddings and on demand.
+#[derive(Clone, Copy)]
+pub enum Analysis {
+    Tempo,
+    Key,
+    Loudness,
+    /// One-shot / loop / long-form; after tempo, whose confidence it uses.
+    Category,
+    Spectral,
+}
+
+impl Analysis {
+    pub const ALL: [Analysis; 5] = [Analysis::Tempo, Analysis::Key, Analysis::Loudness, Analysis::Category, Analysis::Spectral];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Tempo => "tempo detection",
+            Self::Key => "key detection",
+            Self::Loudness => "loudness analysis",
+            Self::Category => "classification",
+            Self::Spectral => "spectral analysis",
         }
-    });
+    }
+
+    /// Runs the pass; returns the files it updated and how many couldn't be decoded.
+    pub fn run(self, conn: &Connection, force: bool, on_progress: &dyn Fn(&worker::Progress), cancel: &Cancel) -> Result<(Vec<i64>, usize)> {
+        match self {
+            Self::Tempo => tempo::analyze(conn, force, on_progress, cancel).map(|s| (s.file_ids, s.failed)),
+            Self::Key => tonal::analyze(conn, force, on_progress, cancel).map(|s| (s.file_ids, s.failed)),
+            Self::Loudness => loudness::analyze(conn, force, on_progress, cancel).map(|s| (s.file_ids, s.failed)),
+            Self::Category => category::analyze(conn, force, on_progress, cancel).map(|s| (s.file_ids, s.failed)),
+            Self::Spectral => spectral::analyze(conn, force, on_progress, cancel).map(|s| (s.file_ids, s.failed)),
+        }
+    }
+}
 
+#[derive(Default)]
+struct ScanQueue {
+    pending: VecDeque<PendingJob>,
+    running: usize,
+}
+
+impl ScanManager {
+    /// No job running or waiting, so background work may use the disk.
+    pub fn is_idle(&self) -> bool {
+        let q = self.queue.lock();
+        q.running == 0 && q.pending.is_empty()
+    }
+
+    /// 0-based position among jobs still waiting to start, or `None` once running/finished.
+    pub fn queue_position(&self, job_id: &str) -> Option<usize> {
+        self.queue.lock().pending.iter().position(|j| j.id == job_id)
+    }
+
+    /// Stops a job: a queued one is dropped, a running one is flagged and its worker killed.
+    /// Returns false if the job is unknown or already finished.
+    pub fn cancel(&self, job_id: &str) -> bool {
+        let pending = {
+            let mut q = self.queue.lock();
+            let i = q.pending.iter().position(|j| j.id == job_id);
+            i.and_then(|i| q.pending.remove(i))
+        };
+        if let Some(job) = pending {
+            job.status.lock().cancel();
+            self.cancels.lock().remove(job_id);
+            return true;
+        }
+        match self.cancels.lock().get(job_id) {
+            Some(c) => {
+                c.request();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub fn is_wav(p: &Path) -> bool {
+    p.extension().and_then(|x| x.to_str()).map(|x| x.eq_ignore_ascii_case("wav")).unwrap_or(false)
+}
+
+/// Yields every WAV file under `root`, canonical as the library stores them. When following
+/// symlinks, each directory is entered at most once (keyed by its canonical path), which breaks
+/// link cycles and avoids double-counting.
+pub fn walk_wavs(root: &Path, opts: &WalkOptions) -> impl Iterator<Item = PathBuf> {
+    // Walked in extended form so nothing below the root is cut off at MAX_PATH
+    let mut wd = WalkDir::new(paths::extended(&paths::canonical(root)))
+        .follow_links(opts.follow_symlinks)
+        .same_file_system(opts.same_file_system);
+    if let Some(d) = opts.max_depth { wd = wd.max_depth(d); }
+    let follow = opts.follow_symlinks;
+    let excludes: Vec<Vec<char>> = opts.excludes.iter().map(|p| p.to_lowercase().chars().collect()).collect();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    wd.into_iter()
+        .filter_entry(move |e| {
+            // The root itself is never excluded, only what's below it
+            if e.depth() > 0 && !excludes.is_empty() {
+                let name: Vec<char> = e.file_name().to_string_lossy().to_lowercase().chars().collect();
+                if excludes.iter().any(|p| wildcard_match(p, &name)) { return false; }
+            }
+            if !follow || !e.file_type().is_dir() { return true; }
+            match fs::canonicalize(e.path()) { Ok(c) => seen.insert(c), Err(_) => true }
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_wav(e.path()))
+        .map(|e| paths::strip_verbatim(e.path()).into_owned())
+}
+
+/// `*` matches any run of characters, `?` any single one.
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|i| wildcard_match(rest, &text[i..])),
+        Some((&c, rest)) => text.split_first().is_some_and(|(&t, tail)| (c == '?' || c == t) && wildcard_match(rest, tail)),
+    }
+}
+
+/// Enqueues a scan and returns its job id. Jobs start in submission order, at most
+/// `settings.scan.max_parallel_jobs` at a time, so scans don't race each other on the DB and worker.
+pub fn start_scan(app: tauri::AppHandle, root: PathBuf, opts: ScanOptions, mgr: Arc<ScanManager>) -> String {
+    let label = root.to_string_lossy().into_owned();
+    enqueue(app, label, Task::Scan { root, opts }, mgr)
+}
+
+/// Enqueues a fresh layout of the existing embeddings with `params`; no rescan or re-embedding.
+pub fn start_reproject(app: tauri::AppHandle, params: ProjectionSettings, mgr: Arc<ScanManager>) -> String {
+    enqueue(app, String::new(), Task::Reproject(params), mgr)
+}
+
+/// Enqueues setting up the worker's venv, so first-run installs can be watched like a scan.
+pub fn start_python_setup(app: tauri::AppHandle, force: bool, mgr: Arc<ScanManager>) -> String {
+    enqueue(app, String::new(), Task::SetupPython(force), mgr)
+}
+
+/// Enqueues copying files out to `dest`; progress shows like a scan's.
+pub fn start_export_files(app: tauri::AppHandle, file_ids: Vec<i64>, dest: PathBuf, opts: FileExportOptions, mgr: Arc<ScanManager>) -> String {
+    let label = dest.to_string_lossy().into_owned();
+    enqueue(app, label, Task::ExportFiles { file_ids, dest, opts }, mgr)
+}
+
+/// Enqueues converting files into `dest` in another format.
+pub fn start_convert(app: tauri::AppHandle, file_ids: Vec<i64>, dest: PathBuf, opts: ConvertOptions, mgr: Arc<ScanManager>) -> String {
+    let label = dest.to_string_lossy().into_owned();
+    enqueue(app, label, Task::Convert { file_ids, dest, opts }, mgr)
+}
+
+/// Enqueues writing the browser viewer of the map into `dest`.
+pub fn start_export_web_map(app: tauri::AppHandle, dest: PathBuf, opts: WebMapOptions, mgr: Arc<ScanManager>) -> String {
+    let label = dest.to_string_lossy().into_owned();
+    enqueue(app, label, Task::ExportWebMap { dest, opts }, mgr)
+}
+
+/// Enqueues slicing a loop and placing the slices on the map.
+pub fn start_slice(app: tauri::AppHandle, file_id: i64, opts: SliceOptions, mgr: Arc<ScanManager>) -> String {
+    enqueue(app, String::new(), Task::Slice { file_id, opts }, mgr)
+}
+
+/// Enqueues machine-tagging embedded files.
+pub fn start_auto_tag(app: tauri::AppHandle, force: bool, mgr: Arc<ScanManager>) -> String {
+    enqueue(app, String::new(), Task::AutoTag(force), mgr)
+}
+
+/// Enqueues one analysis pass over the files it hasn't covered yet (all of them with `force`).
+pub fn start_analysis(app: tauri::AppHandle, kind: Analysis, force: bool, mgr: Arc<ScanManager>) -> String {
+    enqueue(app, String::new(), Task::Analyze { kind, force }, mgr)
+}
+
+/// Enqueues a k-means pass over the existing embeddings.
+pub fn start_recluster(app: tauri::AppHandle, k: Option<usize>, mgr: Arc<ScanManager>) -> String {
+    enqueue(app, String::new(), Task::Recluster(k), mgr)
+}
+
+fn enqueue(app: tauri::AppHandle, root: String, task: Task, mgr: Arc<ScanManager>) -> String {
+    let job_id = Uuid::new_v4().to_string();
+    let status = Arc::new(Mutex::new(ScanStatus { root, stage: "queued".into(), queued_at: now_secs(), ..Default::default() }));
+    mgr.jobs.lock().insert(job_id.clone(), status.clone());
+    mgr.order.lock().push(job_id.clone());
+    let cancel = Arc::new(Cancel::default());
+    mgr.cancels.lock().insert(job_id.clone(), cancel.clone());
+    mgr.queue.lock().pending.push_back(PendingJob { id: job_id.clone(), app, task, status, cancel });
+    pump(&mgr);
     job_id
 }
 
-fn do_scan(app: &tauri::AppHandle, root: &str, status: &Arc<Mutex<ScanStatus>>) -> Result<()> {
-    // Count WAVs
+fn pump(mgr: &Arc<ScanManager>) {
+    let mut q = mgr.queue.lock();
+    while let Some(front) = q.pending.front() {
+        let limit = settings::load(&front.app).scan.max_parallel_jobs.max(1);
+        if q.running >= limit { break; }
+        let Some(job) = q.pending.pop_front() else { break };
+        q.running += 1;
+        let mgr = mgr.clone();
+        thread::spawn(move || {
+            let _slot = Slot { mgr, id: job.id.clone(), status: job.status.clone() };
+            job.status.lock().started_at = Some(now_secs());
+            let res = match &job.task {
+                Task::Scan { root, opts } => do_scan(&job.app, root, opts, &job.status, &job.cancel),
+                Task::Reproject(params) => do_reproject(&job.app, params, &job.status, &job.cancel),
+                Task::Recluster(k) => do_recluster(&job.app, *k, &job.status),
+                Task::SetupPython(force) => do_python_setup(&job.app, *force, &job.status, &job.cancel),
+                Task::ExportFiles { file_ids, dest, opts } => do_export_files(&job.app, file_ids, dest, opts, &job.status, &job.cancel),
+                Task::Convert { file_ids, dest, opts } => do_convert(&job.app, file_ids, dest, opts, &job.status, &job.cancel),
+                Task::ExportWebMap { dest, opts } => do_export_web_map(&job.app, dest, opts, &job.status, &job.cancel),
+                Task::Slice { file_id, opts } => do_slice(&job.app, *file_id, opts, &job.status, &job.cancel),
+                Task::AutoTag(force) => do_auto_tag(&job.app, *force, &job.status, &job.cancel),
+                Task::Analyze { kind, force } => do_analyze(&job.app, *kind, *force, &job.status, &job.cancel),
+            };
+            if job.cancel.is_requested() {
+                job.status.lock().cancel();
+            } else if let Err(e) = res {
+                job.status.lock().finish(Some(e.to_string()));
+            }
+            if let Task::Scan { root, .. } = &job.task {
+                let status = job.status.lock().clone();
+                let recorded = db_path(&job.app).and_then(|p| open_or_create(&p)).and_then(|c| history::record(&c, root, &status));
+                if let Err(e) = recorded { log::warn!("scan history not recorded for {}: {e}", root.display()); }
+            }
+        });
+    }
+}
+
+/// A running job's place in the queue. Dropped when the job's thread ends, panicking or not,
+/// so its slot is freed and the next job starts; a panicked job is marked failed.
+struct Slot {
+    mgr: Arc<ScanManager>,
+    id: String,
+    status: Arc<Mutex<ScanStatus>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            log::warn!("job {} panicked", self.id);
+            self.status.lock().finish(Some("the job crashed".into()));
+        }
+        self.mgr.cancels.lock().remove(&self.id);
+        self.mgr.queue.lock().running -= 1;
+        pump(&self.mgr);
+    }
+}
+
+fn do_scan(app: &tauri::AppHandle, root: &Path, opts: &ScanOptions, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let io = settings::load(app).io;
+    // Resolve the library once so switching libraries mid-scan can't split the job across two DBs
+    let dbfile = db_path(app)?;
+    // Walk once; the collected list drives both the progress total and the probe stage
+    status.lock().stage = "scanning".into();
+    let throttle = io_throttle();
+    let paths: Vec<PathBuf> = walk_wavs(root, &opts.walk).inspect(|_| throttle.wait()).collect();
     {
         let mut s = status.lock();
-        s.stage = "scanning".into();
-        s.total = WalkDir::new(root)
-            .follow_links(true)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.file_type().is_file())
-            .filter(|e| e.path().extension().and_then(|x| x.to_str()).map(|x| x.eq_ignore_ascii_case("wav")).unwrap_or(false))
-            .count();
+        s.total = paths.len();
         s.processed = 0;
+        s.skipped = 0;
     }
 
-    let dbfile = db_path(app)?;
     let mut conn = open_or_create(&dbfile)?;
+    let last_id = events::max_file_id(&conn);
 
-    for entry in WalkDir::new(root).follow_links(true).into_iter().filter_map(|e| e.ok()) {
-        let p = entry.path();
-        if !entry.file_type().is_file() { continue; }
-        if p.extension().and_then(|x| x.to_str()).map(|x| x.eq_ignore_ascii_case("wav")).unwrap_or(false) {
-            let _ = upsert_one(&mut conn, p);
+    // Header reads fan out over `io.concurrency` threads; the connection stays on this thread.
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel::<Option<ProbedFile>>();
+    thread::scope(|sc| {
+        for _ in 0..io.concurrency.max(1) {
+            let tx = tx.clone();
+            let (paths, next, throttle) = (&paths, &next, &*throttle);
+            sc.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(p) = paths.get(i) else { break };
+                throttle.wait();
+                if tx.send(probe_file(p, opts).ok().flatten()).is_err() { break; }
+            });
+        }
+        drop(tx);
+        for (i, probed) in rx.into_iter().enumerate() {
+            // Dropping the receiver makes the probe threads' sends fail, which stops them
+            if cancel.is_requested() { break; }
+            let kept = match probed {
+                Some(f) => db::retry_busy(|| upsert_probed(&conn, &f)).is_ok(),
+                None => false,
+            };
+            if (i + 1) % CHECKPOINT_EVERY == 0 { let _ = db::checkpoint(&conn); }
             let mut s = status.lock();
             s.processed += 1;
+            if !kept { s.skipped += 1; }
         }
-    }
+    });
+    let _ = db::checkpoint(&conn);
+    events::files_added(app, &events::new_file_ids(&conn, last_id));
+    if cancel.is_requested() { return Ok(()); }
 
+    process_new_files(app, &mut conn, &dbfile, status, cancel);
+    Ok(())
+}
+
+/// Everything after files are in the DB: embeddings, projection, clusters, outlier scores,
+/// machine tags and the native analyses. Finishes `status` either way.
+fn process_new_files(app: &tauri::AppHandle, conn: &mut Connection, dbfile: &Path, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) {
     {
         let mut s = status.lock();
         s.stage = "embedding".into();
     }
-    // Run embeddings + umap via python worker
-    match worker::run_pipeline(app, "all") {
+    // Embeddings (native or via the worker), then the worker's UMAP projection
+    match embed::run_pipeline(app, dbfile, &report(status), cancel) {
         Ok(_) => {
+            if let Err(e) = lod::rebuild(conn) { log::warn!("lod: rebuild failed: {e:#}"); }
+            events::projected(app, dbfile);
+            // Fold the new embeddings into the similarity index now rather than on the first query
+            if let Err(e) = app.state::<crate::AppState>().ann.with(conn, dbfile, |_| ()) { log::warn!("ann: index update failed: {e}"); }
+            status.lock().stage = "clustering".into();
+            let k = settings::load(app).clustering.k;
+            if let Err(e) = cluster::recluster(conn, k) {
+                status.lock().finish(Some(format!("clustering failed: {e}")));
+                return;
+            }
+            status.lock().stage = "scoring".into();
+            if let Err(e) = outlier::score(conn, &app.state::<crate::AppState>().ann, dbfile, outlier::DEFAULT_K) {
+                status.lock().finish(Some(format!("outlier scoring failed: {e}")));
+                return;
+            }
+            let tagging = settings::load(app).autotag;
+            if tagging.enabled {
+                match autotag::run(app, conn, &app.state::<crate::AppState>().text, &tagging, false, &report(status), cancel) {
+                    Ok(t) => events::metadata_changed(app, &t.file_ids),
+                    // Usually the text model isn't installed; the scan itself is fine
+                    Err(e) => log::warn!("auto-tagging skipped: {e:#}"),
+                }
+            }
+            for kind in Analysis::ALL {
+                match kind.run(conn, false, &report(status), cancel) {
+                    Ok((ids, _)) => events::metadata_changed(app, &ids),
+                    Err(e) => {
+                        status.lock().finish(Some(format!("{} failed: {e}", kind.label())));
+                        return;
+                    }
+                }
+            }
+            status.lock().finish(None)
+        }
+        Err(e) => status.lock().fail("embedding failed", &e),
+    }
+}
+
+
+/// Mirrors worker/embedder progress into the job's status.
+fn report(status: &Arc<Mutex<ScanStatus>>) -> impl Fn(&worker::Progress) + '_ {
+    move |p| {
+        let mut s = status.lock();
+        s.stage.clone_from(&p.stage);
+        s.processed = p.processed;
+        s.total = p.total;
+    }
+}
+
+fn do_reproject(app: &tauri::AppHandle, params: &ProjectionSettings, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let dbfile = db_path(app)?;
+    status.lock().stage = "projecting".into();
+    match worker::run_pipeline(app, &dbfile, "umap", params, &report(status), cancel) {
+        Ok(_) => {
+            if let Err(e) = open_or_create(&dbfile).and_then(|mut c| lod::rebuild(&mut c)) { log::warn!("lod: rebuild failed: {e:#}"); }
+            events::projected(app, &dbfile);
+            status.lock().finish(None)
+        }
+        Err(e) => status.lock().fail("projection failed", &e),
+    }
+    Ok(())
+}
+
+fn do_python_setup(app: &tauri::AppHandle, force: bool, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let res = pyenv::ensure(app, force, &report(status), cancel);
+    status.lock().finish(res.err().map(|e| format!("python setup failed: {e}")));
+    Ok(())
+}
+
+fn do_export_files(
+    app: &tauri::AppHandle,
+    file_ids: &[i64],
+    dest: &Path,
+    opts: &FileExportOptions,
+    status: &Arc<Mutex<ScanStatus>>,
+    cancel: &Cancel,
+) -> Result<()> {
+    let conn = open_or_create(&db_path(app)?)?;
+    match export::export_files(&conn, file_ids, dest, opts, &report(status), cancel) {
+        Ok(r) => {
             let mut s = status.lock();
-            s.stage = "done".into();
-            s.done = true;
+            s.skipped = r.skipped;
+            s.finish((r.failed > 0).then(|| format!("{} of {} files couldn't be exported", r.failed, file_ids.len())));
         }
+        Err(e) => status.lock().finish(Some(format!("export failed: {e}"))),
+    }
+    Ok(())
+}
+
+fn do_convert(
+    app: &tauri::AppHandle,
+    file_ids: &[i64],
+    dest: &Path,
+    opts: &ConvertOptions,
+    status: &Arc<Mutex<ScanStatus>>,
+    cancel: &Cancel,
+) -> Result<()> {
+    let conn = open_or_create(&db_path(app)?)?;
+    match convert::convert_files(&conn, file_ids, dest, opts, &report(status), cancel) {
+        Ok(r) => status.lock().finish((r.failed > 0).then(|| format!("{} of {} files couldn't be converted", r.failed, file_ids.len()))),
+        Err(e) => status.lock().finish(Some(format!("conversion failed: {e}"))),
+    }
+    Ok(())
+}
+
+fn do_export_web_map(app: &tauri::AppHandle, dest: &Path, opts: &WebMapOptions, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let conn = open_or_create(&db_path(app)?)?;
+    let library = settings::load(app).active_library.unwrap_or_else(|| db::DEFAULT_LIBRARY.to_string());
+    let res = webmap::export(&conn, &library, dest, opts, &report(status), cancel);
+    status.lock().finish(res.err().map(|e| format!("web map export failed: {e:#}")));
+    Ok(())
+}
+
+fn do_slice(app: &tauri::AppHandle, file_id: i64, opts: &SliceOptions, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let dbfile = db_path(app)?;
+    let mut conn = open_or_create(&dbfile)?;
+    status.lock().stage = "slicing".into();
+    let sliced = match slices::slice_file(&mut conn, &dbfile, file_id, opts) {
+        Ok(r) => r,
         Err(e) => {
+            status.lock().finish(Some(format!("slicing failed: {e:#}")));
+            return Ok(());
+        }
+    };
+    events::files_removed(app, &sliced.removed);
+    events::files_added(app, &sliced.added);
+    {
+        let mut s = status.lock();
+        s.total = sliced.added.len();
+        s.processed = sliced.added.len();
+    }
+    process_new_files(app, &mut conn, &dbfile, status, cancel);
+    Ok(())
+}
+
+fn do_auto_tag(app: &tauri::AppHandle, force: bool, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let mut conn = open_or_create(&db_path(app)?)?;
+    status.lock().stage = "tagging".into();
+    let cfg = settings::load(app).autotag;
+    match autotag::run(app, &mut conn, &app.state::<crate::AppState>().text, &cfg, force, &report(status), cancel) {
+        Ok(t) => {
+            events::metadata_changed(app, &t.file_ids);
+            status.lock().finish(None);
+        }
+        Err(e) => status.lock().finish(Some(format!("auto-tagging failed: {e:#}"))),
+    }
+    Ok(())
+}
+
+fn do_analyze(app: &tauri::AppHandle, kind: Analysis, force: bool, status: &Arc<Mutex<ScanStatus>>, cancel: &Cancel) -> Result<()> {
+    let conn = open_or_create(&db_path(app)?)?;
+    match kind.run(&conn, force, &report(status), cancel) {
+        Ok((ids, failed)) => {
+            events::metadata_changed(app, &ids);
             let mut s = status.lock();
-            s.stage = "done".into();
-            s.error = Some(format!("embedding failed: {e}"));
-            s.done = true;
+            s.skipped = failed;
+            s.finish(None);
         }
+        Err(e) => status.lock().finish(Some(format!("{} failed: {e:#}", kind.label()))),
     }
     Ok(())
 }
 
-fn upsert_one(conn: &mut Connection, path: &Path) -> Result<()> {
-    let meta = fs::metadata(path)?;
+fn do_recluster(app: &tauri::AppHandle, k: Option<usize>, status: &Arc<Mutex<ScanStatus>>) -> Result<()> {
+    let mut conn = open_or_create(&db_path(app)?)?;
+    status.lock().stage = "clustering".into();
+    let res = cluster::recluster(&mut conn, k);
+    status.lock().finish(res.err().map(|e| format!("clustering failed: {e}")));
+    Ok(())
+}
+
+/// Upserts between WAL checkpoints during the probe stage.
+const CHECKPOINT_EVERY: usize = 2000;
+
+/// The throttle every file-reading stage goes through (the scan walk, header probes and
+/// hashing), so `io.maxFilesPerSec` bounds them together. Set from the settings at startup and
+/// whenever they're saved.
+static IO_THROTTLE: Mutex<Option<Arc<Throttle>>> = parking_lot::const_mutex(None);
+
+pub fn set_io_rate(rate: Option<f64>) {
+    *IO_THROTTLE.lock() = Some(Arc::new(Throttle::new(rate)));
+}
+
+fn io_throttle() -> Arc<Throttle> {
+    IO_THROTTLE.lock().get_or_insert_with(|| Arc::new(Throttle::new(None))).clone()
+}
+
+/// Spaces out I/O operations across threads to at most `rate` per second.
+pub struct Throttle {
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<f64>) -> Self {
+        let interval = rate.filter(|r| *r > 0.0).map(|r| Duration::from_secs_f64(1.0 / r));
+        Self { interval, next: Mutex::new(Instant::now()) }
+    }
+
+    pub fn wait(&self) {
+        let Some(interval) = self.interval else { return };
+        let slot = {
+            let mut next = self.next.lock();
+            let slot = (*next).max(Instant::now());
+            *next = slot + interval;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now { thread::sleep(slot - now); }
+    }
+}
+
+struct ProbedFile {
+    path: PathBuf,
+    name: String,
+    size_bytes: i64,
+    duration: Option<f64>,
+    mtime: i64,
+    format: Option<WavHeader>,
+}
+
+/// Reads metadata and the WAV header. Returns `Ok(None)` when the file was rejected by the scan filters.
+fn probe_file(path: &Path, opts: &ScanOptions) -> Result<Option<ProbedFile>> {
+    let meta = fs::metadata(paths::extended(path))?;
+    // Size check first so oversized files never have their header read
+    if !opts.accepts_size(meta.len()) { return Ok(None); }
     let size_bytes = meta.len() as i64;
     let mtime = meta
         .modified()
@@ -108,17 +712,57 @@ fn upsert_one(conn: &mut Connection, path: &Path) -> Result<()> {
         .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
         .map(|d| d.as_secs() as i64)
         .unwrap_or(0);
-    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+    // Display name only; the path itself is stored losslessly
+    let name = path.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let format = wav_header(&paths::extended(path)).ok();
+    let duration = format.as_ref().map(|h| h.duration);
+    if !opts.accepts_duration(duration) { return Ok(None); }
+    Ok(Some(ProbedFile { path: path.to_path_buf(), name, size_bytes, duration, mtime, format }))
+}
 
-    let duration = wav_duration_seconds(path).ok();
-    let row = FileRow { path: &path.to_string_lossy(), name, size_bytes, duration, mtime };
+fn upsert_probed(conn: &Connection, f: &ProbedFile) -> Result<()> {
+    let row = FileRow {
+        path: &f.path,
+        name: &f.name,
+        size_bytes: f.size_bytes,
+        duration: f.duration,
+        mtime: f.mtime,
+        sample_rate: f.format.as_ref().map(|h| h.sample_rate),
+        bits_per_sample: f.format.as_ref().map(|h| h.bits_per_sample),
+        channels: f.format.as_ref().map(|h| h.channels),
+    };
     upsert_file(conn, &row)
 }
 
-fn wav_duration_seconds(path: &Path) -> Result<f64> {
+/// Hex SHA-256 of the file contents, streamed so large files aren't loaded whole. Counts
+/// against `io.maxFilesPerSec` like a scan's reads.
+pub fn content_hash(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+    io_throttle().wait();
+    let mut f = fs::File::open(paths::extended(path))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+pub struct WavHeader {
+    pub duration: f64,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub channels: u16,
+}
+
+pub fn wav_header(path: &Path) -> Result<WavHeader> {
     let r = WavReader::open(path)?;
     let spec = r.spec();
     let samples_per_ch = r.duration() as f64; // per channel count
     let sr = spec.sample_rate as f64;
-    Ok(samples_per_ch / sr)
+    Ok(WavHeader { duration: samples_per_ch / sr, sample_rate: spec.sample_rate, bits_per_sample: spec.bits_per_sample, channels: spec.channels })
 }
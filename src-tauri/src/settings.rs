@@ -0,0 +1,363 @@
+use crate::{db, quant::Dtype};
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+
+/// User preferences, persisted as `settings.json` in the app data dir. Missing keys take defaults,
+/// so older files keep loading as new sections are added.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    /// Name of the open library; `None` means the default one.
+    pub active_library: Option<String>,
+    pub io: IoSettings,
+    pub scan: ScanSettings,
+    pub embedding: EmbeddingSettings,
+    pub projection: ProjectionSettings,
+    pub clustering: ClusterSettings,
+    pub worker: WorkerSettings,
+    pub audio: AudioSettings,
+    pub shortcuts: ShortcutSettings,
+    pub osc: OscSettings,
+    pub http: HttpSettings,
+    pub autotag: AutoTagSettings,
+    /// Folders the user keeps their samples in, for one-click rescans.
+    pub library_roots: Vec<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AudioSettings {
+    /// Output device name as listed by `list_audio_devices`; `None` = the system default.
+    pub output_device: Option<String>,
+    /// Linear gain applied to previews; 1.0 = unchanged.
+    pub preview_gain: f32,
+    /// ffmpeg executable for files the built-in decoders can't read; `None` = look on PATH.
+    pub ffmpeg: Option<PathBuf>,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self { Self { output_device: None, preview_gain: 1.0, ffmpeg: None } }
+}
+
+/// Remote control over OSC (see `osc`); off unless enabled.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OscSettings {
+    pub enabled: bool,
+    /// Address to listen on; `0.0.0.0` to take messages from other machines.
+    pub bind: String,
+    /// UDP port to listen on.
+    pub port: u16,
+    /// `host:port` addresses that get now-playing messages, besides the last sender.
+    pub broadcast: Vec<String>,
+}
+
+impl Default for OscSettings {
+    fn default() -> Self { Self { enabled: false, bind: "127.0.0.1".into(), port: 9000, broadcast: Vec::new() } }
+}
+
+/// The HTTP API (see `http`); off unless enabled, and it won't start without a token.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HttpSettings {
+    pub enabled: bool,
+    /// Address to listen on; `0.0.0.0` to reach it from other machines.
+    pub bind: String,
+    pub port: u16,
+    pub token: Option<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self { Self { enabled: false, bind: "127.0.0.1".into(), port: 8765, token: None } }
+}
+
+/// System-wide key bindings, in the global-shortcut plugin's syntax ("CommandOrControl+Alt+Space").
+/// They fire whichever app has focus, so the defaults carry modifiers; `None` disables one.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ShortcutSettings {
+    /// Plays the last auditioned sample again.
+    pub replay: Option<String>,
+    pub stop: Option<String>,
+}
+
+impl Default for ShortcutSettings {
+    fn default() -> Self {
+        Self { replay: Some("CommandOrControl+Alt+Space".into()), stop: Some("CommandOrControl+Alt+Period".into()) }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IoSettings {
+    /// Number of threads reading file metadata/headers during a scan. 1 = sequential.
+    pub concurrency: usize,
+    /// Upper bound on files touched per second across all I/O threads; `None` = unthrottled.
+    pub max_files_per_sec: Option<f64>,
+    /// Fill in missing durations, hashes, waveforms and analyses while no job is running.
+    pub background_analysis: bool,
+}
+
+impl Default for IoSettings {
+    fn default() -> Self { Self { concurrency: 4, max_files_per_sec: None, background_analysis: true } }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ScanSettings {
+    /// How many queued scans may run at once. Values above 1 share the DB and worker.
+    pub max_parallel_jobs: usize,
+    /// File or folder names never scanned, case-insensitive; `*` and `?` are wildcards.
+    pub excludes: Vec<String>,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self { Self { max_parallel_jobs: 1, excludes: Vec::new() } }
+}
+
+/// Watchdog for the Python worker. A stage counts as hung when the worker prints nothing for its
+/// timeout; the model download at startup falls under the embedding one.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WorkerSettings {
+    pub embed_timeout_secs: u64,
+    pub project_timeout_secs: u64,
+    /// Extra attempts after a hang before the job fails.
+    pub retries: u32,
+    /// Interpreter, or venv/conda env directory, to run the worker with instead of the managed
+    /// venv; the `SMAP_PYTHON` environment variable overrides it.
+    pub python: Option<PathBuf>,
+}
+
+impl Default for WorkerSettings {
+    fn default() -> Self { Self { embed_timeout_secs: 600, project_timeout_secs: 1800, retries: 2, python: None } }
+}
+
+/// Where embeddings are computed.
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmbeddingBackend {
+    /// In-process ONNX when the build and model allow it, otherwise the Python worker.
+    #[default]
+    Auto,
+    Native,
+    Python,
+}
+
+/// Hardware embeddings are computed on, natively and in the worker.
+#[derive(Clone, Copy, Default, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Device {
+    /// The first GPU that works, else CPU.
+    #[default]
+    Auto,
+    Cpu,
+    Cuda,
+    /// Any DirectX 12 GPU on Windows.
+    #[serde(rename = "directml")]
+    DirectMl,
+}
+
+impl Device {
+    pub const ALL: [Device; 4] = [Device::Auto, Device::Cpu, Device::Cuda, Device::DirectMl];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Cpu => "cpu",
+            Self::Cuda => "cuda",
+            Self::DirectMl => "directml",
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct EmbeddingSettings {
+    pub backend: EmbeddingBackend,
+    /// CLAP audio encoder exported to ONNX; `None` = `<data_dir>/models/clap-audio.onnx`.
+    pub model_path: Option<PathBuf>,
+    /// Seconds of audio from the start of each file fed to the model.
+    pub clip_seconds: f64,
+    /// CLAP text encoder for prompt search; `None` = `<data_dir>/models/clap-text.onnx`.
+    pub text_model_path: Option<PathBuf>,
+    /// Its `tokenizer.json`; `None` = `<data_dir>/models/clap-tokenizer.json`.
+    pub tokenizer_path: Option<PathBuf>,
+    /// Encoding for newly stored vectors. f16 halves and int8 quarters the size of the
+    /// embeddings table at a small recall cost; `compress_embeddings` converts existing rows.
+    pub storage: Dtype,
+    /// Explicit choices fail if the device isn't usable; `Auto` falls back to CPU.
+    pub device: Device,
+    /// Clips decoded and run through the model together; `None` = sized to installed RAM.
+    pub batch_size: Option<usize>,
+    /// Inference threads; `None` = all cores but one.
+    pub threads: Option<usize>,
+}
+
+impl EmbeddingSettings {
+    /// `batch_size`, or a default to fit this machine: each clip costs a few MB of decoded audio
+    /// plus the model's activations, so small machines stay at a handful.
+    pub fn batch_size(&self) -> usize {
+        if let Some(n) = self.batch_size { return n.clamp(1, 1024); }
+        let mut sys = sysinfo::System::new();
+        sys.refresh_memory();
+        match sys.total_memory() / (1 << 30) {
+            0..=7 => 4,
+            8..=15 => 8,
+            16..=31 => 16,
+            _ => 32,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        if let Some(n) = self.threads { return n.max(1); }
+        // One core left for the UI and audio playback
+        std::thread::available_parallelism().map(|n| n.get().saturating_sub(1).max(1)).unwrap_or(1)
+    }
+}
+
+impl Default for EmbeddingSettings {
+    fn default() -> Self {
+        Self {
+            backend: EmbeddingBackend::Auto,
+            model_path: None,
+            clip_seconds: 10.0,
+            text_model_path: None,
+            tokenizer_path: None,
+            storage: Dtype::F32,
+            device: Device::Auto,
+            batch_size: None,
+            threads: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProjectionMetric {
+    Cosine,
+    Euclidean,
+    Manhattan,
+    Correlation,
+}
+
+impl ProjectionMetric {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Cosine => "cosine",
+            Self::Euclidean => "euclidean",
+            Self::Manhattan => "manhattan",
+            Self::Correlation => "correlation",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProjectionMethod {
+    Umap,
+    /// Near-instant linear projection; good for previews of huge libraries.
+    Pca,
+    /// Tighter clusters than UMAP, slower, and less faithful to global distances.
+    Tsne,
+}
+
+impl ProjectionMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Umap => "umap",
+            Self::Pca => "pca",
+            Self::Tsne => "tsne",
+        }
+    }
+}
+
+/// Parameters for the 2D layout. For UMAP, larger `n_neighbors` favours global structure and
+/// smaller `min_dist` packs clusters tighter; `perplexity` plays the neighbourhood role for t-SNE.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProjectionSettings {
+    pub method: ProjectionMethod,
+    pub n_neighbors: usize,
+    pub min_dist: f64,
+    pub metric: ProjectionMetric,
+    /// Same seed + same embeddings = same layout.
+    pub seed: u64,
+    pub perplexity: f64,
+    /// 2 for the flat map, 3 to also fill `z` for a 3D view.
+    pub components: u8,
+}
+
+impl Default for ProjectionSettings {
+    fn default() -> Self { Self { method: ProjectionMethod::Umap, n_neighbors: 50, min_dist: 0.05, metric: ProjectionMetric::Cosine, seed: 42, perplexity: 30.0, components: 2 } }
+}
+
+/// Zero-shot machine tags after each scan's embeddings (see `autotag`); needs the CLAP text model.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AutoTagSettings {
+    pub enabled: bool,
+    /// Tags to choose from; each is also the prompt, as "the sound of a <label>".
+    pub labels: Vec<String>,
+    /// Softmax share across `labels` needed before a tag is applied, 0-1.
+    pub min_confidence: f32,
+}
+
+impl Default for AutoTagSettings {
+    fn default() -> Self {
+        let labels = ["kick", "snare", "hi-hat", "clap", "tom", "cymbal", "shaker", "bass", "vocal", "synth", "pad", "guitar", "piano", "strings", "brass"];
+        Self { enabled: true, labels: labels.map(String::from).to_vec(), min_confidence: 0.5 }
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ClusterSettings {
+    /// Number of k-means clusters; `None` scales with library size.
+    pub k: Option<usize>,
+}
+
+fn settings_path(app: &tauri::AppHandle) -> Result<PathBuf> {
+    Ok(db::data_dir(app)?.join("settings.json"))
+}
+
+/// Falls back to defaults if the file is missing or unreadable rather than failing the caller.
+pub fn load(app: &tauri::AppHandle) -> Settings {
+    let Ok(p) = settings_path(app) else { return Settings::default() };
+    match std::fs::read_to_string(&p) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            log::warn!("settings: ignoring malformed {}: {e}", p.display());
+            Settings::default()
+        }),
+        Err(_) => Settings::default(),
+    }
+}
+
+/// Rejects values the rest of the app can't work with, before they're saved.
+pub fn validate(settings: &Settings) -> Result<()> {
+    if settings.io.concurrency == 0 { bail!("io.concurrency must be at least 1"); }
+    if settings.scan.max_parallel_jobs == 0 { bail!("scan.maxParallelJobs must be at least 1"); }
+    if !(0.0..=4.0).contains(&settings.audio.preview_gain) { bail!("audio.previewGain must be between 0 and 4"); }
+    if settings.embedding.batch_size == Some(0) { bail!("embedding.batchSize must be at least 1"); }
+    if settings.embedding.threads == Some(0) { bail!("embedding.threads must be at least 1"); }
+    if settings.scan.excludes.iter().any(|p| p.trim().is_empty()) { bail!("scan.excludes can't contain empty patterns"); }
+    if !(0.0..=1.0).contains(&settings.autotag.min_confidence) { bail!("autotag.minConfidence must be between 0 and 1"); }
+    if settings.autotag.labels.iter().any(|l| l.trim().is_empty()) { bail!("autotag.labels can't contain empty labels"); }
+    if let Some(ff) = &settings.audio.ffmpeg {
+        if !ff.is_file() { bail!("audio.ffmpeg: {} not found", ff.display()); }
+    }
+    if let Some(bad) = settings.osc.broadcast.iter().find(|a| a.parse::<std::net::SocketAddr>().is_err()) {
+        bail!("osc.broadcast: {bad:?} is not a host:port address");
+    }
+    Ok(())
+}
+
+pub fn save(app: &tauri::AppHandle, settings: &Settings) -> Result<()> {
+    let p = settings_path(app)?;
+    let text = serde_json::to_string_pretty(settings)?;
+    // Write-then-rename so a crash mid-write never leaves a truncated file
+    let tmp = p.with_extension("json.tmp");
+    std::fs::write(&tmp, text).with_context(|| format!("write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &p).with_context(|| format!("replace {}", p.display()))?;
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! SFZ instruments from a selection. Samples whose names carry a note ("Pad C#3.wav",
+//! "bass_A1") are laid out melodically around those roots, several on one note splitting the
+//! velocity range; otherwise each sample gets its own key from C1 up, like a drum kit.
+
+use crate::{
+    db,
+    export::{self, CopyMode},
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// MIDI note of C1, where kit mappings start.
+const KIT_START: u8 = 36;
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SfzOptions {
+    pub mode: CopyMode,
+    /// Forces one key per sample even when every name has a note.
+    pub kit: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SfzReport {
+    pub path: PathBuf,
+    pub regions: usize,
+    pub melodic: bool,
+}
+
+struct Region {
+    sample: String,
+    lokey: u8,
+    hikey: u8,
+    key_center: u8,
+    lovel: u8,
+    hivel: u8,
+}
+
+/// Writes `<dest>/<name>.sfz` with the samples copied (or linked) into `<dest>/samples`.
+pub fn export_sfz(conn: &Connection, file_ids: &[i64], dest: &Path, name: &str, opts: &SfzOptions) -> Result<SfzReport> {
+    let mut sources = Vec::with_capacity(file_ids.len());
+    for &id in file_ids {
+        if let Some(p) = db::file_path(conn, id)? { sources.push(p); }
+    }
+    if sources.is_empty() { bail!("no files to map"); }
+    let samples_dir = dest.join("samples");
+    std::fs::create_dir_all(&samples_dir).with_context(|| format!("create {}", samples_dir.display()))?;
+    let mut taken = HashSet::new();
+    let mut placed = Vec::with_capacity(sources.len());
+    for src in &sources {
+        let mut target = samples_dir.join(src.file_name().unwrap_or_default());
+        if target.exists() || taken.contains(&target) { target = export::free_name(&target, &taken); }
+        export::place(src, &target, opts.mode).with_context(|| format!("copy {}", src.display()))?;
+        let file = target.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default();
+        taken.insert(target);
+        placed.push((src, file));
+    }
+
+    let roots: Vec<Option<u8>> = sources.iter().map(|p| p.file_stem().and_then(|s| note_in_name(&s.to_string_lossy()))).collect();
+    let melodic = !opts.kit && roots.iter().all(Option::is_some);
+    let regions = if melodic {
+        let mut by_root: BTreeMap<u8, Vec<&str>> = BTreeMap::new();
+        for ((_, file), root) in placed.iter().zip(&roots) { by_root.entry(root.unwrap_or(60)).or_default().push(file); }
+        melodic_regions(&by_root)
+    } else {
+        if placed.len() > usize::from(128 - KIT_START) { bail!("a kit holds at most {} samples", 128 - KIT_START); }
+        placed
+            .iter()
+            .enumerate()
+            .map(|(i, (_, file))| {
+                let key = KIT_START + i as u8;
+                Region { sample: file.clone(), lokey: key, hikey: key, key_center: key, lovel: 1, hivel: 127 }
+            })
+            .collect()
+    };
+
+    let mut sfz = String::from("// Generated by Sample Map\n<control>\ndefault_path=samples/\n\n<group>\n");
+    if !melodic { sfz.push_str("loop_mode=one_shot\n"); }
+    for r in &regions {
+        writeln!(
+            sfz,
+            // `sample` goes last: its value runs to the end of the line, spaces included
+            "<region> lokey={} hikey={} pitch_keycenter={} lovel={} hivel={} sample={}",
+            r.lokey, r.hikey, r.key_center, r.lovel, r.hivel, r.sample
+        )?;
+    }
+    let path = dest.join(format!("{name}.sfz"));
+    std::fs::write(&path, sfz).with_context(|| format!("write {}", path.display()))?;
+    Ok(SfzReport { path, regions: regions.len(), melodic })
+}
+
+/// Each root covers the keys halfway to its neighbours; samples sharing a root split 1-127.
+fn melodic_regions(by_root: &BTreeMap<u8, Vec<&str>>) -> Vec<Region> {
+    let roots: Vec<u8> = by_root.keys().copied().collect();
+    let mut out = Vec::new();
+    for (i, (&root, files)) in by_root.iter().enumerate() {
+        let lokey = if i == 0 { 0 } else { (roots[i - 1] + root) / 2 + 1 };
+        let hikey = roots.get(i + 1).map_or(127, |&next| (root + next) / 2);
+        let layers = files.len();
+        for (j, file) in files.iter().enumerate() {
+            let lovel = (1 + j * 127 / layers) as u8;
+            let hivel = ((j + 1) * 127 / layers) as u8;
+            out.push(Region { sample: file.to_string(), lokey, hikey, key_center: root, lovel, hivel });
+        }
+    }
+    out
+}
+
+/// MIDI note for the first note name in `name` that stands apart from the letters around it:
+/// "C3", "f#2", "Bb-1". Middle C is C3, as most samplers and packs label it.
+fn note_in_name(name: &str) -> Option<u8> {
+    let chars: Vec<char> = name.chars().collect();
+    (0..chars.len()).find_map(|i| {
+        if i > 0 && chars[i - 1].is_ascii_alphabetic() { return None; }
+        let semitone = match chars[i].to_ascii_uppercase() {
+            'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' => 11,
+            _ => return None,
+        };
+        let mut j = i + 1;
+        let accidental = match chars.get(j) {
+            Some('#') => { j += 1; 1 }
+            Some('b') => { j += 1; -1 }
+            _ => 0,
+        };
+        let negative = chars.get(j) == Some(&'-');
+        if negative { j += 1; }
+        let octave = chars.get(j)?.to_digit(10)? as i32;
+        if chars.get(j + 1).is_some_and(|c| c.is_ascii_alphanumeric()) { return None; }
+        let octave = if negative { -octave } else { octave };
+        let midi = (octave + 2) * 12 + semitone + accidental;
+        u8::try_from(midi).ok().filter(|&m| m <= 127)
+    })
+}
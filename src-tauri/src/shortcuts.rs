@@ -0,0 +1,28 @@
+//! Global play/stop bindings, so auditioning works while focus is in a text field or another
+//! window.
+
+use crate::{settings::ShortcutSettings, AppState};
+use anyhow::{anyhow, Result};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut, ShortcutState};
+
+/// Replaces whatever bindings are registered with `cfg`'s. On a bad or already-taken binding
+/// the ones before it stay registered and the error names it.
+pub fn apply(app: &AppHandle, cfg: &ShortcutSettings) -> Result<()> {
+    let gs = app.global_shortcut();
+    gs.unregister_all()?;
+    let replay = |app: &AppHandle| {
+        if let Err(e) = app.state::<AppState>().audio.replay() { log::warn!("shortcut: replay failed: {e}"); }
+    };
+    let stop = |app: &AppHandle| app.state::<AppState>().audio.stop();
+    let bindings: [(&Option<String>, fn(&AppHandle)); 2] = [(&cfg.replay, replay), (&cfg.stop, stop)];
+    for (binding, action) in bindings {
+        let Some(binding) = binding else { continue };
+        let shortcut: Shortcut = binding.parse().map_err(|e| anyhow!("invalid shortcut {binding:?}: {e}"))?;
+        gs.on_shortcut(shortcut, move |app, _, event| {
+            if event.state == ShortcutState::Pressed { action(app); }
+        })
+        .map_err(|e| anyhow!("can't bind {binding:?}: {e}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::{ann::AnnCache, quant};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// 1 - cosine similarity; 0 = same direction, 2 = opposite. Zero vectors are maximally far.
+pub fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut na, mut nb) = (0f32, 0f32, 0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 { return 2.0; }
+    1.0 - dot / (na.sqrt() * nb.sqrt())
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Neighbor {
+    pub file_id: i64,
+    pub distance: f32,
+    /// Absent for files that haven't been projected yet.
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+/// Below this many embeddings an exact scan is fast enough and beats the index on recall.
+const EXACT_MAX: i64 = 20_000;
+
+/// The `k` files whose embeddings are closest to `file_id`'s, nearest first. Hidden files are
+/// skipped and so is the file itself.
+pub fn find_similar(conn: &Connection, ann: &AnnCache, db: &Path, file_id: i64, k: usize) -> Result<Vec<Neighbor>> {
+    nearest(conn, ann, db, &embedding(conn, file_id)?, Some(file_id), k)
+}
+
+fn embedding(conn: &Connection, file_id: i64) -> Result<Vec<f32>> {
+    let row: Option<(Vec<u8>, String)> = conn
+        .query_row("SELECT vec, dtype FROM active_embeddings WHERE file_id = ?", params![file_id], |r| Ok((r.get(0)?, r.get(1)?)))
+        .optional()?;
+    let Some((blob, dtype)) = row else { bail!("file {file_id} has no embedding yet") };
+    Ok(quant::decode_row(&blob, &dtype))
+}
+
+/// Candidates fetched per step; enough to skip samples earlier steps already used.
+const PATH_CANDIDATES: usize = 8;
+
+/// A morph from `file_a` to `file_b`: walks the straight line between their embeddings in
+/// `steps` equal intervals and takes the nearest real sample at each point, never repeating one.
+/// Starts at `file_a` and ends at `file_b` (when they're visible).
+pub fn interpolate_path(conn: &Connection, ann: &AnnCache, db: &Path, file_a: i64, file_b: i64, steps: usize) -> Result<Vec<Neighbor>> {
+    let (a, b) = (embedding(conn, file_a)?, embedding(conn, file_b)?);
+    if a.len() != b.len() { bail!("files {file_a} and {file_b} were embedded by different models"); }
+    let steps = steps.max(1);
+    let mut used = std::collections::HashSet::new();
+    let mut path = Vec::with_capacity(steps + 1);
+    for i in 0..=steps {
+        let t = i as f32 / steps as f32;
+        let q: Vec<f32> = a.iter().zip(&b).map(|(x, y)| x + (y - x) * t).collect();
+        let hit = nearest(conn, ann, db, &q, None, PATH_CANDIDATES)?.into_iter().find(|n| !used.contains(&n.file_id));
+        if let Some(n) = hit {
+            used.insert(n.file_id);
+            path.push(n);
+        }
+    }
+    Ok(path)
+}
+
+/// The `k` visible files nearest to `query` in embedding space, except `exclude`. Large
+/// libraries go through the HNSW index for `db`.
+pub fn nearest(conn: &Connection, ann: &AnnCache, db: &Path, query: &[f32], exclude: Option<i64>, k: usize) -> Result<Vec<Neighbor>> {
+    let total: i64 = conn.query_row("SELECT COUNT(*) FROM active_embeddings", [], |r| r.get(0))?;
+    let scored = if total <= EXACT_MAX { exact(conn, query, exclude, k)? } else { approximate(conn, ann, db, query, exclude, k, total)? };
+
+    let mut coords = conn.prepare("SELECT x, y FROM coords WHERE file_id = ?")?;
+    scored
+        .into_iter()
+        .map(|(id, distance)| {
+            let xy: Option<(f64, f64)> = coords.query_row(params![id], |r| Ok((r.get(0)?, r.get(1)?))).optional()?;
+            Ok(Neighbor { file_id: id, distance, x: xy.map(|c| c.0 as f32), y: xy.map(|c| c.1 as f32) })
+        })
+        .collect()
+}
+
+fn exact(conn: &Connection, query: &[f32], exclude: Option<i64>, k: usize) -> Result<Vec<(i64, f32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT e.file_id, e.vec, e.dtype FROM active_embeddings e JOIN files f ON f.id = e.file_id WHERE f.hidden = 0 AND (?1 IS NULL OR e.file_id <> ?1)",
+    )?;
+    let mut rows = stmt.query(params![exclude])?;
+    let mut scored: Vec<(i64, f32)> = Vec::new();
+    while let Some(r) = rows.next()? {
+        // Decode straight from the row's blob rather than copying it out first
+        let v = quant::decode_row(r.get_ref(1)?.as_blob()?, r.get_ref(2)?.as_str()?);
+        // Vectors from a different model dimension aren't comparable
+        if v.len() != query.len() { continue; }
+        scored.push((r.get(0)?, cosine_distance(query, &v)));
+    }
+    scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+    scored.truncate(k);
+    Ok(scored)
+}
+
+/// Index lookup, widening the candidate set until `k` survive the hidden/self filter.
+fn approximate(conn: &Connection, ann: &AnnCache, db: &Path, query: &[f32], exclude: Option<i64>, k: usize, total: i64) -> Result<Vec<(i64, f32)>> {
+    let mut hidden = conn.prepare("SELECT hidden FROM files WHERE id = ?")?;
+    let mut fetch = (k + 1) * 2;
+    loop {
+        let hits = ann.with(conn, db, |idx| idx.search(query, fetch, fetch.max(64)))?;
+        let exhausted = hits.len() < fetch || fetch as i64 >= total;
+        let mut kept = Vec::with_capacity(k);
+        for (id, d) in hits {
+            if Some(id) == exclude { continue; }
+            let h: Option<bool> = hidden.query_row(params![id], |r| r.get(0)).optional()?;
+            if h == Some(false) { kept.push((id, d)); }
+            if kept.len() == k { break; }
+        }
+        if kept.len() == k || exhausted { return Ok(kept); }
+        fetch *= 4;
+    }
+}
+
+/// The `k` visible files closest to `(x, y)` on the active layout, by Euclidean distance in map
+/// space. Searches a growing square in the R*Tree until it holds `k` points inside the circle it
+/// bounds, so dense areas stay cheap and sparse ones still get an answer.
+pub fn nearest_to_point(conn: &Connection, x: f64, y: f64, k: usize, include_hidden: bool) -> Result<Vec<Neighbor>> {
+    let total: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM coords c JOIN files f ON f.id = c.file_id WHERE ?1 OR f.hidden = 0",
+        params![include_hidden],
+        |r| r.get(0),
+    )?;
+    let k = k.min(total as usize);
+    if k == 0 { return Ok(Vec::new()); }
+    let mut stmt = conn.prepare(
+        "SELECT c.file_id, c.x, c.y FROM coords_rtree r JOIN coords c ON c.file_id = r.id JOIN files f ON f.id = c.file_id \
+         WHERE r.min_x <= ?1 + ?3 AND r.max_x >= ?1 - ?3 AND r.min_y <= ?2 + ?3 AND r.max_y >= ?2 - ?3 AND (?4 OR f.hidden = 0)",
+    )?;
+    // Layouts are normalized to [-1, 1]; start around the spacing k points would have
+    let mut radius = (4.0 * k as f64 / total as f64).sqrt().max(1e-4);
+    loop {
+        let mut hits: Vec<(i64, f64, f64, f64)> = stmt
+            .query_map(params![x, y, radius, include_hidden], |r| {
+                let (px, py): (f64, f64) = (r.get(1)?, r.get(2)?);
+                Ok((r.get(0)?, ((px - x).powi(2) + (py - y).powi(2)).sqrt(), px, py))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let inside = hits.iter().filter(|h| h.1 <= radius).count();
+        // Once the square holds every point, nothing further out exists
+        if inside >= k || hits.len() as i64 >= total {
+            hits.sort_by(|a, b| a.1.total_cmp(&b.1));
+            hits.truncate(k);
+            return Ok(hits
+                .into_iter()
+                .map(|(file_id, d, px, py)| Neighbor { file_id, distance: d as f32, x: Some(px as f32), y: Some(py as f32) })
+                .collect());
+        }
+        radius *= 2.0;
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchedBy {
+    Id,
+    Path,
+    /// A copy of a library file stored elsewhere.
+    Hash,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayoutPosition {
+    pub layout_id: i64,
+    pub name: String,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+    pub file_id: i64,
+    pub matched_by: MatchedBy,
+    /// On the active layout; absent until the file is projected.
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub cluster_id: Option<i64>,
+    /// Every stored layout the file has a position in.
+    pub layouts: Vec<LayoutPosition>,
+    /// Nearest in embedding space; empty until the file is embedded.
+    pub neighbors: Vec<Neighbor>,
+}
+
+/// Where a file sits in the library: by id, by path (however it's spelled), or for a file
+/// outside the library, by a library copy with the same content. `None` when none of those
+/// match.
+pub fn locate(conn: &Connection, ann: &AnnCache, db: &Path, file_id: Option<i64>, path: Option<&Path>, k: usize) -> Result<Option<Location>> {
+    let exists = |id: i64| -> Result<bool> { Ok(conn.query_row("SELECT 1 FROM files WHERE id = ?", params![id], |_| Ok(())).optional()?.is_some()) };
+    let found = match (file_id, path) {
+        (Some(id), _) => exists(id)?.then_some((id, MatchedBy::Id)),
+        (None, Some(p)) => {
+            let canonical = crate::paths::canonical(p);
+            let by_path: Option<i64> =
+                conn.query_row("SELECT id FROM files WHERE path = ?", params![crate::db::SqlPath(&canonical)], |r| r.get(0)).optional()?;
+            match by_path {
+                Some(id) => Some((id, MatchedBy::Path)),
+                None => match crate::scan::content_hash(p) {
+                    Ok(hash) => conn
+                        .query_row("SELECT id FROM files WHERE content_hash = ? ORDER BY hidden, id LIMIT 1", params![hash], |r| r.get(0))
+                        .optional()?
+                        .map(|id| (id, MatchedBy::Hash)),
+                    Err(_) => None,
+                },
+            }
+        }
+        (None, None) => bail!("expected a fileId or path"),
+    };
+    let Some((id, matched_by)) = found else { return Ok(None) };
+
+    let (xy, cluster_id): (Option<(f64, f64)>, Option<i64>) = conn.query_row(
+        "SELECT c.x, c.y, f.cluster_id FROM files f LEFT JOIN coords c ON c.file_id = f.id WHERE f.id = ?",
+        params![id],
+        |r| Ok((r.get::<_, Option<f64>>(0)?.zip(r.get::<_, Option<f64>>(1)?), r.get(2)?)),
+    )?;
+    let layouts = {
+        let mut stmt = conn.prepare(
+            "SELECT c.layout_id, l.name, c.x, c.y FROM layout_coords c JOIN layouts l ON l.id = c.layout_id WHERE c.file_id = ? ORDER BY c.layout_id DESC",
+        )?;
+        let rows = stmt
+            .query_map(params![id], |r| {
+                Ok(LayoutPosition { layout_id: r.get(0)?, name: r.get(1)?, x: r.get::<_, f64>(2)? as f32, y: r.get::<_, f64>(3)? as f32 })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+    let neighbors = match embedding(conn, id) {
+        Ok(q) => nearest(conn, ann, db, &q, Some(id), k)?,
+        Err(_) => Vec::new(),
+    };
+    Ok(Some(Location {
+        file_id: id,
+        matched_by,
+        x: xy.map(|c| c.0 as f32),
+        y: xy.map(|c| c.1 as f32),
+        cluster_id,
+        layouts,
+        neighbors,
+    }))
+}
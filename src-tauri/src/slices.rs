@@ -0,0 +1,182 @@
+//! Loops cut at their transients into one-shots that behave like any other file. Each slice is
+//! written once as a WAV next to the library (`<library>-slices/<parent id>/`) and gets its own
+//! `files` row pointing back at the parent with its offsets, so playback, embedding, the map
+//! and exports need nothing special; re-slicing a file replaces its slices.
+
+use crate::{
+    convert,
+    db::{self, FileRow, SqlPath, StoredPath},
+    playback,
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+
+const HOP_SECONDS: f64 = 0.005;
+/// Slices start this far ahead of their transient so the attack isn't clipped.
+const PRE_ROLL_SECONDS: f64 = 0.002;
+/// Fade at each slice's end, against clicks where the next hit is cut off.
+const FADE_SECONDS: f64 = 0.003;
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SliceOptions {
+    /// Standard deviations above the mean onset strength a transient needs; lower = more slices.
+    pub sensitivity: f64,
+    /// Transients closer than this to the previous one are merged into its slice.
+    pub min_slice_ms: f64,
+    pub max_slices: usize,
+}
+
+impl Default for SliceOptions {
+    fn default() -> Self { Self { sensitivity: 1.5, min_slice_ms: 80.0, max_slices: 128 } }
+}
+
+impl SliceOptions {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=10.0).contains(&self.sensitivity) { bail!("sensitivity must be between 0 and 10"); }
+        if !(5.0..=10_000.0).contains(&self.min_slice_ms) { bail!("minimum slice length must be between 5 ms and 10 s"); }
+        if self.max_slices < 2 { bail!("at least 2 slices are needed"); }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SliceReport {
+    pub added: Vec<i64>,
+    /// Slices of an earlier run that these replaced.
+    pub removed: Vec<i64>,
+}
+
+/// Where slices of files in the library at `dbfile` are written.
+pub fn slices_dir(dbfile: &Path) -> PathBuf {
+    let stem = dbfile.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    dbfile.with_file_name(format!("{stem}-slices"))
+}
+
+pub fn slice_file(conn: &mut Connection, dbfile: &Path, file_id: i64, opts: &SliceOptions) -> Result<SliceReport> {
+    let parent: Option<(PathBuf, String, Option<u16>, Option<i64>)> = conn
+        .query_row("SELECT path, name, bits_per_sample, parent_id FROM files WHERE id = ?", [file_id], |r| {
+            Ok((r.get::<_, StoredPath>(0)?.0, r.get(1)?, r.get(2)?, r.get(3)?))
+        })
+        .optional()?;
+    let Some((path, name, bits, grandparent)) = parent else { bail!("no file with id {file_id}") };
+    if grandparent.is_some() { bail!("{name} is already a slice"); }
+    let (channels, rate, data) = playback::decode_samples(&path).with_context(|| format!("decode {}", path.display()))?;
+    let planes = convert::deinterleave(&data, usize::from(channels.max(1)));
+    let frames = planes.first().map_or(0, Vec::len);
+    let bounds = slice_points(&planes, rate, opts);
+    if bounds.len() < 2 { bail!("no transients found in {name}"); }
+
+    let removed = remove_slices(conn, dbfile, file_id)?;
+    let dir = slices_dir(dbfile).join(file_id.to_string());
+    std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let stem = Path::new(&name).file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| name.clone());
+    let bits = match bits { Some(b @ (16 | 24 | 32)) => b, _ => 24 };
+    let fade = (f64::from(rate) * FADE_SECONDS) as usize;
+    let mut added = Vec::with_capacity(bounds.len());
+    for (n, (&start, end)) in bounds.iter().zip(bounds.iter().skip(1).copied().chain([frames])).enumerate() {
+        if end <= start { continue; }
+        let mut slice: Vec<Vec<f32>> = planes.iter().map(|p| p[start..end].to_vec()).collect();
+        for p in &mut slice {
+            let len = p.len();
+            for (i, v) in p.iter_mut().skip(len.saturating_sub(fade)).enumerate() { *v *= 1.0 - (i as f32 + 1.0) / fade as f32; }
+        }
+        let slice_name = format!("{stem} slice {:02}.wav", n + 1);
+        let target = dir.join(&slice_name);
+        convert::write_wav(&target, &slice, rate, bits)?;
+        let meta = std::fs::metadata(&target)?;
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs() as i64);
+        let (from, to) = (start as f64 / f64::from(rate), end as f64 / f64::from(rate));
+        db::upsert_file(
+            conn,
+            &FileRow {
+                path: &target,
+                name: &slice_name,
+                size_bytes: meta.len() as i64,
+                duration: Some(to - from),
+                mtime,
+                sample_rate: Some(rate),
+                bits_per_sample: Some(bits),
+                channels: Some(channels),
+            },
+        )?;
+        let id: i64 = conn.query_row(
+            "UPDATE files SET parent_id = ?, slice_start = ?, slice_end = ? WHERE path = ? RETURNING id",
+            params![file_id, from, to, SqlPath(&target)],
+            |r| r.get(0),
+        )?;
+        added.push(id);
+    }
+    Ok(SliceReport { added, removed })
+}
+
+/// Deletes a file's slices, rows and WAVs. Returns the removed ids.
+pub fn remove_slices(conn: &mut Connection, dbfile: &Path, file_id: i64) -> Result<Vec<i64>> {
+    let ids: Vec<i64> = {
+        let mut stmt = conn.prepare("SELECT id FROM files WHERE parent_id = ?")?;
+        let ids = stmt.query_map([file_id], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+        ids
+    };
+    db::delete_files(conn, &ids)?;
+    let dir = slices_dir(dbfile).join(file_id.to_string());
+    if dir.exists() { std::fs::remove_dir_all(&dir).with_context(|| format!("remove {}", dir.display()))?; }
+    Ok(ids)
+}
+
+/// Slices of any of `parents`.
+pub fn slices_of(conn: &Connection, parents: &[i64]) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare("SELECT id FROM files WHERE parent_id = ?")?;
+    let mut ids = Vec::new();
+    for p in parents {
+        for id in stmt.query_map([p], |r| r.get(0))? { ids.push(id?); }
+    }
+    Ok(ids)
+}
+
+/// [`remove_slices`] for every one of `parents` that has slices, for when the parents leave the
+/// library: nothing else would clean up rows and WAVs that live outside every root.
+pub fn remove_slices_of(conn: &mut Connection, dbfile: &Path, parents: &[i64]) -> Result<Vec<i64>> {
+    let sliced: Vec<i64> = {
+        let mut stmt = conn.prepare("SELECT DISTINCT parent_id FROM files WHERE parent_id IS NOT NULL")?;
+        let ids = stmt.query_map([], |r| r.get(0))?.collect::<rusqlite::Result<Vec<i64>>>()?;
+        ids.into_iter().filter(|id| parents.contains(id)).collect()
+    };
+    let mut removed = Vec::new();
+    for p in sliced { removed.extend(remove_slices(conn, dbfile, p)?); }
+    Ok(removed)
+}
+
+/// Start frames of the slices: 0, then each transient (less the pre-roll) that is strong enough
+/// and far enough from the last.
+fn slice_points(planes: &[Vec<f32>], rate: u32, opts: &SliceOptions) -> Vec<usize> {
+    let rate_f = f64::from(rate);
+    let hop = ((rate_f * HOP_SECONDS) as usize).max(1);
+    let frames = planes.first().map_or(0, Vec::len);
+    // Onset strength: rise in log energy of the differenced signal, summed over channels
+    let mut flux = Vec::with_capacity(frames / hop + 1);
+    let mut prev = None;
+    for start in (1..frames).step_by(hop) {
+        let end = (start + hop).min(frames);
+        let energy: f64 = planes.iter().map(|p| (start..end).map(|i| f64::from(p[i] - p[i - 1]).powi(2)).sum::<f64>()).sum();
+        let energy = (energy + 1e-10).ln();
+        flux.push(prev.map_or(0.0, |p: f64| (energy - p).max(0.0)));
+        prev = Some(energy);
+    }
+    if flux.len() < 3 { return vec![0]; }
+    let mean = flux.iter().sum::<f64>() / flux.len() as f64;
+    let std = (flux.iter().map(|f| (f - mean).powi(2)).sum::<f64>() / flux.len() as f64).sqrt();
+    let threshold = mean + opts.sensitivity * std;
+    let min_gap = ((opts.min_slice_ms / 1000.0) * rate_f) as usize;
+    let pre_roll = (PRE_ROLL_SECONDS * rate_f) as usize;
+
+    let mut points = vec![0];
+    for i in 1..flux.len() - 1 {
+        if points.len() >= opts.max_slices { break; }
+        if flux[i] < threshold || flux[i] < flux[i - 1] || flux[i] < flux[i + 1] { continue; }
+        let at = (1 + i * hop).saturating_sub(pre_roll);
+        if at >= points.last().copied().unwrap_or(0) + min_gap && frames - at >= min_gap { points.push(at); }
+    }
+    points
+}
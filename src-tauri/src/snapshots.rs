@@ -0,0 +1,133 @@
+//! Named copies of the library's file list (path, size, mtime, hash) at a point in time, and
+//! what changed between two of them: for checking a restored backup, or seeing what a new pack
+//! actually added.
+
+use crate::db::StoredPath;
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{collections::HashMap, path::PathBuf};
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub id: i64,
+    pub name: String,
+    pub created_at: i64,
+    pub file_count: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Moved {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    /// Gone from one path and present at another: same content hash, or for unhashed files the
+    /// same name and size.
+    pub moved: Vec<Moved>,
+    /// Same path, different size, mtime or hash.
+    pub changed: Vec<PathBuf>,
+}
+
+/// Records the current file list under `name`, which must be new.
+pub fn create(conn: &mut Connection, name: &str) -> Result<Snapshot> {
+    let name = name.trim();
+    if name.is_empty() { bail!("snapshot name is empty"); }
+    let tx = conn.transaction()?;
+    let taken: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM snapshots WHERE name = ?)", params![name], |r| r.get(0))?;
+    if taken { bail!("a snapshot named {name:?} already exists"); }
+    let created_at = crate::scan::now_secs();
+    tx.execute("INSERT INTO snapshots(name, created_at) VALUES(?, ?)", params![name, created_at])?;
+    let id = tx.last_insert_rowid();
+    let file_count = tx.execute(
+        "INSERT INTO snapshot_files(snapshot_id, path, name, size_bytes, mtime, content_hash) \
+         SELECT ?, path, name, size_bytes, mtime, content_hash FROM files",
+        params![id],
+    )? as i64;
+    tx.commit()?;
+    Ok(Snapshot { id, name: name.to_string(), created_at, file_count })
+}
+
+/// Newest first.
+pub fn list(conn: &Connection) -> Result<Vec<Snapshot>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.name, s.created_at, (SELECT COUNT(*) FROM snapshot_files f WHERE f.snapshot_id = s.id) \
+         FROM snapshots s ORDER BY s.id DESC",
+    )?;
+    let rows = stmt
+        .query_map([], |r| Ok(Snapshot { id: r.get(0)?, name: r.get(1)?, created_at: r.get(2)?, file_count: r.get(3)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Returns false if there's no such snapshot.
+pub fn delete(conn: &Connection, id: i64) -> Result<bool> {
+    conn.execute("DELETE FROM snapshot_files WHERE snapshot_id = ?", params![id])?;
+    Ok(conn.execute("DELETE FROM snapshots WHERE id = ?", params![id])? > 0)
+}
+
+struct Entry {
+    path: PathBuf,
+    name: String,
+    size_bytes: i64,
+    mtime: i64,
+    hash: Option<String>,
+}
+
+fn entries(conn: &Connection, id: i64) -> Result<HashMap<PathBuf, Entry>> {
+    let exists = conn.query_row("SELECT 1 FROM snapshots WHERE id = ?", params![id], |_| Ok(())).optional()?;
+    if exists.is_none() { bail!("no snapshot with id {id}"); }
+    let mut stmt = conn.prepare("SELECT path, name, size_bytes, mtime, content_hash FROM snapshot_files WHERE snapshot_id = ?")?;
+    let rows = stmt.query_map(params![id], |r| {
+        Ok(Entry { path: r.get::<_, StoredPath>(0)?.0, name: r.get(1)?, size_bytes: r.get(2)?, mtime: r.get(3)?, hash: r.get(4)? })
+    })?;
+    let mut out = HashMap::new();
+    for e in rows {
+        let e = e?;
+        out.insert(e.path.clone(), e);
+    }
+    Ok(out)
+}
+
+/// What happened between snapshot `a` and the later snapshot `b`. Lists are sorted by path.
+pub fn diff(conn: &Connection, a: i64, b: i64) -> Result<SnapshotDiff> {
+    let (before, after) = (entries(conn, a)?, entries(conn, b)?);
+    let mut out = SnapshotDiff::default();
+    let mut removed: Vec<&Entry> = Vec::new();
+    for (path, old) in &before {
+        match after.get(path) {
+            Some(new) => {
+                let rehashed = matches!((&old.hash, &new.hash), (Some(x), Some(y)) if x != y);
+                if old.size_bytes != new.size_bytes || old.mtime != new.mtime || rehashed { out.changed.push(path.clone()); }
+            }
+            None => removed.push(old),
+        }
+    }
+    // Pair each removal with an addition of the same content, at most once each
+    let key = |e: &Entry| match &e.hash {
+        Some(h) => format!("h:{h}"),
+        None => format!("n:{}:{}", e.name, e.size_bytes),
+    };
+    let mut added: HashMap<String, Vec<&Entry>> = HashMap::new();
+    for (path, new) in &after {
+        if !before.contains_key(path) { added.entry(key(new)).or_default().push(new); }
+    }
+    for old in removed {
+        match added.get_mut(&key(old)).and_then(|c| c.pop()) {
+            Some(new) => out.moved.push(Moved { from: old.path.clone(), to: new.path.clone() }),
+            None => out.removed.push(old.path.clone()),
+        }
+    }
+    out.added = added.into_values().flatten().map(|e| e.path.clone()).collect();
+    out.added.sort();
+    out.removed.sort();
+    out.changed.sort();
+    out.moved.sort_by(|x, y| x.from.cmp(&y.from));
+    Ok(out)
+}
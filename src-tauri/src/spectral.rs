@@ -0,0 +1,135 @@
+//! Cheap spectral descriptors that don't depend on the learned embedding: centroid ("brightness")
+//! and 85% rolloff in Hz, flatness (0 = tonal, 1 = noise-like) and zero-crossing rate per second.
+//! Frame values are averaged over the frames within 60 dB of the loudest, so tails and silence
+//! don't drag them down.
+
+use crate::{
+    db::StoredPath,
+    playback,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::path::PathBuf;
+
+const FFT_SIZE: usize = 2048;
+const ROLLOFF: f64 = 0.85;
+/// Only the start of long files is analysed.
+const MAX_SECONDS: f64 = 30.0;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Descriptors {
+    pub centroid: f64,
+    pub rolloff: f64,
+    pub flatness: f64,
+    pub zcr: f64,
+}
+
+/// A descriptor to colour map points by.
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Feature {
+    Brightness,
+    Rolloff,
+    Flatness,
+    ZeroCrossings,
+}
+
+impl Feature {
+    /// Column on `files`; fixed, so safe to format into SQL.
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Brightness => "spectral_centroid",
+            Self::Rolloff => "spectral_rolloff",
+            Self::Flatness => "spectral_flatness",
+            Self::ZeroCrossings => "zero_crossing_rate",
+        }
+    }
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectralSummary {
+    pub analyzed: usize,
+    pub failed: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// Describes files not described yet; with `force`, all of them. Silent files get zeros.
+pub fn analyze(conn: &Connection, force: bool, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<SpectralSummary> {
+    let todo: Vec<(i64, PathBuf)> = {
+        let mut stmt = conn.prepare("SELECT id, path FROM files WHERE spectral_centroid IS NULL OR ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![force], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0)))?.collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let total = todo.len();
+    let mut summary = SpectralSummary::default();
+    for (i, (id, path)) in todo.into_iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "spectral".into(), processed: i, total, batch: None });
+        let d = match playback::decode_samples(&path) {
+            Ok((channels, rate, data)) => {
+                let ch = usize::from(channels.max(1));
+                let mono: Vec<f32> = data.chunks(ch).map(|f| f.iter().sum::<f32>() / ch as f32).collect();
+                describe(&mono, rate)
+            }
+            Err(e) => {
+                log::warn!("spectral: {}: {e:#}", path.display());
+                summary.failed += 1;
+                continue;
+            }
+        };
+        conn.execute(
+            "UPDATE files SET spectral_centroid = ?, spectral_rolloff = ?, spectral_flatness = ?, zero_crossing_rate = ? WHERE id = ?",
+            params![d.centroid, d.rolloff, d.flatness, d.zcr, id],
+        )?;
+        summary.analyzed += 1;
+        summary.file_ids.push(id);
+    }
+    on_progress(&Progress { stage: "spectral".into(), processed: total, total, batch: None });
+    Ok(summary)
+}
+
+pub fn describe(samples: &[f32], rate: u32) -> Descriptors {
+    let rate_f = f64::from(rate);
+    let samples = &samples[..samples.len().min((MAX_SECONDS * rate_f) as usize)];
+    if samples.is_empty() { return Descriptors::default(); }
+    let crossings = samples.windows(2).filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0)).count();
+    let zcr = crossings as f64 * rate_f / samples.len() as f64;
+
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+    let window: Vec<f32> = (0..FFT_SIZE).map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / FFT_SIZE as f32).cos()).collect();
+    let bin_hz = rate_f / FFT_SIZE as f64;
+    let mut buf = vec![Complex::new(0.0f32, 0.0); FFT_SIZE];
+    // (energy, centroid, rolloff, flatness) per frame; short files are zero-padded into one frame
+    let mut frames: Vec<(f64, f64, f64, f64)> = Vec::new();
+    let mut start = 0;
+    loop {
+        for (i, b) in buf.iter_mut().enumerate() { *b = Complex::new(samples.get(start + i).copied().unwrap_or(0.0) * window[i], 0.0); }
+        fft.process(&mut buf);
+        let power: Vec<f64> = buf[1..FFT_SIZE / 2].iter().map(|c| f64::from(c.norm_sqr())).collect();
+        let energy: f64 = power.iter().sum();
+        if energy > 0.0 {
+            let centroid = power.iter().enumerate().map(|(k, p)| (k + 1) as f64 * bin_hz * p).sum::<f64>() / energy;
+            let mut acc = 0.0;
+            let rolloff_bin = power.iter().position(|p| { acc += p; acc >= ROLLOFF * energy }).unwrap_or(power.len() - 1);
+            let log_mean = power.iter().map(|p| (p + 1e-20).ln()).sum::<f64>() / power.len() as f64;
+            let flatness = log_mean.exp() / (energy / power.len() as f64);
+            frames.push((energy, centroid, (rolloff_bin + 1) as f64 * bin_hz, flatness.clamp(0.0, 1.0)));
+        }
+        start += FFT_SIZE / 2;
+        if start + FFT_SIZE > samples.len() { break; }
+    }
+    let loudest = frames.iter().map(|f| f.0).fold(0.0, f64::max);
+    let kept: Vec<_> = frames.iter().filter(|f| f.0 >= loudest * 1e-6).collect();
+    if kept.is_empty() { return Descriptors { zcr, ..Default::default() }; }
+    let n = kept.len() as f64;
+    Descriptors {
+        centroid: kept.iter().map(|f| f.1).sum::<f64>() / n,
+        rolloff: kept.iter().map(|f| f.2).sum::<f64>() / n,
+        flatness: kept.iter().map(|f| f.3).sum::<f64>() / n,
+        zcr,
+    }
+}
@@ -0,0 +1,137 @@
+//! Native tempo estimation for loops: an onset-strength envelope (energy flux of the
+//! first-differenced signal, so transients dominate), autocorrelated over 60-200 BPM lags with
+//! a mild preference for tempos near 120. Loops are usually cut to whole bars, so an estimate
+//! within a few percent of a bar-aligned tempo for the file's length snaps to it.
+
+use crate::{
+    db::StoredPath,
+    playback,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+use std::path::PathBuf;
+
+/// Shorter files are one-shots as far as tempo is concerned.
+pub const MIN_SECONDS: f64 = 3.0;
+/// Below this the estimate is stored as a confidence only, without a BPM.
+const MIN_CONFIDENCE: f64 = 0.2;
+/// Only the start of long files is analysed.
+const MAX_SECONDS: f64 = 60.0;
+const HOP_SECONDS: f64 = 0.005;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+/// How far an estimate may be from a bar-aligned tempo and still snap to it.
+const SNAP_TOLERANCE: f64 = 0.04;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tempo {
+    pub bpm: f64,
+    /// Normalised autocorrelation at the chosen lag, 0-1.
+    pub confidence: f64,
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoSummary {
+    pub analyzed: usize,
+    pub detected: usize,
+    pub failed: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// Estimates files of at least [`MIN_SECONDS`] that have no tempo yet; with `force`, also
+/// redoes earlier estimates. BPMs imported from other tools are never touched.
+pub fn analyze(conn: &Connection, force: bool, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<TempoSummary> {
+    let todo: Vec<(i64, PathBuf, f64)> = {
+        let mut stmt = conn.prepare(
+            "SELECT id, path, duration FROM files WHERE duration >= ?1 \
+             AND ((bpm IS NULL AND bpm_confidence IS NULL) OR (?2 AND bpm_confidence IS NOT NULL)) ORDER BY id",
+        )?;
+        let rows = stmt
+            .query_map(params![MIN_SECONDS, force], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0, r.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let total = todo.len();
+    let mut summary = TempoSummary::default();
+    for (i, (id, path, duration)) in todo.into_iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "tempo".into(), processed: i, total, batch: None });
+        let tempo = match playback::decode_samples(&path) {
+            Ok((channels, rate, data)) => estimate(&mono(&data, channels), rate, duration),
+            Err(e) => {
+                log::warn!("tempo: {}: {e:#}", path.display());
+                summary.failed += 1;
+                continue;
+            }
+        };
+        let (bpm, confidence) = match tempo {
+            Some(t) if t.confidence >= MIN_CONFIDENCE => (Some(t.bpm), t.confidence),
+            Some(t) => (None, t.confidence),
+            None => (None, 0.0),
+        };
+        conn.execute("UPDATE files SET bpm = ?, bpm_confidence = ? WHERE id = ?", params![bpm, confidence, id])?;
+        summary.analyzed += 1;
+        summary.file_ids.push(id);
+        if bpm.is_some() { summary.detected += 1; }
+    }
+    on_progress(&Progress { stage: "tempo".into(), processed: total, total, batch: None });
+    Ok(summary)
+}
+
+fn mono(data: &[f32], channels: u16) -> Vec<f32> {
+    let ch = usize::from(channels.max(1));
+    data.chunks(ch).map(|f| f.iter().sum::<f32>() / ch as f32).collect()
+}
+
+/// Tempo of `samples` (mono) lasting `duration` seconds in all, or `None` when there's too
+/// little signal to tell.
+pub fn estimate(samples: &[f32], rate: u32, duration: f64) -> Option<Tempo> {
+    let rate_f = f64::from(rate);
+    let hop = ((rate_f * HOP_SECONDS) as usize).max(1);
+    let hop_s = hop as f64 / rate_f;
+    let samples = &samples[..samples.len().min((MAX_SECONDS * rate_f) as usize)];
+
+    let mut prev_energy = None;
+    let mut envelope = Vec::with_capacity(samples.len() / hop + 1);
+    for frame in (0..samples.len().saturating_sub(1)).step_by(hop) {
+        let end = (frame + 2 * hop).min(samples.len());
+        let energy: f64 = samples[frame..end].windows(2).map(|w| f64::from(w[1] - w[0]).powi(2)).sum();
+        let energy = (energy + 1e-10).ln();
+        envelope.push(prev_energy.map_or(0.0, |p: f64| (energy - p).max(0.0)));
+        prev_energy = Some(energy);
+    }
+    let min_lag = (60.0 / (MAX_BPM * hop_s)).floor().max(1.0) as usize;
+    let max_lag = (60.0 / (MIN_BPM * hop_s)).ceil() as usize;
+    if envelope.len() < 3 * max_lag { return None; }
+    let mean = envelope.iter().sum::<f64>() / envelope.len() as f64;
+    for v in &mut envelope { *v -= mean; }
+
+    let ac = |lag: usize| envelope.iter().zip(&envelope[lag..]).map(|(a, b)| a * b).sum::<f64>() / (envelope.len() - lag) as f64;
+    let energy = ac(0);
+    if energy <= 0.0 { return None; }
+    let corr: Vec<f64> = (min_lag - 1..=max_lag + 1).map(|lag| ac(lag) / energy).collect();
+    let lag_bpm = |lag: f64| 60.0 / (lag * hop_s);
+    // Log-normal prior around 120 BPM, one octave wide, against halving/doubling errors
+    let prior = |bpm: f64| (-0.5 * (bpm / 120.0).log2().powi(2)).exp();
+    let best = (1..corr.len() - 1).max_by(|&a, &b| {
+        let score = |i: usize| corr[i] * prior(lag_bpm((min_lag - 1 + i) as f64));
+        score(a).total_cmp(&score(b))
+    })?;
+    if corr[best] <= 0.0 { return None; }
+    // Parabolic interpolation between neighbouring lags
+    let (l, c, r) = (corr[best - 1], corr[best], corr[best + 1]);
+    let denom = l - 2.0 * c + r;
+    let offset = if denom.abs() > f64::EPSILON { (0.5 * (l - r) / denom).clamp(-0.5, 0.5) } else { 0.0 };
+    let mut bpm = lag_bpm((min_lag - 1 + best) as f64 + offset);
+
+    let beats = duration * bpm / 60.0;
+    let bars = (beats / 4.0).round();
+    if bars >= 1.0 {
+        let aligned = bars * 4.0 * 60.0 / duration;
+        if ((aligned - bpm) / bpm).abs() <= SNAP_TOLERANCE { bpm = aligned; }
+    }
+    Some(Tempo { bpm: (bpm * 100.0).round() / 100.0, confidence: c.clamp(0.0, 1.0) })
+}
@@ -0,0 +1,233 @@
+//! Key detection for loops and pitch detection for one-shots. Keys come from a chroma profile
+//! (STFT energy folded onto pitch classes) correlated against the Krumhansl-Kessler major and
+//! minor profiles; pitches from YIN over the body of the sound, after its attack.
+//! Keys are stored spelled as in [`KEY_NAMES`] ("F#m", "Bb"), so imported and detected ones
+//! compare equal.
+
+use crate::{
+    db::StoredPath,
+    playback, tempo,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::path::PathBuf;
+
+pub const KEY_NAMES: [&str; 12] = ["C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"];
+
+const MAJOR_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// Below these the estimate is stored as a confidence only.
+const MIN_KEY_CONFIDENCE: f64 = 0.5;
+const MIN_PITCH_CONFIDENCE: f64 = 0.6;
+/// Only the start of long files is analysed.
+const MAX_SECONDS: f64 = 60.0;
+const FFT_SIZE: usize = 4096;
+/// YIN's threshold on the cumulative mean normalised difference.
+const YIN_THRESHOLD: f64 = 0.15;
+const MIN_PITCH_HZ: f64 = 30.0;
+const MAX_PITCH_HZ: f64 = 2000.0;
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TonalSummary {
+    pub analyzed: usize,
+    pub keys: usize,
+    pub pitches: usize,
+    pub failed: usize,
+    #[serde(skip)]
+    pub file_ids: Vec<i64>,
+}
+
+/// Estimates a key for loops (files of at least [`tempo::MIN_SECONDS`]) and a pitch for
+/// shorter files that have neither yet; with `force`, also redoes earlier estimates. Keys
+/// imported from other tools are never touched.
+pub fn analyze(conn: &Connection, force: bool, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<TonalSummary> {
+    let todo: Vec<(i64, PathBuf, Option<f64>)> = {
+        let mut stmt = conn.prepare(
+            "SELECT id, path, duration FROM files \
+             WHERE (musical_key IS NULL AND pitch IS NULL AND tonal_confidence IS NULL) OR (?1 AND tonal_confidence IS NOT NULL) ORDER BY id",
+        )?;
+        let rows = stmt
+            .query_map(params![force], |r| Ok((r.get(0)?, r.get::<_, StoredPath>(1)?.0, r.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+    let total = todo.len();
+    let mut summary = TonalSummary::default();
+    for (i, (id, path, duration)) in todo.into_iter().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "key".into(), processed: i, total, batch: None });
+        let (channels, rate, data) = match playback::decode_samples(&path) {
+            Ok(d) => d,
+            Err(e) => {
+                log::warn!("key: {}: {e:#}", path.display());
+                summary.failed += 1;
+                continue;
+            }
+        };
+        let mono: Vec<f32> = {
+            let ch = usize::from(channels.max(1));
+            data.chunks(ch).map(|f| f.iter().sum::<f32>() / ch as f32).collect()
+        };
+        let is_loop = duration.unwrap_or(mono.len() as f64 / f64::from(rate.max(1))) >= tempo::MIN_SECONDS;
+        let (key, pitch, confidence) = if is_loop {
+            match estimate_key(&mono, rate) {
+                Some((k, c)) if c >= MIN_KEY_CONFIDENCE => (Some(k), None, c),
+                Some((_, c)) => (None, None, c),
+                None => (None, None, 0.0),
+            }
+        } else {
+            match estimate_pitch(&mono, rate) {
+                Some((p, c)) if c >= MIN_PITCH_CONFIDENCE => (None, Some(p), c),
+                Some((_, c)) => (None, None, c),
+                None => (None, None, 0.0),
+            }
+        };
+        conn.execute(
+            "UPDATE files SET musical_key = ?, pitch = ?, tonal_confidence = ? WHERE id = ?",
+            params![key, pitch, confidence, id],
+        )?;
+        summary.analyzed += 1;
+        summary.file_ids.push(id);
+        if key.is_some() { summary.keys += 1; }
+        if pitch.is_some() { summary.pitches += 1; }
+    }
+    on_progress(&Progress { stage: "key".into(), processed: total, total, batch: None });
+    Ok(summary)
+}
+
+/// Best-matching key name and its profile correlation (0-1).
+pub fn estimate_key(samples: &[f32], rate: u32) -> Option<(String, f64)> {
+    let chroma = chroma(samples, rate)?;
+    let mut best: Option<(String, f64)> = None;
+    for root in 0..12 {
+        for (profile, suffix) in [(&MAJOR_PROFILE, ""), (&MINOR_PROFILE, "m")] {
+            let rotated: Vec<f64> = (0..12).map(|pc| profile[(pc + 12 - root) % 12]).collect();
+            let r = pearson(&chroma, &rotated);
+            if best.as_ref().map_or(true, |(_, b)| r > *b) { best = Some((format!("{}{suffix}", KEY_NAMES[root]), r)); }
+        }
+    }
+    best.map(|(k, r)| (k, r.clamp(0.0, 1.0)))
+}
+
+/// Energy per pitch class over 65 Hz - 2 kHz, summed across Hann-windowed frames.
+fn chroma(samples: &[f32], rate: u32) -> Option<[f64; 12]> {
+    let rate_f = f64::from(rate);
+    let samples = &samples[..samples.len().min((MAX_SECONDS * rate_f) as usize)];
+    if samples.len() < FFT_SIZE { return None; }
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+    let window: Vec<f32> = (0..FFT_SIZE).map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / FFT_SIZE as f32).cos()).collect();
+    let classes: Vec<Option<usize>> = (0..FFT_SIZE / 2)
+        .map(|k| {
+            let hz = k as f64 * rate_f / FFT_SIZE as f64;
+            if !(65.0..=2000.0).contains(&hz) { return None; }
+            Some((69.0 + 12.0 * (hz / 440.0).log2()).round().rem_euclid(12.0) as usize)
+        })
+        .collect();
+    let mut chroma = [0.0f64; 12];
+    let mut buf = vec![Complex::new(0.0f32, 0.0); FFT_SIZE];
+    for start in (0..=samples.len() - FFT_SIZE).step_by(FFT_SIZE / 2) {
+        for (b, (s, w)) in buf.iter_mut().zip(samples[start..start + FFT_SIZE].iter().zip(&window)) { *b = Complex::new(s * w, 0.0); }
+        fft.process(&mut buf);
+        for (k, class) in classes.iter().enumerate() {
+            if let Some(pc) = class { chroma[*pc] += f64::from(buf[k].norm_sqr()); }
+        }
+    }
+    (chroma.iter().sum::<f64>() > 0.0).then_some(chroma)
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (ma, mb) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut cov, mut va, mut vb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - ma) * (y - mb);
+        va += (x - ma).powi(2);
+        vb += (y - mb).powi(2);
+    }
+    if va <= 0.0 || vb <= 0.0 { 0.0 } else { cov / (va.sqrt() * vb.sqrt()) }
+}
+
+/// Fundamental as a fractional MIDI note (69.0 = A4) and YIN's periodicity (0-1), the median
+/// over frames from 20 ms in to the first second.
+pub fn estimate_pitch(samples: &[f32], rate: u32) -> Option<(f64, f64)> {
+    let rate_f = f64::from(rate);
+    let window = (rate_f / MIN_PITCH_HZ * 1.2) as usize;
+    let max_tau = (rate_f / MIN_PITCH_HZ) as usize;
+    let min_tau = ((rate_f / MAX_PITCH_HZ) as usize).max(2);
+    let start = (rate_f * 0.02) as usize;
+    let end = samples.len().min(start + rate as usize);
+    let mut found: Vec<(f64, f64)> = Vec::new();
+    let mut pos = start;
+    while pos < end && pos + window + max_tau <= samples.len() {
+        let frame = &samples[pos..pos + window + max_tau];
+        if let Some(hit) = yin(frame, window, min_tau, max_tau) { found.push((rate_f / hit.0, hit.1)); }
+        pos += window / 2;
+    }
+    if found.is_empty() { return None; }
+    found.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (hz, periodicity) = found[found.len() / 2];
+    Some((((69.0 + 12.0 * (hz / 440.0).log2()) * 100.0).round() / 100.0, periodicity))
+}
+
+/// Period in samples (interpolated) and 1 - its normalised difference, for one frame.
+fn yin(frame: &[f32], window: usize, min_tau: usize, max_tau: usize) -> Option<(f64, f64)> {
+    let energy: f64 = frame[..window].iter().map(|&x| f64::from(x).powi(2)).sum();
+    if energy < 1e-6 { return None; }
+    let mut d = vec![0.0f64; max_tau + 1];
+    for (tau, slot) in d.iter_mut().enumerate().skip(1) {
+        *slot = (0..window).map(|i| f64::from(frame[i] - frame[i + tau]).powi(2)).sum();
+    }
+    // Cumulative mean normalised difference
+    let mut cmnd = vec![1.0f64; max_tau + 1];
+    let mut running = 0.0;
+    for tau in 1..=max_tau {
+        running += d[tau];
+        cmnd[tau] = if running > 0.0 { d[tau] * tau as f64 / running } else { 1.0 };
+    }
+    let mut tau = min_tau;
+    while tau < max_tau {
+        if cmnd[tau] < YIN_THRESHOLD {
+            while tau + 1 < max_tau && cmnd[tau + 1] < cmnd[tau] { tau += 1; }
+            break;
+        }
+        tau += 1;
+    }
+    if tau >= max_tau { return None; }
+    let (l, c, r) = (cmnd[tau - 1], cmnd[tau], cmnd[tau + 1]);
+    let denom = l - 2.0 * c + r;
+    let offset = if denom.abs() > f64::EPSILON { (0.5 * (l - r) / denom).clamp(-0.5, 0.5) } else { 0.0 };
+    Some((tau as f64 + offset, (1.0 - c).clamp(0.0, 1.0)))
+}
+
+/// "Fmin", "F minor", "f#m", "D#m", "Ebmaj", Camelot "8A" -> "Fm", "Fm", "F#m", "Ebm", "Eb", "Am".
+pub fn normalize_key(raw: &str) -> Option<String> {
+    let s = raw.trim();
+    if let Some(num) = s.strip_suffix(['A', 'a', 'B', 'b']).and_then(|n| n.parse::<usize>().ok()).filter(|n| (1..=12).contains(n)) {
+        // nB is the major key n-1 fifths above B; nA its relative minor
+        let major = (11 + 7 * (num - 1)) % 12;
+        return Some(if s.ends_with(['A', 'a']) { format!("{}m", KEY_NAMES[(major + 9) % 12]) } else { KEY_NAMES[major].to_string() });
+    }
+    let mut chars = s.chars();
+    let natural = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (shift, rest) = if let Some(r) = rest.strip_prefix(['#', '♯']) {
+        (1, r)
+    } else if let Some(r) = rest.strip_prefix(['b', '♭']) {
+        (11, r)
+    } else {
+        (0, rest)
+    };
+    let minor = match rest.trim().to_ascii_lowercase().as_str() {
+        "" | "maj" | "major" => false,
+        "m" | "min" | "minor" => true,
+        _ => return None,
+    };
+    Some(format!("{}{}", KEY_NAMES[(natural + shift) % 12], if minor { "m" } else { "" }))
+}
@@ -0,0 +1,436 @@
+//! Journal of destructive edits so the last one can be taken back: renames and moves, bulk tag
+//! and metadata edits, trashing files and removing a folder from the library. Each entry is an `operations`
+//! row describing the edit; rows the edit deletes are first copied into `undo_<table>` tables
+//! (created on first use, with the source table's columns plus `op_id`) so they can be put
+//! back with their ids, and with them tags, pins, embeddings and positions. Only the newest
+//! [`KEEP`] entries are kept.
+
+use crate::db::{self, normalize_tag};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, types::ToSql, Connection, OptionalExtension, Transaction};
+use std::path::PathBuf;
+
+pub const KEEP: i64 = 50;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Operation {
+    /// Renames and moves: file id, old path, new path.
+    Move { moves: Vec<(i64, PathBuf, PathBuf)> },
+    /// Pairs that didn't exist before, and machine tags the edit turned into user tags.
+    AddTags { inserted: Vec<(i64, String)>, promoted: Vec<(i64, String, Option<f64>)> },
+    /// The removed rows are in `undo_file_tags`.
+    RemoveTags,
+    /// `bulk_update`: previous rating, color, hidden and favorite per file, of which only the
+    /// `columns` it edited are put back; tags as for `AddTags` and `RemoveTags`.
+    BulkUpdate {
+        columns: Vec<String>,
+        previous: Vec<(i64, Option<u8>, Option<String>, bool, bool)>,
+        inserted: Vec<(i64, String)>,
+        promoted: Vec<(i64, String, Option<f64>)>,
+    },
+    /// Files that went to the OS trash; rows of all removed files are stashed.
+    Trash { trashed: Vec<(i64, PathBuf)> },
+    /// `remove_root`: rows stashed when `deleted`, otherwise the files it hid.
+    RemoveRoot { root: PathBuf, deleted: bool, hidden: Vec<i64> },
+}
+
+impl Operation {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Move { .. } => "move",
+            Self::AddTags { .. } => "addTags",
+            Self::RemoveTags => "removeTags",
+            Self::BulkUpdate { .. } => "bulkUpdate",
+            Self::Trash { .. } => "trash",
+            Self::RemoveRoot { .. } => "removeRoot",
+        }
+    }
+}
+
+/// The `files` columns `bulk_update` edits, in the order of `Operation::BulkUpdate::previous`.
+const BULK_COLUMNS: [&str; 4] = ["rating", "color", "hidden", "favorite"];
+
+/// Per-file tables, `files` first so parents are restored before their dependents.
+fn file_tables() -> impl Iterator<Item = (&'static str, &'static str)> {
+    std::iter::once(("files", "id")).chain(db::FILE_DEPENDENTS.iter().map(|t| (*t, "file_id")))
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationInfo {
+    pub id: i64,
+    pub kind: String,
+    pub created_at: i64,
+}
+
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UndoReport {
+    pub kind: String,
+    /// Files whose rows or paths were put back.
+    pub file_ids: Vec<i64>,
+    /// Files back in the library as new rows (trash, deleted roots).
+    #[serde(skip)]
+    pub readded: bool,
+    /// For a removed root: the folder to add back to `libraryRoots`.
+    #[serde(skip)]
+    pub root: Option<PathBuf>,
+    /// What couldn't be restored, e.g. files the OS trash no longer has.
+    pub failed: Vec<String>,
+}
+
+fn columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let cols = stmt.query_map([], |r| r.get::<_, String>(1))?.collect::<rusqlite::Result<_>>()?;
+    Ok(cols)
+}
+
+fn quoted(cols: &[String]) -> String {
+    cols.iter().map(|c| format!("\"{c}\"")).collect::<Vec<_>>().join(", ")
+}
+
+/// Copies `table` rows matching `cond` into its stash under `op_id`, first creating the stash or
+/// adding columns migrations have added since.
+fn stash(tx: &Transaction, op_id: i64, table: &str, cond: &str, args: &[&dyn ToSql]) -> Result<()> {
+    let store = format!("undo_{table}");
+    tx.execute_batch(&format!("CREATE TABLE IF NOT EXISTS {store} AS SELECT 0 AS op_id, * FROM {table} WHERE 0"))?;
+    let cols = columns(tx, table)?;
+    let have = columns(tx, &store)?;
+    for c in cols.iter().filter(|c| !have.contains(c)) {
+        tx.execute_batch(&format!("ALTER TABLE {store} ADD COLUMN \"{c}\""))?;
+    }
+    let cols = quoted(&cols);
+    let mut args: Vec<&dyn ToSql> = args.to_vec();
+    args.insert(0, &op_id);
+    tx.execute(&format!("INSERT INTO {store}(op_id, {cols}) SELECT ?, {cols} FROM {table} WHERE {cond}"), args.as_slice())?;
+    Ok(())
+}
+
+/// Puts stashed rows of `op_id` matching `cond` back, over the columns both tables still have.
+fn unstash(tx: &Transaction, op_id: i64, table: &str, cond: &str, args: &[&dyn ToSql]) -> Result<usize> {
+    let store = format!("undo_{table}");
+    let have = columns(tx, &store)?;
+    if have.is_empty() { return Ok(0); }
+    let cols: Vec<String> = columns(tx, table)?.into_iter().filter(|c| have.contains(c)).collect();
+    let cols = quoted(&cols);
+    let mut args: Vec<&dyn ToSql> = args.to_vec();
+    args.insert(0, &op_id);
+    Ok(tx.execute(&format!("INSERT OR IGNORE INTO {table}({cols}) SELECT {cols} FROM {store} WHERE op_id = ? AND {cond}"), args.as_slice())?)
+}
+
+/// Appends `op`, dropping entries beyond [`KEEP`]; returns its id for stashing.
+fn begin(tx: &Transaction, op: &Operation) -> Result<i64> {
+    let payload = serde_json::to_string(op).context("encode operation")?;
+    tx.execute("INSERT INTO operations(kind, created_at, payload) VALUES(?, ?, ?)", params![op.kind(), crate::scan::now_secs(), payload])?;
+    let id = tx.last_insert_rowid();
+    let expired: Vec<i64> = {
+        let mut stmt = tx.prepare("SELECT id FROM operations WHERE id <= ? - ?")?;
+        let ids = stmt.query_map(params![id, KEEP], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+        ids
+    };
+    for old in expired { forget(tx, old)?; }
+    Ok(id)
+}
+
+fn has_table(conn: &Connection, name: &str) -> Result<bool> {
+    Ok(conn.query_row("SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)", params![name], |r| r.get(0))?)
+}
+
+/// Stashed ids of `key` in `table` under `op_id`.
+fn stashed_ids(tx: &Transaction, op_id: i64, table: &str, key: &str) -> Result<Vec<i64>> {
+    let store = format!("undo_{table}");
+    if !has_table(tx, &store)? { return Ok(Vec::new()); }
+    let mut stmt = tx.prepare(&format!("SELECT DISTINCT {key} FROM {store} WHERE op_id = ?"))?;
+    let ids = stmt.query_map(params![op_id], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+    Ok(ids)
+}
+
+fn forget(tx: &Transaction, op_id: i64) -> Result<()> {
+    for (table, _) in file_tables() {
+        let store = format!("undo_{table}");
+        if has_table(tx, &store)? { tx.execute(&format!("DELETE FROM {store} WHERE op_id = ?"), params![op_id])?; }
+    }
+    tx.execute("DELETE FROM operations WHERE id = ?", params![op_id])?;
+    Ok(())
+}
+
+/// Journals a finished rename or move.
+pub fn record_move(conn: &mut Connection, moves: Vec<(i64, PathBuf, PathBuf)>) -> Result<()> {
+    if moves.is_empty() { return Ok(()); }
+    let tx = conn.transaction()?;
+    begin(&tx, &Operation::Move { moves })?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Journals `db::add_tags(file_ids, tags)`; call it just before, and [`forget_op`] the returned
+/// entry should the edit fail. `None` when there's nothing to undo.
+pub fn record_add_tags(conn: &mut Connection, file_ids: &[i64], tags: &[String]) -> Result<Option<i64>> {
+    let tags: Vec<String> = tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let tx = conn.transaction()?;
+    let (inserted, promoted) = tag_additions(&tx, file_ids, &tags)?;
+    if inserted.is_empty() && promoted.is_empty() { return Ok(None); }
+    let op_id = begin(&tx, &Operation::AddTags { inserted, promoted })?;
+    tx.commit()?;
+    Ok(Some(op_id))
+}
+
+/// Pairs adding the (normalized) `tags` would insert, and machine tags it would promote.
+#[allow(clippy::type_complexity)]
+fn tag_additions(tx: &Transaction, file_ids: &[i64], tags: &[String]) -> Result<(Vec<(i64, String)>, Vec<(i64, String, Option<f64>)>)> {
+    let (mut inserted, mut promoted) = (Vec::new(), Vec::new());
+    let mut existing = tx.prepare("SELECT source, confidence FROM file_tags WHERE file_id = ? AND tag = ?")?;
+    for &id in file_ids {
+        for t in tags {
+            let row: Option<(String, Option<f64>)> = existing.query_row(params![id, t], |r| Ok((r.get(0)?, r.get(1)?))).optional()?;
+            match row {
+                None => inserted.push((id, t.clone())),
+                Some((source, confidence)) if source == "auto" => promoted.push((id, t.clone(), confidence)),
+                Some(_) => {}
+            }
+        }
+    }
+    Ok((inserted, promoted))
+}
+
+fn revert_additions(tx: &Transaction, inserted: &[(i64, String)], promoted: &[(i64, String, Option<f64>)]) -> Result<()> {
+    for (id, tag) in inserted {
+        tx.execute("DELETE FROM file_tags WHERE file_id = ? AND tag = ? AND source = 'user'", params![id, tag])?;
+    }
+    for (id, tag, confidence) in promoted {
+        tx.execute("UPDATE file_tags SET source = 'auto', confidence = ? WHERE file_id = ? AND tag = ?", params![confidence, id, tag])?;
+    }
+    Ok(())
+}
+
+/// Journals `db::remove_tags(file_ids, tags)`; as for [`record_add_tags`].
+pub fn record_remove_tags(conn: &mut Connection, file_ids: &[i64], tags: &[String]) -> Result<Option<i64>> {
+    let tags: Vec<String> = tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let tx = conn.transaction()?;
+    if !any_tagged(&tx, file_ids, &tags)? { return Ok(None); }
+    let op_id = begin(&tx, &Operation::RemoveTags)?;
+    stash_tags(&tx, op_id, file_ids, &tags)?;
+    tx.commit()?;
+    Ok(Some(op_id))
+}
+
+/// Whether any of `file_ids` carries any of the (normalized) `tags`.
+fn any_tagged(tx: &Transaction, file_ids: &[i64], tags: &[String]) -> Result<bool> {
+    let mut tagged = tx.prepare("SELECT 1 FROM file_tags WHERE file_id = ? AND tag = ?")?;
+    for id in file_ids {
+        for t in tags {
+            if tagged.exists(params![id, t])? { return Ok(true); }
+        }
+    }
+    Ok(false)
+}
+
+fn stash_tags(tx: &Transaction, op_id: i64, file_ids: &[i64], tags: &[String]) -> Result<()> {
+    for id in file_ids {
+        for t in tags { stash(tx, op_id, "file_tags", "file_id = ? AND tag = ?", &[id, t])?; }
+    }
+    Ok(())
+}
+
+/// Journals `db::bulk_update(file_ids, changes)`; as for [`record_add_tags`].
+pub fn record_bulk_update(conn: &mut Connection, file_ids: &[i64], changes: &db::BulkChanges) -> Result<Option<i64>> {
+    let add: Vec<String> = changes.add_tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let remove: Vec<String> = changes.remove_tags.iter().filter_map(|t| normalize_tag(t)).collect();
+    let edited = [changes.rating.is_some(), changes.color.is_some(), changes.hidden.is_some(), changes.favorite.is_some()];
+    let columns: Vec<String> = BULK_COLUMNS.iter().zip(edited).filter(|(_, e)| *e).map(|(c, _)| c.to_string()).collect();
+    let tx = conn.transaction()?;
+    let (inserted, promoted) = tag_additions(&tx, file_ids, &add)?;
+    let untags = any_tagged(&tx, file_ids, &remove)?;
+    let mut previous = Vec::new();
+    if !columns.is_empty() {
+        let mut row = tx.prepare("SELECT rating, color, hidden, favorite FROM files WHERE id = ?")?;
+        for &id in file_ids {
+            previous.extend(row.query_row(params![id], |r| Ok((id, r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))).optional()?);
+        }
+    }
+    if inserted.is_empty() && promoted.is_empty() && !untags && previous.is_empty() { return Ok(None); }
+    let op_id = begin(&tx, &Operation::BulkUpdate { columns, previous, inserted, promoted })?;
+    if untags { stash_tags(&tx, op_id, file_ids, &remove)?; }
+    tx.commit()?;
+    Ok(Some(op_id))
+}
+
+/// Journals deleting the rows of `file_ids` (and all attached to them); call it just before.
+fn record_deletion(conn: &mut Connection, op: &Operation, file_ids: &[i64]) -> Result<Option<i64>> {
+    let tx = conn.transaction()?;
+    let op_id = begin(&tx, op)?;
+    for id in file_ids {
+        for (table, key) in file_tables() { stash(&tx, op_id, table, &format!("{key} = ?"), &[id])?; }
+    }
+    tx.commit()?;
+    Ok(Some(op_id))
+}
+
+/// Journals trashing: `trashed` went to the OS trash, `file_ids` (a superset) leave the library.
+pub fn record_trash(conn: &mut Connection, trashed: Vec<(i64, PathBuf)>, file_ids: &[i64]) -> Result<Option<i64>> {
+    if file_ids.is_empty() { return Ok(None); }
+    record_deletion(conn, &Operation::Trash { trashed }, file_ids)
+}
+
+/// Journals `remove_root` over `file_ids`; as for [`record_add_tags`].
+pub fn record_remove_root(conn: &mut Connection, root: PathBuf, deleted: bool, file_ids: &[i64]) -> Result<Option<i64>> {
+    if deleted { return record_deletion(conn, &Operation::RemoveRoot { root, deleted, hidden: Vec::new() }, file_ids); }
+    let tx = conn.transaction()?;
+    let mut hidden = Vec::new();
+    {
+        let mut visible = tx.prepare("SELECT 1 FROM files WHERE id = ? AND hidden = 0")?;
+        for &id in file_ids {
+            if visible.exists(params![id])? { hidden.push(id); }
+        }
+    }
+    let op_id = begin(&tx, &Operation::RemoveRoot { root, deleted, hidden })?;
+    tx.commit()?;
+    Ok(Some(op_id))
+}
+
+/// Drops a journal entry whose edit didn't go through.
+pub fn forget_op(conn: &mut Connection, op_id: i64) -> Result<()> {
+    let tx = conn.transaction()?;
+    forget(&tx, op_id)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Newest first.
+pub fn list(conn: &Connection) -> Result<Vec<OperationInfo>> {
+    let mut stmt = conn.prepare("SELECT id, kind, created_at FROM operations ORDER BY id DESC")?;
+    let rows = stmt
+        .query_map([], |r| Ok(OperationInfo { id: r.get(0)?, kind: r.get(1)?, created_at: r.get(2)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Reverts the newest journaled operation and drops it from the journal.
+pub fn undo_last(conn: &mut Connection) -> Result<UndoReport> {
+    let row: Option<(i64, String)> = conn.query_row("SELECT id, payload FROM operations ORDER BY id DESC LIMIT 1", [], |r| Ok((r.get(0)?, r.get(1)?))).optional()?;
+    let Some((op_id, payload)) = row else { bail!("nothing to undo") };
+    let op: Operation = serde_json::from_str(&payload).context("decode operation")?;
+    let mut report = UndoReport { kind: op.kind().to_string(), ..Default::default() };
+    match op {
+        Operation::Move { moves } => {
+            // Only files still where the edit left them; later edits win
+            let mut back = Vec::new();
+            for (id, from, to) in moves.into_iter().rev() {
+                if db::file_path(conn, id)?.as_deref() == Some(to.as_path()) {
+                    back.push((id, from));
+                } else {
+                    report.failed.push(format!("{} has moved since", to.display()));
+                }
+            }
+            db::relocate_files(conn, &back)?;
+            report.file_ids = back.into_iter().map(|(id, _)| id).collect();
+            let tx = conn.transaction()?;
+            forget(&tx, op_id)?;
+            tx.commit()?;
+        }
+        Operation::AddTags { inserted, promoted } => {
+            let tx = conn.transaction()?;
+            revert_additions(&tx, &inserted, &promoted)?;
+            report.file_ids = inserted.iter().map(|p| p.0).chain(promoted.iter().map(|p| p.0)).collect();
+            forget(&tx, op_id)?;
+            tx.commit()?;
+        }
+        Operation::RemoveTags => {
+            let tx = conn.transaction()?;
+            unstash(&tx, op_id, "file_tags", "1", &[])?;
+            report.file_ids = stashed_ids(&tx, op_id, "file_tags", "file_id")?;
+            forget(&tx, op_id)?;
+            tx.commit()?;
+        }
+        Operation::BulkUpdate { columns, previous, inserted, promoted } => {
+            let tx = conn.transaction()?;
+            // Tags in reverse order of `db::bulk_update`, which adds before it removes
+            unstash(&tx, op_id, "file_tags", "1", &[])?;
+            revert_additions(&tx, &inserted, &promoted)?;
+            for (id, rating, color, hidden, favorite) in &previous {
+                let values: [&dyn ToSql; 4] = [rating, color, hidden, favorite];
+                for (col, value) in BULK_COLUMNS.iter().zip(values) {
+                    if columns.iter().any(|c| c.as_str() == *col) {
+                        tx.execute(&format!("UPDATE files SET {col} = ? WHERE id = ?"), params![value, id])?;
+                    }
+                }
+            }
+            let mut ids: Vec<i64> = previous.iter().map(|p| p.0).chain(inserted.iter().map(|p| p.0)).chain(promoted.iter().map(|p| p.0)).collect();
+            ids.extend(stashed_ids(&tx, op_id, "file_tags", "file_id")?);
+            ids.sort_unstable();
+            ids.dedup();
+            report.file_ids = ids;
+            forget(&tx, op_id)?;
+            tx.commit()?;
+        }
+        Operation::Trash { trashed } => {
+            let (_, failed) = restore_from_trash(&trashed);
+            report.failed = failed.iter().map(|(_, p)| format!("{} couldn't be restored from the trash", p.display())).collect();
+            let lost: Vec<i64> = failed.into_iter().map(|(id, _)| id).collect();
+            restore_rows(conn, op_id, &lost, &mut report)?;
+        }
+        Operation::RemoveRoot { root, deleted, hidden } => {
+            if deleted {
+                restore_rows(conn, op_id, &[], &mut report)?;
+            } else {
+                db::set_hidden(conn, &hidden, false)?;
+                report.file_ids = hidden;
+                let tx = conn.transaction()?;
+                forget(&tx, op_id)?;
+                tx.commit()?;
+            }
+            report.root = Some(root);
+        }
+    }
+    Ok(report)
+}
+
+/// Brings back the stashed files of `op_id`, except `skip`, with everything attached.
+fn restore_rows(conn: &mut Connection, op_id: i64, skip: &[i64], report: &mut UndoReport) -> Result<()> {
+    let tx = conn.transaction()?;
+    let ids: Vec<i64> = stashed_ids(&tx, op_id, "files", "id")?.into_iter().filter(|id| !skip.contains(id)).collect();
+    for id in &ids {
+        // A file scanned again since comes back as a new row; keep that one
+        let n = unstash(&tx, op_id, "files", "id = ?", &[id])?;
+        if n == 0 { continue; }
+        for table in db::FILE_DEPENDENTS { unstash(&tx, op_id, table, "file_id = ?", &[id])?; }
+        report.file_ids.push(*id);
+    }
+    forget(&tx, op_id)?;
+    tx.commit()?;
+    report.readded = true;
+    Ok(())
+}
+
+/// Puts trashed files back where they were; returns the files restored and those that weren't.
+#[cfg(any(windows, all(unix, not(target_os = "macos"), not(target_os = "ios"), not(target_os = "android"))))]
+fn restore_from_trash(trashed: &[(i64, PathBuf)]) -> (Vec<i64>, Vec<(i64, PathBuf)>) {
+    let items = match trash::os_limited::list() {
+        Ok(items) => items,
+        Err(e) => {
+            log::warn!("undo: list trash: {e}");
+            return (Vec::new(), trashed.to_vec());
+        }
+    };
+    let (mut restored, mut failed) = (Vec::new(), Vec::new());
+    for (id, path) in trashed {
+        // The newest trashing of this path, should it have been trashed more than once
+        let item = items.iter().filter(|i| &i.original_path() == path).max_by_key(|i| i.time_deleted).cloned();
+        match item.map(|i| trash::os_limited::restore_all([i])) {
+            Some(Ok(())) => restored.push(*id),
+            Some(Err(e)) => {
+                log::warn!("undo: restore {}: {e}", path.display());
+                failed.push((*id, path.clone()));
+            }
+            None => failed.push((*id, path.clone())),
+        }
+    }
+    (restored, failed)
+}
+
+/// The trash can't be read back programmatically here, so nothing is restored.
+#[cfg(not(any(windows, all(unix, not(target_os = "macos"), not(target_os = "ios"), not(target_os = "android")))))]
+fn restore_from_trash(trashed: &[(i64, PathBuf)]) -> (Vec<i64>, Vec<(i64, PathBuf)>) {
+    (Vec::new(), trashed.to_vec())
+}
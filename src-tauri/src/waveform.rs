@@ -0,0 +1,76 @@
+//! Small min/max peak thumbnails for drawing a sample's waveform without decoding it. Computed
+//! on first request and kept in the `waveforms` table until the file changes.
+
+use crate::{db::StoredPath, playback};
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// Min/max pairs per thumbnail.
+pub const POINTS: usize = 256;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Thumbnail {
+    pub file_id: i64,
+    /// `POINTS` `[min, max]` pairs in -1..1, channels mixed down.
+    pub peaks: Vec<[f32; 2]>,
+}
+
+/// Thumbnails for `file_ids` in the given order; unknown ids and files that can't be decoded
+/// are left out.
+pub fn thumbnails(conn: &Connection, file_ids: &[i64]) -> Result<Vec<Thumbnail>> {
+    let mut out = Vec::with_capacity(file_ids.len());
+    for &id in file_ids {
+        let row: Option<(StoredPath, i64, Option<Vec<u8>>, Option<i64>)> = conn
+            .query_row(
+                "SELECT f.path, f.mtime, w.peaks, w.mtime FROM files f LEFT JOIN waveforms w ON w.file_id = f.id WHERE f.id = ?",
+                params![id],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .optional()?;
+        let Some((path, mtime, stored, stored_mtime)) = row else { continue };
+        let blob = match stored {
+            Some(b) if stored_mtime == Some(mtime) => b,
+            _ => match compute(&path.0) {
+                Ok(b) => {
+                    conn.execute(
+                        "INSERT INTO waveforms(file_id, mtime, peaks) VALUES(?, ?, ?) ON CONFLICT(file_id) DO UPDATE SET mtime = excluded.mtime, peaks = excluded.peaks",
+                        params![id, mtime, b],
+                    )?;
+                    b
+                }
+                Err(e) => {
+                    log::warn!("waveform: {}: {e:#}", path.0.display());
+                    continue;
+                }
+            },
+        };
+        out.push(Thumbnail { file_id: id, peaks: unpack(&blob) });
+    }
+    Ok(out)
+}
+
+/// Decodes the file and packs its peaks as signed bytes, min then max per point.
+fn compute(path: &Path) -> Result<Vec<u8>> {
+    let (channels, _, data) = playback::decode_samples(path)?;
+    let channels = usize::from(channels.max(1));
+    let frames = data.len() / channels;
+    let mut peaks = [(0f32, 0f32); POINTS];
+    for (j, frame) in data.chunks_exact(channels).enumerate() {
+        let v = frame.iter().sum::<f32>() / channels as f32;
+        let p = &mut peaks[j * POINTS / frames];
+        p.0 = p.0.min(v);
+        p.1 = p.1.max(v);
+    }
+    let blob = peaks.iter().flat_map(|&(lo, hi)| [quantize(lo) as u8, quantize(hi) as u8]).collect();
+    Ok(blob)
+}
+
+fn quantize(v: f32) -> i8 {
+    (v.clamp(-1.0, 1.0) * 127.0).round() as i8
+}
+
+fn unpack(blob: &[u8]) -> Vec<[f32; 2]> {
+    blob.chunks_exact(2).map(|p| [p[0] as i8 as f32 / 127.0, p[1] as i8 as f32 / 127.0]).collect()
+}
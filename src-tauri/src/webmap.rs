@@ -0,0 +1,80 @@
+//! A self-contained copy of the map for people who don't run the app: `index.html` plus the
+//! points, names and waveform thumbnails in `data.js` (a script rather than JSON, so it opens
+//! straight from disk), and optionally small previews to click-play.
+
+use crate::{
+    convert::{self, ConvertOptions},
+    db, waveform,
+    worker::{Cancel, Progress},
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+use std::path::Path;
+
+const VIEWER: &str = include_str!("webmap/index.html");
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WebMapOptions {
+    /// Writes `previews/<id>.wav` for each point, as 22.05 kHz 8-bit mono.
+    pub previews: bool,
+    pub include_hidden: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebPoint {
+    id: i64,
+    x: f32,
+    y: f32,
+    name: String,
+    duration: Option<f64>,
+    cluster: Option<i64>,
+    color: Option<String>,
+    peaks: Vec<[f32; 2]>,
+}
+
+/// Writes the viewer into `dest`, creating it. Points are the active layout's.
+pub fn export(conn: &Connection, library: &str, dest: &Path, opts: &WebMapOptions, on_progress: &dyn Fn(&Progress), cancel: &Cancel) -> Result<usize> {
+    std::fs::create_dir_all(dest).with_context(|| format!("create {}", dest.display()))?;
+    let mut stmt = conn.prepare(
+        "SELECT c.file_id, c.x, c.y, f.name, f.duration, f.cluster_id, f.color FROM coords c JOIN files f ON f.id = c.file_id \
+         WHERE ?1 OR f.hidden = 0 ORDER BY c.file_id",
+    )?;
+    let mut points = stmt
+        .query_map([opts.include_hidden], |r| {
+            Ok(WebPoint {
+                id: r.get(0)?,
+                x: r.get::<_, f64>(1)? as f32,
+                y: r.get::<_, f64>(2)? as f32,
+                name: r.get(3)?,
+                duration: r.get(4)?,
+                cluster: r.get(5)?,
+                color: r.get(6)?,
+                peaks: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if points.is_empty() { bail!("nothing on the map to export"); }
+
+    let total = points.len();
+    let preview = ConvertOptions { sample_rate: Some(22_050), bits: Some(8), channels: Some(1), ..Default::default() };
+    if opts.previews { std::fs::create_dir_all(dest.join("previews"))?; }
+    for (i, p) in points.iter_mut().enumerate() {
+        if cancel.is_requested() { bail!("cancelled"); }
+        on_progress(&Progress { stage: "exporting".into(), processed: i, total, batch: None });
+        if let Some(t) = waveform::thumbnails(conn, &[p.id])?.pop() { p.peaks = t.peaks; }
+        if opts.previews {
+            let Some(src) = db::file_path(conn, p.id)? else { continue };
+            if let Err(e) = convert::convert_file(&src, &dest.join("previews").join(format!("{}.wav", p.id)), &preview) {
+                log::warn!("web map: no preview for {}: {e:#}", src.display());
+            }
+        }
+    }
+
+    let data = serde_json::json!({ "library": library, "previews": opts.previews, "points": points });
+    std::fs::write(dest.join("data.js"), format!("window.SAMPLEMAP = {data};\n"))?;
+    std::fs::write(dest.join("index.html"), VIEWER)?;
+    on_progress(&Progress { stage: "exporting".into(), processed: total, total, batch: None });
+    Ok(total)
+}
@@ -1,9 +1,19 @@
-use crate::db;
+use crate::{db, layouts, models, project, settings::{self, ProjectionSettings}};
 use anyhow::{Context, Result};
-use std::{path::{PathBuf}, process::Command};
+use parking_lot::Mutex;
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    process::Child,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tauri::{AppHandle, Manager};
 
-fn find_worker(app: &AppHandle) -> Result<PathBuf> {
+pub fn find_worker(app: &AppHandle) -> Result<PathBuf> {
     // Prefer bundled resource dir
     if let Ok(dir) = app.path().resource_dir() {
         let p = dir.join("python").join("worker.py");
@@ -28,27 +38,167 @@ fn find_worker(app: &AppHandle) -> Result<PathBuf> {
     anyhow::bail!("worker.py not found in resources or nearby filesystem")
 }
 
-fn find_python() -> String {
-    // Prefer the Windows py launcher
-    if cfg!(target_os = "windows") {
-        return "py".to_string();
+/// A JSON line on the worker's stdout: `{"type": "progress", "stage": "embedding",
+/// "processed": 120, "total": 5000, "batch": 8}`. Lines that aren't progress or a response
+/// (see `host`) are logged.
+#[derive(Clone, serde::Deserialize)]
+pub struct Progress {
+    pub stage: String,
+    pub processed: usize,
+    pub total: usize,
+    #[serde(default)]
+    pub batch: Option<usize>,
+}
+
+/// Why the worker failed, in a form the UI can act on rather than just print.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum WorkerFailure {
+    /// Went quiet for `timeout_secs` during `stage` on every one of `attempts` runs.
+    #[serde(rename_all = "camelCase")]
+    Timeout { stage: String, timeout_secs: u64, attempts: u32 },
+    /// Exited unsuccessfully; `stderr` is its last lines, usually the end of a traceback.
+    Exited { code: Option<i32>, stderr: Vec<String> },
+}
+
+impl std::fmt::Display for WorkerFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout { stage, timeout_secs, attempts } => {
+                write!(f, "python worker stalled during {stage} (no output for {timeout_secs}s, {attempts} attempts)")
+            }
+            Self::Exited { code, stderr } => {
+                match code {
+                    Some(c) => write!(f, "python worker exited with status {c}")?,
+                    None => write!(f, "python worker was killed")?,
+                }
+                // The last line names the exception; the ones before say where
+                let shown: Vec<&str> = stderr.iter().rev().take(3).rev().map(String::as_str).collect();
+                if !shown.is_empty() { write!(f, ": {}", shown.join(" | "))?; }
+                Ok(())
+            }
+        }
     }
-    "python3".to_string()
 }
 
-pub fn run_pipeline(app: &AppHandle, stage: &str) -> Result<()> {
-    let dbp = db::db_path(app)?;
-    let worker = find_worker(app)?;
-    let python = find_python();
-    let args = if cfg!(target_os = "windows") && python == "py" {
-        vec!["-3".into(), worker.to_string_lossy().to_string(), dbp.to_string_lossy().to_string(), stage.into()]
-    } else {
-        vec![worker.to_string_lossy().to_string(), dbp.to_string_lossy().to_string(), stage.into()]
+impl std::error::Error for WorkerFailure {}
+
+/// A job's stop request, plus the worker process it may be waiting on so that can be killed.
+#[derive(Default)]
+pub struct Cancel {
+    requested: AtomicBool,
+    child: Mutex<Option<Arc<Mutex<Child>>>>,
+}
+
+impl Cancel {
+    /// Flags the job and kills its worker, if it's mid-request.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Some(child) = self.child.lock().as_ref() { let _ = child.lock().kill(); }
+    }
+
+    /// Makes `child` the process `request` kills until `detach`; kills it now if already flagged.
+    pub fn attach(&self, child: Arc<Mutex<Child>>) {
+        *self.child.lock() = Some(child);
+        if self.is_requested() { self.request(); }
+    }
+
+    pub fn detach(&self) {
+        *self.child.lock() = None;
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs the worker's `stage`, passing its progress messages to `on_progress`; `cancel` kills it.
+/// A run that stalls past the configured timeout is killed and retried. If Python isn't usable
+/// and the stage includes projection, the map is still laid out from the existing embeddings
+/// with the native PCA in `project`; a plain "umap" stage then counts as done, while "all"
+/// still reports that embedding didn't happen.
+pub fn run_pipeline(
+    app: &AppHandle,
+    dbp: &Path,
+    stage: &str,
+    proj: &ProjectionSettings,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+) -> Result<()> {
+    let limits = settings::load(app).worker;
+    let mut attempt = 1;
+    let err = loop {
+        let e = match run_worker(app, dbp, stage, proj, on_progress, cancel, &limits) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let stalled = matches!(e.downcast_ref::<WorkerFailure>(), Some(WorkerFailure::Timeout { .. }));
+        if stalled && attempt <= limits.retries && !cancel.is_requested() {
+            log::warn!("worker: {e}; retrying");
+            attempt += 1;
+            continue;
+        }
+        if let Some(WorkerFailure::Timeout { stage, timeout_secs, .. }) = e.downcast_ref::<WorkerFailure>() {
+            break WorkerFailure::Timeout { stage: stage.clone(), timeout_secs: *timeout_secs, attempts: attempt }.into();
+        }
+        break e;
     };
-    let status = Command::new(python)
-        .args(args)
-        .status()
-        .context("failed to spawn python worker")?;
-    if !status.success() { anyhow::bail!("python worker exited with status {status}"); }
-    Ok(())
+    if stage == "embed" || cancel.is_requested() { return Err(err); }
+    log::warn!("worker: {err:#}; falling back to native projection");
+    let mut conn = db::open_or_create(dbp)?;
+    on_progress(&Progress { stage: "projecting".into(), processed: 0, total: 0, batch: None });
+    project::run(&mut conn, proj).with_context(|| format!("{err:#}; native projection also failed"))?;
+    if stage == "umap" { Ok(()) } else { Err(err) }
+}
+
+fn run_worker(
+    app: &AppHandle,
+    dbp: &Path,
+    stage: &str,
+    proj: &ProjectionSettings,
+    on_progress: &dyn Fn(&Progress),
+    cancel: &Cancel,
+    limits: &settings::WorkerSettings,
+) -> Result<()> {
+    let host = &app.state::<crate::AppState>().worker;
+    let embedding = settings::load(app).embedding;
+    let db = dbp.to_string_lossy();
+    if stage != "umap" {
+        let todo: Vec<i64> = {
+            let conn = db::open_or_create(dbp)?;
+            let mut stmt = conn.prepare("SELECT id FROM files WHERE id NOT IN (SELECT file_id FROM embeddings WHERE model_id = ?) ORDER BY id")?;
+            let ids = stmt.query_map([models::BUILTIN], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+            ids
+        };
+        let (total, batch_size) = (todo.len(), embedding.batch_size());
+        let timeout = Duration::from_secs(limits.embed_timeout_secs);
+        for (b, ids) in todo.chunks(batch_size).enumerate() {
+            on_progress(&Progress { stage: "embedding".into(), processed: b * batch_size, total, batch: Some(b) });
+            let params = json!({ "db": db, "file_ids": ids, "duration": embedding.clip_seconds, "dtype": embedding.storage.as_str() });
+            host.call(app, "embed_batch", params, timeout, on_progress, cancel)?;
+        }
+        on_progress(&Progress { stage: "embedding".into(), processed: total, total, batch: None });
+    }
+    if stage == "embed" { return Ok(()); }
+
+    // Every projection run lands in a fresh layout so earlier arrangements stay switchable
+    let layout = layouts::create(&db::open_or_create(dbp)?, proj)?;
+    let params = json!({
+        "db": db,
+        "method": proj.method.as_str(),
+        "perplexity": proj.perplexity,
+        "components": proj.components,
+        "n_neighbors": proj.n_neighbors,
+        "min_dist": proj.min_dist,
+        "metric": proj.metric.as_str(),
+        "seed": proj.seed,
+        "dtype": embedding.storage.as_str(),
+        "layout_id": layout,
+    });
+    let res = host.call(app, "project", params, Duration::from_secs(limits.project_timeout_secs), on_progress, cancel);
+    if res.is_err() {
+        // Don't leave an empty entry in the history
+        if let Ok(mut conn) = db::open_or_create(dbp) { let _ = layouts::delete(&mut conn, layout); }
+    }
+    res.map(|_| ())
 }
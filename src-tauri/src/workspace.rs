@@ -0,0 +1,43 @@
+//! The map view as the user left it, kept per library in `meta` so reopening the app (or
+//! switching back to a library) restores it.
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+const KEY: &str = "workspace";
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Workspace {
+    /// Camera centre in map coordinates.
+    pub camera_x: f64,
+    pub camera_y: f64,
+    pub zoom: f64,
+    /// Whatever filter state the frontend keeps; stored as given.
+    pub filters: serde_json::Value,
+    pub selection: Vec<i64>,
+    pub active_layout: Option<i64>,
+}
+
+pub fn save(conn: &Connection, ws: &Workspace) -> Result<()> {
+    conn.execute("INSERT OR REPLACE INTO meta(key, value) VALUES(?, ?)", params![KEY, serde_json::to_string(ws)?])?;
+    Ok(())
+}
+
+/// The saved workspace, minus selected files and a layout that have since been removed.
+/// `None` if nothing was saved or it no longer parses.
+pub fn load(conn: &Connection) -> Result<Option<Workspace>> {
+    let text: Option<String> = conn.query_row("SELECT value FROM meta WHERE key = ?", params![KEY], |r| r.get(0)).optional()?;
+    let Some(mut ws) = text.and_then(|t| serde_json::from_str::<Workspace>(&t).ok()) else { return Ok(None) };
+    let mut file = conn.prepare("SELECT 1 FROM files WHERE id = ?")?;
+    let mut kept = Vec::with_capacity(ws.selection.len());
+    for id in ws.selection {
+        if file.exists(params![id])? { kept.push(id); }
+    }
+    ws.selection = kept;
+    if let Some(id) = ws.active_layout {
+        let exists = conn.prepare("SELECT 1 FROM layouts WHERE id = ?")?.exists(params![id])?;
+        if !exists { ws.active_layout = None; }
+    }
+    Ok(Some(ws))
+}
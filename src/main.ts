@@ -7,7 +7,7 @@ import type { Point as MapPoint } from './scatter'
 const app = document.querySelector<HTMLDivElement>('#app')!
 const statusbar = document.querySelector<HTMLDivElement>('#statusbar')!
 
-let currentPath: string | null = null
+let currentId: number | null = null
 
 app.innerHTML = `
   <div class="toolbar">
@@ -48,14 +48,13 @@ let history: number[] = []
 let cursor = -1 // index in history; -1 means none yet
 
 async function loadCoords() {
-  const page = 50000
-  let off = 0
+  // Packed little-endian records: file_id i64, x f32, y f32 (16 bytes)
+  const buf = await invoke('get_coords_binary', {}) as ArrayBuffer
+  const ids = new BigInt64Array(buf)
+  const xy = new Float32Array(buf)
   const pts: MapPoint[] = []
-  while (true) {
-    const rows = await invoke('get_coords', { offset: off, limit: page }) as { fileId: number, x: number, y: number }[]
-    if (rows.length === 0) break
-    for (const r of rows) pts.push({ id: r.fileId, x: r.x, y: r.y })
-    off += rows.length
+  for (let i = 0; i < buf.byteLength / 16; i++) {
+    pts.push({ id: Number(ids[i * 2]), x: xy[i * 4 + 2], y: xy[i * 4 + 3] })
   }
   scatter.setPoints(pts)
   // Build id->path cache for quick play
@@ -87,7 +86,7 @@ chooseBtn.addEventListener('click', async () => {
 })
 
 revealBtn.addEventListener('click', async () => {
-  if (currentPath) await invoke('reveal_in_explorer', { path: currentPath })
+  if (currentId !== null) await invoke('reveal_in_explorer', { fileId: currentId })
 })
 
 window.addEventListener('keydown', async (e) => {
@@ -106,9 +105,9 @@ window.addEventListener('keydown', async (e) => {
             path = info.path
             idToPath.set(id, path)
           }
-          await invoke('play_file', { path })
+          await invoke('play_file', { fileId: id })
           selPathEl.textContent = `Selected: ${path}`
-          currentPath = path
+          currentId = id
           try { await invoke('copy_to_clipboard', { text: path }) } catch {}
         } catch (err) {
           console.error('replay failed', err)
@@ -169,9 +168,9 @@ scatter.onPick = (res) => {
         path = info.path
         idToPath.set(res.id, path)
       }
-      await invoke('play_file', { path })
+      await invoke('play_file', { fileId: res.id })
       selPathEl.textContent = `Selected: ${path}`
-      currentPath = path
+      currentId = res.id
       try { await invoke('copy_to_clipboard', { text: path }) } catch {}
     } catch (e) {
       console.error('pick play failed', e)
@@ -196,9 +195,9 @@ function selectFromHistory(delta: number) {
         path = info.path
         idToPath.set(id, path)
       }
-      await invoke('play_file', { path })
+      await invoke('play_file', { fileId: id })
       selPathEl.textContent = `Selected: ${path}`
-      currentPath = path
+      currentId = id
       try { await invoke('copy_to_clipboard', { text: path }) } catch {}
     } catch (e) {
       console.error('history play failed', e)